@@ -8,16 +8,20 @@
 //! RQ3: How many contracts have been deployed and destroyed in the same block but in different transactions? And how many in the same transaction?
 //! RQ4: Of the contracts that have been destroyed, for how long do they live?
 
+use crate::info;
 use bincode::{deserialize_from, serialize_into};
 use chrono::DateTime;
-use dgraph_tonic::Client;
-use futures::pin_mut;
-use futures::stream::StreamExt;
+use dgraph_tonic::{Client, Query};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::io::{BufReader, BufWriter, Read};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct Block {
@@ -293,7 +297,114 @@ fn rq_4(data: &Vec<ContractLife>, writer: &mut BufWriter<File>) {
     .unwrap();
 }
 
-pub async fn analyse_lifetimes(endpoint: &str, output_path: &str, cache_file: Option<String>) {
+const LIFETIMES_QUERY: &str = r#"query q($first: int, $offset: int) {
+      items(func: type(Contract), first: $first, offset: $offset) {
+          uid
+          dp: ~ContractDeployment.contract{
+            tx: ContractDeployment.tx_hash
+            b: ContractDeployment.block{
+              n: Block.number
+              d: Block.datetime
+            }
+          }
+          de: ~ContractDestruction.contract @filter(NOT eq(ContractDestruction.actually_deleted, false)){
+            tx: ContractDestruction.tx_hash
+            b: ContractDestruction.block{
+              n: Block.number
+              d: Block.datetime
+            }
+          }
+      }
+    }"#;
+
+/// Number of contracts fetched by every query
+const PAGE_SIZE: u64 = 100000;
+
+#[derive(Deserialize, Debug)]
+struct Count {
+    count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct CountResult {
+    q: Vec<Count>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ItemsResult {
+    items: Vec<ContractLife>,
+}
+
+/// Fetch the deployments and destructions of all the contracts from Dgraph.
+/// The contracts are split in `num_jobs` offset windows of the same size,
+/// each window is paginated by a separate read-only transaction running concurrently.
+async fn fetch_contract_lives(endpoint: &str, num_jobs: usize) -> Vec<ContractLife> {
+    let client = Arc::new(Client::new(endpoint).expect("Dgraph client"));
+
+    let resp = client
+        .new_read_only_txn()
+        .query("{ q(func: type(Contract)) { count(uid) } }")
+        .await
+        .expect("Count query failed");
+    let total = serde_json::from_slice::<CountResult>(&resp.json)
+        .expect("Unexpected count response")
+        .q
+        .first()
+        .map(|c| c.count)
+        .unwrap_or_default();
+    info!("Fetching {} contracts with {} jobs", total, num_jobs);
+
+    let window = total.div_ceil(num_jobs as u64);
+    let loaded = Arc::new(AtomicU64::new(0));
+
+    let handles: Vec<_> = (0..num_jobs as u64)
+        .map(|i| {
+            let client = client.clone();
+            let loaded = loaded.clone();
+            tokio::spawn(async move {
+                let end = total.min((i + 1) * window);
+                let mut offset = i * window;
+                let mut txn = client.new_read_only_txn();
+                let mut contract_lives = Vec::new();
+                while offset < end {
+                    let first = PAGE_SIZE.min(end - offset);
+                    let mut vars = HashMap::new();
+                    vars.insert("$first", first.to_string());
+                    vars.insert("$offset", offset.to_string());
+                    let resp = txn
+                        .query_with_vars(LIFETIMES_QUERY, vars)
+                        .await
+                        .expect("Lifetimes query failed");
+                    let items = serde_json::from_slice::<ItemsResult>(&resp.json)
+                        .expect("Unexpected lifetimes response")
+                        .items;
+                    let fetched = items.len() as u64;
+                    contract_lives.extend(items);
+                    let tot = loaded.fetch_add(fetched, Ordering::Relaxed) + fetched;
+                    info!("Loaded {} contracts.", tot);
+                    if fetched < first {
+                        break;
+                    }
+                    offset += first;
+                }
+                contract_lives
+            })
+        })
+        .collect();
+
+    let mut contract_lives = Vec::with_capacity(total as usize);
+    for handle in handles {
+        contract_lives.extend(handle.await.expect("Fetch task failed"));
+    }
+    contract_lives
+}
+
+pub async fn analyse_lifetimes(
+    endpoint: &str,
+    output_path: &str,
+    cache_file: Option<String>,
+    num_jobs: usize,
+) {
     if !std::path::Path::new(output_path).exists() {
         std::fs::create_dir_all(output_path).unwrap();
     }
@@ -303,9 +414,9 @@ pub async fn analyse_lifetimes(endpoint: &str, output_path: &str, cache_file: Op
 
     let contract_lives =
         if cache_file.is_some() && std::path::Path::new(cache_file.as_ref().unwrap()).exists() {
-            println!("Loading data from cache file...");
+            info!("Loading data from cache file...");
             let data = load_binary(cache_file.as_ref().unwrap()).unwrap();
-            println!(
+            info!(
                 "Loaded {} contracts from cache in {:?}",
                 data.len(),
                 now.elapsed()
@@ -317,44 +428,7 @@ pub async fn analyse_lifetimes(endpoint: &str, output_path: &str, cache_file: Op
                 "Cache file not found, starting extraction from dgraph."
             )
             .unwrap();
-            let query = r#"query stream($first: string, $offset: string) {
-      items(func: type(Contract), first: $first, offset: $offset) {
-          uid
-          dp: ~ContractDeployment.contract{
-            tx: ContractDeployment.tx_hash
-            b: ContractDeployment.block{
-              n: Block.number
-              d: Block.datetime
-            }
-          }
-          de: ~ContractDestruction.contract{
-            tx: ContractDestruction.tx_hash
-            b: ContractDestruction.block{
-              n: Block.number
-              d: Block.datetime
-            }
-          }
-      }
-    }"#;
-            let client = Client::new(endpoint).expect("Dgraph client");
-            let stream = client
-                .new_read_only_txn()
-                .into_stream::<&str, ContractLife>(query, 1000000);
-            pin_mut!(stream);
-            let mut contract_lives: Vec<ContractLife> = Vec::new();
-            while let Some(contract_life) = stream.next().await {
-                match contract_life {
-                    Ok(contract_life) => {
-                        contract_lives.push(contract_life);
-                    }
-                    Err(e) => {
-                        writeln!(&mut writer, "Error: {:?}", e).unwrap();
-                    }
-                }
-                if contract_lives.len() % 1000000 == 0 {
-                    writeln!(&mut writer, "Loaded {} contracts.", contract_lives.len()).unwrap();
-                }
-            }
+            let contract_lives = fetch_contract_lives(endpoint, num_jobs).await;
             if cache_file.is_some() {
                 // store data in binary file
                 writeln!(&mut writer, "Storing data in binary file...").unwrap();
@@ -363,14 +437,15 @@ pub async fn analyse_lifetimes(endpoint: &str, output_path: &str, cache_file: Op
             }
             writeln!(
                 &mut writer,
-                "Loaded data from Dgraph in {:?}",
-                now.elapsed()
+                "Loaded data from Dgraph in {:?} using {} jobs",
+                now.elapsed(),
+                num_jobs
             )
             .unwrap();
             contract_lives
         };
 
-    println!("Number of contracts: {}", contract_lives.len());
+    info!("Number of contracts: {}", contract_lives.len());
 
     let (send, recv) = tokio::sync::oneshot::channel();
 
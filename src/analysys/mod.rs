@@ -1,2 +1,5 @@
 pub mod lifetimes;
+pub mod signatures;
 pub mod similarities;
+pub mod similarity_eval;
+pub mod verify;
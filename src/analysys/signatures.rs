@@ -0,0 +1,208 @@
+//! Export of the function and event names resolved by the decompiler, as a local signature
+//! database in the style of 4byte.directory.
+//! Every row of the CSV is a `selector,name,inputs,count` tuple, where `count` is the number of
+//! skeletons exposing the signature: the same selector can map to several names because of
+//! collisions, the most used name comes first.
+
+use crate::info;
+use dgraph_tonic::{Client, Query};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const FUNCTIONS_QUERY: &str = r#"query q($first: int, $offset: int) {
+    q(func: type(Function), first: $first, offset: $offset) {
+      selector: Function.bytes4
+      name: Function.name
+      inputs: Function.inputs
+      count: count(~Skeleton.functions)
+    }
+  }"#;
+
+const EVENTS_QUERY: &str = r#"query q($first: int, $offset: int) {
+    q(func: type(Event), first: $first, offset: $offset) {
+      selector: Event.signature
+      name: Event.name
+      inputs: Event.inputs
+      count: count(~Skeleton.events)
+    }
+  }"#;
+
+/// Number of signatures fetched by every query
+const PAGE_SIZE: usize = 10000;
+
+#[derive(Deserialize, Debug)]
+struct Signature {
+    selector: Option<String>,
+    name: Option<String>,
+    inputs: Option<String>,
+    #[serde(default)]
+    count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryResult {
+    q: Vec<Signature>,
+}
+
+/// Skeletons using each (selector, name, inputs), duplicated nodes are merged
+type SignatureCounts = BTreeMap<(String, String, String), u64>;
+
+/// Names generated by the decompiler for the selectors it couldn't resolve
+fn is_unresolved(name: &str) -> bool {
+    name.starts_with("Unresolved_") || name.starts_with("Event_")
+}
+
+/// Add a page of signatures to the counts, skipping the unresolved ones.
+/// Function selectors are stored without prefix, they're written as `0x` and 4 bytes
+/// like the event ones
+fn add_signatures(counts: &mut SignatureCounts, page: Vec<Signature>) {
+    for signature in page {
+        let (Some(selector), Some(name)) = (signature.selector, signature.name) else {
+            continue;
+        };
+        if is_unresolved(&name) {
+            continue;
+        }
+        let selector = if selector.starts_with("0x") {
+            selector
+        } else {
+            format!("0x{}", selector)
+        };
+        let key = (selector, name, signature.inputs.unwrap_or_default());
+        *counts.entry(key).or_default() += signature.count;
+    }
+}
+
+/// Quote a CSV field if it contains separators, e.g. the comma separated inputs
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write the CSV, sorted by selector and, for the same selector, by decreasing count
+fn write_csv<W: Write>(counts: SignatureCounts, writer: &mut W) -> std::io::Result<()> {
+    let mut rows = counts.into_iter().collect::<Vec<_>>();
+    rows.sort_by(|((a, ..), a_count), ((b, ..), b_count)| a.cmp(b).then(b_count.cmp(a_count)));
+    writeln!(writer, "selector,name,inputs,count")?;
+    for ((selector, name, inputs), count) in rows {
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            selector,
+            csv_field(&name),
+            csv_field(&inputs),
+            count
+        )?;
+    }
+    Ok(())
+}
+
+/// Stream all the `Function` and `Event` nodes from Dgraph and write the resolved ones
+/// to `output_file` as a signature database.
+/// @param endpoint The Dgraph GRPC endpoint
+/// @param output_file The CSV file to write
+pub async fn export_signatures(
+    endpoint: &str,
+    output_file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = std::time::Instant::now();
+    let client = Client::new(endpoint).expect("Dgraph client");
+    let mut txn = client.new_read_only_txn();
+    let mut counts = SignatureCounts::new();
+    for query in [FUNCTIONS_QUERY, EVENTS_QUERY] {
+        let mut offset = 0;
+        loop {
+            let mut vars = HashMap::new();
+            vars.insert("$first", PAGE_SIZE.to_string());
+            vars.insert("$offset", offset.to_string());
+            let resp = txn.query_with_vars(query, vars).await?;
+            let page = serde_json::from_slice::<QueryResult>(&resp.json)?.q;
+            let done = page.len() < PAGE_SIZE;
+            offset += page.len();
+            add_signatures(&mut counts, page);
+            info!("Loaded {} signatures", offset);
+            if done {
+                break;
+            }
+        }
+    }
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    let exported = counts.len();
+    write_csv(counts, &mut writer)?;
+    writer.flush()?;
+    info!(
+        "Exported {} resolved signatures to {} in {:?}",
+        exported,
+        output_file,
+        now.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(selector: &str, name: &str, inputs: &str, count: u64) -> Signature {
+        Signature {
+            selector: Some(selector.to_string()),
+            name: Some(name.to_string()),
+            inputs: Some(inputs.to_string()),
+            count,
+        }
+    }
+
+    #[test]
+    fn test_signatures_csv() {
+        let mut counts = SignatureCounts::new();
+        add_signatures(
+            &mut counts,
+            vec![
+                signature("a9059cbb", "transfer", "address,uint256", 3),
+                signature("12345678", "Unresolved_12345678", "", 7),
+                signature("095ea7b3", "approve", "address,uint256", 2),
+                signature("a9059cbb", "many_msg_babbage", "bytes1", 1),
+            ],
+        );
+        // the same signature stored twice, e.g. by two streams
+        add_signatures(
+            &mut counts,
+            vec![
+                signature("a9059cbb", "transfer", "address,uint256", 2),
+                signature(
+                    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+                    "Transfer",
+                    "address,address,uint256",
+                    4,
+                ),
+                signature("0xc0d5", "Event_c0d5", "address", 1),
+            ],
+        );
+        let mut csv = Vec::new();
+        write_csv(counts, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            r#"selector,name,inputs,count
+0x095ea7b3,approve,"address,uint256",2
+0xa9059cbb,transfer,"address,uint256",5
+0xa9059cbb,many_msg_babbage,bytes1,1
+0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef,Transfer,"address,address,uint256",4
+"#
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_export_signatures() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let output = std::env::temp_dir().join("eth2dgraph_signatures.csv");
+        export_signatures(&dgraph_endpoint, output.to_str().unwrap())
+            .await
+            .unwrap();
+    }
+}
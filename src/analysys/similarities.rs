@@ -1,21 +1,229 @@
-use dgraph_tonic::{Client, Query};
+use crate::info;
+use crate::utils::minhash::{decode_signature, minhash, minhash_similarity};
+use dgraph_tonic::{Client, Mutate, Mutation, Query};
 use ethabi::Address;
 use ethers::types::Bytes;
+use futures::StreamExt;
 use rayon::prelude::*;
 use serde::Deserialize;
 use serde_json::Value;
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
-    io::{LineWriter, Write},
+    fs::{File, OpenOptions},
+    io::{LineWriter, Read, Write},
     str::FromStr,
     sync::{Arc, Mutex},
     time::Instant,
 };
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+/// Where the similarity edges are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SimilaritySink {
+    /// N-Quads file, to be loaded with the live loader
+    File,
+    /// Edges set directly in Dgraph, between the skeleton uids
+    Dgraph,
+}
+
+/// Metrics and corpus options of `find_similar_skeletons`
+#[derive(Debug, Clone, Copy)]
+pub struct SimilarityOptions {
+    /// Compare the interfaces, storing the pairs at or above `interface_threshold`
+    pub interface_sim: bool,
+    pub interface_threshold: f64,
+    /// Compare the bytecode n-grams, storing the pairs at or above `cosine_threshold`
+    pub cosine_sim: bool,
+    pub cosine_threshold: f64,
+    pub ngram_length: u8,
+    /// Only compare the skeletons deployed since this block, with the whole corpus
+    pub since_block: Option<u64>,
+    /// Size of the MinHash signatures prefiltering the interface comparisons
+    pub minhash_size: Option<usize>,
+    /// Skeletons loaded with each query
+    pub page_size: usize,
+    /// Targets compared at the same time
+    pub target_jobs: usize,
+}
+
+/// Number of edges set in Dgraph with each mutation by the dgraph sink
+const EDGES_PER_MUTATION: usize = 1000;
+
+/// Item sent to the task of the dgraph sink
+enum SinkItem {
+    /// Edge, as N-Quad
+    Edge(String),
+    /// Skeleton whose comparisons are over, checkpointed once its edges are stored
+    Done(String),
+}
+
+/// Output of the similarity edges, shared by the comparison threads.
+/// The dgraph sink hands the edges to a Tokio task storing them in batches
+enum EdgeSink {
+    File {
+        file: Mutex<LineWriter<File>>,
+        checkpoint: Option<Mutex<LineWriter<File>>>,
+    },
+    Dgraph(UnboundedSender<SinkItem>),
+}
+
+impl EdgeSink {
+    /// Open the sink. The dgraph sink also returns the task storing the edges,
+    /// it ends with the number of stored edges once every reference to the sink is dropped.
+    /// With a checkpoint the output file is appended to, to resume the previous run
+    fn open(
+        sink: SimilaritySink,
+        output_path: Option<&str>,
+        endpoint: &str,
+        checkpoint: Option<&str>,
+    ) -> (Arc<Self>, Option<JoinHandle<Result<usize, String>>>) {
+        let checkpoint = checkpoint.map(|path| open_checkpoint(path).expect("Checkpoint file"));
+        match sink {
+            SimilaritySink::File => {
+                let output_path = output_path.expect("Output file");
+                let file = match checkpoint {
+                    Some(_) => OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(output_path)
+                        .unwrap(),
+                    None => File::create(output_path).unwrap(),
+                };
+                let file = Mutex::new(LineWriter::new(file));
+                let checkpoint = checkpoint.map(Mutex::new);
+                (Arc::new(Self::File { file, checkpoint }), None)
+            }
+            SimilaritySink::Dgraph => {
+                let (send, recv) = unbounded_channel();
+                let endpoint = endpoint.to_string();
+                let handle =
+                    tokio::spawn(async move { store_edges(&endpoint, recv, checkpoint).await });
+                (Arc::new(Self::Dgraph(send)), Some(handle))
+            }
+        }
+    }
+
+    /// Store an edge, given as N-Quad
+    fn edge(&self, nquad: String) {
+        match self {
+            Self::File { file, .. } => writeln!(file.lock().unwrap(), "{}", nquad).unwrap(),
+            // if the task failed the error is reported once the comparison is over
+            Self::Dgraph(send) => {
+                let _ = send.send(SinkItem::Edge(nquad));
+            }
+        }
+    }
+
+    /// Comment line, only written by the file sink
+    fn comment(&self, comment: &str) {
+        if let Self::File { file, .. } = self {
+            writeln!(file.lock().unwrap(), "# {}", comment).unwrap();
+        }
+    }
+
+    /// Mark a skeleton as compared with all the following ones, after all its edges.
+    /// The file sink writes the edges line by line, so the uid is checkpointed right away
+    fn done(&self, uid: &str) {
+        match self {
+            Self::File {
+                checkpoint: Some(checkpoint),
+                ..
+            } => writeln!(checkpoint.lock().unwrap(), "{}", uid).unwrap(),
+            Self::File { .. } => {}
+            Self::Dgraph(send) => {
+                let _ = send.send(SinkItem::Done(uid.to_string()));
+            }
+        }
+    }
+}
+
+/// Open the checkpoint to append the uids of the skeletons processed by this run.
+/// A run killed while writing can leave a partial line, it's terminated so the next uid
+/// isn't glued to it
+fn open_checkpoint(path: &str) -> std::io::Result<LineWriter<File>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    if content.last().is_some_and(|b| *b != b'\n') {
+        file.write_all(b"\n")?;
+    }
+    Ok(LineWriter::new(file))
+}
+
+/// Uids of the skeletons already compared with all the following ones by the previous runs,
+/// empty if the checkpoint doesn't exist yet
+fn load_checkpoint(path: &str) -> std::io::Result<HashSet<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Set the edges received from the comparison threads in Dgraph, EDGES_PER_MUTATION at a time.
+/// Edges between two uids are a set in Dgraph: setting an existing edge again only updates
+/// its similarity facet, so edges are not duplicated on re-run.
+/// The skeletons done are written to the checkpoint once the batch with their last edges
+/// has been committed
+async fn store_edges(
+    endpoint: &str,
+    mut items: UnboundedReceiver<SinkItem>,
+    mut checkpoint: Option<LineWriter<File>>,
+) -> Result<usize, String> {
+    let client = Client::new(endpoint).map_err(|e| e.to_string())?;
+    let mut stored = 0;
+    let mut batch = Vec::with_capacity(EDGES_PER_MUTATION);
+    let mut done_uids = Vec::new();
+    loop {
+        let item = items.recv().await;
+        let done = item.is_none();
+        match item {
+            Some(SinkItem::Edge(edge)) => batch.push(edge),
+            Some(SinkItem::Done(uid)) if checkpoint.is_some() => done_uids.push(uid),
+            Some(SinkItem::Done(_)) => {}
+            None => {}
+        }
+        if batch.len() >= EDGES_PER_MUTATION || (done && !batch.is_empty()) {
+            let mut mu = Mutation::new();
+            mu.set_set_nquads(batch.join("\n"));
+            let mut txn = client.new_mutated_txn();
+            txn.mutate(mu).await.map_err(|e| e.to_string())?;
+            txn.commit().await.map_err(|e| e.to_string())?;
+            stored += batch.len();
+            batch.clear();
+        }
+        // the uids received after the last commit wait for the batch of their edges
+        if batch.is_empty() {
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                for uid in done_uids.drain(..) {
+                    writeln!(checkpoint, "{}", uid).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    Ok(stored)
+}
 
 #[derive(Debug, Clone)]
-struct Skeleton {
+pub(super) struct Skeleton {
     interface: HashSet<String>,
+    /// MinHash signature of the interface, used instead of `interface` when set
+    minhash: Option<Vec<u64>>,
     ngrams: HashMap<Vec<u8>, usize>,
 }
 
@@ -31,6 +239,7 @@ struct QueryItem {
     functions: Option<Vec<Named>>,
     events: Option<Vec<Named>>,
     errors: Option<Vec<Named>>,
+    minhash: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -42,7 +251,7 @@ struct QueryResult {
 /// @param endpoint The Dgraph GRPC endpoint
 /// @param address The contract address
 /// @return The skeleton uid of the address
-async fn get_skeleton_from_address(
+pub(super) async fn get_skeleton_from_address(
     endpoint: &str,
     address: &Address,
     ngram_length: u8,
@@ -102,7 +311,11 @@ async fn get_skeleton_from_address(
         interface.insert(error.name);
     }
     let ngrams = bytecode2ngrams(&Bytes::from_str(&item.bytecode.unwrap())?, ngram_length);
-    let skeleton = Skeleton { interface, ngrams };
+    let skeleton = Skeleton {
+        interface,
+        minhash: None,
+        ngrams,
+    };
     Ok(Some((uid.to_string(), skeleton)))
 }
 
@@ -142,14 +355,54 @@ fn bytecode2ngrams(bytecode: &[u8], n: u8) -> HashMap<Vec<u8>, usize> {
     ngrams
 }
 
-/// Load all skeletons from the database
+/// Cursor of a query paginated by uid: each page starts after the last uid of the previous one,
+/// so deep pages cost as much as the first, unlike with an offset
+struct UidCursor {
+    page_size: usize,
+    after: Option<String>,
+    done: bool,
+}
+
+impl UidCursor {
+    fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            after: None,
+            done: false,
+        }
+    }
+
+    /// Variables `$first` and `$after` of the next page, `None` once the last page was read
+    fn next_vars(&self) -> Option<HashMap<&'static str, String>> {
+        if self.done {
+            return None;
+        }
+        let mut vars = HashMap::new();
+        vars.insert("$first", self.page_size.to_string());
+        vars.insert("$after", self.after.clone().unwrap_or("0x0".to_string()));
+        Some(vars)
+    }
+
+    /// Move past a page with `len` results, a partial page is the last one
+    fn advance(&mut self, len: usize, last_uid: Option<&str>) {
+        match last_uid {
+            Some(uid) if len >= self.page_size => self.after = Some(uid.to_string()),
+            _ => self.done = true,
+        }
+    }
+}
+
+/// Load all skeletons from the database, `page_size` at a time.
+/// With `minhash_size`, the interface of the skeletons with a stored MinHash signature
+/// is loaded as signature, and the ABI is loaded only for the others
 async fn load_skeletons(
     endpoint: &str,
     ngram_length: u8,
+    minhash_size: Option<usize>,
+    page_size: usize,
 ) -> Result<HashMap<String, Skeleton>, Box<dyn std::error::Error>> {
     let now = Instant::now();
-    let query = r#"query q($first: int, $offset: int) {
-    q(func: type(Skeleton), first: $first, offset: $offset) {
+    let with_abi = r#"
       uid
       bytecode: Skeleton.bytecode
       functions: Skeleton.functions{
@@ -160,29 +413,45 @@ async fn load_skeletons(
       }
       errors: Skeleton.errors{
         name: Error.name
-      }
+      }"#;
+    let queries = match minhash_size {
+        Some(_) => vec![
+            r#"query q($first: int, $after: string) {
+    q(func: type(Skeleton), first: $first, after: $after) @filter(has(Skeleton.minhash)) {
+      uid
+      bytecode: Skeleton.bytecode
+      minhash: Skeleton.minhash
     }
-  }"#;
+  }"#
+            .to_string(),
+            format!(
+                r#"query q($first: int, $after: string) {{
+    q(func: type(Skeleton), first: $first, after: $after) @filter(not has(Skeleton.minhash)) {{{with_abi}
+    }}
+  }}"#
+            ),
+        ],
+        None => vec![format!(
+            r#"query q($first: int, $after: string) {{
+    q(func: type(Skeleton), first: $first, after: $after) {{{with_abi}
+    }}
+  }}"#
+        )],
+    };
     let client = Client::new(endpoint).expect("Dgraph client");
     let mut txn = client.new_read_only_txn();
     let mut raw_skeletons = Vec::new();
-    let window = 1000;
-    let mut offset = 0;
-    loop {
-        let mut vars = HashMap::new();
-        vars.insert("$first", window.to_string());
-        vars.insert("$offset", offset.to_string());
-        let resp = txn.query_with_vars(query, vars).await?;
-        let data: QueryResult = serde_json::from_slice(&resp.json)?;
-        let done = data.q.len() < 1000;
-        raw_skeletons.extend(data.q);
-        println!("Loaded {} skeletons", raw_skeletons.len());
-        if done {
-            break;
+    for query in queries {
+        let mut cursor = UidCursor::new(page_size);
+        while let Some(vars) = cursor.next_vars() {
+            let resp = txn.query_with_vars(&query, vars).await?;
+            let data: QueryResult = serde_json::from_slice(&resp.json)?;
+            cursor.advance(data.q.len(), data.q.last().map(|item| item.uid.as_str()));
+            raw_skeletons.extend(data.q);
+            info!("Loaded {} skeletons", raw_skeletons.len());
         }
-        offset += 1000;
     }
-    println!(
+    info!(
         "Loaded {} skeletons in {:?}",
         raw_skeletons.len(),
         now.elapsed()
@@ -201,10 +470,23 @@ async fn load_skeletons(
             interface.insert(error.name);
         }
         let ngrams = bytecode2ngrams(&Bytes::from_str(&item.bytecode.unwrap())?, ngram_length);
-        let skeleton = Skeleton { interface, ngrams };
+        let stored = item.minhash.as_deref().and_then(decode_signature);
+        let minhash = match (minhash_size, stored) {
+            (Some(size), Some(mut signature)) => {
+                signature.truncate(size);
+                Some(signature)
+            }
+            (Some(size), None) => Some(minhash(&interface, size)),
+            (None, _) => None,
+        };
+        let skeleton = Skeleton {
+            interface,
+            minhash,
+            ngrams,
+        };
         result.insert(item.uid, skeleton);
     }
-    println!(
+    info!(
         "Processed {} skeletons in {:?}",
         result.len(),
         now.elapsed()
@@ -212,6 +494,115 @@ async fn load_skeletons(
     Ok(result)
 }
 
+/// Similarity edges already stored in Dgraph for a skeleton, as uids of the other skeletons
+#[derive(Debug, Default, Clone)]
+struct StoredEdges {
+    interface: HashSet<String>,
+    code: HashSet<String>,
+}
+
+/// Load the skeletons deployed from `since_block` on, with the similarity edges they already have.
+/// Skeletons first deployed before `since_block` but deployed again later are included too.
+async fn load_new_skeletons(
+    endpoint: &str,
+    since_block: u64,
+) -> Result<HashMap<String, StoredEdges>, Box<dyn std::error::Error>> {
+    #[derive(Deserialize, Debug)]
+    struct Uid {
+        uid: String,
+    }
+    #[derive(Deserialize, Debug)]
+    struct SkeletonItem {
+        uid: String,
+        si: Option<Vec<Uid>>,
+        sc: Option<Vec<Uid>>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct DeploymentItem {
+        s: Option<SkeletonItem>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct BlockItem {
+        d: Option<Vec<DeploymentItem>>,
+    }
+    #[derive(Deserialize, Debug)]
+    struct BlocksResult {
+        q: Vec<BlockItem>,
+    }
+
+    let query = r#"query q($since: int, $first: int, $offset: int) {
+    q(func: ge(Block.number, $since), first: $first, offset: $offset) @filter(has(~ContractDeployment.block)) {
+      d: ~ContractDeployment.block {
+        s: ContractDeployment.skeleton {
+          uid
+          si: Skeleton.similar_interface {
+            uid
+          }
+          sc: Skeleton.similar_code {
+            uid
+          }
+        }
+      }
+    }
+  }"#;
+    let client = Client::new(endpoint).expect("Dgraph client");
+    let mut txn = client.new_read_only_txn();
+    let mut result: HashMap<String, StoredEdges> = HashMap::new();
+    let window = 1000;
+    let mut offset = 0;
+    loop {
+        let mut vars = HashMap::new();
+        vars.insert("$since", since_block.to_string());
+        vars.insert("$first", window.to_string());
+        vars.insert("$offset", offset.to_string());
+        let resp = txn.query_with_vars(query, vars).await?;
+        let data: BlocksResult = serde_json::from_slice(&resp.json)?;
+        let done = data.q.len() < window;
+        for deployment in data.q.into_iter().flat_map(|b| b.d.unwrap_or_default()) {
+            if let Some(skeleton) = deployment.s {
+                let edges = result.entry(skeleton.uid).or_default();
+                edges
+                    .interface
+                    .extend(skeleton.si.unwrap_or_default().into_iter().map(|u| u.uid));
+                edges
+                    .code
+                    .extend(skeleton.sc.unwrap_or_default().into_iter().map(|u| u.uid));
+            }
+        }
+        if done {
+            break;
+        }
+        offset += window;
+    }
+    info!(
+        "Found {} skeletons deployed since block {}",
+        result.len(),
+        since_block
+    );
+    Ok(result)
+}
+
+/// In incremental mode only pairs with at least one new skeleton are compared,
+/// pairs of old skeletons were compared by the previous runs
+fn is_new_pair(a: &str, b: &str, new: &HashMap<String, StoredEdges>) -> bool {
+    new.contains_key(a) || new.contains_key(b)
+}
+
+/// Whether the interface (`code` false) or code (`code` true) similarity edge between
+/// two skeletons is already stored, looking at the edges of the new one of the pair
+fn has_stored_edge(a: &str, b: &str, code: bool, new: &HashMap<String, StoredEdges>) -> bool {
+    let stored = |from: &str, to: &str| {
+        new.get(from).is_some_and(|edges| {
+            if code {
+                edges.code.contains(to)
+            } else {
+                edges.interface.contains(to)
+            }
+        })
+    };
+    stored(a, b) || stored(b, a)
+}
+
 /// Calculate the cosine similarity between two bytecodes, without normalizing the vectors
 fn cosine_similarity(a: &HashMap<Vec<u8>, usize>, b: &HashMap<Vec<u8>, usize>) -> f64 {
     let (shortest, longest) = if a.len() < b.len() { (a, b) } else { (b, a) };
@@ -237,72 +628,121 @@ fn interface_similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
     a.intersection(b).count() as f64 / a.union(b).count() as f64
 }
 
+/// Interface similarity of two skeletons, estimated from the MinHash signatures if both have one
+fn skeleton_interface_similarity(a: &Skeleton, b: &Skeleton) -> f64 {
+    match (&a.minhash, &b.minhash) {
+        (Some(a), Some(b)) => minhash_similarity(a, b),
+        _ => interface_similarity(&a.interface, &b.interface),
+    }
+}
+
+/// Interface and cosine similarity of two skeletons, as compared against the thresholds
+pub(super) fn skeleton_similarities(a: &Skeleton, b: &Skeleton) -> (f64, f64) {
+    (
+        skeleton_interface_similarity(a, b),
+        cosine_similarity(&a.ngrams, &b.ngrams),
+    )
+}
+
+/// Compare in parallel each skeleton with the ones following it in `uids`, calling `compare`
+/// on each pair, and mark it as done in the sink once all its pairs are compared.
+/// The skeletons in `processed` were done by a previous run and are skipped: `uids` must be
+/// in the same order as in that run, for them to have been compared with the same skeletons
+fn compare_corpus<F>(uids: &[String], processed: &HashSet<String>, sink: &EdgeSink, compare: F)
+where
+    F: Fn(usize, usize) + Sync,
+{
+    uids.par_iter().enumerate().for_each(|(i, uid)| {
+        if processed.contains(uid) {
+            return;
+        }
+        for j in i + 1..uids.len() {
+            compare(i, j);
+        }
+        sink.done(uid);
+        info!("Processed skeleton: {} with index: {}", uid, i);
+    });
+}
+
 async fn compare_all(
     endpoint: &str,
-    output_path: &str,
-    interface_sim: bool,
-    interface_threshold: f64,
-    cosine_sim: bool,
-    cosine_threshold: f64,
-    ngram_length: u8,
+    sink: Arc<EdgeSink>,
+    options: SimilarityOptions,
+    checkpoint: Option<&str>,
 ) {
-    let skeletons = load_skeletons(endpoint, ngram_length).await.unwrap();
+    let SimilarityOptions {
+        interface_sim,
+        interface_threshold,
+        cosine_sim,
+        cosine_threshold,
+        ngram_length,
+        since_block,
+        minhash_size,
+        page_size,
+        ..
+    } = options;
+    let new_skeletons = match since_block {
+        Some(since_block) => Some(load_new_skeletons(endpoint, since_block).await.unwrap()),
+        None => None,
+    };
+    let processed = match checkpoint {
+        Some(path) => load_checkpoint(path).expect("Checkpoint file"),
+        None => HashSet::new(),
+    };
+    if !processed.is_empty() {
+        info!(
+            "Resuming from the checkpoint, {} skeletons already processed",
+            processed.len()
+        );
+    }
+    let skeletons = load_skeletons(endpoint, ngram_length, minhash_size, page_size)
+        .await
+        .unwrap();
     let (send, recv) = tokio::sync::oneshot::channel();
-    let uids = skeletons.keys().cloned().collect::<Vec<String>>();
-    // create output file
-    let file = File::create(output_path).unwrap();
-    let file = Arc::new(Mutex::new(LineWriter::new(file)));
+    // sorted, so a resumed run compares each skeleton with the same ones
+    let mut uids = skeletons.keys().cloned().collect::<Vec<String>>();
+    uids.sort();
     // If no target address is specified, compute similarity between all skeletons
     rayon::spawn(move || {
         // Compute in parallel the similarity of each skeleton with all other skeletons.
-        uids.par_iter().enumerate().for_each(|(i, uid)| {
+        compare_corpus(&uids, &processed, &sink, |i, j| {
             // compute similarity of uid[i] with uids[i+1..]
-            let skeleton = &skeletons[uid];
-            let file = file.clone();
-            skeletons
-                .iter()
-                .skip(i + 1)
-                .for_each(|(other_uid, other_skeleton)| {
-                    if interface_sim {
-                        let interface_similarity =
-                            interface_similarity(&skeleton.interface, &other_skeleton.interface);
-                        if interface_similarity >= interface_threshold {
-                            let mut file = file.lock().unwrap();
-                            write!(
-                                file,
-                                "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .\n",
-                                uid, other_uid, interface_similarity
-                            )
-                            .unwrap();
-                            write!(
-                                file,
-                                "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .\n",
-                                other_uid, uid, interface_similarity
-                            )
-                            .unwrap();
-                        }
-                    }
-                    if cosine_sim {
-                        let ngram_similarity =
-                            cosine_similarity(&skeleton.ngrams, &other_skeleton.ngrams);
-                        if ngram_similarity >= cosine_threshold {
-                            let mut file = file.lock().unwrap();
-                            write!(
-                                file,
-                                "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .\n",
-                                uid, other_uid, ngram_similarity
-                            )
-                            .unwrap();
-                            write!(
-                                file,
-                                "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .\n",
-                                other_uid, uid, ngram_similarity
-                            )
-                            .unwrap();
-                        }
-                    }
-                });
-            println!("Processed skeleton: {} with index: {}", uid, i);
+            let (uid, other_uid) = (&uids[i], &uids[j]);
+            let (skeleton, other_skeleton) = (&skeletons[uid], &skeletons[other_uid]);
+            // with --since-block, skip the pairs compared by the previous runs
+            // and the edges already stored
+            let skip_edge = |code: bool| match &new_skeletons {
+                Some(new) => {
+                    !is_new_pair(uid, other_uid, new) || has_stored_edge(uid, other_uid, code, new)
+                }
+                None => false,
+            };
+            if interface_sim && !skip_edge(false) {
+                let interface_similarity = skeleton_interface_similarity(skeleton, other_skeleton);
+                if interface_similarity >= interface_threshold {
+                    sink.edge(format!(
+                        "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .",
+                        uid, other_uid, interface_similarity
+                    ));
+                    sink.edge(format!(
+                        "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .",
+                        other_uid, uid, interface_similarity
+                    ));
+                }
+            }
+            if cosine_sim && !skip_edge(true) {
+                let ngram_similarity = cosine_similarity(&skeleton.ngrams, &other_skeleton.ngrams);
+                if ngram_similarity >= cosine_threshold {
+                    sink.edge(format!(
+                        "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .",
+                        uid, other_uid, ngram_similarity
+                    ));
+                    sink.edge(format!(
+                        "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .",
+                        other_uid, uid, ngram_similarity
+                    ));
+                }
+            }
         });
         // Send the result back to Tokio.
         let _ = send.send(());
@@ -311,27 +751,162 @@ async fn compare_all(
     recv.await.expect("Panic in rayon::spawn");
 }
 
+/// Similarity edges of a target skeleton with every other skeleton of the corpus,
+/// as N-Quads in both directions
+fn target_similarities(
+    target_uid: &str,
+    target: &Skeleton,
+    corpus: &HashMap<String, Skeleton>,
+    options: &SimilarityOptions,
+) -> Vec<String> {
+    let SimilarityOptions {
+        interface_sim,
+        interface_threshold,
+        cosine_sim,
+        cosine_threshold,
+        ..
+    } = *options;
+    let mut edges = Vec::new();
+    for (uid, skeleton) in corpus {
+        if uid == target_uid {
+            continue;
+        }
+        if interface_sim {
+            let similarity = skeleton_interface_similarity(target, skeleton);
+            if similarity >= interface_threshold {
+                for (from, to) in [(target_uid, uid.as_str()), (uid.as_str(), target_uid)] {
+                    edges.push(format!(
+                        "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .",
+                        from, to, similarity
+                    ));
+                }
+            }
+        }
+        if cosine_sim {
+            let similarity = cosine_similarity(&target.ngrams, &skeleton.ngrams);
+            if similarity >= cosine_threshold {
+                for (from, to) in [(target_uid, uid.as_str()), (uid.as_str(), target_uid)] {
+                    edges.push(format!(
+                        "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .",
+                        from, to, similarity
+                    ));
+                }
+            }
+        }
+    }
+    edges.sort();
+    edges
+}
+
+/// Compare every target with the corpus, `target_jobs` targets at a time.
+/// Returns the edges of each target, in the order of the targets
+fn compare_targets_with_corpus(
+    targets: &[(Address, String, Skeleton)],
+    corpus: &HashMap<String, Skeleton>,
+    options: &SimilarityOptions,
+) -> Vec<Vec<String>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.target_jobs)
+        .build()
+        .expect("Thread pool");
+    pool.install(|| {
+        targets
+            .par_iter()
+            .map(|(address, uid, skeleton)| {
+                let edges = target_similarities(uid, skeleton, corpus, options);
+                info!("Processed {:?}: {} edges", address, edges.len());
+                edges
+            })
+            .collect()
+    })
+}
+
+/// Compute the similarities of a list of addresses against all the skeletons,
+/// loading the corpus once. Output is grouped by target address, in the file sink each
+/// group starts with a `# <address> <skeleton uid>` comment line
+async fn compare_targets(
+    endpoint: &str,
+    sink: Arc<EdgeSink>,
+    addresses: HashSet<Address>,
+    options: SimilarityOptions,
+) {
+    let SimilarityOptions {
+        ngram_length,
+        minhash_size,
+        page_size,
+        target_jobs,
+        ..
+    } = options;
+    let mut addresses = addresses.into_iter().collect::<Vec<Address>>();
+    addresses.sort();
+    let mut targets = futures::stream::iter(addresses)
+        .map(|address| async move {
+            let skeleton = get_skeleton_from_address(endpoint, &address, ngram_length)
+                .await
+                .unwrap();
+            (address, skeleton)
+        })
+        .buffered(target_jobs)
+        .filter_map(|(address, skeleton)| async move {
+            if skeleton.is_none() {
+                info!("No skeleton found for address: {:?}", address);
+            }
+            skeleton.map(|(uid, skeleton)| (address, uid, skeleton))
+        })
+        .collect::<Vec<(Address, String, Skeleton)>>()
+        .await;
+    // the corpus signatures are compared with the ones of the targets
+    if let Some(size) = minhash_size {
+        for (_, _, skeleton) in targets.iter_mut() {
+            skeleton.minhash = Some(minhash(&skeleton.interface, size));
+        }
+    }
+    info!("Comparing {} target skeletons", targets.len());
+    let corpus = load_skeletons(endpoint, ngram_length, minhash_size, page_size)
+        .await
+        .unwrap();
+    let (send, recv) = tokio::sync::oneshot::channel();
+    rayon::spawn(move || {
+        let edges = compare_targets_with_corpus(&targets, &corpus, &options);
+        let _ = send.send((targets, edges));
+    });
+    let (targets, edges) = recv.await.expect("Panic in rayon::spawn");
+    for ((address, uid, _), edges) in targets.iter().zip(edges) {
+        sink.comment(&format!("{:?} {}", address, uid));
+        for edge in edges {
+            sink.edge(edge);
+        }
+    }
+}
+
 async fn compare_single(
     endpoint: &str,
-    output_path: &str,
+    sink: Arc<EdgeSink>,
     address: String,
-    interface_sim: bool,
-    interface_threshold: f64,
-    cosine_sim: bool,
-    cosine_threshold: f64,
-    ngram_length: u8,
+    options: SimilarityOptions,
 ) {
+    let SimilarityOptions {
+        interface_sim,
+        interface_threshold,
+        cosine_sim,
+        cosine_threshold,
+        ngram_length,
+        page_size,
+        ..
+    } = options;
     // If target address is specified, compute similarity only with that skeleton
     let address = Address::from_str(&address).unwrap();
-    let target = get_skeleton_from_address(endpoint, &address, ngram_length).await.unwrap();
+    let target = get_skeleton_from_address(endpoint, &address, ngram_length)
+        .await
+        .unwrap();
     if target.is_none() {
-        println!("No skeleton found for address: {:?}", address);
+        info!("No skeleton found for address: {:?}", address);
         return;
     }
     let (target_uid, target_skeleton) = target.unwrap();
-    println!("Comparing skeleton: {}", target_uid);
-    let query = r#"query q($first: int, $offset: int) {
-    q(func: type(Skeleton), first: $first, offset: $offset) {
+    info!("Comparing skeleton: {}", target_uid);
+    let query = r#"query q($first: int, $after: string) {
+    q(func: type(Skeleton), first: $first, after: $after) {
       uid
       bytecode: Skeleton.bytecode
       functions: Skeleton.functions{
@@ -347,19 +922,15 @@ async fn compare_single(
     }"#;
     let client = Client::new(endpoint).expect("Dgraph client");
     let mut txn = client.new_read_only_txn();
-    let window = 1000;
-    let mut offset = 0;
-    let file = File::create(output_path).unwrap();
-    let file = Arc::new(Mutex::new(LineWriter::new(file)));
-    loop {
-        let mut vars = HashMap::new();
-        vars.insert("$first", window.to_string());
-        vars.insert("$offset", offset.to_string());
+    let mut cursor = UidCursor::new(page_size);
+    let mut processed = 0;
+    while let Some(vars) = cursor.next_vars() {
         let resp = txn.query_with_vars(query, vars).await.unwrap();
         let data: QueryResult = serde_json::from_slice(&resp.json).unwrap();
-        let done = data.q.len() < 1000;
+        cursor.advance(data.q.len(), data.q.last().map(|item| item.uid.as_str()));
+        processed += data.q.len();
         let (send, recv) = tokio::sync::oneshot::channel();
-        let file = file.clone();
+        let sink = sink.clone();
         let target_skeleton = target_skeleton.clone();
         let target_uid = target_uid.clone();
         rayon::spawn(move || {
@@ -381,38 +952,31 @@ async fn compare_single(
                     let interface_similarity =
                         interface_similarity(&target_skeleton.interface, &interface);
                     if interface_similarity >= interface_threshold {
-                        let mut file = file.lock().unwrap();
-                        writeln!(
-                            file,
+                        sink.edge(format!(
                             "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .",
                             target_uid, item.uid, interface_similarity
-                        )
-                        .unwrap();
-                        writeln!(
-                            file,
+                        ));
+                        sink.edge(format!(
                             "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .",
                             item.uid, target_uid, interface_similarity
-                        )
-                        .unwrap();
+                        ));
                     }
                 }
                 if cosine_sim {
-                    let ngrams = bytecode2ngrams(&Bytes::from_str(&item.bytecode.unwrap()).unwrap(), ngram_length);
+                    let ngrams = bytecode2ngrams(
+                        &Bytes::from_str(&item.bytecode.unwrap()).unwrap(),
+                        ngram_length,
+                    );
                     let cosine_similarity = cosine_similarity(&target_skeleton.ngrams, &ngrams);
                     if cosine_similarity >= cosine_threshold {
-                        let mut file = file.lock().unwrap();
-                        writeln!(
-                            file,
+                        sink.edge(format!(
                             "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .",
                             target_uid, item.uid, cosine_similarity
-                        )
-                        .unwrap();
-                        writeln!(
-                            file,
+                        ));
+                        sink.edge(format!(
                             "<{}> <Skeleton.similar_code> <{}> (similarity={:.4}) .",
                             item.uid, target_uid, cosine_similarity
-                        )
-                        .unwrap();
+                        ));
                     }
                 }
             });
@@ -420,64 +984,70 @@ async fn compare_single(
         });
         // Wait for the rayon task.
         recv.await.expect("Panic in rayon::spawn");
-        if done {
-            break;
-        }
-        offset += 1000;
-        println!("Processed {} skeletons", offset);
+        info!("Processed {} skeletons", processed);
     }
 }
 
 pub async fn find_similar_skeletons(
     endpoint: &str,
-    output_path: &str,
+    sink: SimilaritySink,
+    output_path: Option<&str>,
     address: Option<String>,
-    interface_sim: bool,
-    interface_threshold: f64,
-    cosine_sim: bool,
-    cosine_threshold: f64,
-    ngram_length: u8,
+    addresses: Option<HashSet<Address>>,
+    checkpoint: Option<&str>,
+    options: SimilarityOptions,
 ) {
-    if !interface_sim && !cosine_sim {
+    if !options.interface_sim && !options.cosine_sim {
         panic!("At least one similarity metric must be enabled");
     }
 
     let now = Instant::now();
 
-    if address.is_none() {
-        compare_all(
-            endpoint,
-            output_path,
-            interface_sim,
-            interface_threshold,
-            cosine_sim,
-            cosine_threshold,
-            ngram_length,
-        )
-        .await;
+    let (edge_sink, stored_edges) = EdgeSink::open(sink, output_path, endpoint, checkpoint);
+
+    if let Some(addresses) = addresses {
+        compare_targets(endpoint, edge_sink, addresses, options).await;
+    } else if address.is_none() {
+        compare_all(endpoint, edge_sink, options, checkpoint).await;
     } else {
-        compare_single(
-            endpoint,
-            output_path,
-            address.unwrap(),
-            interface_sim,
-            interface_threshold,
-            cosine_sim,
-            cosine_threshold,
-            ngram_length,
-        )
-        .await;
+        compare_single(endpoint, edge_sink, address.unwrap(), options).await;
+    }
+    // the comparisons dropped the sink, the dgraph one stores the last batch and ends
+    if let Some(stored_edges) = stored_edges {
+        match stored_edges.await.expect("Panic in Dgraph sink") {
+            Ok(count) => info!("Stored {} similarity edges in Dgraph", count),
+            Err(e) => eprintln!("ERROR: could not store the similarity edges: {}", e),
+        }
     }
-    println!("Done. Total time elapsed: {:?}", now.elapsed());
+    info!("Done. Total time elapsed: {:?}", now.elapsed());
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{bytecode2ngrams, cosine_similarity};
+    use super::{
+        bytecode2ngrams, compare_corpus, compare_targets_with_corpus, cosine_similarity,
+        has_stored_edge, is_new_pair, load_checkpoint, store_edges, EdgeSink, SimilarityOptions,
+        SimilaritySink, SinkItem, Skeleton, StoredEdges, UidCursor,
+    };
     use crate::analysys::similarities::get_skeleton_from_address;
+    use dgraph_tonic::{Client, Mutate, Mutation, Query};
     use ethabi::Address;
     use ethers::types::Bytes;
-    use std::str::FromStr;
+    use std::{
+        collections::{HashMap, HashSet},
+        io::Write,
+        panic::{catch_unwind, AssertUnwindSafe},
+        str::FromStr,
+        sync::Mutex,
+    };
+
+    fn skeleton(interface: &[&str]) -> Skeleton {
+        Skeleton {
+            interface: interface.iter().map(|s| s.to_string()).collect(),
+            minhash: None,
+            ngrams: HashMap::new(),
+        }
+    }
 
     #[test]
     fn test_similar_bytecode_similarity() {
@@ -504,6 +1074,56 @@ mod tests {
         assert!(skeleton.is_some());
     }
 
+    #[tokio::test]
+    #[ignore]
+    async fn test_store_edges_twice() {
+        let endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let client = Client::new(endpoint.clone()).expect("Dgraph client");
+
+        let mut mu = Mutation::new();
+        mu.set_set_nquads(
+            r#"
+            _:a <dgraph.type> "Skeleton" .
+            _:b <dgraph.type> "Skeleton" .
+        "#,
+        );
+        let mut txn = client.new_mutated_txn();
+        let created = txn.mutate(mu).await.unwrap();
+        txn.commit().await.unwrap();
+        let (a, b) = (&created.uids["a"], &created.uids["b"]);
+
+        for similarity in [0.8, 0.9] {
+            let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+            for (from, to) in [(a, b), (b, a)] {
+                send.send(SinkItem::Edge(format!(
+                    "<{}> <Skeleton.similar_interface> <{}> (similarity={:.4}) .",
+                    from, to, similarity
+                )))
+                .unwrap();
+            }
+            drop(send);
+            assert_eq!(store_edges(&endpoint, recv, None).await.unwrap(), 2);
+        }
+
+        // the second run updates the facet instead of adding edges
+        let query = format!(
+            r#"{{
+                q(func: uid({})) {{
+                    count(Skeleton.similar_interface)
+                    Skeleton.similar_interface @facets(similarity) {{ uid }}
+                }}
+            }}"#,
+            a
+        );
+        let resp = client.new_read_only_txn().query(query).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&resp.json).unwrap();
+        assert_eq!(json["q"][0]["count(Skeleton.similar_interface)"], 1);
+        assert_eq!(
+            json["q"][0]["Skeleton.similar_interface"][0]["Skeleton.similar_interface|similarity"],
+            0.9
+        );
+    }
+
     #[test]
     fn test_short_bytecodes() {
         let bytes = Bytes::from_str("0x6d00000000000000000000000000003318585733ff").unwrap();
@@ -515,4 +1135,189 @@ mod tests {
         assert!(similarity > 0.83);
     }
 
+    #[test]
+    fn test_incremental_pairs() {
+        let mut new = HashMap::new();
+        new.insert(
+            "0x3".to_string(),
+            StoredEdges {
+                interface: ["0x1".to_string()].into_iter().collect(),
+                code: Default::default(),
+            },
+        );
+        new.insert("0x4".to_string(), StoredEdges::default());
+
+        // old-old pairs were compared by the previous runs
+        assert!(!is_new_pair("0x1", "0x2", &new));
+        assert!(is_new_pair("0x1", "0x3", &new));
+        assert!(is_new_pair("0x4", "0x3", &new));
+
+        // stored edges are found from both sides of the pair
+        assert!(has_stored_edge("0x3", "0x1", false, &new));
+        assert!(has_stored_edge("0x1", "0x3", false, &new));
+        assert!(!has_stored_edge("0x1", "0x3", true, &new));
+        assert!(!has_stored_edge("0x4", "0x3", false, &new));
+    }
+
+    #[test]
+    fn test_uid_cursor() {
+        // what Dgraph returns for `first` and `after` over a corpus of `size` skeletons
+        let page = |size: u64, vars: &HashMap<&str, String>| {
+            let first = vars["$first"].parse::<usize>().unwrap();
+            let after = u64::from_str_radix(vars["$after"].trim_start_matches("0x"), 16).unwrap();
+            (1..=size)
+                .filter(|uid| *uid > after)
+                .take(first)
+                .map(|uid| format!("{:#x}", uid))
+                .collect::<Vec<String>>()
+        };
+        let load = |size: u64, page_size: usize| {
+            let mut cursor = UidCursor::new(page_size);
+            let (mut loaded, mut queries) = (Vec::new(), 0);
+            while let Some(vars) = cursor.next_vars() {
+                let uids = page(size, &vars);
+                cursor.advance(uids.len(), uids.last().map(|uid| uid.as_str()));
+                loaded.extend(uids);
+                queries += 1;
+            }
+            (loaded, queries)
+        };
+
+        // the last page is partial
+        let (loaded, queries) = load(2500, 1000);
+        assert_eq!(queries, 3);
+        assert_eq!(loaded.len(), 2500);
+        assert_eq!(loaded.iter().collect::<HashSet<_>>().len(), 2500);
+        assert_eq!(loaded.last().unwrap(), "0x9c4");
+        // the last page is full, the next one is empty
+        let (loaded, queries) = load(2000, 1000);
+        assert_eq!((loaded.len(), queries), (2000, 3));
+        let (loaded, queries) = load(0, 1000);
+        assert_eq!((loaded.len(), queries), (0, 1));
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let dir = std::env::temp_dir().join(format!(
+            "eth2dgraph_similarity_checkpoint_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("edges.rdf");
+        let checkpoint = dir.join("checkpoint.txt");
+        let sink = || {
+            let (sink, _) = EdgeSink::open(
+                SimilaritySink::File,
+                output.to_str(),
+                "",
+                checkpoint.to_str(),
+            );
+            sink
+        };
+        let mut uids = (1..=20).map(|i| format!("0x{:x}", i)).collect::<Vec<_>>();
+        uids.sort();
+
+        // the first run is interrupted while comparing the 6th skeleton
+        let first = Mutex::new(Vec::new());
+        let interrupted = catch_unwind(AssertUnwindSafe(|| {
+            compare_corpus(&uids, &Default::default(), &sink(), |i, j| {
+                if i == 5 && j == 10 {
+                    panic!("interrupted");
+                }
+                first.lock().unwrap().push((i, j));
+            })
+        }));
+        assert!(interrupted.is_err());
+        // killed while writing the checkpoint
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&checkpoint)
+            .unwrap();
+        file.write_all(b"0x").unwrap();
+        drop(file);
+
+        let processed = load_checkpoint(checkpoint.to_str().unwrap()).unwrap();
+        assert!(!processed.is_empty());
+        assert!(!processed.contains(&uids[5]));
+
+        let resumed = Mutex::new(Vec::new());
+        compare_corpus(&uids, &processed, &sink(), |i, j| {
+            resumed.lock().unwrap().push((i, j))
+        });
+        let resumed = resumed.into_inner().unwrap();
+        assert!(resumed.iter().all(|(i, _)| !processed.contains(&uids[*i])));
+
+        // the pairs of the skeletons checkpointed by the first run and the ones of the
+        // resumed run cover every pair exactly once
+        let mut pairs = first
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .filter(|(i, _)| processed.contains(&uids[*i]))
+            .chain(resumed)
+            .collect::<Vec<_>>();
+        pairs.sort();
+        let all = (0..uids.len())
+            .flat_map(|i| (i + 1..uids.len()).map(move |j| (i, j)))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, all);
+
+        // every skeleton is in the checkpoint, the partial line doesn't hide any
+        let processed = load_checkpoint(checkpoint.to_str().unwrap()).unwrap();
+        assert!(uids.iter().all(|uid| processed.contains(uid)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_multiple_targets() {
+        let mut corpus = HashMap::new();
+        corpus.insert("0x1".to_string(), skeleton(&["transfer", "approve"]));
+        corpus.insert(
+            "0x2".to_string(),
+            skeleton(&["transfer", "approve", "mint"]),
+        );
+        corpus.insert("0x3".to_string(), skeleton(&["swap", "sync"]));
+        let targets = vec![
+            (
+                Address::from_low_u64_be(1),
+                "0x1".to_string(),
+                corpus["0x1"].clone(),
+            ),
+            (
+                Address::from_low_u64_be(2),
+                "0x10".to_string(),
+                skeleton(&["swap", "sync", "skim"]),
+            ),
+        ];
+
+        let options = SimilarityOptions {
+            interface_sim: true,
+            interface_threshold: 0.5,
+            cosine_sim: false,
+            cosine_threshold: 0.0,
+            ngram_length: 5,
+            since_block: None,
+            minhash_size: None,
+            page_size: 1000,
+            target_jobs: 2,
+        };
+        let edges = compare_targets_with_corpus(&targets, &corpus, &options);
+        assert_eq!(edges.len(), 2);
+        // the target is not compared with itself, 0x3 is too different
+        assert_eq!(
+            edges[0],
+            vec![
+                "<0x1> <Skeleton.similar_interface> <0x2> (similarity=0.6667) .",
+                "<0x2> <Skeleton.similar_interface> <0x1> (similarity=0.6667) .",
+            ]
+        );
+        // a target outside the corpus
+        assert_eq!(
+            edges[1],
+            vec![
+                "<0x10> <Skeleton.similar_interface> <0x3> (similarity=0.6667) .",
+                "<0x3> <Skeleton.similar_interface> <0x10> (similarity=0.6667) .",
+            ]
+        );
+    }
 }
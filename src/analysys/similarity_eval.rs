@@ -0,0 +1,259 @@
+//! Offline evaluation of the similarity thresholds, on a labeled set of contract pairs.
+//! Every line of the labels CSV is an `address_a,address_b,similar` tuple, where `similar` is
+//! `true`/`false` (or `1`/`0`). The skeletons of the pairs are loaded from Dgraph and compared
+//! like `find_similar_skeletons` does, then the precision, recall and F1 of both metrics are
+//! reported for a sweep of thresholds, to pick `--interface-threshold` and `--cosine-threshold`.
+
+use super::similarities::{get_skeleton_from_address, skeleton_similarities};
+use crate::info;
+use ethabi::Address;
+use std::collections::{hash_map::Entry, HashMap};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+struct LabeledPair {
+    a: Address,
+    b: Address,
+    similar: bool,
+}
+
+/// Precision, recall and F1 of a metric at a threshold, `None` when undefined
+#[derive(Debug, Clone, PartialEq)]
+struct ThresholdMetrics {
+    threshold: f64,
+    true_positives: usize,
+    false_positives: usize,
+    false_negatives: usize,
+    precision: Option<f64>,
+    recall: Option<f64>,
+    f1: Option<f64>,
+}
+
+/// Parse the labels CSV, skipping empty lines, comments and the header
+fn parse_labels(content: &str) -> Result<Vec<LabeledPair>, String> {
+    let mut pairs = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split(',').map(|f| f.trim()).collect::<Vec<_>>();
+        if i == 0 && fields.first().is_some_and(|f| !f.starts_with("0x")) {
+            continue;
+        }
+        let [a, b, similar] = fields[..] else {
+            return Err(format!(
+                "line {}: expected 3 fields, got {}",
+                i + 1,
+                fields.len()
+            ));
+        };
+        let address =
+            |s: &str| Address::from_str(s).map_err(|e| format!("line {}: {}: {}", i + 1, s, e));
+        let similar = match similar.to_lowercase().as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            other => return Err(format!("line {}: invalid label {}", i + 1, other)),
+        };
+        pairs.push(LabeledPair {
+            a: address(a)?,
+            b: address(b)?,
+            similar,
+        });
+    }
+    Ok(pairs)
+}
+
+/// Thresholds from 0 to 1 with the given step
+fn thresholds(step: f64) -> Vec<f64> {
+    let steps = (1.0 / step).round() as usize;
+    (0..=steps).map(|i| (i as f64 * step).min(1.0)).collect()
+}
+
+/// Metrics of the (similarity, label) scores at each threshold, a pair is predicted similar
+/// when its similarity is at least the threshold, as when the edges are stored
+fn sweep(scores: &[(f64, bool)], thresholds: &[f64]) -> Vec<ThresholdMetrics> {
+    thresholds
+        .iter()
+        .map(|&threshold| {
+            let (mut tp, mut fp, mut fn_) = (0, 0, 0);
+            for &(similarity, similar) in scores {
+                match (similarity >= threshold, similar) {
+                    (true, true) => tp += 1,
+                    (true, false) => fp += 1,
+                    (false, true) => fn_ += 1,
+                    (false, false) => {}
+                }
+            }
+            let ratio = |n: usize, d: usize| (d > 0).then(|| n as f64 / d as f64);
+            let precision = ratio(tp, tp + fp);
+            let recall = ratio(tp, tp + fn_);
+            let f1 = match (precision, recall) {
+                (Some(p), Some(r)) if p + r > 0.0 => Some(2.0 * p * r / (p + r)),
+                (Some(_), Some(_)) => Some(0.0),
+                _ => None,
+            };
+            ThresholdMetrics {
+                threshold,
+                true_positives: tp,
+                false_positives: fp,
+                false_negatives: fn_,
+                precision,
+                recall,
+                f1,
+            }
+        })
+        .collect()
+}
+
+/// Table of the metrics, one line per metric and threshold
+fn format_table(sweeps: &[(&str, Vec<ThresholdMetrics>)]) -> String {
+    let value = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.4}", v));
+    let mut table = format!(
+        "{:<10} {:>9} {:>6} {:>6} {:>6} {:>9} {:>9} {:>9}\n",
+        "metric", "threshold", "tp", "fp", "fn", "precision", "recall", "f1"
+    );
+    for (metric, metrics) in sweeps {
+        for m in metrics {
+            table.push_str(&format!(
+                "{:<10} {:>9.2} {:>6} {:>6} {:>6} {:>9} {:>9} {:>9}\n",
+                metric,
+                m.threshold,
+                m.true_positives,
+                m.false_positives,
+                m.false_negatives,
+                value(m.precision),
+                value(m.recall),
+                value(m.f1)
+            ));
+        }
+    }
+    table
+}
+
+/// Compare the skeletons of the labeled pairs and print the precision, recall and F1 of the
+/// interface and cosine similarities for thresholds from 0 to 1.
+/// @param endpoint The Dgraph GRPC endpoint
+/// @param labels_file The CSV of labeled pairs
+/// @param ngram_length Length of the N-grams of the cosine similarity
+/// @param step Step between the thresholds
+pub async fn evaluate_similarities(
+    endpoint: &str,
+    labels_file: &str,
+    ngram_length: u8,
+    step: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pairs = parse_labels(&std::fs::read_to_string(labels_file)?)?;
+    info!("Loaded {} labeled pairs", pairs.len());
+
+    let mut skeletons = HashMap::new();
+    let mut interface_scores = Vec::new();
+    let mut cosine_scores = Vec::new();
+    let mut skipped = 0;
+    for pair in pairs {
+        for address in [pair.a, pair.b] {
+            if let Entry::Vacant(entry) = skeletons.entry(address) {
+                let skeleton = get_skeleton_from_address(endpoint, &address, ngram_length).await?;
+                entry.insert(skeleton.map(|(_, s)| s));
+            }
+        }
+        let (Some(a), Some(b)) = (&skeletons[&pair.a], &skeletons[&pair.b]) else {
+            skipped += 1;
+            continue;
+        };
+        let (interface, cosine) = skeleton_similarities(a, b);
+        interface_scores.push((interface, pair.similar));
+        cosine_scores.push((cosine, pair.similar));
+    }
+    if skipped > 0 {
+        info!(
+            "WARNING: {} pairs skipped, a contract has no skeleton in Dgraph",
+            skipped
+        );
+    }
+
+    let thresholds = thresholds(step);
+    let sweeps = [
+        ("interface", sweep(&interface_scores, &thresholds)),
+        ("cosine", sweep(&cosine_scores, &thresholds)),
+    ];
+    print!("{}", format_table(&sweeps));
+    for (metric, metrics) in &sweeps {
+        let best = metrics
+            .iter()
+            .filter(|m| m.f1.is_some())
+            .max_by(|a, b| a.f1.partial_cmp(&b.f1).unwrap());
+        if let Some(best) = best {
+            info!(
+                "Best {} threshold: {:.2} (F1 {:.4})",
+                metric,
+                best.threshold,
+                best.f1.unwrap()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = parse_labels(
+            "address_a,address_b,similar\n\
+             0x0000000000000000000000000000000000000001,0x0000000000000000000000000000000000000002,true\n\
+             # proxies of different implementations\n\
+             \n\
+             0x0000000000000000000000000000000000000003, 0x0000000000000000000000000000000000000004, 0\n",
+        )
+        .unwrap();
+        assert_eq!(labels.len(), 2);
+        assert!(labels[0].similar);
+        assert_eq!(labels[1].b, Address::from_low_u64_be(4));
+        assert!(!labels[1].similar);
+
+        assert!(parse_labels("0x01,0x02").is_err());
+        assert!(parse_labels(
+            "0x0000000000000000000000000000000000000001,0x0000000000000000000000000000000000000002,maybe"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_threshold_sweep() {
+        assert_eq!(thresholds(0.25), vec![0.0, 0.25, 0.5, 0.75, 1.0]);
+
+        let scores = [
+            (0.9, true),
+            (0.8, true),
+            (0.6, false),
+            (0.4, true),
+            (0.2, false),
+        ];
+        let metrics = sweep(&scores, &[0.0, 0.5, 0.7, 0.95]);
+        let summary = metrics
+            .iter()
+            .map(|m| (m.true_positives, m.false_positives, m.false_negatives))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![(3, 2, 0), (2, 1, 1), (2, 0, 1), (0, 0, 3)]);
+
+        assert_eq!(metrics[0].precision, Some(0.6));
+        assert_eq!(metrics[0].recall, Some(1.0));
+        assert_eq!(metrics[2].precision, Some(1.0));
+        assert!((metrics[2].f1.unwrap() - 0.8).abs() < 1e-9);
+        // nothing predicted similar over the last threshold
+        assert_eq!(metrics[3].precision, None);
+        assert_eq!(metrics[3].recall, Some(0.0));
+        assert_eq!(metrics[3].f1, None);
+
+        let table = format_table(&[("interface", metrics)]);
+        assert_eq!(table.lines().count(), 5);
+        assert!(table
+            .lines()
+            .nth(3)
+            .unwrap()
+            .ends_with("1.0000    0.6667    0.8000"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Consistency checks of the graph loaded in Dgraph.
+//! Every check counts the nodes breaking an invariant, e.g. deployments without skeleton
+//! or blocks referenced by transactions that were never stored.
+//! Dangling nodes are usually left by interrupted streams, reorgs or partial bulk loads.
+
+use crate::models::predicates::*;
+use dgraph_tonic::{Client, Query};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+struct Count {
+    count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct CountResult {
+    q: Vec<Count>,
+}
+
+/// An invariant of the graph: no node matched by `root` must miss `predicate`
+struct Invariant {
+    description: String,
+    root: String,
+    predicate: &'static str,
+}
+
+impl Invariant {
+    fn new(description: &str, root: String, predicate: &'static str) -> Self {
+        Self {
+            description: description.to_string(),
+            root,
+            predicate,
+        }
+    }
+
+    fn query(&self) -> String {
+        format!(
+            r#"{{
+  q(func: {}) @filter(NOT has({})) {{
+    count(uid)
+  }}
+}}"#,
+            self.root, self.predicate
+        )
+    }
+}
+
+fn invariants() -> Vec<Invariant> {
+    let referenced = |predicate: &str| format!("has(~{})", predicate);
+    vec![
+        Invariant::new(
+            "ContractDeployment without skeleton",
+            format!("type({})", CONTRACT_DEPLOYMENT),
+            CONTRACT_DEPLOYMENT_SKELETON,
+        ),
+        Invariant::new(
+            "ContractDeployment without contract",
+            format!("type({})", CONTRACT_DEPLOYMENT),
+            CONTRACT_DEPLOYMENT_CONTRACT,
+        ),
+        Invariant::new(
+            "Skeleton referenced by a deployment but not stored",
+            referenced(CONTRACT_DEPLOYMENT_SKELETON),
+            SKELETON_BYTECODE,
+        ),
+        Invariant::new(
+            "Account without address",
+            format!("type({})", ACCOUNT),
+            ACCOUNT_ADDRESS,
+        ),
+        Invariant::new(
+            "Contract referenced by a deployment but not stored",
+            referenced(CONTRACT_DEPLOYMENT_CONTRACT),
+            ACCOUNT_ADDRESS,
+        ),
+        Invariant::new(
+            "Block referenced by a transaction but not stored",
+            referenced(TRANSACTION_BLOCK),
+            BLOCK_NUMBER,
+        ),
+        Invariant::new(
+            "Block referenced by a deployment but not stored",
+            referenced(CONTRACT_DEPLOYMENT_BLOCK),
+            BLOCK_NUMBER,
+        ),
+        Invariant::new(
+            "Block referenced by a destruction but not stored",
+            referenced(CONTRACT_DESTRUCTION_BLOCK),
+            BLOCK_NUMBER,
+        ),
+        Invariant::new(
+            "Block referenced by a token transfer but not stored",
+            referenced(TOKEN_TRANSFER_BLOCK),
+            BLOCK_NUMBER,
+        ),
+        Invariant::new(
+            "Block referenced by a log but not stored",
+            referenced(LOG_BLOCK),
+            BLOCK_NUMBER,
+        ),
+        Invariant::new(
+            "Function referenced by a skeleton but not stored",
+            referenced(SKELETON_FUNCTIONS),
+            FUNCTION_SIGNATURE,
+        ),
+        Invariant::new(
+            "Event referenced by a skeleton but not stored",
+            referenced(SKELETON_EVENTS),
+            EVENT_SIGNATURE,
+        ),
+        Invariant::new(
+            "Error referenced by a skeleton but not stored",
+            referenced(SKELETON_ERRORS),
+            ERROR_SIGNATURE,
+        ),
+    ]
+}
+
+/// Run all the consistency checks against Dgraph and print a report.
+/// @param endpoint The Dgraph GRPC endpoint
+/// @return true if all the invariants hold
+pub async fn verify_graph(endpoint: &str) -> bool {
+    let client = Client::new(endpoint).expect("Dgraph client");
+    let mut txn = client.new_read_only_txn();
+    let mut failed = 0;
+    let invariants = invariants();
+    for invariant in &invariants {
+        let resp = txn
+            .query(invariant.query())
+            .await
+            .expect("Verification query failed");
+        let result: CountResult =
+            serde_json::from_slice(&resp.json).expect("Unexpected query response");
+        let count = result.q.first().map(|c| c.count).unwrap_or_default();
+        if count == 0 {
+            println!("[OK]   {}", invariant.description);
+        } else {
+            failed += 1;
+            println!("[FAIL] {}: {} nodes", invariant.description, count);
+        }
+    }
+    println!(
+        "{} of {} invariants hold",
+        invariants.len() - failed,
+        invariants.len()
+    );
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invariant_query() {
+        let invariant = Invariant::new(
+            "ContractDeployment without skeleton",
+            format!("type({})", CONTRACT_DEPLOYMENT),
+            CONTRACT_DEPLOYMENT_SKELETON,
+        );
+        let expected = r#"{
+  q(func: type(ContractDeployment)) @filter(NOT has(ContractDeployment.skeleton)) {
+    count(uid)
+  }
+}"#;
+        assert_eq!(invariant.query(), expected);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_verify_graph() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        verify_graph(&dgraph_endpoint).await;
+    }
+}
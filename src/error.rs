@@ -0,0 +1,72 @@
+use std::fmt;
+
+/// Errors stopping a command, printed by `main` before exiting with a non-zero code
+#[derive(Debug)]
+pub enum Error {
+    /// The arguments of the command are invalid
+    InvalidArgs(String),
+    /// The Tokio runtime couldn't be started
+    Runtime(std::io::Error),
+    /// The node endpoint is not a valid URL
+    InvalidEndpoint { endpoint: String, error: String },
+    /// The node is unreachable, or can't serve the requests of the command
+    Node(String),
+    /// Dgraph is unreachable, or rejected a request
+    Dgraph(String),
+    /// The output folders couldn't be created
+    Output { path: String, error: std::io::Error },
+    /// The block range is empty or its dates couldn't be resolved
+    BlockRange(String),
+    /// A block couldn't be processed
+    Block { block: u64, error: String },
+    /// Some blocks of the range couldn't be processed, the others were
+    FailedBlocks(Vec<u64>),
+    /// An analysis couldn't be completed, or its checks failed
+    Analysis(String),
+    /// The output folders couldn't be merged
+    Merge(String),
+    /// The Dgraph loader couldn't be run, or failed
+    Load(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgs(e) => write!(f, "{}", e),
+            Error::Runtime(e) => write!(f, "could not start the Tokio runtime: {}", e),
+            Error::InvalidEndpoint { endpoint, error } => {
+                write!(f, "invalid endpoint {}: {}", endpoint, error)
+            }
+            Error::Node(e) => write!(f, "{}", e),
+            Error::Dgraph(e) => write!(f, "{}", e),
+            Error::Output { path, error } => {
+                write!(f, "could not create the output folder {}: {}", path, error)
+            }
+            Error::BlockRange(e) => write!(f, "could not resolve the block range: {}", e),
+            Error::Block { block, error } => {
+                write!(f, "could not process block {}: {}", block, error)
+            }
+            Error::FailedBlocks(blocks) => write!(
+                f,
+                "{} blocks could not be processed: {}",
+                blocks.len(),
+                blocks
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::Analysis(e) | Error::Load(e) => write!(f, "{}", e),
+            Error::Merge(e) => write!(f, "could not merge the outputs: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Runtime(e) | Error::Output { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use super::extract::{http_provider, BlockOptions, Extractor, ExtractorOptions, RetryPolicy};
+use super::layout::OutputLayout;
+use super::writer::WriterConfig;
+use crate::models::skeleton::ErcThresholds;
+use crate::{error::Error, utils::decompile::default_tmp_dir, BenchArgs};
+use std::path::PathBuf;
+
+/// Result of an extraction run with a given number of tasks
+struct BenchRun {
+    num_tasks: usize,
+    contracts: u64,
+    seconds: f64,
+}
+
+impl BenchRun {
+    fn contracts_per_sec(&self) -> f64 {
+        self.contracts as f64 / self.seconds
+    }
+}
+
+/// Extract the same block range once for every number of tasks to test,
+/// in a temporary output folder and without decompilation, then print
+/// the throughput of each run.
+pub async fn run_bench(args: BenchArgs) -> Result<(), Error> {
+    let num_tasks = args.num_tasks.clone().unwrap_or_else(|| {
+        let cpus = num_cpus::get();
+        vec![cpus, 2 * cpus, 5 * cpus, 10 * cpus]
+    });
+    let to_block = args.from_block + args.blocks - 1;
+    let tmp_dir: PathBuf =
+        std::env::temp_dir().join(format!("eth2dgraph-bench-{}", std::process::id()));
+
+    println!(
+        "Benchmarking blocks from {} to {} with {:?} tasks",
+        args.from_block, to_block, num_tasks
+    );
+
+    let mut runs = Vec::new();
+    for n in num_tasks {
+        let output_path = tmp_dir.join(format!("tasks_{}", n));
+        let extractor = Extractor::new(
+            http_provider(&args.endpoint, None, RetryPolicy::default())?,
+            args.from_block,
+            to_block,
+            ExtractorOptions {
+                num_tasks: n,
+                max_blocks_in_flight: None,
+                block: BlockOptions {
+                    decompiler_timeout: 5000,
+                    skip_decompilation: true,
+                    tmp_dir: default_tmp_dir(),
+                    erc_thresholds: ErcThresholds::default(),
+                    max_block_retries: 3,
+                    trace_method: args.trace_method,
+                    ..Default::default()
+                },
+                writer: WriterConfig {
+                    output_path: output_path.to_string_lossy().to_string(),
+                    layout: OutputLayout::default(),
+                    output_size_kb: 8192,
+                    compression_level: 6,
+                    ..Default::default()
+                },
+                known_skeletons: None,
+                dump_skeletons: None,
+                max_cached_skeletons: None,
+                max_output_bytes: None,
+                since: None,
+                force: false,
+            },
+        );
+        let (shutdown_send, _shutdown_recv) = tokio::sync::mpsc::channel::<()>(1);
+        let (_stop_send, stop_recv) = tokio::sync::mpsc::channel::<()>(1);
+
+        let now = std::time::Instant::now();
+        let (contracts, _, _, _) = extractor.run(shutdown_send, stop_recv).await?;
+        runs.push(BenchRun {
+            num_tasks: n,
+            contracts,
+            seconds: now.elapsed().as_secs_f64(),
+        });
+
+        let _ = tokio::fs::remove_dir_all(&output_path).await;
+    }
+    let _ = tokio::fs::remove_dir_all(&tmp_dir).await;
+
+    println!();
+    println!(
+        "{:>10} | {:>10} | {:>10} | {:>13}",
+        "num_tasks", "contracts", "seconds", "contracts/sec"
+    );
+    for run in &runs {
+        println!(
+            "{:>10} | {:>10} | {:>10.2} | {:>13.2}",
+            run.num_tasks,
+            run.contracts,
+            run.seconds,
+            run.contracts_per_sec()
+        );
+    }
+    if let Some(best) = runs
+        .iter()
+        .max_by(|a, b| a.contracts_per_sec().total_cmp(&b.contracts_per_sec()))
+    {
+        println!();
+        println!("Best: {} tasks", best.num_tasks);
+    }
+    Ok(())
+}
@@ -1,8 +1,37 @@
-use ethers::providers::Middleware;
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use ethers::providers::{Middleware, MiddlewareError, ProviderError};
+use ethers::types::TransactionReceipt;
+use futures::{StreamExt, TryStreamExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+};
 
+use super::traces::{get_traces, TraceMethod};
+use crate::info;
 use crate::models::block::Block;
 
+/// Number of receipts fetched at the same time when the node lacks `eth_getBlockReceipts`
+const TX_RECEIPT_JOBS: usize = 8;
+
+/// Whether the node serves `eth_getBlockReceipts`, probed on the first block and cached for the run
+static BLOCK_RECEIPTS_SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+/// Errors returned by non-archive nodes for the blocks out of their pruning window:
+/// geth and Erigon lack the state to trace them, nodes with pruned history lack the block
+const PRUNED_ERRORS: [&str; 4] = [
+    "missing trie node",
+    "block not found",
+    "header not found",
+    "pruned",
+];
+
+/// Whether an error of the node means the requested block is pruned
+fn is_pruned_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    PRUNED_ERRORS.iter().any(|e| error.contains(e))
+}
+
 pub async fn get_block<T>(
     block: u64,
     eth_client: Arc<T>,
@@ -17,10 +46,175 @@ where
     }
 }
 
+/// Whether an error of the node means it doesn't implement the method called
+fn is_method_not_found<E: MiddlewareError>(e: &E) -> bool {
+    e.as_error_response().is_some_and(|e| {
+        let message = e.message.to_lowercase();
+        // -32601 is the JSON-RPC code for a method that doesn't exist, some providers
+        // answer with a generic code and say it in the message
+        e.code == -32601
+            || (message.contains("method")
+                && [
+                    "not found",
+                    "does not exist",
+                    "not supported",
+                    "not available",
+                ]
+                .iter()
+                .any(|m| message.contains(m)))
+    })
+}
+
+/// Receipts of the transactions of a block, in the order of the transactions.
+/// Nodes lacking `eth_getBlockReceipts` get `eth_getTransactionReceipt` for each transaction
+/// instead: the first block tells which one the node supports, the others use it right away
+pub async fn get_receipts<T>(
+    block: u64,
+    eth_client: Arc<T>,
+) -> Result<Vec<TransactionReceipt>, <T as Middleware>::Error>
+where
+    T: Middleware,
+{
+    get_receipts_cached(block, eth_client, &BLOCK_RECEIPTS_SUPPORTED).await
+}
+
+async fn get_receipts_cached<T>(
+    block: u64,
+    eth_client: Arc<T>,
+    supported: &OnceLock<bool>,
+) -> Result<Vec<TransactionReceipt>, <T as Middleware>::Error>
+where
+    T: Middleware,
+{
+    if supported.get() != Some(&false) {
+        match eth_client.get_block_receipts(block).await {
+            Ok(receipts) => {
+                let _ = supported.set(true);
+                return Ok(receipts);
+            }
+            Err(e) if is_method_not_found(&e) => {
+                if supported.set(false).is_ok() {
+                    info!(
+                        "The node doesn't support eth_getBlockReceipts ({}), fetching the receipts one transaction at a time",
+                        e
+                    );
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    get_receipts_by_transaction(block, eth_client).await
+}
+
+/// Fetch the receipts of a block with `eth_getTransactionReceipt`, `TX_RECEIPT_JOBS` at a time
+async fn get_receipts_by_transaction<T>(
+    block: u64,
+    eth_client: Arc<T>,
+) -> Result<Vec<TransactionReceipt>, T::Error>
+where
+    T: Middleware,
+{
+    let block_data = eth_client.get_block(block).await?.ok_or_else(|| {
+        T::Error::from_provider_err(ProviderError::CustomError(format!(
+            "Block {} not found",
+            block
+        )))
+    })?;
+    futures::stream::iter(block_data.transactions)
+        .map(|tx_hash| {
+            let eth_client = eth_client.clone();
+            async move {
+                eth_client
+                    .get_transaction_receipt(tx_hash)
+                    .await?
+                    .ok_or_else(|| {
+                        T::Error::from_provider_err(ProviderError::CustomError(format!(
+                            "Receipt of {:?} not found",
+                            tx_hash
+                        )))
+                    })
+            }
+        })
+        .buffered(TX_RECEIPT_JOBS)
+        .try_collect()
+        .await
+}
+
+/// Check that the node still has the data of `block`, by fetching and tracing it.
+/// Non-archive nodes fail every block out of their pruning window, this is reported once
+/// before starting; other errors are ignored, they're handled while extracting.
+pub async fn check_block_available<T>(
+    block: u64,
+    eth_client: Arc<T>,
+    trace_method: TraceMethod,
+) -> Result<(), String>
+where
+    T: Middleware,
+{
+    let pruned = |e: String| {
+        format!(
+            "block {} is not available ({}), this appears to be a non-archive node and the requested blocks are pruned",
+            block, e
+        )
+    };
+    match eth_client.get_block(block).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(pruned("block not found".to_string())),
+        Err(e) if is_pruned_error(&e.to_string()) => return Err(pruned(e.to_string())),
+        Err(_) => {}
+    }
+    match get_traces(block, eth_client, trace_method).await {
+        Err(e) if is_pruned_error(&e.to_string()) => Err(pruned(e.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Parse an RFC3339 date, like 2023-01-01T00:00:00Z
+pub fn parse_rfc3339(date: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|d| d.with_timezone(&Utc))
+        .map_err(|e| format!("invalid RFC3339 date {}: {}", date, e))
+}
+
+/// First block with a timestamp greater or equal to `timestamp`, found with a binary search
+/// over the blocks from genesis to `latest`. Probed blocks are kept in `cache`, so searching
+/// both ends of a range fetches the shared blocks once.
+/// Returns None if all the blocks up to `latest` are older than `timestamp`
+pub async fn first_block_at<T>(
+    timestamp: u64,
+    latest: u64,
+    eth_client: Arc<T>,
+    cache: &mut HashMap<u64, Block>,
+) -> Result<Option<u64>, String>
+where
+    T: Middleware,
+{
+    // the result is in [low, high], high = latest + 1 meaning no block
+    let mut low = 0;
+    let mut high = latest + 1;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if !cache.contains_key(&mid) {
+            let block = get_block(mid, eth_client.clone())
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("block {} not found", mid))?;
+            cache.insert(mid, block);
+        }
+        if cache[&mid].get_timestamp() >= timestamp {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Ok(if low > latest { None } else { Some(low) })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ethers::providers::Provider;
+    use ethers::providers::{JsonRpcError, MockResponse, Provider};
+    use ethers::types::{Trace, TxHash};
 
     #[tokio::test]
     async fn test_get_block() {
@@ -34,4 +228,153 @@ mod tests {
 
         assert_eq!(b.unwrap().get_number(), block);
     }
+
+    #[tokio::test]
+    async fn test_first_block_at() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+
+        let eth_client = Arc::new(Provider::try_from(eth_node).unwrap());
+
+        let block = 1000000;
+        let timestamp = get_block(block, eth_client.clone())
+            .await
+            .unwrap()
+            .unwrap()
+            .get_timestamp();
+        let latest = eth_client.get_block_number().await.unwrap().as_u64();
+
+        let mut cache = HashMap::new();
+        let found = first_block_at(timestamp, latest, eth_client.clone(), &mut cache)
+            .await
+            .unwrap();
+        assert_eq!(found, Some(block));
+        let after = first_block_at(timestamp + 1, latest, eth_client.clone(), &mut cache)
+            .await
+            .unwrap();
+        assert_eq!(after, Some(block + 1));
+        let future = first_block_at(u64::MAX, latest, eth_client, &mut cache)
+            .await
+            .unwrap();
+        assert_eq!(future, None);
+    }
+
+    #[tokio::test]
+    async fn test_pruned_block() {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let block = ethers::types::Block::<TxHash> {
+            number: Some(1.into()),
+            ..Default::default()
+        };
+        let error = |message: &str| {
+            MockResponse::Error(JsonRpcError {
+                code: -32000,
+                message: message.to_string(),
+                data: None,
+            })
+        };
+
+        // geth: the block is there, its state is not. Responses are popped from the last one
+        mock.push_response(error("missing trie node 2f0d4a0c7c1e (path ) <nil>"));
+        mock.push::<ethers::types::Block<TxHash>, _>(block.clone())
+            .unwrap();
+        let err = check_block_available(1, provider.clone(), TraceMethod::Parity)
+            .await
+            .unwrap_err();
+        assert!(err.contains("non-archive node"), "{}", err);
+
+        // the block itself is pruned
+        mock.push_response(error("block not found"));
+        assert!(
+            check_block_available(1, provider.clone(), TraceMethod::Parity)
+                .await
+                .is_err()
+        );
+
+        // other errors are left to the extraction
+        mock.push_response(error("request timed out"));
+        mock.push::<ethers::types::Block<TxHash>, _>(block.clone())
+            .unwrap();
+        assert!(
+            check_block_available(1, provider.clone(), TraceMethod::Parity)
+                .await
+                .is_ok()
+        );
+
+        mock.push::<Vec<Trace>, _>(Vec::new()).unwrap();
+        mock.push::<ethers::types::Block<TxHash>, _>(block).unwrap();
+        assert!(check_block_available(1, provider, TraceMethod::Parity)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_receipts_fallback() {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let supported = OnceLock::new();
+        let txs = [TxHash::from_low_u64_be(1), TxHash::from_low_u64_be(2)];
+        let block = ethers::types::Block::<TxHash> {
+            number: Some(1.into()),
+            transactions: txs.to_vec(),
+            ..Default::default()
+        };
+        let receipt = |tx: TxHash| TransactionReceipt {
+            transaction_hash: tx,
+            gas_used: Some(21_000.into()),
+            ..Default::default()
+        };
+        // the node lacks eth_getBlockReceipts: the block and each receipt are fetched instead.
+        // Responses are popped from the last one
+        mock.push(receipt(txs[1])).unwrap();
+        mock.push(receipt(txs[0])).unwrap();
+        mock.push(block.clone()).unwrap();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32601,
+            message: "the method eth_getBlockReceipts does not exist/is not available".to_string(),
+            data: None,
+        }));
+        let receipts = get_receipts_cached(1, provider.clone(), &supported)
+            .await
+            .unwrap();
+        assert_eq!(
+            receipts
+                .iter()
+                .map(|r| r.transaction_hash)
+                .collect::<Vec<_>>(),
+            txs
+        );
+        assert_eq!(supported.get(), Some(&false));
+
+        // the next blocks skip eth_getBlockReceipts, any request for it would find no response
+        mock.push(receipt(txs[1])).unwrap();
+        mock.push(receipt(txs[0])).unwrap();
+        mock.push(block).unwrap();
+        assert_eq!(
+            get_receipts_cached(1, provider.clone(), &supported)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // other errors aren't taken for a missing method
+        let supported = OnceLock::new();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "request timed out".to_string(),
+            data: None,
+        }));
+        assert!(get_receipts_cached(1, provider, &supported).await.is_err());
+        assert_eq!(supported.get(), None);
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let date = parse_rfc3339("2023-01-01T00:00:00Z").unwrap();
+        assert_eq!(date.timestamp(), 1672531200);
+        let date = parse_rfc3339("2023-01-01T02:00:00+02:00").unwrap();
+        assert_eq!(date.timestamp(), 1672531200);
+        assert!(parse_rfc3339("2023-01-01").is_err());
+    }
 }
@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::io::{LineWriter, Write};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Step of the extraction in which an error happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorStage {
+    Network,
+    Decompilation,
+    Writer,
+}
+
+/// Failure recorded in `<output_path>/errors.jsonl`, one JSON object per line
+#[derive(Debug, Serialize)]
+pub struct ExtractionError {
+    /// Block being processed, None for errors not related to a block (e.g. writer failures)
+    block: Option<u64>,
+    stage: ErrorStage,
+    error: String,
+}
+
+impl ExtractionError {
+    pub fn new(block: Option<u64>, stage: ErrorStage, error: impl ToString) -> Self {
+        Self {
+            block,
+            stage,
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Write the errors received to `output_file` until all the senders are dropped.
+/// Every line is flushed as soon as it's complete, so the file is up to date even if the
+/// extraction is killed. Returns the number of errors written.
+pub async fn error_log_task(
+    output_file: &str,
+    mut receiver: UnboundedReceiver<ExtractionError>,
+) -> usize {
+    let file = std::fs::File::create(output_file).unwrap();
+    let mut file = LineWriter::new(file);
+    let mut count = 0;
+    while let Some(error) = receiver.recv().await {
+        let line = serde_json::to_string(&error).unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            eprintln!("ERROR: could not write to {}: {}", output_file, e);
+        }
+        count += 1;
+    }
+    let _ = file.flush();
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{error_log_task, ErrorStage, ExtractionError};
+
+    #[tokio::test]
+    async fn test_error_log() {
+        let path =
+            std::env::temp_dir().join(format!("eth2dgraph-errors-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let task = {
+            let path = path.clone();
+            tokio::spawn(async move { error_log_task(&path, receiver).await })
+        };
+
+        sender
+            .send(ExtractionError::new(
+                Some(10),
+                ErrorStage::Network,
+                "timeout",
+            ))
+            .unwrap();
+        sender
+            .send(ExtractionError::new(
+                None,
+                ErrorStage::Writer,
+                "disk \"full\"",
+            ))
+            .unwrap();
+        drop(sender);
+
+        assert_eq!(task.await.unwrap(), 2);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({"block": 10, "stage": "network", "error": "timeout"}),
+                serde_json::json!({"block": null, "stage": "writer", "error": "disk \"full\""}),
+            ]
+        );
+    }
+}
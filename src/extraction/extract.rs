@@ -1,155 +1,509 @@
 use super::writer::WriteCommand;
 use crate::{
+    error::Error,
     extraction::{
-        blocks::get_block,
-        logs::{get_all_logs, get_transfer_from_logs, get_transfer_logs},
-        traces::get_traces,
-        writer::writer_task,
+        blocks::{check_block_available, first_block_at, get_block, get_receipts},
+        errors::{error_log_task, ErrorStage, ExtractionError},
+        logs::{
+            get_all_logs, get_transfer_from_logs, get_transfer_logs, may_contain_transfers,
+            ContractScope, TransferSignatures,
+        },
+        manifest::{FileCounters, Manifest, MANIFEST_FILE},
+        rate_limit::RateLimitedClient,
+        s3::is_s3_url,
+        skeleton_cache::{SkeletonCache, SkeletonStats, MAX_DECOMPILATION_ATTEMPTS},
+        skeleton_set::dump_skeleton_set,
+        traces::{check_trace_method, get_state_changes, get_traces, TraceMethod},
+        writer::{
+            is_stdout, ndjson_writer_task, write_output_file, writer_task, UnextractedBlocks,
+            WriterConfig,
+        },
     },
+    info,
     models::{
-        contract_destruction::ContractDestruction, skeleton::Skeleton, transaction::Transaction,
+        account::Account,
+        block::Block,
+        contract_deployment::{
+            count_batch_deploys, index_block_deployments, link_deploy_tx_inputs, ContractDeployment,
+        },
+        contract_destruction::ContractDestruction,
+        contract_gas_usage::ContractGasUsage,
+        internal_transaction::InternalTransaction,
+        skeleton::{ErcThresholds, Skeleton},
+        state_change::StateChange,
+        trace::Traces,
+        transaction::Transaction,
+        transfer::TransferCounts,
+        SerializeDgraph,
+    },
+    utils::decompile::{
+        decompile, decompiler_version, default_tmp_dir, probe_decompiler_version, scaled_timeout,
     },
-    utils::decompile::decompile,
     ExtractArgs,
 };
-use dashmap::DashMap;
-use ethers::providers::{Middleware, Provider, RetryClientBuilder};
+use dashmap::DashSet;
+use ethers::providers::{Http, Middleware, Provider, RetryClient, RetryClientBuilder};
+use ethers::types::{Address, Log};
 use primitive_types::H256;
 use std::{
-    path::Path,
+    collections::{HashMap, HashSet},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, AtomicU8, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
 };
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender};
 use tokio::sync::Semaphore;
 use tokio::time::Duration;
 
+/// Options of the extraction of each block, shared by the tasks of the blocks
+#[derive(Clone, Default)]
+pub struct BlockOptions {
+    pub include_tx: bool,
+    pub include_token_transfers: bool,
+    pub include_logs: bool,
+    pub include_internal_tx: bool,
+    pub include_accounts: bool,
+    pub skip_failed_deployments: bool,
+    pub resolve_names: bool,
+    pub resolve_interfaces: bool,
+    pub hash_creation_code: bool,
+    pub aggregate_gas: bool,
+    pub include_state_diffs: bool,
+    pub detect_batch_deploys: bool,
+    pub gas_breakdown: bool,
+    pub transfer_contracts: Option<Arc<HashSet<Address>>>,
+    pub transfer_signatures: Option<Arc<TransferSignatures>>,
+    pub only_contracts: Option<ContractScope>,
+    pub scs_path: Option<String>,
+    pub decompiler_timeout: u64,
+    pub max_decompiler_timeout: Option<u64>,
+    pub skip_decompilation: bool,
+    pub store_decompiled_source: bool,
+    pub keep_decompiler_output: bool,
+    pub tmp_dir: PathBuf,
+    pub min_code_size: usize,
+    pub min_entropy: f64,
+    pub minhash_size: usize,
+    pub erc_thresholds: ErcThresholds,
+    pub max_block_retries: u32,
+    pub max_block_size: Option<usize>,
+    pub trace_method: TraceMethod,
+}
+
+/// Options of an extraction run
+pub struct ExtractorOptions {
+    /// Blocks fetched at the same time, 0 for 5 per CPU
+    pub num_tasks: usize,
+    /// Blocks held in memory between their fetch and the writer, by default `num_tasks`
+    pub max_blocks_in_flight: Option<usize>,
+    pub block: BlockOptions,
+    pub writer: WriterConfig,
+    pub known_skeletons: Option<HashSet<H256>>,
+    pub dump_skeletons: Option<String>,
+    pub max_cached_skeletons: Option<usize>,
+    pub max_output_bytes: Option<u64>,
+    /// Manifest of the run continued with --since, its counters are continued
+    pub since: Option<Manifest>,
+    pub force: bool,
+}
+
 pub struct Extractor<T>
 where
     T: Middleware,
 {
-    output_path: String,
-    output_size: usize,
-    compression_level: u32,
+    eth_provider: Arc<T>,
     from_block: u64,
     to_block: u64,
-    num_tasks: usize,
+    options: ExtractorOptions,
+}
+
+/// Counters, channels and caches shared by the tasks of the blocks
+struct BlockTask<T> {
     eth_provider: Arc<T>,
-    include_tx: bool,
-    include_token_transfers: bool,
-    include_logs: bool,
-    scs_path: Option<String>,
-    decompiler_timeout: u64,
-    skip_decompilation: bool,
+    cnt_total: Arc<AtomicU64>,
+    cnt_failed: Arc<AtomicU64>,
+    /// Permits of the blocks being fetched
+    rpc_tasks: Arc<Semaphore>,
+    writer: Sender<WriteCommand>,
+    error_log: UnboundedSender<ExtractionError>,
+    skeletons: Arc<SkeletonCache>,
+    /// Addresses deployed in the range, only filled with `--only-contracts range`
+    deployed_contracts: Arc<DashSet<Address>>,
+    /// Heimdall processes running, to scale the decompiler timeout
+    running_decompilations: Arc<AtomicUsize>,
+    options: Arc<BlockOptions>,
+}
+
+/// Block, logs, traces and state changes of a block
+type BlockData = (Option<Block>, Vec<Log>, Traces, Vec<StateChange>);
+
+/// Bytes written to it, to size a serialization without buffering it
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// JSON size of the fetched data of a block, if it's over `max_size`
+fn oversized(
+    block: &Option<Block>,
+    logs: &[Log],
+    traces: &Traces,
+    state_changes: &[StateChange],
+    max_size: Option<usize>,
+) -> Option<usize> {
+    let max_size = max_size?;
+    let mut counter = ByteCounter::default();
+    if let Some(block) = block {
+        serde_json::to_writer(&mut counter, &**block).ok()?;
+    }
+    serde_json::to_writer(&mut counter, logs).ok()?;
+    serde_json::to_writer(&mut counter, traces).ok()?;
+    for state_change in state_changes {
+        SerializeDgraph::serialize_dgraph(
+            state_change,
+            &mut serde_json::Serializer::new(&mut counter),
+        )
+        .ok()?;
+    }
+    (counter.0 > max_size).then_some(counter.0)
+}
+
+/// Results of the sub-calls of a block fetch, `None` until they succeed
+#[derive(Debug, Default)]
+struct BlockFetch {
+    block: Option<Option<Block>>,
+    logs: Option<Vec<Log>>,
+    traces: Option<Traces>,
+    state_changes: Option<Vec<StateChange>>,
+}
+
+impl BlockFetch {
+    /// Logs and state changes not included are empty from the start, so they're never fetched
+    fn new(with_logs: bool, with_state_changes: bool) -> Self {
+        Self {
+            logs: (!with_logs).then(Vec::new),
+            state_changes: (!with_state_changes).then(Vec::new),
+            ..Default::default()
+        }
+    }
+
+    /// Take the results once all the sub-calls succeeded
+    fn complete(&mut self) -> Option<BlockData> {
+        if self.block.is_none()
+            || self.logs.is_none()
+            || self.traces.is_none()
+            || self.state_changes.is_none()
+        {
+            return None;
+        }
+        Some((
+            self.block.take().unwrap(),
+            self.logs.take().unwrap(),
+            self.traces.take().unwrap(),
+            self.state_changes.take().unwrap(),
+        ))
+    }
+}
+
+/// Issue a sub-call of a block fetch unless it already succeeded, returning its error
+async fn fetch_missing<R, E, F, Fut>(
+    result: &mut Option<R>,
+    what: &'static str,
+    fetch: F,
+) -> Option<(&'static str, E)>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<R, E>>,
+{
+    if result.is_some() {
+        return None;
+    }
+    match fetch().await {
+        Ok(r) => {
+            *result = Some(r);
+            None
+        }
+        Err(e) => Some((what, e)),
+    }
 }
 
 impl<T> Extractor<T>
 where
     T: Middleware + 'static,
 {
-    pub fn new(
-        eth_provider: T,
-        output_path: String,
-        output_size: usize,
-        compression_level: u32,
-        from_block: u64,
-        to_block: u64,
-        num_tasks: usize,
-        include_tx: bool,
-        include_token_transfers: bool,
-        include_logs: bool,
-        scs_path: Option<String>,
-        decompiler_timeout: u64,
-        skip_decompilation: bool,
-    ) -> Self {
+    pub fn new(eth_provider: T, from_block: u64, to_block: u64, options: ExtractorOptions) -> Self {
         Self {
-            output_path,
-            output_size,
-            compression_level,
+            eth_provider: Arc::new(eth_provider),
             from_block,
             to_block,
-            num_tasks,
-            eth_provider: Arc::new(eth_provider),
-            include_tx,
-            include_logs,
-            include_token_transfers,
-            scs_path,
-            decompiler_timeout,
-            skip_decompilation,
+            options,
         }
     }
 
-    async fn extract_at(
+    /// Fetch block, logs, traces and state changes of a block concurrently, issuing only the
+    /// sub-calls that didn't succeed in a previous attempt and storing their results in `fetched`.
+    /// All the logs are fetched with `include_logs`, otherwise only the transfer logs: they're
+    /// fetched after the block, and only if its logs bloom may contain transfer events.
+    /// Returns the sub-calls that failed, to be retried
+    async fn fetch_block_data(
         block: u64,
         eth_provider: Arc<T>,
-        cnt_total: Arc<AtomicU64>,
-        cnt_failed: Arc<AtomicU64>,
-        writer: Sender<WriteCommand>,
-        skeletons: Arc<DashMap<H256, AtomicU8>>,
-        include_tx: bool,
-        include_token_transfers: bool,
+        fetched: &mut BlockFetch,
         include_logs: bool,
-        scs_path: Option<String>,
-        decompiler_timeout: u64,
-        skip_decompilation: bool,
-    ) {
-        let c = eth_provider.clone();
-        let block_data = get_block(block, c);
-
-        let c = eth_provider.clone();
-        let traces = get_traces(block, c);
-
-        let (block_data, logs, traces) = if include_token_transfers || include_logs {
-            let c = eth_provider.clone();
-
-            let (block_data, logs, traces) = if include_token_transfers && !include_logs {
-                tokio::join!(block_data, get_transfer_logs(block, c), traces)
-            } else {
-                tokio::join!(block_data, get_all_logs(block, c), traces)
-            };
-
-            if block_data.is_err() || logs.is_err() || traces.is_err() {
-                println!("Network error while processing block {}", block);
-                return;
+        transfer_signatures: Option<&TransferSignatures>,
+        trace_method: TraceMethod,
+    ) -> Vec<(&'static str, <T as Middleware>::Error)> {
+        let BlockFetch {
+            block: block_data,
+            logs,
+            traces,
+            state_changes,
+        } = fetched;
+
+        let fetch_block = fetch_missing(block_data, "block", || {
+            get_block(block, eth_provider.clone())
+        });
+        let fetch_traces = fetch_missing(traces, "traces", || {
+            get_traces(block, eth_provider.clone(), trace_method)
+        });
+        let fetch_logs = async {
+            if !include_logs {
+                // transfer logs are fetched below, once the block is known
+                return None;
             }
+            fetch_missing(logs, "logs", || get_all_logs(block, eth_provider.clone())).await
+        };
+        let fetch_state_changes = fetch_missing(state_changes, "state changes", || {
+            get_state_changes(block, eth_provider.clone())
+        });
 
-            // can unwrap now
-
-            let block_data = block_data.unwrap();
-            let logs = logs.unwrap();
-            let traces = traces.unwrap();
-
-            (block_data, logs, traces)
-        } else {
-            // don't need logs if we don't include token transfers
+        let (block_error, traces_error, logs_error, state_changes_error) =
+            tokio::join!(fetch_block, fetch_traces, fetch_logs, fetch_state_changes);
+        let mut failed = [block_error, traces_error, logs_error, state_changes_error]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        if let (false, Some(block_data)) = (include_logs, block_data.as_ref()) {
+            // the bloom filter has no false negatives, skip the request if there can't be transfers
+            let may_have_transfers = block_data
+                .as_ref()
+                .and_then(|b| b.logs_bloom)
+                .map(|bloom| may_contain_transfers(&bloom, transfer_signatures))
+                .unwrap_or(true);
+            let error = fetch_missing(logs, "logs", || async {
+                if may_have_transfers {
+                    get_transfer_logs(block, eth_provider.clone(), transfer_signatures).await
+                } else {
+                    Ok(Vec::new())
+                }
+            })
+            .await;
+            failed.extend(error);
+        }
 
-            let (block_data, traces) = tokio::join!(block_data, traces);
+        failed
+    }
 
-            if block_data.is_err() || traces.is_err() {
-                println!("Network error while processing block {}", block);
-                return;
+    async fn extract_at(block: u64, task: BlockTask<T>) {
+        let BlockTask {
+            eth_provider,
+            cnt_total,
+            cnt_failed,
+            rpc_tasks,
+            writer,
+            error_log,
+            skeletons,
+            deployed_contracts,
+            running_decompilations,
+            options,
+        } = task;
+        let BlockOptions {
+            include_tx,
+            include_token_transfers,
+            include_logs,
+            include_internal_tx,
+            include_accounts,
+            skip_failed_deployments,
+            resolve_names,
+            resolve_interfaces,
+            hash_creation_code,
+            aggregate_gas,
+            include_state_diffs,
+            detect_batch_deploys,
+            gas_breakdown,
+            transfer_contracts,
+            transfer_signatures,
+            only_contracts,
+            scs_path,
+            decompiler_timeout,
+            max_decompiler_timeout,
+            skip_decompilation,
+            store_decompiled_source,
+            keep_decompiler_output,
+            tmp_dir,
+            min_code_size,
+            min_entropy,
+            minhash_size,
+            erc_thresholds,
+            max_block_retries,
+            max_block_size,
+            trace_method,
+        } = (*options).clone();
+        // retry the failed sub-calls of the block fetch with exponential backoff before giving up,
+        // failed blocks are recorded by the writer so they can be extracted again later
+        let mut attempt = 0;
+        let mut fetched =
+            BlockFetch::new(include_token_transfers || include_logs, include_state_diffs);
+        let (block_data, mut logs, traces, state_changes) = loop {
+            // the permit is held only while fetching, blocks waiting for decompilation
+            // or for the writer don't count against the concurrent RPC tasks
+            let permit = rpc_tasks.acquire().await.unwrap();
+            let failed = Self::fetch_block_data(
+                block,
+                eth_provider.clone(),
+                &mut fetched,
+                include_logs,
+                transfer_signatures.as_deref(),
+                trace_method,
+            )
+            .await;
+            drop(permit);
+            match fetched.complete() {
+                Some(data) => break data,
+                None => {
+                    let e = failed
+                        .iter()
+                        .map(|(what, e)| format!("{}: {}", what, e))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let _ = error_log.send(ExtractionError::new(
+                        Some(block),
+                        ErrorStage::Network,
+                        format!("attempt {}/{}: {}", attempt + 1, max_block_retries + 1, e),
+                    ));
+                    if attempt >= max_block_retries {
+                        eprintln!(
+                            "Network error while processing block {}, giving up after {} retries: {}",
+                            block, attempt, e
+                        );
+                        writer.send(WriteCommand::FailedBlock(block)).await.unwrap();
+                        return;
+                    }
+                    attempt += 1;
+                    let backoff = 500 * 2u64.pow(attempt.min(6));
+                    info!(
+                        "Network error while processing block {}, retrying in {}ms ({}/{})",
+                        block, backoff, attempt, max_block_retries
+                    );
+                    tokio::time::sleep(Duration::from_millis(backoff)).await;
+                }
             }
-
-            // can unwrap now
-
-            let block_data = block_data.unwrap();
-            let traces = traces.unwrap();
-
-            (block_data, Vec::new(), traces)
         };
 
+        if let Some(size) = oversized(&block_data, &logs, &traces, &state_changes, max_block_size) {
+            // the fetched data is dropped right away, instead of going through the decompilation and the writer
+            info!(
+                "Block {} is {} bytes, over --max-block-size-bytes, skipping it",
+                block, size
+            );
+            writer
+                .send(WriteCommand::SkippedBlock(block))
+                .await
+                .unwrap();
+            return;
+        }
+
         if block_data.is_none() {
-            println!("Block {} not found", block);
+            info!("Block {} not found", block);
+            let _ = error_log.send(ExtractionError::new(
+                Some(block),
+                ErrorStage::Network,
+                "block not found",
+            ));
+            writer.send(WriteCommand::FailedBlock(block)).await.unwrap();
             return;
         }
 
-        let block_data = block_data.unwrap();
+        let mut block_data = block_data.unwrap();
+        if gas_breakdown {
+            // the breakdown is the only use of the receipts: if they can't be fetched the block
+            // is stored without it, rather than failing with its deployments and transactions
+            let permit = rpc_tasks.acquire().await.unwrap();
+            let receipts = get_receipts(block, eth_provider.clone()).await;
+            drop(permit);
+            match receipts {
+                Ok(receipts) => block_data.set_gas_breakdown(
+                    traces.get_gas_breakdown(block_data.transactions.len(), &receipts),
+                ),
+                Err(e) => {
+                    let _ = error_log.send(ExtractionError::new(
+                        Some(block),
+                        ErrorStage::Network,
+                        format!("receipts: {}, stored without the gas breakdown", e),
+                    ));
+                }
+            }
+        }
         let destructions: Vec<ContractDestruction> = Vec::from(&traces);
-        let deployments = Vec::from(traces);
+        let internal_txs: Vec<InternalTransaction> = if include_internal_tx {
+            Vec::from(&traces)
+        } else {
+            Vec::new()
+        };
+        let mut accounts: Vec<Account> = if include_accounts {
+            Vec::from(&traces)
+        } else {
+            Vec::new()
+        };
+        let gas_usages: Vec<ContractGasUsage> = if aggregate_gas {
+            Vec::from(&traces)
+        } else {
+            Vec::new()
+        };
+        let mut deployments: Vec<ContractDeployment> = Vec::from(traces);
+        index_block_deployments(&mut deployments);
+        if skip_failed_deployments {
+            deployments.retain(|d| !d.failed());
+        }
+        // the transactions are in the block data, link the factory calls to their deployments
+        if include_tx {
+            link_deploy_tx_inputs(&mut deployments, &block_data.transactions);
+        }
+        if detect_batch_deploys {
+            count_batch_deploys(&mut deployments);
+        }
 
-        println!(
+        // keep only the logs emitted by contracts deployed in this block or,
+        // with the range scope, in the blocks of the range processed so far
+        match only_contracts {
+            Some(ContractScope::Block) => {
+                let deployed: HashSet<Address> =
+                    deployments.iter().map(|d| d.contract_address()).collect();
+                logs.retain(|log| deployed.contains(&log.address));
+            }
+            Some(ContractScope::Range) => {
+                for deployment in deployments.iter() {
+                    deployed_contracts.insert(deployment.contract_address());
+                }
+                logs.retain(|log| deployed_contracts.contains(&log.address));
+            }
+            None => {}
+        }
+
+        info!(
             "Block {} discovered with {} deploys, {} destructions.",
             block,
             deployments.len(),
@@ -164,73 +518,127 @@ where
                 deployment.check_verification(scs_path.as_ref().unwrap());
             }
 
-            // resolve name
-            deployment.resolve_name(eth_provider.clone()).await;
+            // resolve name, one eth_call per deployment
+            if resolve_names {
+                deployment.resolve_name(eth_provider.clone()).await;
+            }
+
+            // ERC-165 interfaces, up to one eth_call per known interface
+            if resolve_interfaces {
+                deployment
+                    .resolve_supported_interfaces(eth_provider.clone())
+                    .await;
+            }
+
+            // the writer stores the creation code once per hash
+            deployment.set_hash_creation_code(hash_creation_code);
 
             let skeleton_hash = deployment.skeleton_hash();
+            let trivial = (min_code_size > 0 || min_entropy > 0.0)
+                && Skeleton::new(deployment.skeleton().clone())
+                    .is_trivial(min_code_size, min_entropy);
 
             if skip_decompilation {
                 // just store skeleton without decompiling
-                let already_wrote = skeletons.get(&skeleton_hash);
-                if already_wrote.is_none() {
-                    drop(already_wrote);
+                if skeletons.discover(skeleton_hash) {
                     // newly discovered skeleton
                     // just store it without performing decompilation
-                    skeletons.insert(skeleton_hash, AtomicU8::new(1));
                     let skeleton = Skeleton::new(deployment.skeleton().clone());
                     writer.send(WriteCommand::Skeleton(skeleton)).await.unwrap();
                 }
+            } else if trivial {
+                // too small or repetitive to be worth spawning heimdall
+                if skeletons.discover(skeleton_hash) {
+                    info!(
+                        "Skipping decompilation of the trivial skeleton of {:?}",
+                        deployment.contract_address()
+                    );
+                    let mut skeleton = Skeleton::new(deployment.skeleton().clone());
+                    skeleton.set_skipped_decompilation(true);
+                    writer.send(WriteCommand::Skeleton(skeleton)).await.unwrap();
+                }
             } else {
                 // Caching logic:
                 // 1 - check if skeleton is already discovered, if yes skip decompilation
-                // 2 - if not, try to decompile up to 10 times
-                // 3 - if decompilation fails more than 10 times, skip decompilation
+                // 2 - if not, try to decompile up to MAX_DECOMPILATION_ATTEMPTS times
+                // 3 - if decompilation fails more than MAX_DECOMPILATION_ATTEMPTS times, skip decompilation
 
                 // Implementation:
-                // - skeletons are stored in a concurrent DashMap<H256, AtomicU8>
-                // - the key is the skeleton hash
-                // - the value is an AtomicU8 that stores the number of times the skeleton has failed to decompile
+                // - skeletons are stored in a SkeletonCache, a concurrent DashMap keyed by skeleton hash
+                // - the value stores the number of times the skeleton has failed to decompile
                 // - the value is initialized to 1 when the skeleton is discovered
-                // - the value is incremented by 1 every time the skeleton fails to decompile
+                // - the value is incremented by 1 every time a decompilation is attempted
                 // - the value is set to 0 when the skeleton is successfully decompiled
+                // - the cache counts the skeletons discovered, decompiled and given up on, for the summary
+                // - with --max-cached-skeletons, evicted skeletons are handled as newly discovered
 
                 // Things to avoid:
                 // - keep lock during decompilation -> would slow down the process
 
-                let cached_value = skeletons.entry(skeleton_hash).or_insert(AtomicU8::new(1));
-
-                match cached_value.value().load(Ordering::Relaxed) {
+                match skeletons.start_attempt(skeleton_hash) {
                     0 => {
                         // skeleton already discovered and succesfully decompiled
                         // skip decompilation
-                        drop(cached_value);
-                        println!("Skeleton already discovered and decompiled");
+                        info!("Skeleton already discovered and decompiled");
                     }
-                    1..=10 => {
+                    attempt @ 1..=MAX_DECOMPILATION_ATTEMPTS => {
                         // must be decompiled
 
-                        // increment attempt counter, if not 0
-                        let _ = cached_value.value().fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| if x == 0 { None } else { Some(x + 1) } );
-                        drop(cached_value);
-
-                        // perform decompilation
+                        // perform decompilation, in adaptive mode the timeout grows
+                        // with the decompilations sharing the cores with this one
+                        let running = running_decompilations.fetch_add(1, Ordering::SeqCst) + 1;
+                        let timeout = match max_decompiler_timeout {
+                            Some(max_timeout) => scaled_timeout(
+                                decompiler_timeout,
+                                max_timeout,
+                                running,
+                                num_cpus::get(),
+                            ),
+                            None => decompiler_timeout,
+                        };
+                        if timeout > decompiler_timeout {
+                            info!(
+                                "{} decompilations running, timeout of {:?} scaled to {}ms",
+                                running,
+                                deployment.contract_address(),
+                                timeout
+                            );
+                        }
                         let mut skeleton = Skeleton::new(deployment.skeleton().clone());
+                        skeleton.set_minhash_size(minhash_size);
+                        skeleton.set_erc_thresholds(erc_thresholds);
+                        skeleton
+                            .set_decompiler_version(decompiler_version().ok().map(String::from));
                         let abi = decompile(
                             &deployment.contract_address(),
                             &deployment.deployed_code(),
-                            decompiler_timeout,
+                            &tmp_dir,
+                            timeout,
+                            store_decompiled_source,
+                            keep_decompiler_output,
                         )
                         .await;
-
-                        if abi.is_ok() {
-                            // decompilation successful
-                            skeleton.set_abi(abi.unwrap());
-                            skeleton.set_failed_decompilation(false);
-                            skeletons.get(&skeleton_hash).unwrap().store(0, Ordering::Relaxed);
-                        } else {
-                            // decompilation failed
-                            // increment attempt counter
-                            cnt_failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        running_decompilations.fetch_sub(1, Ordering::SeqCst);
+
+                        match abi {
+                            Ok(decompiled) => {
+                                // decompilation successful
+                                skeleton.set_abi(decompiled.abi);
+                                skeleton.set_decompiled_source(decompiled.source);
+                                skeleton.set_failed_decompilation(false);
+                                skeletons.set_decompiled(skeleton_hash);
+                            }
+                            Err(e) => {
+                                // decompilation failed
+                                // increment attempt counter
+                                cnt_failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                                skeletons.set_failed(attempt);
+                                let _ = error_log.send(ExtractionError::new(
+                                    Some(block),
+                                    ErrorStage::Decompilation,
+                                    format!("{:?} for {:?}", e, deployment.contract_address()),
+                                ));
+                            }
                         }
 
                         // store skeleton
@@ -239,8 +647,7 @@ where
                     _ => {
                         // skeleton already discovered and failed more than 10 times to decompile
                         // skip decompilation
-                        drop(cached_value);
-                        println!("Skeleton already discovered and failed more than 10 times to decompile");
+                        info!("Skeleton already discovered and failed more than 10 times to decompile");
                     }
                 };
             }
@@ -261,19 +668,44 @@ where
         }
 
         if include_token_transfers {
-            let transfers = get_transfer_from_logs(&logs);
+            let transfers = get_transfer_from_logs(
+                &logs,
+                block_data.get_timestamp(),
+                transfer_contracts.as_deref(),
+                transfer_signatures.as_deref(),
+            );
+            block_data.set_transfer_counts(Some(TransferCounts::new(&transfers)));
 
             for transfer in transfers {
+                if include_accounts {
+                    accounts.push(Account::new(transfer.sender(), block, false));
+                    accounts.push(Account::new(transfer.recipient(), block, false));
+                }
                 writer.send(WriteCommand::Transfer(transfer)).await.unwrap();
             }
         }
 
         if include_logs {
             for log in logs {
-                writer.send(WriteCommand::Log(log.into())).await.unwrap();
+                let log = crate::models::log::Log::new(log, block_data.get_timestamp());
+                writer.send(WriteCommand::Log(log)).await.unwrap();
             }
         }
 
+        for internal_tx in internal_txs {
+            writer
+                .send(WriteCommand::InternalTx(internal_tx))
+                .await
+                .unwrap();
+        }
+
+        for state_change in state_changes {
+            writer
+                .send(WriteCommand::StateChange(state_change))
+                .await
+                .unwrap();
+        }
+
         // store transactions
         if include_tx {
             for tx in block_data.transactions.iter() {
@@ -282,150 +714,567 @@ where
             }
         }
 
+        if include_accounts {
+            if let Some(miner) = block_data.author {
+                accounts.push(Account::new(miner, block, false));
+            }
+            // duplicates are merged by the writer
+            for account in accounts {
+                writer.send(WriteCommand::Account(account)).await.unwrap();
+            }
+        }
+
+        // summed up by the writer over the whole range
+        for usage in gas_usages {
+            writer.send(WriteCommand::GasUsage(usage)).await.unwrap();
+        }
+
         // store block data
         writer.send(WriteCommand::Block(block_data)).await.unwrap();
+        writer
+            .send(WriteCommand::BlockComplete(block))
+            .await
+            .unwrap();
 
-        println!("Block {} processed", block);
+        info!("Block {} processed", block);
     }
 
-    pub async fn run(self, _sender: Sender<()>, mut receiver: Receiver<()>) -> (u64, u64, u64) {
-        let num_tasks = if self.num_tasks == 0 {
+    pub async fn run(
+        self,
+        _sender: Sender<()>,
+        mut receiver: Receiver<()>,
+    ) -> Result<(u64, u64, u64, SkeletonStats), Error> {
+        let ExtractorOptions {
+            num_tasks,
+            max_blocks_in_flight,
+            block: block_options,
+            writer: mut writer_config,
+            known_skeletons,
+            dump_skeletons,
+            max_cached_skeletons,
+            max_output_bytes,
+            since,
+            force,
+        } = self.options;
+        let block_options = Arc::new(block_options);
+        let num_tasks = if num_tasks == 0 {
             5 * num_cpus::get()
         } else {
-            self.num_tasks
+            num_tasks
         };
 
-        println!("Using {} jobs", num_tasks);
-
-        // create output folders if they don't exists
-        if !Path::new(&self.output_path).exists() {
-            tokio::try_join!(
-                tokio::fs::create_dir_all(&self.output_path),
-                tokio::fs::create_dir_all(format!("{}/static/skeletons/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/static/events/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/static/functions/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/static/errors/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/static/blocks/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/static/deployments/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/static/destructions/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/dynamic/transactions/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/dynamic/transfers/", &self.output_path)),
-                tokio::fs::create_dir_all(format!("{}/dynamic/logs/", &self.output_path)),
+        // blocks fetched and not yet handed to the writer, each one holds its whole data in memory
+        let max_blocks_in_flight = max_blocks_in_flight.unwrap_or(num_tasks);
+
+        info!(
+            "Using {} jobs, up to {} blocks in flight",
+            num_tasks, max_blocks_in_flight
+        );
+
+        // create output folders if they don't exists, objects in S3 and stdout need no folders
+        let output_path = writer_config.output_path.clone();
+        let layout = writer_config.layout.clone();
+        let to_s3 = is_s3_url(&output_path);
+        let to_stdout = is_stdout(&output_path);
+        if !to_s3 && !to_stdout && !Path::new(&output_path).exists() {
+            futures::future::try_join_all(
+                layout
+                    .dirs()
+                    .map(|dir| tokio::fs::create_dir_all(Path::new(&output_path).join(dir))),
             )
-            .unwrap();
+            .await
+            .map_err(|error| Error::Output {
+                path: output_path.clone(),
+                error,
+            })?;
+        }
+
+        // fail early if the node is unreachable, instead of failing every block,
+        // or if it can't trace blocks with the chosen method
+        let head = self
+            .eth_provider
+            .get_block_number()
+            .await
+            .map_err(|e| Error::Node(format!("could not reach the node: {}", e)))?
+            .as_u64();
+        check_trace_method(
+            self.from_block,
+            self.eth_provider.clone(),
+            block_options.trace_method,
+        )
+        .await
+        .map_err(Error::Node)?;
+        // non-archive nodes fail every block out of their pruning window,
+        // blocks not mined yet are missing too but they're not pruned
+        if self.from_block <= head {
+            if let Err(e) = check_block_available(
+                self.from_block,
+                self.eth_provider.clone(),
+                block_options.trace_method,
+            )
+            .await
+            {
+                if !force {
+                    return Err(Error::Node(format!(
+                        "{}, run with --force to extract anyway",
+                        e
+                    )));
+                }
+                info!("WARNING: {}, extracting anyway because of --force", e);
+            }
+        }
+
+        // the version is probed once, and stored with every decompiled skeleton
+        if !block_options.skip_decompilation {
+            probe_decompiler_version();
         }
 
         // counters to keep track of the progress
         let cnt_total = Arc::new(AtomicU64::new(0));
         let cnt_failed = Arc::new(AtomicU64::new(0));
 
-        // shared hashmap to access the list of already processed skeletons
+        // shared cache to access the list of already processed skeletons
         // the key is the the skeleton's bytecode hash,
         // the value is a u8 indicating how many times the decompilation failed, if it's 0 the skeleton was successfully decompiled
-        let skeletons: Arc<DashMap<H256, AtomicU8>> = Arc::new(DashMap::new());
+        let skeletons = Arc::new(SkeletonCache::new(max_cached_skeletons));
+
+        // skeletons extracted by a previous run are marked as decompiled, so they're skipped
+        if let Some(known_skeletons) = known_skeletons {
+            info!("Skipping {} known skeletons", known_skeletons.len());
+            for hash in known_skeletons {
+                skeletons.insert(hash, 0);
+            }
+        }
+
+        // addresses deployed in the range, only filled with `--only-contracts range`
+        let deployed_contracts: Arc<DashSet<Address>> = Arc::new(DashSet::new());
+
+        // number of heimdall processes currently running, used to scale the decompiler timeout
+        let running_decompilations = Arc::new(AtomicUsize::new(0));
 
         // the semaphore is used to limit the number of concurrent tasks, otherwise the system
-        // would spawn millions of tasks. The semaphore allows spawning at max <num_tasks> tasks in parallel.
-        let semaphore = Arc::new(Semaphore::new(num_tasks));
+        // would spawn millions of tasks. The semaphore allows spawning at max <max_blocks_in_flight> tasks in parallel.
+        let semaphore = Arc::new(Semaphore::new(max_blocks_in_flight));
+        // the RPC requests of the tasks are limited separately, at max <num_tasks> blocks are fetched in parallel
+        let rpc_tasks = Arc::new(Semaphore::new(num_tasks));
+
+        // spawn error log task, errors are sent on a dedicated channel by the extraction and writer tasks
+        let (error_log, error_log_receiver) = tokio::sync::mpsc::unbounded_channel();
+        // with S3 output the errors are written to a local file, uploaded at the end,
+        // with stdout output the local file is kept
+        let errors_file = if to_s3 || to_stdout {
+            std::env::temp_dir()
+                .join(format!("eth2dgraph-errors-{}.jsonl", std::process::id()))
+                .to_string_lossy()
+                .to_string()
+        } else {
+            format!("{}/errors.jsonl", output_path)
+        };
+        let ef = errors_file.clone();
+        let error_log_handle =
+            tokio::spawn(async move { error_log_task(&ef, error_log_receiver).await });
 
         // spawn writer task
         let (writer, writer_receiver) = tokio::sync::mpsc::channel(10000);
-        let output = self.output_path.to_string();
-        let output_size = self.output_size;
-        let compression_level = self.compression_level;
+        // the files continue the counters of the run continued with --since
+        if let Some(manifest) = &since {
+            writer_config.counters = manifest.files.clone();
+        }
+        // bytes written so far, watched by the loop below to stop at --max-output-bytes
+        let written = max_output_bytes.map(|_| Arc::new(AtomicU64::new(0)));
+        let w = written.clone();
+        let el = error_log.clone();
         let writer_handle = tokio::spawn(async move {
-            writer_task(&output, writer_receiver, output_size, compression_level).await;
+            if is_stdout(&writer_config.output_path) {
+                let stdout = std::io::BufWriter::new(std::io::stdout());
+                ndjson_writer_task(stdout, writer_receiver, el).await;
+                return UnextractedBlocks::default();
+            }
+            writer_task(writer_config, writer_receiver, w, el).await
         });
 
-        println!(
+        // blocks failed or skipped by the runs continued with --since are extracted again first
+        let pending = since
+            .as_ref()
+            .map(Manifest::pending_blocks)
+            .unwrap_or_default();
+        if !pending.is_empty() {
+            info!(
+                "Extracting again {} blocks failed or skipped by the previous runs",
+                pending.len()
+            );
+        }
+        info!(
             "Processing blocks from {} to {}",
             &self.from_block, &self.to_block
         );
 
-        let mut block = self.from_block;
-        while block <= self.to_block {
+        let mut output_full = false;
+        let mut spawned = 0;
+        for block in pending
+            .iter()
+            .copied()
+            .chain(self.from_block..=self.to_block)
+        {
             // acquire a permit from the semaphore, this will block if the semaphore is full
             // to avoid spawning too many tasks.
             let permit = Arc::clone(&semaphore).acquire_owned().await.unwrap();
-            let c = self.eth_provider.clone();
-            let cnt_failed = cnt_failed.clone(); // clone the counter to pass it to the task
-            let cnt_total = cnt_total.clone(); // clone the counter to pass it to the task
-            let w = writer.clone();
-            let s = skeletons.clone();
-            let scs = self.scs_path.clone();
+            let task = BlockTask {
+                eth_provider: self.eth_provider.clone(),
+                cnt_total: cnt_total.clone(),
+                cnt_failed: cnt_failed.clone(),
+                rpc_tasks: rpc_tasks.clone(),
+                writer: writer.clone(),
+                error_log: error_log.clone(),
+                skeletons: skeletons.clone(),
+                deployed_contracts: deployed_contracts.clone(),
+                running_decompilations: running_decompilations.clone(),
+                options: block_options.clone(),
+            };
             tokio::spawn(async move {
-                Self::extract_at(
-                    block,
-                    c,
-                    cnt_total,
-                    cnt_failed,
-                    w,
-                    s,
-                    self.include_tx,
-                    self.include_token_transfers,
-                    self.include_logs,
-                    scs,
-                    self.decompiler_timeout,
-                    self.skip_decompilation,
-                )
-                .await;
+                Self::extract_at(block, task).await;
                 drop(permit); // release the permit
             });
-            block += 1;
+            spawned += 1;
             if receiver.try_recv().is_ok() {
                 break;
             }
+            // the blocks already spawned are completed and written like on Ctrl-C
+            if let (Some(max), Some(written)) = (max_output_bytes, &written) {
+                if written.load(Ordering::SeqCst) >= max {
+                    output_full = true;
+                    break;
+                }
+            }
         }
 
-        block -= 1;
+        // the pending blocks come first, then the ones of the range
+        let retried = &pending[..spawned.min(pending.len())];
+        let block = self.from_block + (spawned - retried.len()) as u64 - 1;
 
         // Wait for all the tasks to finish acquiring all the permits, this will implicitly wait
         // for all the tasks to finish. Otherwise the program would exit before all the tasks
         // are finished. I did it this way to avoid collecting all the handles (potentially millions) in a vector and
         // waiting for all of them to finish.
-        let _ = semaphore.acquire_many(num_tasks as u32).await;
+        let _ = semaphore.acquire_many(max_blocks_in_flight as u32).await;
 
         drop(writer); // close the writer channel, this will cause the writer task to finish
 
         // wait for the writer task to finish, it can take a while since it's compressing the output
-        let _ = writer_handle.await;
+        let unextracted = writer_handle.await.unwrap_or_default();
+
+        if output_full {
+            info!(
+                "Output reached {} bytes, over --max-output-bytes: stopped after block {}, continue from block {}",
+                written.unwrap().load(Ordering::SeqCst),
+                block,
+                block + 1
+            );
+        }
 
-        let _ = tokio::fs::remove_dir(".tmp").await;
+        // the manifest of a local folder records the range and the files, for the next --since
+        if !to_s3 && !to_stdout {
+            let mut manifest = since.unwrap_or_default();
+            manifest.add_range(self.from_block, block);
+            manifest.update_unextracted(retried, &unextracted);
+            let written = manifest
+                .update_files(&output_path, &layout)
+                .and_then(|_| manifest.write(&output_path).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                eprintln!("ERROR: could not write {}: {}", MANIFEST_FILE, e);
+            }
+        }
 
-        (
+        // all the senders are dropped once the writer is done, this waits for the last errors to be written
+        drop(error_log);
+        if let Ok(count) = error_log_handle.await {
+            if to_s3 {
+                let o = format!("{}/errors.jsonl", output_path);
+                let ef = errors_file.clone();
+                let uploaded = tokio::task::spawn_blocking(move || {
+                    let content = std::fs::read(&ef).map_err(|e| e.to_string())?;
+                    write_output_file(&o, &content)
+                })
+                .await
+                .unwrap();
+                if let Err(e) = uploaded {
+                    eprintln!("ERROR: {}", e);
+                }
+                let _ = tokio::fs::remove_file(&errors_file).await;
+            }
+            if count == 0 && to_stdout {
+                let _ = tokio::fs::remove_file(&errors_file).await;
+            } else if count > 0 && to_stdout {
+                // info is printed to stdout, mixed with the entities
+                eprintln!("{} errors written to {}", count, errors_file);
+            } else if count > 0 {
+                info!("{} errors written to {}/errors.jsonl", count, output_path);
+            }
+        }
+
+        // the outputs kept with --keep-decompiler-output keep the folder
+        let _ = tokio::fs::remove_dir(&block_options.tmp_dir).await;
+
+        if let Some(dump_skeletons) = &dump_skeletons {
+            let hashes = skeletons.hashes();
+            let count = hashes.len();
+            match dump_skeleton_set(dump_skeletons, hashes) {
+                Ok(_) => info!("Wrote {} skeleton hashes to {}", count, dump_skeletons),
+                Err(e) => eprintln!("ERROR: could not write {}: {}", dump_skeletons, e),
+            }
+        }
+
+        Ok((
             cnt_total.load(std::sync::atomic::Ordering::Relaxed),
             cnt_failed.load(std::sync::atomic::Ordering::Relaxed),
             block,
-        )
+            skeletons.stats(),
+        ))
     }
 }
 
-pub async fn run_extraction(args: ExtractArgs) {
-    let now = std::time::Instant::now();
+/// Retries of the HTTP provider on rate limited and timed out requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    rate_limit_retries: u32,
+    timeout_retries: u32,
+    initial_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(10, 5, 500)
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(rate_limit_retries: u32, timeout_retries: u32, initial_backoff_ms: u64) -> Self {
+        Self {
+            rate_limit_retries,
+            timeout_retries,
+            initial_backoff_ms,
+        }
+    }
+
+    /// Retries can be disabled, but the backoff can't be 0: rate limited requests would be
+    /// sent again right away
+    pub fn validate(&self) -> Result<(), String> {
+        if self.initial_backoff_ms == 0 {
+            return Err("RPC initial backoff must be at least 1 ms".to_string());
+        }
+        Ok(())
+    }
+}
 
+/// HTTP provider retrying rate limited and timed out requests, as set by `retry`.
+/// With `max_rps`, requests are throttled to that rate across all the users of the provider
+pub fn http_provider(
+    endpoint: &str,
+    max_rps: Option<NonZeroU32>,
+    retry: RetryPolicy,
+) -> Result<Provider<RateLimitedClient<RetryClient<Http>>>, Error> {
+    let url = reqwest::Url::parse(endpoint).map_err(|e| Error::InvalidEndpoint {
+        endpoint: endpoint.to_string(),
+        error: e.to_string(),
+    })?;
     let client = RetryClientBuilder::default()
-        .rate_limit_retries(10)
-        .timeout_retries(5)
-        .initial_backoff(Duration::from_millis(500))
+        .rate_limit_retries(retry.rate_limit_retries)
+        .timeout_retries(retry.timeout_retries)
+        .initial_backoff(Duration::from_millis(retry.initial_backoff_ms))
         .build(
-            ethers::providers::Http::new(reqwest::Url::parse(&args.endpoint).unwrap()),
+            Http::new(url),
             Box::<ethers::providers::HttpRateLimitRetryPolicy>::default(),
         );
+    Ok(Provider::new(RateLimitedClient::new(client, max_rps)))
+}
+
+/// Block range of the extraction, dates are resolved to the first block mined
+/// at or after `--from-date` and the last one mined at or before `--to-date`
+async fn resolve_block_range(
+    args: &ExtractArgs,
+    since: Option<&Manifest>,
+) -> Result<(u64, u64), String> {
+    // a run continuing a manifest starts after its last block, and stops at the head by default
+    let from_block = match since {
+        Some(manifest) => Some(
+            manifest
+                .last_block()
+                .ok_or("the manifest has no extracted range")?
+                + 1,
+        ),
+        None => args.from_block,
+    };
+    if let (None, None, Some(to_block)) = (args.from_date, args.to_date, args.to_block) {
+        let from_block = from_block.unwrap();
+        if since.is_some() && from_block > to_block {
+            return Err(format!(
+                "empty range, from block {} is after to block {}",
+                from_block, to_block
+            ));
+        }
+        return Ok((from_block, to_block));
+    }
+    let eth_provider = Arc::new(
+        http_provider(&args.endpoint, args.max_rps, args.retry_policy())
+            .map_err(|e| e.to_string())?,
+    );
+    let latest = eth_provider
+        .get_block_number()
+        .await
+        .map_err(|e| e.to_string())?
+        .as_u64();
+    let mut cache = HashMap::new();
+    let from_block = match args.from_date {
+        Some(date) => first_block_at(
+            date.timestamp() as u64,
+            latest,
+            eth_provider.clone(),
+            &mut cache,
+        )
+        .await?
+        .ok_or_else(|| format!("no block mined at or after {}", date.to_rfc3339()))?,
+        None => from_block.unwrap(),
+    };
+    let to_block = match args.to_date {
+        Some(date) => {
+            match first_block_at(
+                date.timestamp() as u64 + 1,
+                latest,
+                eth_provider.clone(),
+                &mut cache,
+            )
+            .await?
+            {
+                Some(0) => return Err(format!("no block mined before {}", date.to_rfc3339())),
+                Some(block) => block - 1,
+                None => latest,
+            }
+        }
+        None => args.to_block.unwrap_or(latest),
+    };
+    for (date, block) in [(args.from_date, from_block), (args.to_date, to_block)] {
+        if let Some(date) = date {
+            // the block is usually probed by the search, but not when it's the latest one
+            if !cache.contains_key(&block) {
+                let b = get_block(block, eth_provider.clone())
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("block {} not found", block))?;
+                cache.insert(block, b);
+            }
+            info!(
+                "{} -> block {} mined at {}",
+                date.to_rfc3339(),
+                block,
+                cache[&block].get_rfc3339()
+            );
+        }
+    }
+    if from_block > to_block {
+        return Err(format!(
+            "empty range, from block {} is after to block {}",
+            from_block, to_block
+        ));
+    }
+    Ok((from_block, to_block))
+}
+
+pub async fn run_extraction(args: ExtractArgs) -> Result<(), Error> {
+    let now = std::time::Instant::now();
+
+    // all the tasks share the provider, and so the request budget
+    let eth_provider = http_provider(&args.endpoint, args.max_rps, args.retry_policy())?;
+    let rpc_requests = eth_provider.as_ref().request_counter();
+
+    let since = args
+        .since
+        .as_deref()
+        .map(Manifest::load)
+        .transpose()
+        .map_err(Error::BlockRange)?;
+    let (from_block, to_block) = resolve_block_range(&args, since.as_ref())
+        .await
+        .map_err(Error::BlockRange)?;
+    if let Some(manifest) = &since {
+        info!(
+            "Continuing the extraction up to block {}, from block {} to {}",
+            manifest.last_block().unwrap(),
+            from_block,
+            to_block
+        );
+    }
 
+    if (args.include.include_tx || args.include.include_transfers)
+        && (to_block - from_block) > 1e6 as u64
+    {
+        info!("WARNING: Extracting transactions and/or token transfers for a large number of blocks may produce a large number of files");
+    }
+
+    let block = BlockOptions {
+        include_tx: args.include.include_tx,
+        include_token_transfers: args.include.include_transfers,
+        include_logs: args.include.include_logs,
+        include_internal_tx: args.include.include_internal_tx,
+        include_accounts: args.include.include_accounts,
+        skip_failed_deployments: args.include.skip_failed_deployments,
+        resolve_names: args.include.resolve_names,
+        resolve_interfaces: args.include.resolve_interfaces,
+        hash_creation_code: args.hash_creation_code,
+        aggregate_gas: args.aggregate_gas,
+        include_state_diffs: args.include_state_diffs,
+        detect_batch_deploys: args.detect_batch_deploys,
+        gas_breakdown: args.include.gas_breakdown,
+        transfer_contracts: args.include.transfer_contracts.map(Arc::new),
+        transfer_signatures: args.include.transfer_signatures.map(Arc::new),
+        only_contracts: args.only_contracts,
+        scs_path: args.scs_path,
+        decompiler_timeout: args.decompiler_timeout,
+        max_decompiler_timeout: args.max_decompiler_timeout,
+        skip_decompilation: args.skip_decompilation,
+        store_decompiled_source: args.store_decompiled_source,
+        keep_decompiler_output: args.keep_decompiler_output,
+        tmp_dir: args.tmp_dir.unwrap_or_else(default_tmp_dir),
+        min_code_size: args.min_code_size,
+        min_entropy: args.min_entropy,
+        minhash_size: args.minhash_size,
+        erc_thresholds: ErcThresholds {
+            erc20: args.erc20_threshold,
+            erc721: args.erc721_threshold,
+        },
+        max_block_retries: args.max_block_retries,
+        max_block_size: args.max_block_size_bytes,
+        trace_method: args.trace_method,
+    };
+    let writer = WriterConfig {
+        output_path: args.output_path,
+        layout: args.layout_config.unwrap_or_default(),
+        counters: FileCounters::new(),
+        output_size_kb: args.size_output,
+        compression_level: args.compression_level,
+        deterministic: args.deterministic,
+        validate: args.validate_output,
+        content_addressed_skeletons: args.content_addressed_skeletons,
+        flush_concurrency: args.flush_concurrency,
+        checksums: args.checksums,
+        flush_on_block: args.flush_on_block,
+        transfers_csv: args.transfers_csv,
+    };
     let extractor = Extractor::new(
-        Provider::new(client),
-        args.output_path,
-        args.size_output,
-        args.compression_level,
-        args.from_block,
-        args.to_block,
-        args.num_tasks,
-        args.include_tx,
-        args.include_transfers,
-        args.include_logs,
-        args.scs_path,
-        args.decompiler_timeout,
-        args.skip_decompilation,
+        eth_provider,
+        from_block,
+        to_block,
+        ExtractorOptions {
+            num_tasks: args.num_tasks,
+            max_blocks_in_flight: args.max_blocks_in_flight,
+            block,
+            writer,
+            known_skeletons: args.known_skeletons,
+            dump_skeletons: args.dump_skeletons,
+            max_cached_skeletons: args.max_cached_skeletons,
+            max_output_bytes: args.max_output_bytes,
+            since,
+            force: args.force,
+        },
     );
 
     let (shutdown_send, mut shutdown_recv) = tokio::sync::mpsc::channel::<()>(1);
@@ -433,35 +1282,417 @@ pub async fn run_extraction(args: ExtractArgs) {
 
     let jh = tokio::spawn(async move { extractor.run(shutdown_send, stop_recv).await });
 
-    let (total, failed, last_block) = tokio::select! {
+    let (total, failed, last_block, skeleton_stats) = tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             stop_send.send(()).await.unwrap();
-            jh.await.unwrap()
+            jh.await.unwrap()?
         },
         _ = shutdown_recv.recv() => {
-            jh.await.unwrap()
+            jh.await.unwrap()?
         },
     };
 
-    println!(
+    info!(
         "Analysis completed! Extracted blocks from {} to {}",
-        args.from_block, last_block
+        from_block, last_block
     );
-    println!("Total: {} contracts", total);
+    info!("Total: {} contracts", total);
     if total > 0 {
-        println!("Failed: {} contracts", failed);
-        println!("Success ratio: {}", (total - failed) as f64 / total as f64);
+        info!("Failed: {} contracts", failed);
+        info!("Success ratio: {}", (total - failed) as f64 / total as f64);
+    }
+    if skeleton_stats.discovered > 0 && !args.skip_decompilation {
+        // the ratios above count every deployment, these count each bytecode once
+        info!(
+            "Skeletons: {} discovered, {} decompiled, {} failed {} times",
+            skeleton_stats.discovered,
+            skeleton_stats.decompiled,
+            skeleton_stats.exhausted,
+            MAX_DECOMPILATION_ATTEMPTS
+        );
+        info!(
+            "Skeleton success ratio: {}",
+            skeleton_stats.decompiled as f64 / skeleton_stats.discovered as f64
+        );
+        if let Ok(version) = decompiler_version() {
+            info!("Decompiler: heimdall {}", version);
+        }
     }
 
     let elapsed = now.elapsed();
 
-    println!("Elapsed: {:?}", elapsed);
-    println!("Contracts/sec: {}", total as f64 / elapsed.as_secs_f64());
+    info!("Elapsed: {:?}", elapsed);
+    info!("Contracts/sec: {}", total as f64 / elapsed.as_secs_f64());
+    let rpc_requests = rpc_requests.load(Ordering::Relaxed);
+    info!(
+        "RPC requests: {} ({:.1}/sec)",
+        rpc_requests,
+        rpc_requests as f64 / elapsed.as_secs_f64()
+    );
 
-    if last_block - args.from_block > 0 {
-        println!(
+    if last_block - from_block > 0 {
+        info!(
             "Blocks/sec: {}",
-            (last_block - args.from_block) as f64 / elapsed.as_secs_f64()
+            (last_block - from_block) as f64 / elapsed.as_secs_f64()
         );
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::layout::OutputLayout;
+    use crate::extraction::manifest::BlockRange;
+    use ethers::providers::{JsonRpcError, MockResponse};
+    use ethers::types::{Trace, Transaction as EthTransaction};
+    use std::io::Read;
+
+    fn extract_args(args: &[&str]) -> ExtractArgs {
+        use clap::Parser;
+        let args = ["eth2dgraph", "extract"].iter().chain(args);
+        match crate::Cli::try_parse_from(args).unwrap().command {
+            crate::Commands::Extract(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_endpoint() {
+        let args = extract_args(&["-e", "localhost 8545", "-f", "1", "-t", "1"]);
+        let res = run_extraction(args).await;
+        assert!(
+            matches!(res, Err(Error::InvalidEndpoint { .. })),
+            "{:?}",
+            res
+        );
+
+        // nothing listens on port 1, and the request is not retried
+        let output =
+            std::env::temp_dir().join(format!("eth2dgraph_endpoint_{}", std::process::id()));
+        let args = extract_args(&[
+            "-e",
+            "http://127.0.0.1:1",
+            "-f",
+            "1",
+            "-t",
+            "1",
+            "-o",
+            output.to_str().unwrap(),
+            "--rpc-timeout-retries",
+            "0",
+        ]);
+        let res = run_extraction(args).await;
+        let _ = std::fs::remove_dir_all(&output);
+        assert!(matches!(res, Err(Error::Node(_))), "{:?}", res);
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_output() {
+        // the output path is below a file, so its folders can't be created
+        let file = std::env::temp_dir().join(format!("eth2dgraph_file_{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let output = file.join("output");
+        let args = extract_args(&["-f", "1", "-t", "1", "-o", output.to_str().unwrap()]);
+        let res = run_extraction(args).await;
+        let _ = std::fs::remove_file(&file);
+        match res {
+            Err(e @ Error::Output { .. }) => {
+                assert!(e.to_string().contains(output.to_str().unwrap()))
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        assert_eq!(RetryPolicy::default(), RetryPolicy::new(10, 5, 500));
+        // no retries at all is allowed, a zero backoff isn't
+        assert!(RetryPolicy::new(0, 0, 1).validate().is_ok());
+        assert!(RetryPolicy::new(10, 5, 0).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_only_failed_subcall() {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let block = ethers::types::Block::<EthTransaction> {
+            number: Some(1.into()),
+            ..Default::default()
+        };
+        // block, traces and logs are requested in this order, responses are popped from the last one
+        mock.push::<Vec<Log>, _>(vec![Log::default()]).unwrap();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "header not found".to_string(),
+            data: None,
+        }));
+        mock.push::<ethers::types::Block<EthTransaction>, _>(block)
+            .unwrap();
+
+        let mut fetched = BlockFetch::new(true, false);
+        let failed = Extractor::fetch_block_data(
+            1,
+            provider.clone(),
+            &mut fetched,
+            true,
+            None,
+            TraceMethod::Parity,
+        )
+        .await;
+        assert_eq!(
+            failed.iter().map(|(what, _)| *what).collect::<Vec<_>>(),
+            vec!["traces"]
+        );
+        assert!(fetched.block.is_some());
+        assert_eq!(fetched.logs.as_ref().map(|l| l.len()), Some(1));
+        assert!(fetched.complete().is_none());
+
+        // only the traces are requested again, any other request would find no response
+        mock.push::<Vec<Trace>, _>(Vec::new()).unwrap();
+        let failed =
+            Extractor::fetch_block_data(1, provider, &mut fetched, true, None, TraceMethod::Parity)
+                .await;
+        assert!(failed.is_empty());
+        let (block_data, logs, traces, state_changes) = fetched.complete().unwrap();
+        assert_eq!(block_data.unwrap().number, Some(1.into()));
+        assert_eq!(logs.len(), 1);
+        assert!(traces.0.is_empty());
+        assert!(state_changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_skip_oversized_block() {
+        let tx = EthTransaction {
+            input: vec![0xab; 4096].into(),
+            ..Default::default()
+        };
+        let block = Some(Block::from(ethers::types::Block {
+            number: Some(1.into()),
+            transactions: vec![tx],
+            ..Default::default()
+        }));
+        let traces = Traces(Vec::new());
+        // the hex of the input alone is over 8kB
+        let size = oversized(&block, &[], &traces, &[], Some(8192)).unwrap();
+        assert!(size > 8192);
+        assert!(oversized(&block, &[], &traces, &[], Some(size)).is_none());
+        assert!(oversized(&block, &[], &traces, &[], None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_since_retries_failed_blocks() {
+        let output =
+            std::env::temp_dir().join(format!("eth2dgraph_since_failed_{}", std::process::id()));
+        let (provider, mock) = Provider::mocked();
+        // the head is behind the range, every other request finds no response and fails
+        mock.push(ethers::types::U64::from(1)).unwrap();
+        let since = Manifest {
+            ranges: vec![BlockRange {
+                from_block: 1,
+                to_block: 10,
+            }],
+            failed_blocks: vec![5],
+            ..Default::default()
+        };
+        let extractor = Extractor::new(
+            provider,
+            11,
+            11,
+            ExtractorOptions {
+                num_tasks: 1,
+                max_blocks_in_flight: None,
+                block: BlockOptions {
+                    skip_decompilation: true,
+                    max_block_retries: 0,
+                    ..Default::default()
+                },
+                writer: WriterConfig {
+                    output_path: output.to_str().unwrap().to_string(),
+                    output_size_kb: 1024,
+                    compression_level: 6,
+                    ..Default::default()
+                },
+                known_skeletons: None,
+                dump_skeletons: None,
+                max_cached_skeletons: None,
+                max_output_bytes: None,
+                since: Some(since),
+                force: false,
+            },
+        );
+        let (shutdown_send, _shutdown_recv) = tokio::sync::mpsc::channel::<()>(1);
+        let (_stop_send, stop_recv) = tokio::sync::mpsc::channel::<()>(1);
+        let res = extractor.run(shutdown_send, stop_recv).await;
+        let manifest = Manifest::load(output.join(MANIFEST_FILE).to_str().unwrap());
+        let failed = std::fs::read_to_string(output.join("failed_blocks.txt"));
+        let _ = std::fs::remove_dir_all(&output);
+
+        assert_eq!(res.unwrap().2, 11);
+        let manifest = manifest.unwrap();
+        assert_eq!(manifest.last_block(), Some(11));
+        // block 5 was extracted again and failed again, so it's kept for the next run
+        assert_eq!(manifest.failed_blocks, vec![5, 11]);
+        assert_eq!(failed.unwrap(), "5\n11\n");
+    }
+
+    #[tokio::test]
+    async fn test_receipts_error_keeps_block() {
+        let output =
+            std::env::temp_dir().join(format!("eth2dgraph_receipts_{}", std::process::id()));
+        let (provider, mock) = Provider::mocked();
+        // requests: head, trace method check, block, traces and receipts,
+        // responses are popped from the last one pushed
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "request timed out".to_string(),
+            data: None,
+        }));
+        mock.push::<Vec<Trace>, _>(Vec::new()).unwrap();
+        mock.push(ethers::types::Block::<EthTransaction> {
+            number: Some(11.into()),
+            hash: Some(ethers::types::H256::from_low_u64_be(11)),
+            author: Some(Address::from_low_u64_be(1)),
+            ..Default::default()
+        })
+        .unwrap();
+        mock.push::<Vec<Trace>, _>(Vec::new()).unwrap();
+        mock.push(ethers::types::U64::from(1)).unwrap();
+        let extractor = Extractor::new(
+            provider,
+            11,
+            11,
+            ExtractorOptions {
+                num_tasks: 1,
+                max_blocks_in_flight: None,
+                block: BlockOptions {
+                    skip_decompilation: true,
+                    max_block_retries: 0,
+                    gas_breakdown: true,
+                    ..Default::default()
+                },
+                writer: WriterConfig {
+                    output_path: output.to_str().unwrap().to_string(),
+                    output_size_kb: 1024,
+                    compression_level: 6,
+                    ..Default::default()
+                },
+                known_skeletons: None,
+                dump_skeletons: None,
+                max_cached_skeletons: None,
+                max_output_bytes: None,
+                since: None,
+                force: false,
+            },
+        );
+        let (shutdown_send, _shutdown_recv) = tokio::sync::mpsc::channel::<()>(1);
+        let (_stop_send, stop_recv) = tokio::sync::mpsc::channel::<()>(1);
+        let res = extractor.run(shutdown_send, stop_recv).await;
+        let blocks = std::fs::read(output.join("static/blocks/blocks_0.json.gz"));
+        let errors = std::fs::read_to_string(output.join("errors.jsonl"));
+        let failed = output.join("failed_blocks.txt").exists();
+        let _ = std::fs::remove_dir_all(&output);
+
+        assert!(res.is_ok(), "{:?}", res.err());
+        // the block is written, only without the gas breakdown
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&blocks.unwrap()[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert!(json.contains(r#""Block.number":11"#), "{}", json);
+        assert!(!json.contains("Block.gas_calls"));
+        assert!(!failed);
+        assert!(errors.unwrap().contains("stored without the gas breakdown"));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_max_output_bytes() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+        let output =
+            std::env::temp_dir().join(format!("eth2dgraph_max_output_{}", std::process::id()));
+        // every block goes to its own file, the first one is over the limit
+        let args = extract_args(&[
+            "-e",
+            &eth_node,
+            "-f",
+            "1000000",
+            "-t",
+            "1001000",
+            "-n",
+            "1",
+            "-o",
+            output.to_str().unwrap(),
+            "--skip-decompilation",
+            "--size-output",
+            "0",
+            "--max-output-bytes",
+            "1",
+        ]);
+        let res = run_extraction(args).await;
+        let blocks_files = std::fs::read_dir(output.join(OutputLayout::default().blocks))
+            .map(|files| files.count())
+            .unwrap_or_default();
+        let _ = std::fs::remove_dir_all(&output);
+        res.unwrap();
+        assert!(blocks_files > 0);
+        assert!(blocks_files < 20, "{} files written", blocks_files);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_since_manifest() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+        let output = std::env::temp_dir().join(format!("eth2dgraph_since_{}", std::process::id()));
+        let output_path = output.to_str().unwrap();
+        let manifest_path = output.join(MANIFEST_FILE);
+        let manifest_path = manifest_path.to_str().unwrap();
+        // every block goes to its own file
+        let run = |range: &[&str]| {
+            let mut args = vec![
+                "-e",
+                &eth_node,
+                "-n",
+                "1",
+                "-o",
+                output_path,
+                "--skip-decompilation",
+                "--size-output",
+                "0",
+            ];
+            args.extend_from_slice(range);
+            run_extraction(extract_args(&args))
+        };
+
+        let count_blocks_files = || {
+            std::fs::read_dir(output.join(OutputLayout::default().blocks))
+                .map(|files| files.count())
+                .unwrap_or_default()
+        };
+        let first = run(&["-f", "1000000", "-t", "1000001"]).await;
+        let first_manifest = Manifest::load(manifest_path);
+        let first_files = count_blocks_files();
+        let second = run(&["--since", manifest_path, "-t", "1000003"]).await;
+        let manifest = Manifest::load(manifest_path);
+        let blocks_files = count_blocks_files();
+        // a manifest already covering the range leaves nothing to extract
+        let up_to_date = run(&["--since", manifest_path, "-t", "1000003"]).await;
+        let _ = std::fs::remove_dir_all(&output);
+        first.unwrap();
+        second.unwrap();
+        assert!(up_to_date.is_err());
+
+        let manifest = manifest.unwrap();
+        let ranges = manifest
+            .ranges
+            .iter()
+            .map(|r| (r.from_block, r.to_block))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(1000000, 1000001), (1000002, 1000003)]);
+        // the second run continued the counters instead of overwriting the files of the first
+        let first_manifest = first_manifest.unwrap();
+        assert_eq!(first_manifest.files["blocks"], first_files);
+        assert_eq!(manifest.files["blocks"], blocks_files);
+        assert!(blocks_files > first_files);
+    }
 }
@@ -0,0 +1,151 @@
+use primitive_types::H256;
+use serde::Deserialize;
+use std::path::{Component, Path};
+
+/// Folder of each entity's output files, relative to the output path.
+/// The default is the `static/` and `dynamic/` layout, an empty path writes the files
+/// directly in the output path.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputLayout {
+    pub blocks: String,
+    pub skeletons: String,
+    pub events: String,
+    pub functions: String,
+    pub errors: String,
+    pub deployments: String,
+    pub creation_codes: String,
+    pub destructions: String,
+    pub transactions: String,
+    pub transfers: String,
+    pub logs: String,
+    pub internal_txs: String,
+    pub state_changes: String,
+    pub accounts: String,
+    pub gas_usage: String,
+}
+
+impl Default for OutputLayout {
+    fn default() -> Self {
+        Self {
+            blocks: "static/blocks".to_string(),
+            skeletons: "static/skeletons".to_string(),
+            events: "static/events".to_string(),
+            functions: "static/functions".to_string(),
+            errors: "static/errors".to_string(),
+            deployments: "static/deployments".to_string(),
+            creation_codes: "static/creation_codes".to_string(),
+            destructions: "static/destructions".to_string(),
+            transactions: "dynamic/transactions".to_string(),
+            transfers: "dynamic/transfers".to_string(),
+            logs: "dynamic/logs".to_string(),
+            internal_txs: "dynamic/internal_txs".to_string(),
+            state_changes: "dynamic/state_changes".to_string(),
+            accounts: "dynamic/accounts".to_string(),
+            gas_usage: "dynamic/gas_usage".to_string(),
+        }
+    }
+}
+
+impl OutputLayout {
+    /// The folders of all the entities
+    pub fn dirs(&self) -> [&str; 15] {
+        [
+            &self.blocks,
+            &self.skeletons,
+            &self.events,
+            &self.functions,
+            &self.errors,
+            &self.deployments,
+            &self.creation_codes,
+            &self.destructions,
+            &self.transactions,
+            &self.transfers,
+            &self.logs,
+            &self.internal_txs,
+            &self.state_changes,
+            &self.accounts,
+            &self.gas_usage,
+        ]
+    }
+
+    /// Check that every folder stays inside the output path
+    pub fn validate(&self) -> Result<(), String> {
+        for dir in self.dirs() {
+            let valid = Path::new(dir)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !valid {
+                return Err(format!(
+                    "Invalid output folder {:?}: it must be relative to the output path, without ..",
+                    dir
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of the counter-th output file of an entity, e.g. `<output_path>/static/blocks/blocks_0.json.gz`
+    pub fn file_path(output_path: &str, dir: &str, name: &str, counter: usize) -> String {
+        let dir = dir.trim_end_matches('/');
+        if dir.is_empty() {
+            format!("{}/{}_{}.json.gz", output_path, name, counter)
+        } else {
+            format!("{}/{}/{}_{}.json.gz", output_path, dir, name, counter)
+        }
+    }
+
+    /// Path of a content-addressed file, named after the hash of its content and sharded by its
+    /// first two characters, e.g. `<output_path>/static/skeletons/ab/ab12...ef.json.gz`
+    pub fn content_addressed_path(output_path: &str, dir: &str, hash: H256) -> String {
+        let hash = format!("{:x}", hash);
+        let dir = dir.trim_end_matches('/');
+        if dir.is_empty() {
+            format!("{}/{}/{}.json.gz", output_path, &hash[..2], hash)
+        } else {
+            format!("{}/{}/{}/{}.json.gz", output_path, dir, &hash[..2], hash)
+        }
+    }
+}
+
+/// Load the layout from a TOML file with a folder per entity, e.g. `blocks = "raw/blocks"`.
+/// Missing entities keep their default folder
+pub fn load_output_layout(path: &str) -> Result<OutputLayout, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let layout: OutputLayout =
+        toml::from_str(&content).map_err(|e| format!("Invalid layout in {}: {}", path, e))?;
+    layout.validate()?;
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OutputLayout;
+
+    #[test]
+    fn test_output_layout() {
+        let layout: OutputLayout =
+            toml::from_str("blocks = \"blocks\"\ntransfers = \"\"\n").unwrap();
+        assert_eq!(layout.blocks, "blocks");
+        assert_eq!(layout.logs, OutputLayout::default().logs);
+        assert!(layout.validate().is_ok());
+        assert_eq!(
+            OutputLayout::file_path("out", &layout.blocks, "blocks", 3),
+            "out/blocks/blocks_3.json.gz"
+        );
+        assert_eq!(
+            OutputLayout::file_path("out", &layout.transfers, "transfers", 0),
+            "out/transfers_0.json.gz"
+        );
+
+        assert!(toml::from_str::<OutputLayout>("block = \"blocks\"").is_err());
+        for dir in ["/tmp/blocks", "../blocks", "static/../../blocks"] {
+            let layout = OutputLayout {
+                blocks: dir.to_string(),
+                ..Default::default()
+            };
+            assert!(layout.validate().is_err(), "{}", dir);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Load of an extracted output folder into Dgraph, running the `dgraph` binary with the folders
+//! of the entities as `--files` and the schema shipped in `dgraph/contracts.schema`.
+//! `dgraph bulk` builds the posting lists of a new cluster offline: it's the fastest way to load
+//! a whole extraction, but it needs Zero running without Alphas, which are then started on the
+//! `--out` folder. `dgraph live` sends the mutations to running Alphas, to add an extraction to
+//! an existing graph.
+
+use crate::extraction::layout::OutputLayout;
+use crate::info;
+use crate::LoadArgs;
+use std::path::Path;
+use std::process::Command;
+
+/// Dgraph loader run on the output folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Loader {
+    /// `dgraph bulk`, into a new cluster
+    Bulk,
+    /// `dgraph live`, into running Alphas
+    Live,
+}
+
+/// Folders and compressed files of the output path, the loaders look for data files recursively.
+/// Only used when an entity is written in the output path itself, so it's the only case where
+/// data files in the output path are loaded. `manifest.json` and the other JSON files written
+/// next to the data are left out
+fn root_entries(output_path: &str) -> Result<Vec<String>, String> {
+    let mut entries = Vec::new();
+    let dir = std::fs::read_dir(output_path)
+        .map_err(|e| format!("could not read {}: {}", output_path, e))?;
+    for entry in dir {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if path.is_dir() || name.ends_with(".json.gz") {
+            entries.push(path.to_string_lossy().into_owned());
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Folders of the entities found in the output path. When an entity is written directly in the
+/// output path all its folders and data files are loaded
+fn data_dirs(output_path: &str, layout: &OutputLayout) -> Result<Vec<String>, String> {
+    if !Path::new(output_path).is_dir() {
+        return Err(format!("output folder {} not found", output_path));
+    }
+    let mut dirs = Vec::new();
+    for dir in layout.dirs() {
+        let dir = dir.trim_end_matches('/');
+        if dir.is_empty() {
+            return root_entries(output_path);
+        }
+        let path = format!("{}/{}", output_path.trim_end_matches('/'), dir);
+        if Path::new(&path).is_dir() && !dirs.contains(&path) {
+            dirs.push(path);
+        }
+    }
+    if dirs.is_empty() {
+        return Err(format!(
+            "no extracted files in {}, is it the output path of extract?",
+            output_path
+        ));
+    }
+    Ok(dirs)
+}
+
+/// Arguments of the `dgraph` binary for the loader
+fn loader_args(
+    loader: Loader,
+    dirs: &[String],
+    schema: &str,
+    alpha: &str,
+    zero: &str,
+    bulk_out: &str,
+) -> Vec<String> {
+    let mut args = match loader {
+        Loader::Bulk => vec!["bulk"],
+        Loader::Live => vec!["live"],
+    }
+    .into_iter()
+    .map(String::from)
+    .collect::<Vec<_>>();
+    args.extend([
+        "--files".to_string(),
+        dirs.join(","),
+        "--schema".to_string(),
+        schema.to_string(),
+    ]);
+    if loader == Loader::Live {
+        args.extend(["--alpha".to_string(), alpha.to_string()]);
+    }
+    args.extend(["--zero".to_string(), zero.to_string()]);
+    if loader == Loader::Bulk {
+        args.extend(["--out".to_string(), bulk_out.to_string()]);
+    }
+    args
+}
+
+/// Run the Dgraph loader on an extracted output folder, waiting for it to complete
+pub fn run_load(args: LoadArgs) -> Result<(), String> {
+    if !Path::new(&args.schema).is_file() {
+        return Err(format!(
+            "schema {} not found, pass the path of dgraph/contracts.schema with --schema",
+            args.schema
+        ));
+    }
+    let output_path = &args.output_path;
+    let dirs = data_dirs(output_path, &args.layout_config.unwrap_or_default())?;
+    let dgraph_bin = &args.dgraph_bin;
+    let args = loader_args(
+        args.loader,
+        &dirs,
+        &args.schema,
+        &args.dgraph_alpha,
+        &args.zero,
+        &args.bulk_out,
+    );
+    info!("Running {} {}", dgraph_bin, args.join(" "));
+    let status = Command::new(dgraph_bin)
+        .args(&args)
+        .status()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!(
+                "could not find the dgraph binary {}: install Dgraph (https://dgraph.io/docs/installation/) \
+                 or pass its path with --dgraph-bin",
+                dgraph_bin
+            ),
+            _ => format!("could not run {}: {}", dgraph_bin, e),
+        })?;
+    if !status.success() {
+        return Err(format!("dgraph {} failed: {}", args[0], status));
+    }
+    info!("Loaded {} into Dgraph", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::manifest::MANIFEST_FILE;
+
+    #[test]
+    fn test_loader_args() {
+        let output = std::env::temp_dir().join(format!("eth2dgraph_load_{}", std::process::id()));
+        let output_path = output.to_str().unwrap();
+        let layout = OutputLayout::default();
+        assert!(data_dirs(output_path, &layout).is_err());
+        std::fs::create_dir_all(output.join("static/blocks")).unwrap();
+        std::fs::create_dir_all(output.join("dynamic/transfers")).unwrap();
+
+        let dirs = data_dirs(output_path, &layout).unwrap();
+        assert_eq!(
+            dirs,
+            vec![
+                format!("{}/static/blocks", output_path),
+                format!("{}/dynamic/transfers", output_path)
+            ]
+        );
+        let bulk = loader_args(
+            Loader::Bulk,
+            &dirs,
+            "contracts.schema",
+            "localhost:9080",
+            "localhost:5080",
+            "out",
+        );
+        assert_eq!(bulk[0], "bulk");
+        assert_eq!(bulk[2], dirs.join(","));
+        assert!(bulk.ends_with(&[
+            "--zero".to_string(),
+            "localhost:5080".to_string(),
+            "--out".to_string(),
+            "out".to_string()
+        ]));
+        assert!(!bulk.contains(&"--alpha".to_string()));
+        let live = loader_args(
+            Loader::Live,
+            &dirs,
+            "contracts.schema",
+            "localhost:9080",
+            "localhost:5080",
+            "out",
+        );
+        assert_eq!(live[0], "live");
+        assert!(live.windows(2).any(|a| a == ["--alpha", "localhost:9080"]));
+        assert!(!live.contains(&"--out".to_string()));
+
+        // entities written in the output path itself load its folders and data files
+        let layout = OutputLayout {
+            transfers: String::new(),
+            ..OutputLayout::default()
+        };
+        std::fs::write(output.join("transfers_0.json.gz"), b"").unwrap();
+        std::fs::write(output.join(MANIFEST_FILE), b"{}").unwrap();
+        // with the default layout only the folders of the entities are loaded
+        assert_eq!(
+            data_dirs(output_path, &OutputLayout::default()).unwrap(),
+            dirs
+        );
+        assert_eq!(
+            data_dirs(output_path, &layout).unwrap(),
+            vec![
+                format!("{}/dynamic", output_path),
+                format!("{}/static", output_path),
+                format!("{}/transfers_0.json.gz", output_path)
+            ]
+        );
+
+        let err = run_load(LoadArgs {
+            output_path: output_path.to_string(),
+            loader: Loader::Bulk,
+            dgraph_alpha: "localhost:9080".to_string(),
+            zero: "localhost:5080".to_string(),
+            schema: concat!(env!("CARGO_MANIFEST_DIR"), "/dgraph/contracts.schema").to_string(),
+            bulk_out: "out".to_string(),
+            dgraph_bin: "eth2dgraph-missing-dgraph".to_string(),
+            layout_config: None,
+        })
+        .unwrap_err();
+        assert!(err.contains("could not find the dgraph binary"), "{}", err);
+        let _ = std::fs::remove_dir_all(&output);
+    }
+}
@@ -1,28 +1,179 @@
-use ethabi::{ParamType, Token};
-use ethers::types::{Address, H256, U256};
+use ethabi::{param_type::Reader, ParamType, Token};
+use ethers::abi::ethereum_types::BloomInput;
+use ethers::types::{Address, Bloom, H256, U256};
 use ethers::{
     providers::Middleware,
-    types::{Filter, Log, Topic},
+    types::{Filter, Log},
     utils::keccak256,
 };
-use std::sync::Arc;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::models::transfer::{TokenTransfer, TokenType};
 
+/// Which deployed contracts logs and transfers are restricted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ContractScope {
+    /// Contracts deployed in the same block the log was emitted in
+    Block,
+    /// Contracts deployed in any block of the range extracted so far
+    Range,
+}
+
+/// Parameter of a non-standard transfer event
+#[derive(Debug, Clone)]
+pub struct TransferParam {
+    name: String,
+    kind: ParamType,
+    indexed: bool,
+}
+
+/// Layout of a non-standard transfer event, with its parameters in declaration order.
+/// The parameters named `from`, `to` and `value` are the ones stored in the transfer.
+#[derive(Debug, Clone)]
+pub struct TransferLayout {
+    token_type: TokenType,
+    params: Vec<TransferParam>,
+}
+
+/// Custom transfer layouts by topic 0 of the event
+pub type TransferSignatures = HashMap<H256, TransferLayout>;
+
+/// Parse the JSON mapping the topic 0 of each custom transfer event to its layout, e.g.
+/// `{"0x...": {"token_type": "erc20", "params": [{"name": "from", "type": "address", "indexed": true}, ...]}}`.
+/// `token_type` is `erc20`, `erc721`, `erc1155` or `weth`, `erc20` if missing.
+/// An `id` parameter, if any, is stored as the id of the token moved.
+pub fn parse_transfer_signatures(json: &str) -> Result<TransferSignatures, String> {
+    #[derive(Deserialize)]
+    struct RawParam {
+        name: String,
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        indexed: bool,
+    }
+    #[derive(Deserialize)]
+    struct RawLayout {
+        token_type: Option<TokenType>,
+        params: Vec<RawParam>,
+    }
+
+    let raw: HashMap<H256, RawLayout> =
+        serde_json::from_str(json).map_err(|e| format!("Invalid transfer signatures: {}", e))?;
+    let mut signatures = HashMap::new();
+    for (topic0, layout) in raw {
+        let mut params = Vec::new();
+        for param in layout.params {
+            let kind = Reader::read(&param.kind)
+                .map_err(|e| format!("Invalid type {} in {:?}: {}", param.kind, topic0, e))?;
+            params.push(TransferParam {
+                name: param.name,
+                kind,
+                indexed: param.indexed,
+            });
+        }
+        for required in ["from", "to", "value"] {
+            if !params.iter().any(|p| p.name == required) {
+                return Err(format!("Missing {} parameter in {:?}", required, topic0));
+            }
+        }
+        signatures.insert(
+            topic0,
+            TransferLayout {
+                token_type: layout.token_type.unwrap_or(TokenType::ERC20),
+                params,
+            },
+        );
+    }
+    Ok(signatures)
+}
+
+/// Load the custom transfer layouts from a JSON file, see `parse_transfer_signatures`
+pub fn load_transfer_signatures(path: &str) -> Result<TransferSignatures, String> {
+    let json =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    parse_transfer_signatures(&json)
+}
+
+/// Decoder of a standard transfer event, a log can move several tokens (e.g. ERC-1155 batches).
+/// Logs not matching the layout of the event give no transfers
+type TransferDecoder = fn(&Log, u64) -> Vec<TokenTransfer>;
+
+lazy_static! {
+    /// The mainnet WETH9 contract, the only emitter of `Deposit` and `Withdrawal` decoded as
+    /// transfers: other contracts use the same signatures for unrelated events (e.g. vaults and
+    /// bridges). WETH deployments of other chains are not decoded
+    static ref WETH_ADDRESS: Address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+        .parse()
+        .unwrap();
+
+    /// Decoders of the standard transfer events, by topic 0 of the event
+    static ref STANDARD_TRANSFERS: HashMap<H256, TransferDecoder> = HashMap::from([
+        (
+            H256::from(keccak256(b"Transfer(address,address,uint256)")),
+            decode_transfer as TransferDecoder,
+        ),
+        (
+            H256::from(keccak256(
+                b"TransferSingle(address,address,address,uint256,uint256)"
+            )),
+            decode_transfer_single,
+        ),
+        (
+            H256::from(keccak256(
+                b"TransferBatch(address,address,address,uint256[],uint256[])"
+            )),
+            decode_transfer_batch,
+        ),
+        (
+            H256::from(keccak256(b"Deposit(address,uint256)")),
+            decode_weth_deposit,
+        ),
+        (
+            H256::from(keccak256(b"Withdrawal(address,uint256)")),
+            decode_weth_withdrawal,
+        ),
+    ]);
+}
+
+/// Topic 0 of the standard transfer events and of the custom ones
+fn transfer_topics(signatures: Option<&TransferSignatures>) -> Vec<H256> {
+    let mut topics: Vec<H256> = STANDARD_TRANSFERS.keys().copied().collect();
+    if let Some(signatures) = signatures {
+        topics.extend(
+            signatures
+                .keys()
+                .filter(|t| !STANDARD_TRANSFERS.contains_key(t)),
+        );
+    }
+    topics
+}
+
+/// Check if the logs bloom of a block may contain transfer events.
+/// False positives are possible, false negatives are not.
+pub fn may_contain_transfers(bloom: &Bloom, signatures: Option<&TransferSignatures>) -> bool {
+    transfer_topics(signatures)
+        .iter()
+        .any(|topic| bloom.contains_input(BloomInput::Raw(topic.as_bytes())))
+}
+
 pub async fn get_transfer_logs<T>(
     block: u64,
     eth_client: Arc<T>,
+    signatures: Option<&TransferSignatures>,
 ) -> Result<Vec<Log>, <T as Middleware>::Error>
 where
     T: Middleware,
 {
-    let transfer_event_sig = keccak256(b"Transfer(address,address,uint256)");
-
-    // filter only for Transfer events
+    // filter only for Transfer events, standard or custom ones
     let filter = Filter::new()
         .from_block(block)
         .to_block(block)
-        .topic0(Topic::Value(Some(transfer_event_sig.into())));
+        .topic0(transfer_topics(signatures));
 
     let logs: Vec<Log> = eth_client.get_logs(&filter).await?;
 
@@ -42,70 +193,202 @@ where
     eth_client.get_logs(&filter).await
 }
 
-pub fn get_transfer_from_logs(logs: &[Log]) -> Vec<TokenTransfer> {
-    let transfer_event_sig = keccak256(b"Transfer(address,address,uint256)");
+/// Decode a transfer event with a custom layout, None if the log doesn't match it
+fn decode_custom_transfer(
+    log: &Log,
+    timestamp: u64,
+    layout: &TransferLayout,
+) -> Option<TokenTransfer> {
+    let (indexed, not_indexed): (Vec<&TransferParam>, Vec<&TransferParam>) =
+        layout.params.iter().partition(|p| p.indexed);
+    if log.topics.len() != indexed.len() + 1 {
+        return None;
+    }
+
+    let mut tokens: HashMap<&str, Token> = HashMap::new();
+    for (param, topic) in indexed.iter().zip(&log.topics[1..]) {
+        // indexed dynamic types are hashed, decoding them fails
+        let token = ethabi::decode(&[param.kind.clone()], topic.as_bytes())
+            .ok()?
+            .pop()?;
+        tokens.insert(&param.name, token);
+    }
+    let data_types = not_indexed
+        .iter()
+        .map(|p| p.kind.clone())
+        .collect::<Vec<ParamType>>();
+    let data = ethabi::decode(&data_types, &log.data).ok()?;
+    for (param, token) in not_indexed.iter().zip(data) {
+        tokens.insert(&param.name, token);
+    }
+
+    let mut transfer = TokenTransfer::new(
+        log.address,
+        tokens.remove("from")?.into_address()?,
+        tokens.remove("to")?.into_address()?,
+        tokens.remove("value")?.into_uint()?,
+        log.block_number?,
+        log.transaction_hash?,
+        layout.token_type,
+    );
+    transfer.set_timestamp(timestamp);
+    if let Some(id) = tokens.remove("id") {
+        transfer.set_token_id(id.into_uint()?);
+    }
+    Some(transfer)
+}
+
+/// Transfer emitted by a log, None if the log is pending
+fn transfer(
+    log: &Log,
+    timestamp: u64,
+    from: Address,
+    to: Address,
+    value: U256,
+    token_type: TokenType,
+) -> Option<TokenTransfer> {
+    let mut transfer = TokenTransfer::new(
+        log.address,
+        from,
+        to,
+        value,
+        log.block_number?,
+        log.transaction_hash?,
+        token_type,
+    );
+    transfer.set_timestamp(timestamp);
+    Some(transfer)
+}
+
+/// ERC-20 and ERC-721 `Transfer(address indexed from, address indexed to, uint256 value)`,
+/// ERC-721 tokens also index the id of the token
+fn decode_transfer(log: &Log, timestamp: u64) -> Vec<TokenTransfer> {
+    let (value, token_type) = match (log.topics.len(), log.data.len()) {
+        (3, 32) => (U256::from_big_endian(&log.data), TokenType::ERC20),
+        (4, 0) => (
+            U256::from_big_endian(log.topics[3].as_bytes()),
+            TokenType::ERC721,
+        ),
+        _ => return Vec::new(),
+    };
+    let from = Address::from(log.topics[1]);
+    let to = Address::from(log.topics[2]);
+    transfer(log, timestamp, from, to, value, token_type)
+        .into_iter()
+        .collect()
+}
+
+/// ERC-1155 `TransferSingle(address indexed operator, address indexed from, address indexed to, uint256 id, uint256 value)`
+fn decode_transfer_single(log: &Log, timestamp: u64) -> Vec<TokenTransfer> {
+    if log.topics.len() != 4 || log.data.len() != 64 {
+        return Vec::new();
+    }
+    let from = Address::from(log.topics[2]);
+    let to = Address::from(log.topics[3]);
+    let id = U256::from_big_endian(&log.data[..32]);
+    let value = U256::from_big_endian(&log.data[32..]);
+    transfer(log, timestamp, from, to, value, TokenType::ERC1155)
+        .map(|mut transfer| {
+            transfer.set_token_id(id);
+            transfer
+        })
+        .into_iter()
+        .collect()
+}
+
+/// ERC-1155 `TransferBatch(address indexed operator, address indexed from, address indexed to, uint256[] ids, uint256[] values)`,
+/// a transfer for each id
+fn decode_transfer_batch(log: &Log, timestamp: u64) -> Vec<TokenTransfer> {
+    if log.topics.len() != 4 {
+        return Vec::new();
+    }
+    let array = ParamType::Array(Box::new(ParamType::Uint(256)));
+    let (ids, values) = match ethabi::decode(&[array.clone(), array], &log.data).as_deref() {
+        Ok([Token::Array(ids), Token::Array(values)]) if ids.len() == values.len() => {
+            (ids.clone(), values.clone())
+        }
+        _ => return Vec::new(),
+    };
+    let from = Address::from(log.topics[2]);
+    let to = Address::from(log.topics[3]);
+    ids.into_iter()
+        .zip(values)
+        .filter_map(|(id, value)| {
+            let mut transfer = transfer(
+                log,
+                timestamp,
+                from,
+                to,
+                value.into_uint()?,
+                TokenType::ERC1155,
+            )?;
+            transfer.set_token_id(id.into_uint()?);
+            Some(transfer)
+        })
+        .collect()
+}
+
+/// WETH `Deposit(address indexed dst, uint256 wad)`, a mint of the wrapped ether
+fn decode_weth_deposit(log: &Log, timestamp: u64) -> Vec<TokenTransfer> {
+    if log.address != *WETH_ADDRESS || log.topics.len() != 2 || log.data.len() != 32 {
+        return Vec::new();
+    }
+    let to = Address::from(log.topics[1]);
+    let value = U256::from_big_endian(&log.data);
+    transfer(log, timestamp, Address::zero(), to, value, TokenType::Weth)
+        .into_iter()
+        .collect()
+}
+
+/// WETH `Withdrawal(address indexed src, uint256 wad)`, a burn of the wrapped ether
+fn decode_weth_withdrawal(log: &Log, timestamp: u64) -> Vec<TokenTransfer> {
+    if log.address != *WETH_ADDRESS || log.topics.len() != 2 || log.data.len() != 32 {
+        return Vec::new();
+    }
+    let from = Address::from(log.topics[1]);
+    let value = U256::from_big_endian(&log.data);
+    transfer(
+        log,
+        timestamp,
+        from,
+        Address::zero(),
+        value,
+        TokenType::Weth,
+    )
+    .into_iter()
+    .collect()
+}
 
+/// Decode the token transfers from the logs of a block with the given timestamp.
+/// If `contracts` is set, only transfers emitted by those contracts are returned.
+/// Events whose topic 0 is in `signatures` are decoded with the custom layout,
+/// the others are dispatched by topic 0 to the decoders of the standard events:
+/// ERC-20 and ERC-721 `Transfer`, ERC-1155 `TransferSingle` and `TransferBatch`,
+/// WETH `Deposit` and `Withdrawal`, only when emitted by the WETH contract.
+pub fn get_transfer_from_logs(
+    logs: &[Log],
+    timestamp: u64,
+    contracts: Option<&HashSet<Address>>,
+    signatures: Option<&TransferSignatures>,
+) -> Vec<TokenTransfer> {
     let mut transfers = Vec::new();
 
     for log in logs {
-        if !log.topics.is_empty() && log.topics[0] == transfer_event_sig.into() {
-            let token_type = if log.topics.len() == 3 {
-                TokenType::ERC20
-            } else if log.topics.len() == 4 {
-                TokenType::ERC721
-            } else {
-                continue;
-            };
-
-            let data: Vec<H256> = log
-                .data
-                .chunks_exact(256 / 8)
-                .map(H256::from_slice)
-                .collect();
-            // merge the data and topics
-
-            let mut params_data = log.topics.clone();
-            params_data.extend(data);
-            // param 1 is the from address
-            // param 2 is the to address
-            // param 3 is the value
-            if params_data.len() != 4 {
-                continue;
-            }
-            let params_types =
-                [ParamType::Address, ParamType::Address, ParamType::Uint(256)].as_slice();
-
-            let params_data = params_data
-                .into_iter()
-                .flat_map(|x| x.as_bytes().to_vec())
-                .collect::<Vec<u8>>();
-
-            let params = ethabi::decode_whole(params_types, &params_data[32..]);
-
-            if let Ok(params) = params {
-                let from: Address = match params[0] {
-                    Token::Address(ref addr) => *addr,
-                    _ => continue,
-                };
-                let to: Address = match params[1] {
-                    Token::Address(ref addr) => *addr,
-                    _ => continue,
-                };
-                let value: U256 = match params[2] {
-                    Token::Uint(ref value) => *value,
-                    _ => continue,
-                };
-
-                transfers.push(TokenTransfer::new(
-                    log.address,
-                    from,
-                    to,
-                    value,
-                    log.block_number.unwrap(),
-                    log.transaction_hash.unwrap().0.into(),
-                    token_type,
-                ));
+        if contracts.is_some_and(|c| !c.contains(&log.address)) {
+            continue;
+        }
+        let topic0 = match log.topics.first() {
+            Some(topic0) => topic0,
+            None => continue,
+        };
+        if let Some(layout) = signatures.and_then(|s| s.get(topic0)) {
+            if let Some(transfer) = decode_custom_transfer(log, timestamp, layout) {
+                transfers.push(transfer);
             }
+            continue;
+        }
+        if let Some(decode) = STANDARD_TRANSFERS.get(topic0) {
+            transfers.extend(decode(log, timestamp));
         }
     }
 
@@ -116,6 +399,7 @@ pub fn get_transfer_from_logs(logs: &[Log]) -> Vec<TokenTransfer> {
 mod tests {
 
     use super::*;
+    use crate::models::{transfer::TransferCounts, SerializeDgraph};
     use ethers::providers::Provider;
 
     #[tokio::test]
@@ -126,7 +410,7 @@ mod tests {
 
         let block = 1000000;
 
-        get_transfer_logs(block, eth_client).await.unwrap();
+        get_transfer_logs(block, eth_client, None).await.unwrap();
     }
 
     #[tokio::test]
@@ -137,10 +421,265 @@ mod tests {
 
         let block = 10000000;
 
-        let logs = get_transfer_logs(block, eth_client).await.unwrap();
+        let logs = get_transfer_logs(block, eth_client, None).await.unwrap();
 
-        let transfers = get_transfer_from_logs(&logs);
+        let transfers = get_transfer_from_logs(&logs, 0, None, None);
 
         println!("{:?}", transfers);
     }
+
+    #[tokio::test]
+    async fn test_transfers_bloom() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+
+        let eth_client = Arc::new(Provider::try_from(eth_node).unwrap());
+
+        // blocks with and without transfers
+        for block in [46147, 1000000, 10000000, 16000000] {
+            let bloom = eth_client
+                .get_block(block)
+                .await
+                .unwrap()
+                .unwrap()
+                .logs_bloom
+                .unwrap();
+            let logs = get_transfer_logs(block, eth_client.clone(), None)
+                .await
+                .unwrap();
+            if !logs.is_empty() {
+                assert!(may_contain_transfers(&bloom, None));
+            }
+        }
+    }
+
+    #[test]
+    fn test_may_contain_transfers() {
+        let mut bloom = Bloom::default();
+        assert!(!may_contain_transfers(&bloom, None));
+        bloom.accrue(BloomInput::Raw(&keccak256(
+            b"Transfer(address,address,uint256)",
+        )));
+        assert!(may_contain_transfers(&bloom, None));
+    }
+
+    #[test]
+    fn test_transfer_contracts_allowlist() {
+        let transfer_event_sig: H256 = keccak256(b"Transfer(address,address,uint256)").into();
+        let transfer_log = |contract: Address| Log {
+            address: contract,
+            topics: vec![
+                transfer_event_sig,
+                H256::from(Address::from_low_u64_be(10)),
+                H256::from(Address::from_low_u64_be(11)),
+            ],
+            data: H256::from_low_u64_be(100).as_bytes().to_vec().into(),
+            block_number: Some(1.into()),
+            transaction_hash: Some(H256::from_low_u64_be(1)),
+            ..Default::default()
+        };
+        let allowed = Address::from_low_u64_be(1);
+        let logs = vec![
+            transfer_log(allowed),
+            transfer_log(Address::from_low_u64_be(2)),
+            transfer_log(allowed),
+        ];
+
+        assert_eq!(get_transfer_from_logs(&logs, 0, None, None).len(), 3);
+
+        let allowlist = HashSet::from([allowed]);
+        let transfers = get_transfer_from_logs(&logs, 0, Some(&allowlist), None);
+        assert_eq!(transfers.len(), 2);
+        let excluded = format!("{:?}", Address::from_low_u64_be(2));
+        assert!(!format!("{:?}", transfers).contains(&excluded));
+    }
+
+    #[test]
+    fn test_transfer_standards() {
+        // a block with an ERC-20, an ERC-721, two ERC-1155 and two WETH transfers
+        let topic0 = |signature: &[u8]| H256::from(keccak256(signature));
+        let account = |n: u64| H256::from(Address::from_low_u64_be(n));
+        let word = |n: u64| H256::from_low_u64_be(n).as_bytes().to_vec();
+        let log = |contract: u64, topics: Vec<H256>, data: Vec<u8>| Log {
+            address: Address::from_low_u64_be(contract),
+            topics,
+            data: data.into(),
+            block_number: Some(1.into()),
+            transaction_hash: Some(H256::from_low_u64_be(contract)),
+            ..Default::default()
+        };
+        let transfer = topic0(b"Transfer(address,address,uint256)");
+        let batch = ethabi::encode(&[
+            Token::Array(vec![Token::Uint(7.into()), Token::Uint(8.into())]),
+            Token::Array(vec![Token::Uint(70.into()), Token::Uint(80.into())]),
+        ]);
+        let logs = vec![
+            log(1, vec![transfer, account(10), account(11)], word(100)),
+            log(
+                2,
+                vec![transfer, account(10), account(11), H256::from_low_u64_be(5)],
+                vec![],
+            ),
+            log(
+                3,
+                vec![
+                    topic0(b"TransferSingle(address,address,address,uint256,uint256)"),
+                    account(9),
+                    account(10),
+                    account(11),
+                ],
+                [word(6), word(60)].concat(),
+            ),
+            log(
+                4,
+                vec![
+                    topic0(b"TransferBatch(address,address,address,uint256[],uint256[])"),
+                    account(9),
+                    account(10),
+                    account(11),
+                ],
+                batch,
+            ),
+            Log {
+                address: *WETH_ADDRESS,
+                ..log(
+                    5,
+                    vec![topic0(b"Deposit(address,uint256)"), account(10)],
+                    word(1000),
+                )
+            },
+            Log {
+                address: *WETH_ADDRESS,
+                ..log(
+                    5,
+                    vec![topic0(b"Withdrawal(address,uint256)"), account(11)],
+                    word(500),
+                )
+            },
+            // not a transfer, a Transfer with an unexpected layout, and the WETH events
+            // emitted by another contract
+            log(
+                6,
+                vec![topic0(b"Approval(address,address,uint256)")],
+                word(1),
+            ),
+            log(7, vec![transfer, account(10)], word(1)),
+            log(
+                8,
+                vec![topic0(b"Deposit(address,uint256)"), account(10)],
+                word(1000),
+            ),
+            log(
+                8,
+                vec![topic0(b"Withdrawal(address,uint256)"), account(11)],
+                word(500),
+            ),
+        ];
+
+        let transfers = get_transfer_from_logs(&logs, 0, None, None);
+        let json = transfers
+            .iter()
+            .map(|t| {
+                let mut serializer = serde_json::Serializer::new(Vec::new());
+                t.serialize_dgraph(&mut serializer).unwrap();
+                serde_json::from_slice::<serde_json::Value>(&serializer.into_inner()).unwrap()
+            })
+            .collect::<Vec<serde_json::Value>>();
+        let kinds = transfers
+            .iter()
+            .map(TokenTransfer::token_type)
+            .collect::<Vec<TokenType>>();
+        assert_eq!(
+            kinds,
+            [
+                TokenType::ERC20,
+                TokenType::ERC721,
+                TokenType::ERC1155,
+                TokenType::ERC1155,
+                TokenType::ERC1155,
+                TokenType::Weth,
+                TokenType::Weth,
+            ]
+        );
+
+        assert_eq!(json[0]["TokenTransfer.value"], "100");
+        assert_eq!(json[1]["TokenTransfer.token_id"], "5");
+        assert_eq!(json[2]["TokenTransfer.token_id"], "6");
+        assert_eq!(json[2]["TokenTransfer.value"], "60");
+        assert_eq!(json[3]["TokenTransfer.token_id"], "7");
+        assert_eq!(json[4]["TokenTransfer.token_id"], "8");
+        assert_eq!(json[4]["TokenTransfer.value"], "80");
+        for t in &transfers[..5] {
+            assert_eq!(t.sender(), Address::from_low_u64_be(10));
+            assert_eq!(t.recipient(), Address::from_low_u64_be(11));
+        }
+        // deposits mint and withdrawals burn the wrapped ether
+        assert_eq!(transfers[5].sender(), Address::zero());
+        assert_eq!(transfers[5].recipient(), Address::from_low_u64_be(10));
+        assert_eq!(json[5]["TokenTransfer.value"], "1000");
+        assert_eq!(transfers[6].sender(), Address::from_low_u64_be(11));
+        assert_eq!(transfers[6].recipient(), Address::zero());
+        // the counts of the block, WETH is an ERC-20
+        assert_eq!(
+            TransferCounts::new(&transfers),
+            TransferCounts {
+                erc20: 3,
+                erc721: 1,
+                erc1155: 3,
+            }
+        );
+
+        let mut bloom = Bloom::default();
+        bloom.accrue(BloomInput::Raw(
+            topic0(b"Deposit(address,uint256)").as_bytes(),
+        ));
+        assert!(may_contain_transfers(&bloom, None));
+    }
+
+    #[test]
+    fn test_custom_transfer_signature() {
+        // TransferWithFee(address indexed from, uint256 value, address indexed to, uint256 fee)
+        let topic0: H256 = keccak256(b"TransferWithFee(address,uint256,address,uint256)").into();
+        let signatures = parse_transfer_signatures(&format!(
+            r#"{{
+                "{:?}": {{
+                    "params": [
+                        {{ "name": "from", "type": "address", "indexed": true }},
+                        {{ "name": "value", "type": "uint256" }},
+                        {{ "name": "to", "type": "address", "indexed": true }},
+                        {{ "name": "fee", "type": "uint256" }}
+                    ]
+                }}
+            }}"#,
+            topic0
+        ))
+        .unwrap();
+
+        let from = Address::from_low_u64_be(10);
+        let to = Address::from_low_u64_be(11);
+        let mut data = H256::from_low_u64_be(100).as_bytes().to_vec();
+        data.extend(H256::from_low_u64_be(1).as_bytes());
+        let log = Log {
+            address: Address::from_low_u64_be(1),
+            topics: vec![topic0, H256::from(from), H256::from(to)],
+            data: data.into(),
+            block_number: Some(1.into()),
+            transaction_hash: Some(H256::from_low_u64_be(1)),
+            ..Default::default()
+        };
+
+        // without the custom layout the event is not a transfer
+        assert!(get_transfer_from_logs(&[log.clone()], 0, None, None).is_empty());
+
+        let transfers = get_transfer_from_logs(&[log], 0, None, Some(&signatures));
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].sender(), from);
+        assert_eq!(transfers[0].recipient(), to);
+        assert!(format!("{:?}", transfers[0]).contains("value: 100"));
+
+        assert!(parse_transfer_signatures(&format!(
+            r#"{{ "{:?}": {{ "params": [{{ "name": "from", "type": "address" }}] }} }}"#,
+            topic0
+        ))
+        .is_err());
+    }
 }
@@ -0,0 +1,203 @@
+//! `manifest.json` of an output folder, written at the end of each extraction to a local folder:
+//! the block ranges extracted into the folder and the next counter of the files of each entity.
+//! `extract --since <manifest>` extracts the blocks after the last one it covers, continuing the
+//! counters, so the new files can sit next to the old ones without overwriting them. The blocks of
+//! the ranges that failed or were skipped are kept in the manifest, and extracted again first.
+
+use crate::extraction::layout::OutputLayout;
+use crate::extraction::merge::{entities, entity_files, file_counter};
+use crate::extraction::writer::UnextractedBlocks;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Name of the manifest in the output path
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Next counter of the `<name>_<n>.json.gz` files of each entity, by name
+pub type FileCounters = BTreeMap<String, usize>;
+
+/// Blocks extracted by a run, both included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockRange {
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Ranges extracted into the folder, in the order of the runs
+    pub ranges: Vec<BlockRange>,
+    /// Next counter of the files of each entity, entities without files are left out
+    pub files: FileCounters,
+    /// Blocks of the ranges that couldn't be extracted, see `failed_blocks.txt`
+    #[serde(default)]
+    pub failed_blocks: Vec<u64>,
+    /// Blocks of the ranges over `--max-block-size-bytes`, see `skipped_blocks.txt`
+    #[serde(default)]
+    pub skipped_blocks: Vec<u64>,
+}
+
+impl Manifest {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        serde_json::from_str(&content).map_err(|e| format!("invalid manifest {}: {}", path, e))
+    }
+
+    /// Last block extracted by any run
+    pub fn last_block(&self) -> Option<u64> {
+        self.ranges.iter().map(|r| r.to_block).max()
+    }
+
+    /// Record a run, unless it stopped before extracting its first block
+    pub fn add_range(&mut self, from_block: u64, to_block: u64) {
+        if to_block >= from_block {
+            self.ranges.push(BlockRange {
+                from_block,
+                to_block,
+            });
+        }
+    }
+
+    /// Failed and skipped blocks, sorted
+    pub fn pending_blocks(&self) -> Vec<u64> {
+        let mut blocks = [self.failed_blocks.as_slice(), &self.skipped_blocks].concat();
+        blocks.sort();
+        blocks.dedup();
+        blocks
+    }
+
+    /// Record the blocks a run couldn't extract. The pending blocks it `retried` are replaced by
+    /// the outcome of the run, the ones it didn't get to stay pending
+    pub fn update_unextracted(&mut self, retried: &[u64], unextracted: &UnextractedBlocks) {
+        for (blocks, new) in [
+            (&mut self.failed_blocks, &unextracted.failed),
+            (&mut self.skipped_blocks, &unextracted.skipped),
+        ] {
+            blocks.retain(|b| !retried.contains(b));
+            blocks.extend(new);
+            blocks.sort();
+            blocks.dedup();
+        }
+    }
+
+    /// Update the counters with the files found in the output path. Counters are never lowered,
+    /// so a run writing to another folder keeps the ones of the folders it continues
+    pub fn update_files(&mut self, output_path: &str, layout: &OutputLayout) -> Result<(), String> {
+        for (dir, name, _) in entities(layout) {
+            let files = entity_files(&Path::new(output_path).join(dir), name)?;
+            if let Some(counter) = files.last().and_then(|f| file_counter(f, name)) {
+                let next = self.files.entry(name.to_string()).or_default();
+                *next = (*next).max(counter + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the manifest to `<output_path>/manifest.json`
+    pub fn write(&self, output_path: &str) -> Result<(), std::io::Error> {
+        let path = Path::new(output_path).join(MANIFEST_FILE);
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_manifest() {
+        let output =
+            std::env::temp_dir().join(format!("eth2dgraph_manifest_{}", std::process::id()));
+        let output_path = output.to_str().unwrap();
+        let layout = OutputLayout::default();
+        let touch = |file: &str| {
+            let path = output.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        };
+
+        // first run, blocks 100 to 199
+        touch("static/blocks/blocks_0.json.gz");
+        touch("static/blocks/blocks_1.json.gz");
+        touch("dynamic/logs/logs_0.json.gz");
+        let mut manifest = Manifest::default();
+        manifest.add_range(100, 199);
+        manifest.update_files(output_path, &layout).unwrap();
+        manifest.write(output_path).unwrap();
+
+        let first = Manifest::load(output.join(MANIFEST_FILE).to_str().unwrap()).unwrap();
+        assert_eq!(first, manifest);
+        assert_eq!(first.last_block(), Some(199));
+        assert_eq!(first.files["blocks"], 2);
+        assert_eq!(first.files["logs"], 1);
+        assert!(!first.files.contains_key("transactions"));
+
+        // the second run starts from the counters of the first one, and adds transactions
+        let mut manifest = first.clone();
+        touch("static/blocks/blocks_2.json.gz");
+        touch("dynamic/transactions/transactions_0.json.gz");
+        manifest.add_range(200, 250);
+        // a run stopped before its first block is not recorded
+        manifest.add_range(251, 250);
+        manifest.update_files(output_path, &layout).unwrap();
+        manifest.write(output_path).unwrap();
+
+        let second = Manifest::load(output.join(MANIFEST_FILE).to_str().unwrap()).unwrap();
+        assert_eq!(
+            second.ranges,
+            vec![
+                BlockRange {
+                    from_block: 100,
+                    to_block: 199
+                },
+                BlockRange {
+                    from_block: 200,
+                    to_block: 250
+                }
+            ]
+        );
+        assert_eq!(second.last_block(), Some(250));
+        assert_eq!(second.files["blocks"], 3);
+        assert_eq!(second.files["logs"], 1);
+        assert_eq!(second.files["transactions"], 1);
+
+        // a run into another folder keeps the counters of the manifest it continues
+        let mut elsewhere = second.clone();
+        elsewhere.update_files("/nonexistent", &layout).unwrap();
+        assert_eq!(elsewhere.files, second.files);
+
+        assert!(Manifest::load("/nonexistent/manifest.json").is_err());
+        let _ = std::fs::remove_dir_all(&output);
+    }
+
+    #[test]
+    fn test_unextracted_blocks() {
+        let mut manifest = Manifest::default();
+        manifest.update_unextracted(
+            &[],
+            &UnextractedBlocks {
+                failed: vec![7, 3],
+                skipped: vec![5],
+            },
+        );
+        assert_eq!(manifest.pending_blocks(), vec![3, 5, 7]);
+
+        // 3 and 5 are extracted again, 3 fails again and a new block fails,
+        // the run stops before getting to 7
+        manifest.update_unextracted(
+            &[3, 5],
+            &UnextractedBlocks {
+                failed: vec![3, 12],
+                skipped: vec![],
+            },
+        );
+        assert_eq!(manifest.failed_blocks, vec![3, 7, 12]);
+        assert!(manifest.skipped_blocks.is_empty());
+
+        // manifests written before the lists were added have no pending blocks
+        let old: Manifest = serde_json::from_str(r#"{"ranges": [], "files": {}}"#).unwrap();
+        assert!(old.pending_blocks().is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+//! Merge of the output folders of several extractions, e.g. of ranges extracted on different
+//! machines, into a single tree ready for the bulk loader.
+//! The files of the per-block entities are copied as they are, since the ranges don't overlap.
+//! Skeletons, functions, events, errors and creation codes are shared among ranges, so they're
+//! read back and written once per uid, which is derived from the same hash the writer dedups them on.
+//! Accounts and gas usages are copied too: the bulk loader merges the nodes with the same uid,
+//! but it keeps only one of the values of their scalar predicates.
+
+use crate::extraction::layout::OutputLayout;
+use crate::info;
+use flate2::Compression;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Files written by the merge and entities dropped because another input already had them
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MergeSummary {
+    pub files: usize,
+    pub duplicates: usize,
+}
+
+/// Folder and file name of each entity, and whether it's deduplicated across the inputs
+pub(crate) fn entities(layout: &OutputLayout) -> [(&str, &'static str, bool); 15] {
+    [
+        (&layout.blocks, "blocks", false),
+        (&layout.skeletons, "skeletons", true),
+        (&layout.events, "events", true),
+        (&layout.functions, "functions", true),
+        (&layout.errors, "errors", true),
+        (&layout.deployments, "deployments", false),
+        (&layout.creation_codes, "creation_codes", true),
+        (&layout.destructions, "destructions", false),
+        (&layout.transactions, "transactions", false),
+        (&layout.transfers, "transfers", false),
+        (&layout.logs, "logs", false),
+        (&layout.internal_txs, "internal_txs", false),
+        (&layout.state_changes, "state_changes", false),
+        (&layout.accounts, "accounts", false),
+        (&layout.gas_usage, "gas_usage", false),
+    ]
+}
+
+/// Counter of a `<name>_<n>.json.gz` file, `None` for the files of other entities
+pub(crate) fn file_counter(path: &Path, name: &str) -> Option<usize> {
+    path.file_name()
+        .and_then(|f| f.to_str())
+        .and_then(|f| f.strip_prefix(name))
+        .and_then(|f| f.strip_prefix('_'))
+        .and_then(|f| f.strip_suffix(".json.gz"))
+        .and_then(|c| c.parse::<usize>().ok())
+}
+
+/// The `<name>_<n>.json.gz` files of an entity in a folder, sorted by counter
+pub(crate) fn entity_files(dir: &Path, name: &str) -> Result<Vec<PathBuf>, String> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| format!("could not read {:?}: {}", dir, e))? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if let Some(counter) = file_counter(&path, name) {
+            files.push((counter, path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The content-addressed files of a folder, `<first 2 hash chars>/<hash>.json.gz`, sorted by hash
+fn content_addressed_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let pattern = format!("{}/??/*.json.gz", dir.display());
+    let mut files = glob::glob(&pattern)
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    files.sort();
+    Ok(files)
+}
+
+fn read_json_gz(path: &Path) -> Result<Vec<serde_json::Value>, String> {
+    let mut json = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|f| flate2::read::GzDecoder::new(f).read_to_end(&mut json))
+        .map_err(|e| format!("could not read {:?}: {}", path, e))?;
+    serde_json::from_slice(&json).map_err(|e| format!("invalid output file {:?}: {}", path, e))
+}
+
+fn write_json_gz(
+    path: &str,
+    values: &[serde_json::Value],
+    compression_level: u32,
+) -> Result<(), String> {
+    let json = serde_json::to_vec(values).map_err(|e| e.to_string())?;
+    let mut encoder =
+        flate2::write::GzEncoder::new(Vec::new(), Compression::new(compression_level));
+    let compressed = encoder
+        .write_all(&json)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| format!("could not compress {}: {}", path, e))?;
+    std::fs::write(path, compressed).map_err(|e| format!("could not write {}: {}", path, e))
+}
+
+/// Key of a deduplicated entity, its uid. Entities without one are never dropped
+fn dedup_key(value: &serde_json::Value) -> Option<&str> {
+    value.get("uid").and_then(|uid| uid.as_str())
+}
+
+/// Merge the output folders of several extractions into `output`, which must be empty or missing.
+/// All the folders use the same layout.
+/// @param inputs The output folders of the extractions
+/// @param output The merged output folder
+/// @param layout The folders of the entities, in the inputs and in the output
+/// @param size_output Max size of the uncompressed deduplicated files, in KB
+/// @param compression_level Compression level of the deduplicated files, from 0 to 9
+pub fn merge_outputs(
+    inputs: &[String],
+    output: &str,
+    layout: &OutputLayout,
+    size_output: usize,
+    compression_level: u32,
+) -> Result<MergeSummary, String> {
+    let output_dir = Path::new(output);
+    if output_dir.is_dir()
+        && std::fs::read_dir(output_dir)
+            .map_err(|e| e.to_string())?
+            .next()
+            .is_some()
+    {
+        return Err(format!("output folder {} is not empty", output));
+    }
+    for input in inputs {
+        if !Path::new(input).is_dir() {
+            return Err(format!("input folder {} not found", input));
+        }
+    }
+
+    let mut summary = MergeSummary::default();
+    for (dir, name, dedup) in entities(layout) {
+        std::fs::create_dir_all(output_dir.join(dir))
+            .map_err(|e| format!("could not create {}/{}: {}", output, dir, e))?;
+        let mut counter = 0;
+        if !dedup {
+            for input in inputs {
+                for file in entity_files(&Path::new(input).join(dir), name)? {
+                    let o = OutputLayout::file_path(output, dir, name, counter);
+                    std::fs::copy(&file, &o)
+                        .map_err(|e| format!("could not copy {:?} to {}: {}", file, o, e))?;
+                    counter += 1;
+                }
+            }
+            summary.files += counter;
+            continue;
+        }
+
+        let mut stored: HashSet<String> = HashSet::new();
+        // content-addressed files are kept as they are, they can't collide with different content
+        for input in inputs {
+            for file in content_addressed_files(&Path::new(input).join(dir))? {
+                let relative = file.strip_prefix(input).map_err(|e| e.to_string())?;
+                let o = output_dir.join(relative);
+                if o.exists() {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                for value in read_json_gz(&file)? {
+                    if let Some(key) = dedup_key(&value) {
+                        stored.insert(key.to_string());
+                    }
+                }
+                if let Some(parent) = o.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                }
+                std::fs::copy(&file, &o)
+                    .map_err(|e| format!("could not copy {:?} to {:?}: {}", file, o, e))?;
+                summary.files += 1;
+            }
+        }
+
+        let mut values = Vec::new();
+        let mut size = 0;
+        for input in inputs {
+            for file in entity_files(&Path::new(input).join(dir), name)? {
+                for value in read_json_gz(&file)? {
+                    if let Some(key) = dedup_key(&value) {
+                        if !stored.insert(key.to_string()) {
+                            summary.duplicates += 1;
+                            continue;
+                        }
+                    }
+                    size += value.to_string().len();
+                    values.push(value);
+                    if size / 1024 > size_output {
+                        let o = OutputLayout::file_path(output, dir, name, counter);
+                        write_json_gz(&o, &values, compression_level)?;
+                        counter += 1;
+                        values.clear();
+                        size = 0;
+                    }
+                }
+            }
+        }
+        if !values.is_empty() {
+            let o = OutputLayout::file_path(output, dir, name, counter);
+            write_json_gz(&o, &values, compression_level)?;
+            counter += 1;
+        }
+        summary.files += counter;
+    }
+
+    // the lists of failed and skipped blocks and extraction errors are concatenated
+    for log in ["failed_blocks.txt", "skipped_blocks.txt", "errors.jsonl"] {
+        let mut content = Vec::new();
+        for input in inputs {
+            let path = Path::new(input).join(log);
+            if path.exists() {
+                let mut c = std::fs::read(&path).map_err(|e| e.to_string())?;
+                if !c.is_empty() && !c.ends_with(b"\n") {
+                    c.push(b'\n');
+                }
+                content.append(&mut c);
+            }
+        }
+        if !content.is_empty() {
+            std::fs::write(output_dir.join(log), content).map_err(|e| e.to_string())?;
+        }
+    }
+
+    info!(
+        "Merged {} inputs into {}: {} files written, {} duplicated entities dropped",
+        inputs.len(),
+        output,
+        summary.files,
+        summary.duplicates
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_outputs, read_json_gz, write_json_gz};
+    use crate::extraction::layout::OutputLayout;
+    use crate::extraction::writer::flush;
+    use crate::models::skeleton::Skeleton;
+    use ethers::types::Bytes;
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn test_merge_outputs() {
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_merge_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let layout = OutputLayout::default();
+        let skeleton = |code: &'static [u8]| Skeleton::new(Bytes::from_static(code));
+        let function = |sig: &str| json!({"dgraph.type": "Function", "uid": format!("_:{}", sig)});
+
+        // two machines extracted different ranges, finding a skeleton and a function in common
+        let mut inputs = Vec::new();
+        for (i, codes, sigs) in [
+            (
+                0,
+                [&[0x60u8, 0x80][..], &[0x60, 0x40]],
+                ["0xa9059cbb", "0x095ea7b3"],
+            ),
+            (
+                1,
+                [&[0x60, 0x80], &[0x60, 0x20]],
+                ["0xa9059cbb", "0x70a08231"],
+            ),
+        ] {
+            let input = dir.join(format!("shard_{}", i));
+            for d in [&layout.skeletons, &layout.functions, &layout.transactions] {
+                std::fs::create_dir_all(input.join(d)).unwrap();
+            }
+            let input = input.to_str().unwrap().to_string();
+            let mut skeletons = codes.iter().map(|c| skeleton(c)).collect();
+            let o = OutputLayout::file_path(&input, &layout.skeletons, "skeletons", 0);
+            flush(&mut skeletons, &o, 6, false, false).unwrap();
+            let functions = sigs.iter().map(|s| function(s)).collect::<Vec<_>>();
+            let o = OutputLayout::file_path(&input, &layout.functions, "functions", 0);
+            write_json_gz(&o, &functions, 6).unwrap();
+            for counter in 0..2 {
+                let tx =
+                    json!({"dgraph.type": "Transaction", "Transaction.block": i * 2 + counter});
+                let o =
+                    OutputLayout::file_path(&input, &layout.transactions, "transactions", counter);
+                write_json_gz(&o, &[tx], 6).unwrap();
+            }
+            std::fs::write(
+                Path::new(&input).join("failed_blocks.txt"),
+                format!("{}\n", i),
+            )
+            .unwrap();
+            inputs.push(input);
+        }
+
+        let output = dir.join("merged").to_str().unwrap().to_string();
+        let summary = merge_outputs(&inputs, &output, &layout, 8192, 6).unwrap();
+        assert_eq!(summary.duplicates, 2);
+
+        let read = |dir: &str, name: &str, counter: usize| {
+            read_json_gz(Path::new(&OutputLayout::file_path(
+                &output, dir, name, counter,
+            )))
+            .unwrap()
+        };
+        let skeletons = read(&layout.skeletons, "skeletons", 0);
+        let mut codes = skeletons
+            .iter()
+            .map(|s| s["Skeleton.bytecode"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        codes.sort();
+        assert_eq!(codes, vec!["0x6020", "0x6040", "0x6080"]);
+        assert_eq!(read(&layout.functions, "functions", 0).len(), 3);
+        // the dynamic files are copied with new counters
+        let blocks = (0..4)
+            .map(|c| read(&layout.transactions, "transactions", c)[0]["Transaction.block"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![0, 1, 2, 3]);
+        let failed = std::fs::read_to_string(Path::new(&output).join("failed_blocks.txt")).unwrap();
+        assert_eq!(failed, "0\n1\n");
+        // 1 skeletons, 1 functions and 4 transactions files
+        assert_eq!(summary.files, 6);
+
+        // the output must be empty
+        assert!(merge_outputs(&inputs, &output, &layout, 8192, 6).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
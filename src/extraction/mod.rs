@@ -1,6 +1,17 @@
+pub mod bench;
 pub mod blocks;
+pub mod errors;
 pub mod extract;
+pub mod layout;
+pub mod load;
+pub mod manifest;
 pub mod logs;
+pub mod merge;
+pub mod rate_limit;
+pub mod s3;
+pub mod skeleton_cache;
+pub mod skeleton_set;
 pub mod stream;
+pub mod trace_tx;
 pub mod traces;
 pub mod writer;
@@ -0,0 +1,84 @@
+use async_trait::async_trait;
+use ethers::providers::JsonRpcClient;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::Debug,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// JSON-RPC client sending at most `max_rps` requests per second, the budget is shared
+/// by all the tasks using the same provider.
+/// Requests over the budget wait for a token instead of being sent and retried on 429.
+/// It also counts the requests sent, to report the effective rate
+#[derive(Debug)]
+pub struct RateLimitedClient<C> {
+    inner: C,
+    limiter: Option<Arc<DefaultDirectRateLimiter>>,
+    requests: Arc<AtomicU64>,
+}
+
+impl<C> RateLimitedClient<C> {
+    /// Wrap a client, without limit if `max_rps` is None
+    pub fn new(inner: C, max_rps: Option<NonZeroU32>) -> Self {
+        Self {
+            inner,
+            limiter: max_rps.map(|rps| Arc::new(RateLimiter::direct(Quota::per_second(rps)))),
+            requests: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Counter of the requests sent, it keeps counting after the client is moved into a provider
+    pub fn request_counter(&self) -> Arc<AtomicU64> {
+        self.requests.clone()
+    }
+}
+
+#[async_trait]
+impl<C: JsonRpcClient> JsonRpcClient for RateLimitedClient<C> {
+    type Error = C::Error;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        if let Some(limiter) = &self.limiter {
+            limiter.until_ready().await;
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.inner.request(method, params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimitedClient;
+    use ethers::providers::{JsonRpcClient, MockProvider};
+    use ethers::types::U64;
+    use std::num::NonZeroU32;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mock = MockProvider::new();
+        for i in 0..20u64 {
+            mock.push(U64::from(i)).unwrap();
+        }
+        let client = RateLimitedClient::new(mock, NonZeroU32::new(10));
+        let requests = client.request_counter();
+
+        // the bucket starts full, the 10 requests over the budget take one second
+        let now = Instant::now();
+        for _ in 0..20 {
+            let _: U64 = client.request("eth_blockNumber", ()).await.unwrap();
+        }
+        assert!(now.elapsed() >= Duration::from_millis(900));
+        assert_eq!(requests.load(Ordering::Relaxed), 20);
+    }
+}
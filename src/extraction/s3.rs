@@ -0,0 +1,62 @@
+/// True if the output path points to an S3 bucket, i.e. `s3://bucket/prefix`
+pub fn is_s3_url(path: &str) -> bool {
+    path.starts_with("s3://")
+}
+
+/// Split an `s3://bucket/key` url in bucket and key
+#[cfg(any(feature = "s3", test))]
+pub fn parse_s3_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("s3://")?;
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        return None;
+    }
+    Some((bucket, key.trim_start_matches('/')))
+}
+
+#[cfg(feature = "s3")]
+static CLIENT: tokio::sync::OnceCell<aws_sdk_s3::Client> = tokio::sync::OnceCell::const_new();
+
+/// Upload a whole object to S3.
+/// The client is configured from the standard AWS environment variables, S3-compatible
+/// storages are reached by setting AWS_ENDPOINT_URL, in that case path-style urls are used
+#[cfg(feature = "s3")]
+pub async fn put_object(url: &str, body: Vec<u8>) -> Result<(), String> {
+    let (bucket, key) = parse_s3_url(url).ok_or_else(|| format!("invalid s3 url {}", url))?;
+    let client = CLIENT
+        .get_or_init(|| async {
+            let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+            let s3_config = aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(std::env::var("AWS_ENDPOINT_URL").is_ok())
+                .build();
+            aws_sdk_s3::Client::from_conf(s3_config)
+        })
+        .await;
+    client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .body(body.into())
+        .send()
+        .await
+        .map_err(|e| format!("could not upload {}: {}", url, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_s3_url, parse_s3_url};
+
+    #[test]
+    fn test_parse_s3_url() {
+        assert!(is_s3_url("s3://bucket/prefix"));
+        assert!(!is_s3_url("./extracted"));
+        assert_eq!(
+            parse_s3_url("s3://bucket/prefix/blocks_0.json.gz"),
+            Some(("bucket", "prefix/blocks_0.json.gz"))
+        );
+        assert_eq!(parse_s3_url("s3://bucket"), Some(("bucket", "")));
+        assert_eq!(parse_s3_url("s3:///key"), None);
+        assert_eq!(parse_s3_url("/tmp/out"), None);
+    }
+}
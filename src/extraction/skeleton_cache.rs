@@ -0,0 +1,244 @@
+use dashmap::DashMap;
+use primitive_types::H256;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/// Number of decompilation attempts of a skeleton before giving up on it
+pub const MAX_DECOMPILATION_ATTEMPTS: u8 = 10;
+
+/// Counters of the unique skeletons met during an extraction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkeletonStats {
+    /// Skeletons discovered by the extraction, known skeletons excluded
+    pub discovered: u64,
+    /// Skeletons successfully decompiled
+    pub decompiled: u64,
+    /// Skeletons that failed all their decompilation attempts
+    pub exhausted: u64,
+}
+
+struct CacheEntry {
+    /// Number of failed decompilations, 0 once decompiled
+    failures: AtomicU8,
+    /// Value of the cache clock when the skeleton was last seen
+    last_seen: AtomicU64,
+}
+
+/// Skeletons already processed during an extraction, with the number of times
+/// their decompilation failed.
+/// With a maximum size, the least recently seen skeletons are evicted once it's exceeded:
+/// an evicted skeleton met again is decompiled and written again, so the cap trades
+/// some duplicated work for a bounded memory usage.
+/// The cache also counts the skeletons discovered, decompiled and given up on: an evicted
+/// skeleton met again is counted again, so with a maximum size the counts are upper bounds.
+pub struct SkeletonCache {
+    entries: DashMap<H256, CacheEntry>,
+    clock: AtomicU64,
+    max_entries: Option<usize>,
+    evicting: AtomicBool,
+    discovered: AtomicU64,
+    decompiled: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl SkeletonCache {
+    pub fn new(max_entries: Option<usize>) -> Self {
+        Self {
+            entries: DashMap::new(),
+            clock: AtomicU64::new(0),
+            max_entries,
+            evicting: AtomicBool::new(false),
+            discovered: AtomicU64::new(0),
+            decompiled: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Counters of the skeletons discovered, decompiled and given up on so far
+    pub fn stats(&self) -> SkeletonStats {
+        SkeletonStats {
+            discovered: self.discovered.load(Ordering::Relaxed),
+            decompiled: self.decompiled.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Hashes of the skeletons currently in the cache
+    pub fn hashes(&self) -> Vec<H256> {
+        self.entries.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Insert a skeleton with the given failure counter, returns false if it was already present
+    pub fn insert(&self, hash: H256, failures: u8) -> bool {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let inserted = match self.entries.entry(hash) {
+            dashmap::mapref::entry::Entry::Occupied(entry) => {
+                entry.get().last_seen.store(now, Ordering::Relaxed);
+                false
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(CacheEntry {
+                    failures: AtomicU8::new(failures),
+                    last_seen: AtomicU64::new(now),
+                });
+                true
+            }
+        };
+        if inserted {
+            self.evict();
+        }
+        inserted
+    }
+
+    /// Insert a skeleton found by the extraction, returns false if it was already present
+    pub fn discover(&self, hash: H256) -> bool {
+        let inserted = self.insert(hash, 1);
+        if inserted {
+            self.discovered.fetch_add(1, Ordering::Relaxed);
+        }
+        inserted
+    }
+
+    /// Failure counter of a skeleton, 1 if it's newly discovered.
+    /// The counter is incremented unless the skeleton was already decompiled, so the
+    /// returned value is the number of the decompilation attempt about to start
+    pub fn start_attempt(&self, hash: H256) -> u8 {
+        self.discover(hash);
+        match self.entries.get(&hash) {
+            Some(entry) => {
+                let failures = entry.failures.load(Ordering::Relaxed);
+                let _ = entry
+                    .failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                        if x == 0 {
+                            None
+                        } else {
+                            Some(x.saturating_add(1))
+                        }
+                    });
+                failures
+            }
+            // evicted right after the insertion, handle it as a new skeleton
+            None => 1,
+        }
+    }
+
+    /// Mark a skeleton as decompiled, if it's still in the cache
+    pub fn set_decompiled(&self, hash: H256) {
+        if let Some(entry) = self.entries.get(&hash) {
+            if entry.failures.swap(0, Ordering::Relaxed) != 0 {
+                self.decompiled.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Record the failure of the given decompilation attempt, the skeleton
+    /// is given up on once the last one fails
+    pub fn set_failed(&self, attempt: u8) {
+        if attempt == MAX_DECOMPILATION_ATTEMPTS {
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Evict the least recently seen tenth of the entries once the cache exceeds its maximum size.
+    /// Only one task evicts at a time, the others keep going
+    fn evict(&self) {
+        let max_entries = match self.max_entries {
+            Some(max_entries) => max_entries,
+            None => return,
+        };
+        if self.entries.len() <= max_entries
+            || self
+                .evicting
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+        {
+            return;
+        }
+        let mut last_seen = self
+            .entries
+            .iter()
+            .map(|entry| (entry.last_seen.load(Ordering::Relaxed), *entry.key()))
+            .collect::<Vec<(u64, H256)>>();
+        let keep = max_entries - max_entries / 10;
+        if last_seen.len() > keep {
+            let to_evict = last_seen.len() - keep;
+            last_seen.select_nth_unstable(to_evict - 1);
+            for (_, hash) in &last_seen[..to_evict] {
+                self.entries.remove(hash);
+            }
+        }
+        self.evicting.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SkeletonCache, SkeletonStats, MAX_DECOMPILATION_ATTEMPTS};
+    use primitive_types::H256;
+
+    #[test]
+    fn test_eviction() {
+        let cache = SkeletonCache::new(Some(10));
+        let hash = |i: u8| H256::repeat_byte(i);
+        for i in 0..10 {
+            assert!(cache.insert(hash(i), 1));
+        }
+        assert!(!cache.insert(hash(0), 1));
+        assert_eq!(cache.hashes().len(), 10);
+
+        // 1 is the least recently seen, it's evicted when the cap is exceeded
+        cache.insert(hash(10), 1);
+        assert_eq!(cache.hashes().len(), 9);
+        assert!(cache.hashes().contains(&hash(0)));
+        assert!(!cache.hashes().contains(&hash(1)));
+        assert!(cache.hashes().contains(&hash(10)));
+
+        // an evicted skeleton is handled as a new one
+        assert_eq!(cache.start_attempt(hash(1)), 1);
+        assert_eq!(cache.start_attempt(hash(1)), 2);
+        cache.set_decompiled(hash(1));
+        assert_eq!(cache.start_attempt(hash(1)), 0);
+
+        let unbounded = SkeletonCache::new(None);
+        for i in 0..100 {
+            unbounded.insert(hash(i), 0);
+        }
+        assert_eq!(unbounded.hashes().len(), 100);
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = SkeletonCache::new(None);
+        let hash = |i: u8| H256::repeat_byte(i);
+        // known skeletons are not discovered by the extraction
+        cache.insert(hash(0), 0);
+        assert_eq!(cache.start_attempt(hash(0)), 0);
+
+        // decompiled at the second attempt, by two deployments
+        assert_eq!(cache.start_attempt(hash(1)), 1);
+        cache.set_failed(1);
+        assert_eq!(cache.start_attempt(hash(1)), 2);
+        assert_eq!(cache.start_attempt(hash(1)), 3);
+        cache.set_decompiled(hash(1));
+        cache.set_decompiled(hash(1));
+
+        // failing all the attempts
+        for attempt in 1..=MAX_DECOMPILATION_ATTEMPTS {
+            assert_eq!(cache.start_attempt(hash(2)), attempt);
+            cache.set_failed(attempt);
+        }
+        assert!(cache.start_attempt(hash(2)) > MAX_DECOMPILATION_ATTEMPTS);
+
+        assert!(cache.discover(hash(3)));
+        assert!(!cache.discover(hash(3)));
+
+        assert_eq!(
+            cache.stats(),
+            SkeletonStats {
+                discovered: 3,
+                decompiled: 1,
+                exhausted: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use primitive_types::H256;
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Write},
+    str::FromStr,
+};
+
+/// Load a gzipped list of skeleton hashes, one hex hash per line.
+/// Empty lines are skipped.
+pub fn load_skeleton_set(path: &str) -> Result<HashSet<H256>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let mut hashes = HashSet::new();
+    for (i, line) in BufReader::new(GzDecoder::new(file)).lines().enumerate() {
+        let line = line.map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hash = H256::from_str(line)
+            .map_err(|e| format!("Invalid hash at line {} of {}: {}", i + 1, path, e))?;
+        hashes.insert(hash);
+    }
+    Ok(hashes)
+}
+
+/// Write the skeleton hashes gzipped, one hex hash per line, sorted to get the same file across runs
+pub fn dump_skeleton_set(
+    path: &str,
+    hashes: impl IntoIterator<Item = H256>,
+) -> std::io::Result<()> {
+    let mut hashes: Vec<H256> = hashes.into_iter().collect();
+    hashes.sort_unstable();
+    let mut encoder = GzEncoder::new(std::fs::File::create(path)?, Compression::default());
+    for hash in hashes {
+        writeln!(encoder, "{:?}", hash)?;
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump_skeleton_set, load_skeleton_set};
+    use primitive_types::H256;
+    use std::io::Write;
+
+    #[test]
+    fn test_skeleton_set_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("eth2dgraph-sk-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("skeletons.gz");
+        let path = path.to_str().unwrap();
+
+        let hashes = vec![H256::repeat_byte(2), H256::repeat_byte(1)];
+        dump_skeleton_set(path, hashes.clone()).unwrap();
+        let loaded = load_skeleton_set(path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(hashes.iter().all(|h| loaded.contains(h)));
+
+        // hashes without the 0x prefix and empty lines are accepted, garbage is not
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(path).unwrap(),
+            flate2::Compression::default(),
+        );
+        writeln!(encoder, "{}\n", "01".repeat(32)).unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(
+            load_skeleton_set(path).unwrap(),
+            [H256::repeat_byte(1)].into_iter().collect()
+        );
+
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(path).unwrap(),
+            flate2::Compression::default(),
+        );
+        writeln!(encoder, "not a hash").unwrap();
+        encoder.finish().unwrap();
+        assert!(load_skeleton_set(path).unwrap_err().contains("line 1"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
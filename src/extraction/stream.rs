@@ -1,18 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
-use crate::utils::decompile::decompile;
+use crate::info;
+use crate::utils::decompile::{
+    decompile, decompiler_version, default_tmp_dir, probe_decompiler_version,
+};
+use crate::utils::schema::check_schema;
 use crate::{
-    extraction::logs::get_transfer_from_logs,
+    error::Error,
+    extraction::{
+        blocks::get_receipts,
+        extract::{http_provider, RetryPolicy},
+        logs::get_transfer_from_logs,
+        traces::{check_trace_method, get_traces},
+    },
     models::{
-        block::Block, contract_destruction::ContractDestruction, skeleton::Skeleton, trace::Traces,
+        account::Account,
+        block::Block,
+        contract_deployment::{index_block_deployments, link_deploy_tx_inputs, ContractDeployment},
+        contract_destruction::ContractDestruction,
+        internal_transaction::InternalTransaction,
+        proxy_upgrade::{
+            detect_upgrades, read_implementations, stored_implementations, upsert_implementation,
+        },
+        skeleton::Skeleton,
+        transaction::Transaction,
     },
-    StreamDgraphArgs,
+    BackfillArgs, StreamDgraphArgs,
 };
 use dgraph_tonic::{Client, ClientVariant, IClient, Query};
-use ethabi::ethereum_types::U64;
-use ethers::providers::{Middleware, Ws};
+use ethers::providers::{Middleware, Provider, Ws};
+use ethers::types::Address;
 use futures::StreamExt;
 use serde::Deserialize;
 use tokio::sync::Semaphore;
@@ -24,11 +43,27 @@ pub enum LiveBlockErr {
     DgraphError,
 }
 
+/// Await the delete of the data stored for a block, unless `delete` is false
+async fn delete_if<F>(delete: bool, delete_data: F) -> Result<(), anyhow::Error>
+where
+    F: std::future::Future<Output = Result<(), anyhow::Error>>,
+{
+    if delete {
+        delete_data.await
+    } else {
+        Ok(())
+    }
+}
+
+/// Store a block in Dgraph. With `delete_existing` the data already stored for the block
+/// (transfers, logs, destructions, ...) is deleted first, so it can be processed again;
+/// without it the block is assumed not to be stored yet
 pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
     block_n: u64,
     eth_node: Arc<T>,
     dgraph: Arc<ClientVariant<S>>,
     args: Arc<StreamDgraphArgs>,
+    delete_existing: bool,
 ) -> Result<(), LiveBlockErr> {
     let now = tokio::time::Instant::now();
     let with_tx = eth_node.get_block_with_txs(block_n);
@@ -36,10 +71,25 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
     let filter = ethers::core::types::Filter::default()
         .from_block(block_n)
         .to_block(block_n);
-    let logs = eth_node.get_logs(&filter);
-    let traces = eth_node.trace_block(ethers::types::BlockNumber::Number(U64::from(block_n)));
+    // logs are needed only for token transfers and logs, like when extracting
+    let with_logs = args.include.include_transfers || args.include.include_logs;
+    let logs = async {
+        if with_logs {
+            eth_node.get_logs(&filter).await
+        } else {
+            Ok(Vec::new())
+        }
+    };
+    let traces = get_traces(block_n, eth_node.clone(), args.trace_method);
+    let receipts = async {
+        if args.include.gas_breakdown {
+            get_receipts(block_n, eth_node.clone()).await.map(Some)
+        } else {
+            Ok(None)
+        }
+    };
 
-    let (with_tx, logs, traces) = tokio::join!(with_tx, logs, traces);
+    let (with_tx, logs, traces, receipts) = tokio::join!(with_tx, logs, traces, receipts);
 
     let with_tx = with_tx
         .map_err(|_| LiveBlockErr::NetworkError)?
@@ -48,10 +98,35 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
     let logs = logs.map_err(|_| LiveBlockErr::NetworkError)?;
 
     let traces = traces.map_err(|_| LiveBlockErr::NetworkError)?;
-    let traces = Traces::from(traces);
+    // without the receipts the block is stored without the gas breakdown, not skipped
+    let receipts = receipts.unwrap_or_else(|e| {
+        eprintln!("Error fetching the receipts of block {}: {}", block_n, e);
+        eprintln!("Continue without the gas breakdown...");
+        None
+    });
+    let gas_breakdown = receipts
+        .and_then(|receipts| traces.get_gas_breakdown(with_tx.transactions.len(), &receipts));
 
     let destructions: Vec<ContractDestruction> = Vec::from(&traces);
-    let deployments = Vec::from(traces);
+    let internal_txs: Vec<InternalTransaction> = if args.include.include_internal_tx {
+        Vec::from(&traces)
+    } else {
+        Vec::new()
+    };
+    let mut accounts: Vec<Account> = if args.include.include_accounts {
+        Vec::from(&traces)
+    } else {
+        Vec::new()
+    };
+    let mut deployments: Vec<ContractDeployment> = Vec::from(traces);
+    index_block_deployments(&mut deployments);
+    if args.include.skip_failed_deployments {
+        deployments.retain(|d| !d.failed());
+    }
+    // the transactions are in the block data, link the factory calls to their deployments
+    if args.include.include_tx {
+        link_deploy_tx_inputs(&mut deployments, &with_tx.transactions);
+    }
 
     let stats = (
         block_n,
@@ -61,80 +136,150 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
         destructions.len(),
     );
 
-    let block = Block::from(with_tx.clone());
+    let mut block = Block::from(with_tx.clone());
+    block.set_gas_breakdown(gas_breakdown);
     block
         .upsert(&dgraph)
         .await
         .map_err(|_| LiveBlockErr::DgraphError)?;
 
-    if args.include_tokens {
-        let res = crate::models::block::Block::upsert_delete_transfers(
-            block.number.as_ref().unwrap().as_u64(),
-            &dgraph,
+    if args.include.include_transfers {
+        let res = delete_if(
+            delete_existing,
+            crate::models::block::Block::upsert_delete_transfers(
+                block.number.as_ref().unwrap().as_u64(),
+                &dgraph,
+            ),
         )
         .await;
         match res {
             Ok(_) => {
-                let transfers = get_transfer_from_logs(&logs);
+                let transfers = get_transfer_from_logs(
+                    &logs,
+                    block.get_timestamp(),
+                    args.include.transfer_contracts.as_ref(),
+                    args.include.transfer_signatures.as_ref(),
+                );
                 for transfer in transfers {
+                    if args.include.include_accounts {
+                        accounts.push(Account::new(transfer.sender(), block_n, false));
+                        accounts.push(Account::new(transfer.recipient(), block_n, false));
+                    }
                     let res = transfer.upsert(&dgraph).await;
                     if let Err(_) = res {
-                        println!("Error upserting transfer: {:?}", transfer);
-                        println!("Continuing...");
+                        eprintln!("Error upserting transfer: {:?}", transfer);
+                        eprintln!("Continuing...");
                     }
                 }
             }
             Err(_) => {
-                println!(
+                eprintln!(
                     "Error deleting transfers for block {}",
                     block.number.as_ref().unwrap().as_u64()
                 );
-                println!("Continue skipping storing transfers...");
+                eprintln!("Continue skipping storing transfers...");
             }
         }
     }
 
-    if args.include_logs {
-        let res = crate::models::block::Block::upsert_delete_logs(
-            block.number.as_ref().unwrap().as_u64(),
-            &dgraph,
+    if args.include.include_logs {
+        let res = delete_if(
+            delete_existing,
+            crate::models::block::Block::upsert_delete_logs(
+                block.number.as_ref().unwrap().as_u64(),
+                &dgraph,
+            ),
+        )
+        .await;
+        if res.is_err() {
+            // logs are upserted on their key, storing them again doesn't duplicate them
+            eprintln!(
+                "Error deleting logs for block {}",
+                block.number.as_ref().unwrap().as_u64()
+            );
+            eprintln!("Continuing...");
+        }
+        for log in logs {
+            let log = crate::models::log::Log::new(log, block.get_timestamp());
+            let res = log.upsert(&dgraph).await;
+            if let Err(_) = res {
+                eprintln!("Error upserting log: {:?}", log);
+                eprintln!("Continuing...");
+            }
+        }
+    }
+
+    if args.include.include_tx {
+        // a reorged block can drop transactions stored for the orphaned one
+        let res = delete_if(
+            delete_existing,
+            crate::models::block::Block::upsert_detach_transactions(block_n, &dgraph),
+        )
+        .await;
+        if let Err(e) = res {
+            eprintln!("Error detaching transactions for block {}: {}", block_n, e);
+            eprintln!("Continuing...");
+        }
+        for tx in with_tx.transactions {
+            let tx = Transaction::from(tx);
+            let res = tx.upsert(&dgraph).await;
+            if let Err(_) = res {
+                eprintln!("Error upserting tx: {:?}", tx);
+                eprintln!("Continuing...");
+            }
+        }
+    }
+
+    if args.include.include_internal_tx {
+        let res = delete_if(
+            delete_existing,
+            crate::models::block::Block::upsert_delete_internal_txs(block_n, &dgraph),
         )
         .await;
         match res {
             Ok(_) => {
-                for log in logs {
-                    let log = crate::models::log::Log::from(log);
-                    let res = log.upsert(&dgraph).await;
+                for internal_tx in internal_txs {
+                    let res = internal_tx.upsert(&dgraph).await;
                     if let Err(_) = res {
-                        println!("Error upserting log: {:?}", log);
-                        println!("Continuing...");
+                        eprintln!("Error upserting internal tx: {:?}", internal_tx);
+                        eprintln!("Continuing...");
                     }
                 }
             }
             Err(_) => {
-                println!(
-                    "Error deleting logs for block {}",
-                    block.number.as_ref().unwrap().as_u64()
-                );
-                println!("Continue skipping storing logs...");
+                eprintln!("Error deleting internal txs for block {}", block_n);
+                eprintln!("Continue skipping storing internal txs...");
             }
         }
     }
 
-    if args.include_tx {
-        for tx in with_tx.transactions {
-            let tx = crate::models::transaction::Transaction::from(tx);
-            let res = tx.upsert(&dgraph).await;
+    if args.include.include_accounts {
+        if let Some(miner) = with_tx.author {
+            accounts.push(Account::new(miner, block_n, false));
+        }
+        // merge duplicates, so each account is updated once per block
+        let mut merged: HashMap<Address, Account> = HashMap::new();
+        for account in accounts {
+            merged
+                .entry(account.address())
+                .and_modify(|a| a.merge(&account))
+                .or_insert(account);
+        }
+        for account in merged.into_values() {
+            let res = account.upsert(&dgraph).await;
             if let Err(_) = res {
-                println!("Error upserting tx: {:?}", tx);
-                println!("Continuing...");
+                eprintln!("Error upserting account: {:?}", account);
+                eprintln!("Continuing...");
             }
         }
     }
 
-    let res = crate::models::block::Block::upsert_delete_destructions(
-        block.number.as_ref().unwrap().as_u64(),
-        &dgraph,
+    let res = delete_if(
+        delete_existing,
+        crate::models::block::Block::upsert_delete_destructions(
+            block.number.as_ref().unwrap().as_u64(),
+            &dgraph,
+        ),
     )
     .await;
     match res {
@@ -142,32 +287,47 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
             for destruction in destructions {
                 let res = destruction.upsert(&dgraph).await;
                 if let Err(_) = res {
-                    println!("Error upserting destruction: {:?}", destruction);
-                    println!("Continuing...");
+                    eprintln!("Error upserting destruction: {:?}", destruction);
+                    eprintln!("Continuing...");
                 }
             }
         }
         Err(_) => {
-            println!(
+            eprintln!(
                 "Error deleting destructions for block {}",
                 block.number.as_ref().unwrap().as_u64()
             );
-            println!("Continue skipping storing destructions...");
+            eprintln!("Continue skipping storing destructions...");
         }
     }
 
-    let res = crate::models::block::Block::upsert_delete_deployments(
-        block.number.as_ref().unwrap().as_u64(),
-        &dgraph,
+    let res = delete_if(
+        delete_existing,
+        crate::models::block::Block::upsert_delete_deployments(
+            block.number.as_ref().unwrap().as_u64(),
+            &dgraph,
+        ),
     )
     .await;
     match res {
         Ok(_) => {
-            for deployment in deployments {
+            for mut deployment in deployments {
                 // if args.scs_path.is_some() {
                 //     deployment.check_verification(args.scs_path.as_ref().unwrap());
                 // }
 
+                // resolve name, one eth_call per deployment
+                if args.include.resolve_names {
+                    deployment.resolve_name(eth_node.clone()).await;
+                }
+
+                // ERC-165 interfaces, up to one eth_call per known interface
+                if args.include.resolve_interfaces {
+                    deployment
+                        .resolve_supported_interfaces(eth_node.clone())
+                        .await;
+                }
+
                 // Steps:
                 // 1: check if the skeleton already exists
                 //   If not:
@@ -219,15 +379,19 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
                     let decompiled_skeleton = decompile(
                         &deployment.contract_address(),
                         &deployment.deployed_code(),
+                        &default_tmp_dir(),
                         args.decompiler_timeout,
+                        false,
+                        false,
                     )
                     .await;
 
                     let mut skeleton = Skeleton::new(deployment.skeleton().clone());
+                    skeleton.set_decompiler_version(decompiler_version().ok().map(String::from));
 
                     match decompiled_skeleton {
                         Ok(decompiled_skeleton) => {
-                            skeleton.set_abi(decompiled_skeleton);
+                            skeleton.set_abi(decompiled_skeleton.abi);
                         }
                         Err(_) => {
                             skeleton.set_failed_decompilation(true);
@@ -238,31 +402,94 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
                     match skeleton.upsert(&dgraph).await {
                         Ok(uid) => uid,
                         Err(_) => {
-                            println!("Error upserting skeleton: {:?}", skeleton);
-                            println!("Continuing...");
+                            eprintln!("Error upserting skeleton: {:?}", skeleton);
+                            eprintln!("Continuing...");
                             continue;
                         }
                     }
                 };
 
+                // flag redeployments of addresses destroyed before (e.g. with CREATE2)
+                match deployment.find_previous_deployment(&dgraph).await {
+                    Ok(Some(previous)) => {
+                        info!(
+                            "Contract {:?} redeployed after destruction",
+                            deployment.contract_address()
+                        );
+                        deployment.set_previous_deployment(previous);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("Error looking for previous deployments: {:?}", e);
+                        eprintln!("Continuing...");
+                    }
+                }
+
                 // 2: upsert the deployment, using the skeleton uid
                 let res = deployment.upsert(&skeleton_uid, &dgraph).await;
                 if let Err(e) = res {
-                    println!("Error upserting deployment: {:?}", e);
-                    println!("Continuing...");
+                    eprintln!("Error upserting deployment: {:?}", e);
+                    eprintln!("Continuing...");
                 }
             }
         }
         Err(_) => {
-            println!(
+            eprintln!(
                 "Error deleting deployments for block {}",
                 block.number.as_ref().unwrap().as_u64()
             );
-            println!("Continue skipping storing deployments...");
+            eprintln!("Continue skipping storing deployments...");
+        }
+    }
+
+    // implementations of the watched proxies at the end of the block and of the previous one,
+    // compared on the node so that blocks processed concurrently or out of order don't race
+    if let Some(watchlist) = &args.proxy_watchlist {
+        let (previous, current) = tokio::join!(
+            read_implementations(watchlist, block_n.saturating_sub(1), eth_node.clone()),
+            read_implementations(watchlist, block_n, eth_node.clone())
+        );
+        let previous = previous.map_err(|_| LiveBlockErr::NetworkError)?;
+        let current = current.map_err(|_| LiveBlockErr::NetworkError)?;
+        let res = delete_if(
+            delete_existing,
+            crate::models::block::Block::upsert_delete_proxy_upgrades(block_n, &dgraph),
+        )
+        .await;
+        let stored = stored_implementations(watchlist, &dgraph).await;
+        match (res, stored) {
+            (Ok(_), Ok(stored)) => {
+                for upgrade in detect_upgrades(&previous, &current, block_n) {
+                    info!(
+                        "Proxy {:?} upgraded from {:?} to {:?}",
+                        upgrade.proxy(),
+                        upgrade.old_implementation(),
+                        upgrade.new_implementation()
+                    );
+                    if let Err(e) = upgrade.upsert(&dgraph).await {
+                        eprintln!("Error upserting proxy upgrade {:?}: {}", upgrade, e);
+                        eprintln!("Continuing...");
+                    }
+                }
+                for (proxy, implementation) in &current {
+                    if stored.contains_key(proxy) {
+                        continue;
+                    }
+                    if let Err(e) = upsert_implementation(*proxy, *implementation, &dgraph).await {
+                        eprintln!("Error upserting implementation of {:?}: {}", proxy, e);
+                        eprintln!("Continuing...");
+                    }
+                }
+            }
+            _ => {
+                eprintln!("Error loading proxy implementations for block {}", block_n);
+                eprintln!("Continue skipping proxy upgrades...");
+            }
         }
     }
+
     let elapsed = now.elapsed();
-    println!(
+    info!(
         "Procesed block {} in {}s, stats: {:?}",
         block_n,
         elapsed.as_secs_f32(),
@@ -272,59 +499,176 @@ pub async fn process_live_block<T: Middleware + 'static, S: IClient>(
     Ok(())
 }
 
+/// Number of attempts to reach the Ethereum node and Dgraph at startup
+const MAX_STARTUP_RETRIES: u32 = 10;
+
+/// Number of retries of a block during a backfill before it's reported as failed
+const MAX_BACKFILL_RETRIES: u32 = 3;
+
+/// Retry an operation with exponential backoff, starting from 500ms up to 32s,
+/// returning the last error if it keeps failing after `max_retries` retries
+async fn retry_with_backoff<F, Fut, R, E>(what: &str, max_retries: u32, mut f: F) -> Result<R, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<R, E>>,
+    E: std::fmt::Debug,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt < max_retries => {
+                attempt += 1;
+                let backoff = 500 * 2u64.pow(attempt.min(7) - 1);
+                info!(
+                    "{} failed: {:?}, retrying in {}ms ({}/{})",
+                    what, e, backoff, attempt, max_retries
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Parse the response of the last block query, None if Dgraph has no blocks
+fn parse_last_block(json: &[u8]) -> Result<Option<u64>, serde_json::Error> {
+    #[derive(serde::Deserialize, Debug)]
+    struct QueryItem {
+        b: u64,
+    }
+    #[derive(serde::Deserialize, Debug)]
+    struct QueryResult {
+        last_block: Vec<QueryItem>,
+    }
+    let last_block: QueryResult = serde_json::from_slice(json)?;
+    Ok(last_block.last_block.first().map(|i| i.b))
+}
+
+/// Blocks received from the subscription, waiting for `confirmations` blocks mined on top of them
+struct ConfirmationQueue {
+    confirmations: u64,
+    unconfirmed: VecDeque<u64>,
+    /// First block not queued yet, if not set the first head minus the confirmations
+    next: Option<u64>,
+}
+
+impl ConfirmationQueue {
+    fn new(confirmations: u64, start: Option<u64>) -> Self {
+        Self {
+            confirmations,
+            unconfirmed: VecDeque::new(),
+            next: start,
+        }
+    }
+
+    /// Queue the blocks up to the new head, and return the ones it confirms in ascending order.
+    /// Blocks skipped by the subscription are queued too, heads already seen (e.g. after a reorg)
+    /// add nothing
+    fn push(&mut self, head: u64) -> Vec<u64> {
+        let next = *self
+            .next
+            .get_or_insert(head.saturating_sub(self.confirmations));
+        if head >= next {
+            self.unconfirmed.extend(next..=head);
+            self.next = Some(head + 1);
+        }
+        let mut confirmed = Vec::new();
+        while let Some(&block) = self.unconfirmed.front() {
+            if block + self.confirmations > head {
+                break;
+            }
+            confirmed.push(block);
+            self.unconfirmed.pop_front();
+        }
+        confirmed
+    }
+}
+
+/// Process the blocks after the last one in Dgraph, up to the chain head minus the confirmations.
+/// Returns the first block not processed, None if the sync didn't run
 pub async fn sync_to_live<T: Middleware + 'static, S: IClient + 'static>(
     args: Arc<StreamDgraphArgs>,
     eth_node: Arc<T>,
     dgraph_client: Arc<ClientVariant<S>>,
-) {
+) -> Result<Option<u64>, Error> {
     let num_jobs = args.num_jobs;
-    println!("Starting sync to live with {} threads", num_jobs);
-    // get last indexed block in Dgraph
-    let mut txn = dgraph_client.new_read_only_txn();
+    info!("Starting sync to live with {} threads", num_jobs);
+    // get last indexed block in Dgraph, retrying in case Dgraph is restarting
     let query = r#"{
         last_block(func: has(Block.number), orderdesc: Block.number, first: 1) {
           b: Block.number
       }
     }"#;
-    let resp = txn.query(query).await.expect("Query failed");
-    #[derive(serde::Deserialize, Debug)]
-    struct QueryItem {
-        b: u64,
-    }
-    #[derive(serde::Deserialize, Debug)]
-    struct QueryResult {
-        last_block: Vec<QueryItem>,
+    let resp = retry_with_backoff("Last block query", MAX_STARTUP_RETRIES, || async {
+        dgraph_client.new_read_only_txn().query(query).await
+    })
+    .await
+    .map_err(|e| Error::Dgraph(format!("could not query the last block: {:?}", e)))?;
+    let last_block = parse_last_block(&resp.json)
+        .map_err(|e| Error::Dgraph(format!("could not parse the last block: {}", e)))?;
+    let first_block = match (last_block, args.start_block) {
+        (Some(last_block), _) => {
+            info!("Last block in Dgraph: {}", last_block);
+            last_block + 1
+        }
+        (None, Some(start_block)) => {
+            info!("No blocks in Dgraph, starting from block {}", start_block);
+            start_block
+        }
+        (None, None) => {
+            info!("No blocks in Dgraph and no start block, starting from chain head");
+            return Ok(None);
+        }
+    };
+    // blocks after the last one in Dgraph aren't stored yet, unless a previous sync was interrupted
+    let delete_existing = !args.assume_empty;
+    if args.assume_empty {
+        info!("Assuming the blocks to sync are not in Dgraph, skipping the deletes");
     }
-    let last_block: QueryResult =
-        serde_json::from_slice(&resp.json).expect("Could not parse last block");
-    let last_block = last_block.last_block.get(0).unwrap().b;
-    println!("Last block in Dgraph: {}", last_block);
-    println!("Syncing to live chain...");
+    info!("Syncing to live chain...");
     let semaphore = Arc::new(Semaphore::new(num_jobs));
     let done = Arc::new(AtomicBool::new(false));
-    let curr_block = Arc::new(AtomicU64::new(last_block + 1));
+    // first block not available, the stream starts from here
+    let stopped_at = Arc::new(AtomicU64::new(u64::MAX));
+    let mut confirmed_head = 0;
+    let mut next_block = first_block;
     while !done.load(Ordering::Relaxed) {
+        let curr_block = next_block;
+        next_block += 1;
+        // with confirmations the sync stops before the head, instead of at the first block not mined yet
+        if args.confirmations > 0 && curr_block > confirmed_head {
+            if let Ok(head) = eth_node.get_block_number().await {
+                confirmed_head = head.as_u64().saturating_sub(args.confirmations);
+            }
+            if curr_block > confirmed_head {
+                info!("Block {} not confirmed yet", curr_block);
+                info!("Quitting sync...");
+                stopped_at.fetch_min(curr_block, Ordering::Relaxed);
+                break;
+            }
+        }
         let a = args.clone();
         let eth = eth_node.clone();
         let dgraph = dgraph_client.clone();
         let d = done.clone();
+        let s = stopped_at.clone();
         let permit = Arc::clone(&semaphore).acquire_owned().await.unwrap();
-        let block_no = curr_block.clone();
         tokio::spawn(async move {
-            let curr_block = block_no.fetch_add(1, Ordering::Relaxed);
-            match process_live_block(curr_block, eth, dgraph, a).await {
+            match process_live_block(curr_block, eth, dgraph, a, delete_existing).await {
                 Ok(_) => {}
                 Err(e) => match e {
                     LiveBlockErr::BlockNotAvailable => {
-                        println!("Block {} not available yet", curr_block);
-                        println!("Quitting sync...");
+                        info!("Block {} not available yet", curr_block);
+                        info!("Quitting sync...");
+                        s.fetch_min(curr_block, Ordering::Relaxed);
                         d.store(true, Ordering::Relaxed);
                     }
                     LiveBlockErr::NetworkError => {
-                        println!("Network error, retrying");
+                        info!("Network error, retrying");
                     }
                     LiveBlockErr::DgraphError => {
-                        println!("Dgraph error, retrying");
+                        info!("Dgraph error, retrying");
                     }
                 },
             };
@@ -333,51 +677,363 @@ pub async fn sync_to_live<T: Middleware + 'static, S: IClient + 'static>(
     }
 
     let _ = semaphore.acquire_many(num_jobs as u32).await;
+
+    match stopped_at.load(Ordering::Relaxed) {
+        u64::MAX => Ok(None),
+        block => Ok(Some(block)),
+    }
+}
+
+/// Process the blocks from `from_block` to `to_block` (included) like the stream does,
+/// replacing the data of each block already stored in Dgraph.
+/// Returns the blocks that failed after all the retries, in ascending order
+pub async fn backfill<T: Middleware + 'static, S: IClient + 'static>(
+    from_block: u64,
+    to_block: u64,
+    args: Arc<StreamDgraphArgs>,
+    eth_node: Arc<T>,
+    dgraph_client: Arc<ClientVariant<S>>,
+) -> Vec<u64> {
+    let num_jobs = args.num_jobs;
+    let semaphore = Arc::new(Semaphore::new(num_jobs));
+    let failed = Arc::new(std::sync::Mutex::new(Vec::new()));
+    for block_n in from_block..=to_block {
+        let a = args.clone();
+        let eth = eth_node.clone();
+        let dgraph = dgraph_client.clone();
+        let f = failed.clone();
+        let permit = Arc::clone(&semaphore).acquire_owned().await.unwrap();
+        tokio::spawn(async move {
+            let what = format!("Block {}", block_n);
+            let res = retry_with_backoff(&what, MAX_BACKFILL_RETRIES, || {
+                process_live_block(block_n, eth.clone(), dgraph.clone(), a.clone(), true)
+            })
+            .await;
+            if res.is_err() {
+                f.lock().unwrap().push(block_n);
+            }
+            drop(permit); // release the permit
+        });
+    }
+
+    let _ = semaphore.acquire_many(num_jobs as u32).await;
+
+    let mut failed = failed.lock().unwrap().clone();
+    failed.sort_unstable();
+    failed
 }
 
-pub async fn run_stream_extraction(args: StreamDgraphArgs) {
-    println!("Running stream extraction");
-    println!("Args: {:?}", args);
+pub async fn run_backfill(args: BackfillArgs) -> Result<(), Error> {
+    info!("Running backfill");
+    info!("Args: {:?}", args);
 
-    let args = Arc::new(args);
+    let (from_block, to_block) = (args.from_block.unwrap(), args.to_block.unwrap());
+    let args = Arc::new(StreamDgraphArgs {
+        endpoint: args.endpoint,
+        trace_method: args.trace_method,
+        dgraph: args.dgraph,
+        include: args.include,
+        decompiler_timeout: args.decompiler_timeout,
+        no_sync: true,
+        start_block: None,
+        num_jobs: args.num_jobs,
+        strict_schema: args.strict_schema,
+        confirmations: 0,
+        include_pending: false,
+        assume_empty: false,
+        proxy_watchlist: None,
+    });
 
-    let ws = Ws::connect(&args.endpoint)
+    let eth_provider = Arc::new(http_provider(&args.endpoint, None, RetryPolicy::default())?);
+    probe_decompiler_version();
+    let dgraph_client = Arc::new(dgraph_client(&args.dgraph)?);
+
+    // fail early if Dgraph is unreachable, and warn if it has no schema
+    check_schema(&args.dgraph, args.strict_schema)
         .await
-        .expect("Could not connect to ws");
+        .map_err(Error::Dgraph)?;
+
+    // fail early if the node can't trace blocks with the chosen method
+    check_trace_method(from_block, eth_provider.clone(), args.trace_method)
+        .await
+        .map_err(Error::Node)?;
+
+    let mut failed = backfill(from_block, to_block, args, eth_provider, dgraph_client).await;
+
+    if !failed.is_empty() {
+        // the other blocks are stored, only the failed ones need to be backfilled again
+        failed.sort_unstable();
+        return Err(Error::FailedBlocks(failed));
+    }
+    info!("Backfilled blocks from {} to {}", from_block, to_block);
+    Ok(())
+}
+
+/// Upsert the transactions of the node mempool as pending, `num_jobs` at a time.
+/// The subscription only gives the hashes, so each transaction is fetched first: the ones
+/// already mined or dropped by then are skipped. Transactions are matched by hash, so a hash
+/// notified again updates the same node, and a pending upsert never reverts a mined one.
+/// The result of the subscription is sent to `subscribed`, nothing is streamed if it failed
+async fn stream_pending_transactions<S: IClient + 'static>(
+    eth_node: Arc<Provider<Ws>>,
+    dgraph_client: Arc<ClientVariant<S>>,
+    num_jobs: usize,
+    subscribed: tokio::sync::oneshot::Sender<Result<(), Error>>,
+) {
+    let mut stream = match eth_node.subscribe_pending_txs().await {
+        Ok(stream) => {
+            let _ = subscribed.send(Ok(()));
+            stream
+        }
+        Err(e) => {
+            let _ = subscribed.send(Err(Error::Node(format!(
+                "could not subscribe to pending transactions: {}",
+                e
+            ))));
+            return;
+        }
+    };
+    let semaphore = Arc::new(Semaphore::new(num_jobs));
+    while let Some(hash) = stream.next().await {
+        let eth = eth_node.clone();
+        let dgraph = dgraph_client.clone();
+        let permit = Arc::clone(&semaphore).acquire_owned().await.unwrap();
+        tokio::spawn(async move {
+            match eth.get_transaction(hash).await {
+                Ok(Some(tx)) if tx.block_number.is_none() => {
+                    if Transaction::from(tx).upsert(&dgraph).await.is_err() {
+                        eprintln!("Error upserting pending tx: {:?}", hash);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => eprintln!("Error getting pending tx: {:?}", hash),
+            }
+            drop(permit); // release the permit
+        });
+    }
+}
+
+/// Dgraph client of the endpoint, connecting lazily on the first request
+fn dgraph_client(endpoint: &str) -> Result<Client, Error> {
+    Client::new(endpoint).map_err(|e| Error::InvalidEndpoint {
+        endpoint: endpoint.to_string(),
+        error: e.to_string(),
+    })
+}
+
+pub async fn run_stream_extraction(args: StreamDgraphArgs) -> Result<(), Error> {
+    info!("Running stream extraction");
+    info!("Args: {:?}", args);
+
+    let args = Arc::new(args);
+
+    let ws = retry_with_backoff("Connection to ws", MAX_STARTUP_RETRIES, || {
+        Ws::connect(&args.endpoint)
+    })
+    .await
+    .map_err(|e| Error::Node(format!("could not connect to {}: {}", args.endpoint, e)))?;
     let eth_provider = Arc::new(ethers::providers::Provider::new(ws));
-    let dgraph_client = Arc::new(Client::new(&args.dgraph).expect("Dgraph client"));
+    let dgraph_client = Arc::new(dgraph_client(&args.dgraph)?);
+
+    // fail early if Dgraph is unreachable, and warn if it has no schema
+    check_schema(&args.dgraph, args.strict_schema)
+        .await
+        .map_err(Error::Dgraph)?;
+
+    // fail early if the node can't trace blocks with the chosen method
+    let head = eth_provider
+        .get_block_number()
+        .await
+        .map_err(|e| Error::Node(format!("could not get the last block number: {}", e)))?;
+    check_trace_method(head.as_u64(), eth_provider.clone(), args.trace_method)
+        .await
+        .map_err(Error::Node)?;
 
+    probe_decompiler_version();
+
+    let mut next_block = None;
     if !args.no_sync {
         // sync Dgraph with last available block
         let a = args.clone();
         let eth = eth_provider.clone();
         let dgraph = dgraph_client.clone();
-        sync_to_live(a, eth, dgraph).await;
+        next_block = sync_to_live(a, eth, dgraph).await?;
     }
 
-    println!("Starting stream extraction");
+    info!("Starting stream extraction");
+
+    if args.include_pending {
+        info!("Streaming pending transactions");
+        let eth = eth_provider.clone();
+        let dgraph = dgraph_client.clone();
+        let (subscribed, subscription) = tokio::sync::oneshot::channel();
+        tokio::spawn(stream_pending_transactions(
+            eth,
+            dgraph,
+            args.num_jobs,
+            subscribed,
+        ));
+        subscription.await.unwrap_or_else(|_| {
+            Err(Error::Node(
+                "the pending transactions stream stopped before subscribing".to_string(),
+            ))
+        })?;
+    }
 
     let mut stream = eth_provider
         .subscribe_blocks()
         .await
-        .expect("Could not subscribe to blocks");
+        .map_err(|e| Error::Node(format!("could not subscribe to blocks: {}", e)))?;
 
+    // blocks are processed once confirmed, starting from the first one not synced
+    let mut queue = ConfirmationQueue::new(args.confirmations, next_block);
     while let Some(block) = stream.next().await {
-        let block_n = block.number.unwrap().as_u64();
-        let a = args.clone();
-        let eth = eth_provider.clone();
-        let dgraph = dgraph_client.clone();
-        process_live_block(block_n, eth, dgraph, a)
-            .await
-            .expect("Could not process block");
+        let head = block.number.unwrap().as_u64();
+        for block_n in queue.push(head) {
+            let a = args.clone();
+            let eth = eth_provider.clone();
+            let dgraph = dgraph_client.clone();
+            process_live_block(block_n, eth, dgraph, a, true)
+                .await
+                .map_err(|e| Error::Block {
+                    block: block_n,
+                    error: format!("{:?}", e),
+                })?;
+        }
     }
 
-    println!("Finished stream extraction");
+    info!("Finished stream extraction");
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{extraction::traces::TraceMethod, IncludeArgs};
+
+    #[test]
+    fn test_parse_last_block() {
+        assert_eq!(parse_last_block(br#"{"last_block":[]}"#).unwrap(), None);
+        assert_eq!(
+            parse_last_block(br#"{"last_block":[{"b":16100001}]}"#).unwrap(),
+            Some(16100001)
+        );
+        assert!(parse_last_block(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_confirmation_queue() {
+        // without a start block the first head minus the confirmations is processed first
+        let mut queue = ConfirmationQueue::new(3, None);
+        assert_eq!(queue.push(100), vec![97]);
+        assert_eq!(queue.push(101), vec![98]);
+        // heads seen again, e.g. after a reorg, confirm nothing
+        assert!(queue.push(101).is_empty());
+        assert!(queue.push(99).is_empty());
+        // blocks skipped by the subscription are processed too
+        assert_eq!(queue.push(104), vec![99, 100, 101]);
+
+        // after the sync the stream starts from the first block not synced
+        let mut queue = ConfirmationQueue::new(2, Some(90));
+        assert_eq!(queue.push(93), vec![90, 91]);
+        assert_eq!(queue.push(94), vec![92]);
+
+        // without confirmations every head is processed as soon as it's received
+        let mut queue = ConfirmationQueue::new(0, None);
+        assert_eq!(queue.push(5), vec![5]);
+        assert_eq!(queue.push(6), vec![6]);
+    }
+
+    #[tokio::test]
+    async fn test_assume_empty_skips_deletes() {
+        let deleted = AtomicBool::new(false);
+        let delete = || async {
+            deleted.store(true, Ordering::Relaxed);
+            Ok(())
+        };
+        // initial sync with --assume-empty
+        delete_if(false, delete()).await.unwrap();
+        assert!(!deleted.load(Ordering::Relaxed));
+        // live blocks, or syncing without it
+        delete_if(true, delete()).await.unwrap();
+        assert!(deleted.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_process_block_without_deletes() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let eth_endpoint = std::env::var("ETH_NODE").expect("Ethereum endpoint");
+        let provider =
+            Arc::new(ethers::providers::Provider::try_from(eth_endpoint.clone()).unwrap());
+        let dgraph = Arc::new(Client::new(dgraph_endpoint.clone()).expect("Dgraph client"));
+        let args = Arc::new(StreamDgraphArgs {
+            endpoint: eth_endpoint,
+            trace_method: TraceMethod::Parity,
+            dgraph: dgraph_endpoint,
+            include: IncludeArgs {
+                include_tx: false,
+                include_transfers: true,
+                include_logs: false,
+                include_internal_tx: false,
+                include_accounts: false,
+                transfer_contracts: None,
+                transfer_signatures: None,
+                skip_failed_deployments: false,
+                resolve_names: false,
+                resolve_interfaces: false,
+                gas_breakdown: false,
+            },
+            decompiler_timeout: 5000,
+            no_sync: true,
+            start_block: None,
+            num_jobs: 1,
+            strict_schema: false,
+            confirmations: 0,
+            include_pending: false,
+            assume_empty: true,
+            proxy_watchlist: None,
+        });
+        let block_n = 16100001;
+        let count_transfers = || async {
+            let query = format!(
+                "{{ q(func: eq(Block.number, {})) {{ count(~TokenTransfer.block) }} }}",
+                block_n
+            );
+            let resp = dgraph.new_read_only_txn().query(query).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&resp.json).unwrap();
+            json["q"][0]["count(~TokenTransfer.block)"]
+                .as_u64()
+                .unwrap()
+        };
+
+        process_live_block(
+            block_n,
+            provider.clone(),
+            dgraph.clone(),
+            args.clone(),
+            true,
+        )
+        .await
+        .unwrap();
+        let transfers = count_transfers().await;
+        assert!(transfers > 0);
+        // without the deletes the transfers stored by the first run are kept
+        process_live_block(
+            block_n,
+            provider.clone(),
+            dgraph.clone(),
+            args.clone(),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(count_transfers().await, 2 * transfers);
+        process_live_block(block_n, provider, dgraph.clone(), args, true)
+            .await
+            .unwrap();
+        assert_eq!(count_transfers().await, transfers);
+    }
 
     #[tokio::test]
     async fn test_not_available_block() {
@@ -392,16 +1048,33 @@ mod tests {
         let dgraph = Arc::new(Client::new(dgraph_endpoint.clone()).expect("Dgraph client"));
         let args = StreamDgraphArgs {
             endpoint: eth_endpoint,
+            trace_method: TraceMethod::Parity,
             dgraph: dgraph_endpoint,
-            include_tx: false,
-            include_tokens: false,
-            include_logs: false,
+            include: IncludeArgs {
+                include_tx: false,
+                include_transfers: false,
+                include_logs: false,
+                include_internal_tx: false,
+                include_accounts: false,
+                transfer_contracts: None,
+                transfer_signatures: None,
+                skip_failed_deployments: false,
+                resolve_names: false,
+                resolve_interfaces: false,
+                gas_breakdown: false,
+            },
             decompiler_timeout: 5000,
             no_sync: false,
+            start_block: None,
             num_jobs: 1,
+            strict_schema: false,
+            confirmations: 0,
+            include_pending: false,
+            assume_empty: false,
+            proxy_watchlist: None,
         };
         let args = Arc::new(args);
-        let res = process_live_block(190000000, provider, dgraph, args).await;
+        let res = process_live_block(190000000, provider, dgraph, args, true).await;
         match res {
             Ok(_) => panic!("Block should not be available"),
             Err(e) => match e {
@@ -411,6 +1084,56 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    #[ignore]
+    async fn test_backfill() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let eth_endpoint = std::env::var("ETH_NODE").expect("Ethereum endpoint");
+        let provider =
+            Arc::new(ethers::providers::Provider::try_from(eth_endpoint.clone()).unwrap());
+        let dgraph = Arc::new(Client::new(dgraph_endpoint.clone()).expect("Dgraph client"));
+        let args = Arc::new(StreamDgraphArgs {
+            endpoint: eth_endpoint,
+            trace_method: TraceMethod::Parity,
+            dgraph: dgraph_endpoint,
+            include: IncludeArgs {
+                include_tx: false,
+                include_transfers: true,
+                include_logs: false,
+                include_internal_tx: false,
+                include_accounts: false,
+                transfer_contracts: None,
+                transfer_signatures: None,
+                skip_failed_deployments: false,
+                resolve_names: false,
+                resolve_interfaces: false,
+                gas_breakdown: false,
+            },
+            decompiler_timeout: 5000,
+            no_sync: true,
+            start_block: None,
+            num_jobs: 2,
+            strict_schema: false,
+            confirmations: 0,
+            include_pending: false,
+            assume_empty: false,
+            proxy_watchlist: None,
+        });
+
+        // backfilling twice the same range must replace the data, not fail
+        for _ in 0..2 {
+            let failed = backfill(
+                16100001,
+                16100003,
+                args.clone(),
+                provider.clone(),
+                dgraph.clone(),
+            )
+            .await;
+            assert!(failed.is_empty(), "failed blocks: {:?}", failed);
+        }
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_live_block_process() {
@@ -425,13 +1148,30 @@ mod tests {
         let _dgraph = Client::new(dgraph_endpoint.clone()).expect("Dgraph client");
         let _args = StreamDgraphArgs {
             endpoint: eth_endpoint,
+            trace_method: TraceMethod::Parity,
             dgraph: dgraph_endpoint,
-            include_tx: true,
-            include_tokens: true,
-            include_logs: true,
+            include: IncludeArgs {
+                include_tx: true,
+                include_transfers: true,
+                include_logs: true,
+                include_internal_tx: false,
+                include_accounts: false,
+                transfer_contracts: None,
+                transfer_signatures: None,
+                skip_failed_deployments: false,
+                resolve_names: false,
+                resolve_interfaces: false,
+                gas_breakdown: false,
+            },
             decompiler_timeout: 5000,
             no_sync: true,
+            start_block: None,
             num_jobs: 1,
+            strict_schema: false,
+            confirmations: 0,
+            include_pending: false,
+            assume_empty: false,
+            proxy_watchlist: None,
         };
         // let args = Rc::new(args);
         // process_live_block(block_no, &provider, &dgraph, args)
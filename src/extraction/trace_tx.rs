@@ -0,0 +1,142 @@
+use super::extract::{http_provider, RetryPolicy};
+use super::traces::get_transaction_traces;
+use crate::models::{
+    contract_deployment::ContractDeployment, contract_destruction::ContractDestruction,
+    trace::Traces, SerializeDgraph,
+};
+use crate::{error::Error, TraceTxArgs};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn to_json<T: SerializeDgraph>(entity: &T) -> Value {
+    entity
+        .serialize_dgraph(serde_json::value::Serializer)
+        .unwrap()
+}
+
+/// Deployments and destructions of the traces of a transaction, serialized like in the outputs
+fn tx_entities(traces: &Traces) -> Value {
+    let deployments: Vec<ContractDeployment> = Vec::from(traces.clone());
+    let destructions: Vec<ContractDestruction> = Vec::from(traces);
+    json!({
+        "traces": traces.0.len(),
+        "deployments": deployments.iter().map(to_json).collect::<Vec<_>>(),
+        "destructions": destructions.iter().map(to_json).collect::<Vec<_>>(),
+    })
+}
+
+/// Fetch the traces of a transaction and print the contract deployments and destructions
+/// built from them as JSON, to debug the conversion without extracting a whole block
+pub async fn run_trace_tx(args: TraceTxArgs) -> Result<(), Error> {
+    let eth_client = Arc::new(http_provider(&args.endpoint, None, RetryPolicy::default())?);
+    let traces = get_transaction_traces(args.tx, eth_client, args.trace_method)
+        .await
+        .map_err(|e| Error::Node(format!("could not trace {:?}: {}", args.tx, e)))?;
+    let entities = tx_entities(&traces);
+    println!("{}", serde_json::to_string_pretty(&entities).unwrap());
+    if traces.0.is_empty() {
+        eprintln!("Transaction {:?} has no traces", args.tx);
+    } else if entities["deployments"].as_array().unwrap().is_empty()
+        && entities["destructions"].as_array().unwrap().is_empty()
+    {
+        eprintln!(
+            "Transaction {:?} doesn't create or destroy contracts",
+            args.tx
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::traces::TraceMethod;
+    use ethers::providers::Provider;
+    use ethers::types::{Trace, Transaction, TxHash, H256};
+
+    #[tokio::test]
+    async fn test_trace_tx() {
+        let (provider, mock) = Provider::mocked();
+        let tx_hash = TxHash::from_low_u64_be(1);
+        // responses are popped from the last one pushed
+        mock.push::<serde_json::Value, _>(json!({
+            "type": "CALL",
+            "from": "0x0000000000000000000000000000000000000001",
+            "to": "0x0000000000000000000000000000000000000002",
+            "gas": "0x100",
+            "gasUsed": "0x80",
+            "input": "0x",
+            "calls": [
+                {
+                    "type": "CREATE",
+                    "from": "0x0000000000000000000000000000000000000002",
+                    "to": "0x0000000000000000000000000000000000000003",
+                    "gas": "0x50",
+                    "gasUsed": "0x40",
+                    "input": "0x6000",
+                    "output": "0x00"
+                },
+                {
+                    "type": "SELFDESTRUCT",
+                    "from": "0x0000000000000000000000000000000000000002",
+                    "to": "0x0000000000000000000000000000000000000001",
+                    "value": "0x0",
+                    "gas": "0x0",
+                    "gasUsed": "0x0",
+                    "input": "0x"
+                }
+            ]
+        }))
+        .unwrap();
+        mock.push::<Transaction, _>(Transaction {
+            hash: tx_hash,
+            block_hash: Some(H256::from_low_u64_be(2)),
+            block_number: Some(20_000_000.into()),
+            transaction_index: Some(3.into()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let traces = get_transaction_traces(tx_hash, Arc::new(provider), TraceMethod::Geth)
+            .await
+            .unwrap();
+        assert_eq!(traces.0.len(), 3);
+        assert!(traces
+            .0
+            .iter()
+            .all(|t| t.transaction_position == Some(3) && t.block_number == 20_000_000));
+
+        let entities = tx_entities(&traces);
+        assert_eq!(entities["traces"], 3);
+        let deployments = entities["deployments"].as_array().unwrap();
+        assert_eq!(deployments.len(), 1);
+        assert_eq!(
+            deployments[0]["ContractDeployment.contract"]["Account.address"],
+            "0x0000000000000000000000000000000000000003"
+        );
+        let destructions = entities["destructions"].as_array().unwrap();
+        assert_eq!(destructions.len(), 1);
+        assert_eq!(
+            destructions[0]["ContractDestruction.contract"]["Account.address"],
+            "0x0000000000000000000000000000000000000002"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_tx_without_creations() {
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Vec<Trace>, _>(vec![]).unwrap();
+
+        let traces = get_transaction_traces(
+            TxHash::from_low_u64_be(1),
+            Arc::new(provider),
+            TraceMethod::Parity,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            tx_entities(&traces),
+            json!({"traces": 0, "deployments": [], "destructions": []})
+        );
+    }
+}
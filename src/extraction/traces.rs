@@ -1,8 +1,91 @@
+use crate::info;
+use crate::models::state_change::StateChange;
 use crate::models::trace::Traces;
-use ethers::types::TxHash;
-use ethers::{providers::Middleware, types::Trace};
+use ethers::providers::{Middleware, MiddlewareError, ProviderError};
+use ethers::types::{
+    Action, ActionType, Call, CallFrame, CallResult, CallType, Create, CreateResult,
+    GethDebugBuiltInTracerType, GethDebugTracerType, GethDebugTracingOptions, GethTrace,
+    GethTraceFrame, Res, Suicide, Trace, TraceType, TxHash, H256,
+};
+use futures::{StreamExt, TryStreamExt};
 use std::{collections::HashMap, sync::Arc};
 
+/// RPC method used to fetch the traces of a block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceMethod {
+    /// `trace_block`, supported by Erigon, Nethermind and OpenEthereum
+    #[default]
+    Parity,
+    /// `debug_traceBlockByNumber` with the `callTracer`, supported by geth
+    Geth,
+}
+
+impl TraceMethod {
+    fn rpc_method(&self) -> &'static str {
+        match self {
+            TraceMethod::Parity => "trace_block",
+            TraceMethod::Geth => "debug_traceBlockByNumber",
+        }
+    }
+
+    fn other(&self) -> &'static str {
+        match self {
+            TraceMethod::Parity => "geth",
+            TraceMethod::Geth => "parity",
+        }
+    }
+}
+
+/// Number of transactions traced at the same time when a block is traced transaction by transaction
+const TX_TRACE_JOBS: usize = 8;
+
+/// Whether the node or the provider refused to return a response because of its size,
+/// or cut it before its end: sending the same request again would fail the same way
+fn is_response_too_large<E: MiddlewareError>(e: &E) -> bool {
+    if let Some(e) = e.as_error_response() {
+        let message = e.message.to_lowercase();
+        return [
+            "response size",
+            "response too large",
+            "response is too large",
+            "response too big",
+            "size exceeded",
+            "exceeds the limit",
+        ]
+        .iter()
+        .any(|m| message.contains(m));
+    }
+    // a truncated response fails to deserialize at its end
+    e.as_serde_error().is_some_and(|e| e.is_eof())
+}
+
+/// Fetch the traces of a block with `trace_transaction`, one transaction at a time,
+/// for the blocks whose `trace_block` response is over the limits of the node or provider.
+/// The rewards are only returned by `trace_block`, so they're missing from these traces
+async fn get_traces_by_transaction<T>(
+    block: u64,
+    eth_client: Arc<T>,
+) -> Result<Vec<Trace>, T::Error>
+where
+    T: Middleware,
+{
+    let block_data = eth_client.get_block(block).await?.ok_or_else(|| {
+        T::Error::from_provider_err(ProviderError::CustomError(format!(
+            "Block {} not found",
+            block
+        )))
+    })?;
+    let traces = futures::stream::iter(block_data.transactions)
+        .map(|tx_hash| {
+            let eth_client = eth_client.clone();
+            async move { eth_client.trace_transaction(tx_hash).await }
+        })
+        .buffered(TX_TRACE_JOBS)
+        .try_collect::<Vec<Vec<Trace>>>()
+        .await?;
+    Ok(traces.into_iter().flatten().collect())
+}
+
 fn propagate_errors(traces: &mut Vec<Trace>) {
     // group traces by transaction hash
     let mut txs: HashMap<TxHash, Vec<&mut Trace>> = HashMap::new();
@@ -33,28 +116,284 @@ fn propagate_errors(traces: &mut Vec<Trace>) {
     });
 }
 
+/// Convert a geth call frame, and recursively its subcalls, into parity style traces
+fn flatten_call_frame(
+    frame: CallFrame,
+    trace_address: Vec<usize>,
+    tx_position: usize,
+    tx_hash: TxHash,
+    block_number: u64,
+    block_hash: H256,
+    traces: &mut Vec<Trace>,
+) {
+    let to = frame
+        .to
+        .as_ref()
+        .and_then(|to| to.as_address().copied())
+        .unwrap_or_default();
+    let value = frame.value.unwrap_or_default();
+    let (action, action_type, result) = match frame.typ.as_str() {
+        "CREATE" | "CREATE2" => (
+            Action::Create(Create {
+                from: frame.from,
+                value,
+                gas: frame.gas,
+                init: frame.input,
+            }),
+            ActionType::Create,
+            Some(Res::Create(CreateResult {
+                gas_used: frame.gas_used,
+                code: frame.output.unwrap_or_default(),
+                address: to,
+            })),
+        ),
+        "SELFDESTRUCT" => (
+            Action::Suicide(Suicide {
+                address: frame.from,
+                refund_address: to,
+                balance: value,
+            }),
+            ActionType::Suicide,
+            None,
+        ),
+        typ => {
+            let call_type = match typ {
+                "STATICCALL" => CallType::StaticCall,
+                "DELEGATECALL" => CallType::DelegateCall,
+                "CALLCODE" => CallType::CallCode,
+                _ => CallType::Call,
+            };
+            (
+                Action::Call(Call {
+                    from: frame.from,
+                    to,
+                    value,
+                    gas: frame.gas,
+                    input: frame.input,
+                    call_type,
+                }),
+                ActionType::Call,
+                Some(Res::Call(CallResult {
+                    gas_used: frame.gas_used,
+                    output: frame.output.unwrap_or_default(),
+                })),
+            )
+        }
+    };
+    let calls = frame.calls.unwrap_or_default();
+    traces.push(Trace {
+        action,
+        // like parity, failed traces have no result
+        result: result.filter(|_| frame.error.is_none()),
+        trace_address: trace_address.clone(),
+        subtraces: calls.len(),
+        transaction_position: Some(tx_position),
+        transaction_hash: Some(tx_hash),
+        block_number,
+        block_hash,
+        action_type,
+        error: frame.error,
+    });
+    for (i, call) in calls.into_iter().enumerate() {
+        let mut child_address = trace_address.clone();
+        child_address.push(i);
+        flatten_call_frame(
+            call,
+            child_address,
+            tx_position,
+            tx_hash,
+            block_number,
+            block_hash,
+            traces,
+        );
+    }
+}
+
+/// Fetch the traces of a block with `debug_traceBlockByNumber` and the `callTracer`.
+/// Geth doesn't return the transaction hashes, so the block is fetched too
+async fn get_geth_traces<T>(block: u64, eth_client: Arc<T>) -> Result<Vec<Trace>, T::Error>
+where
+    T: Middleware,
+{
+    let options = GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(
+            GethDebugBuiltInTracerType::CallTracer,
+        )),
+        ..Default::default()
+    };
+    let (block_data, geth_traces) = tokio::join!(
+        eth_client.get_block(block),
+        eth_client.debug_trace_block_by_number(Some(block.into()), options)
+    );
+    let block_data = block_data?.ok_or_else(|| {
+        T::Error::from_provider_err(ProviderError::CustomError(format!(
+            "Block {} not found",
+            block
+        )))
+    })?;
+    let geth_traces = geth_traces?;
+    if geth_traces.len() != block_data.transactions.len() {
+        return Err(T::Error::from_provider_err(ProviderError::CustomError(
+            format!(
+                "Block {} has {} transactions but {} traces",
+                block,
+                block_data.transactions.len(),
+                geth_traces.len()
+            ),
+        )));
+    }
+    let block_hash = block_data.hash.unwrap_or_default();
+    let mut traces = Vec::new();
+    for (position, (tx_hash, geth_trace)) in block_data
+        .transactions
+        .into_iter()
+        .zip(geth_traces)
+        .enumerate()
+    {
+        match geth_trace {
+            GethTrace::Known(GethTraceFrame::CallTracer(frame)) => flatten_call_frame(
+                frame,
+                vec![],
+                position,
+                tx_hash,
+                block,
+                block_hash,
+                &mut traces,
+            ),
+            _ => {
+                return Err(T::Error::from_provider_err(ProviderError::CustomError(
+                    format!("Unexpected trace format for transaction {:?}", tx_hash),
+                )))
+            }
+        }
+    }
+    Ok(traces)
+}
+
 pub async fn get_traces<T>(
     block: u64,
     eth_client: Arc<T>,
+    trace_method: TraceMethod,
 ) -> Result<Traces, <T as Middleware>::Error>
 where
     T: Middleware,
 {
-    let traces = eth_client.trace_block(block.into()).await;
-    if traces.is_err() {
-        return Err(traces.err().unwrap());
-    }
-    let mut traces = traces.unwrap();
+    let mut traces = match trace_method {
+        TraceMethod::Parity => match eth_client.trace_block(block.into()).await {
+            Ok(traces) => traces,
+            Err(e) if is_response_too_large(&e) => {
+                info!(
+                    "Traces of block {} are too large ({}), tracing its transactions one by one",
+                    block, e
+                );
+                get_traces_by_transaction(block, eth_client).await?
+            }
+            Err(e) => return Err(e),
+        },
+        TraceMethod::Geth => get_geth_traces(block, eth_client).await?,
+    };
     propagate_errors(&mut traces); // ensure all failed traces are marked as such
     Ok(traces.into())
 }
 
+/// Fetch the traces of a single transaction, with `trace_transaction` or `debug_traceTransaction`
+/// and the `callTracer`. Geth doesn't return the position of the transaction, so it's fetched too
+pub async fn get_transaction_traces<T>(
+    tx_hash: TxHash,
+    eth_client: Arc<T>,
+    trace_method: TraceMethod,
+) -> Result<Traces, <T as Middleware>::Error>
+where
+    T: Middleware,
+{
+    let mut traces = match trace_method {
+        TraceMethod::Parity => eth_client.trace_transaction(tx_hash).await?,
+        TraceMethod::Geth => {
+            let tx = eth_client.get_transaction(tx_hash).await?.ok_or_else(|| {
+                T::Error::from_provider_err(ProviderError::CustomError(format!(
+                    "Transaction {:?} not found",
+                    tx_hash
+                )))
+            })?;
+            let (Some(block), Some(position)) = (tx.block_number, tx.transaction_index) else {
+                return Err(T::Error::from_provider_err(ProviderError::CustomError(
+                    format!("Transaction {:?} is pending", tx_hash),
+                )));
+            };
+            let options = GethDebugTracingOptions {
+                tracer: Some(GethDebugTracerType::BuiltInTracer(
+                    GethDebugBuiltInTracerType::CallTracer,
+                )),
+                ..Default::default()
+            };
+            let GethTrace::Known(GethTraceFrame::CallTracer(frame)) =
+                eth_client.debug_trace_transaction(tx_hash, options).await?
+            else {
+                return Err(T::Error::from_provider_err(ProviderError::CustomError(
+                    format!("Unexpected trace format for transaction {:?}", tx_hash),
+                )));
+            };
+            let mut traces = Vec::new();
+            flatten_call_frame(
+                frame,
+                vec![],
+                position.as_usize(),
+                tx_hash,
+                block.as_u64(),
+                tx.block_hash.unwrap_or_default(),
+                &mut traces,
+            );
+            traces
+        }
+    };
+    propagate_errors(&mut traces);
+    Ok(traces.into())
+}
+
+/// Fetch the storage slots changed by the transactions of a block, replaying them with
+/// `trace_replayBlockTransactions` and the `stateDiff` tracer.
+/// Only parity style nodes (Erigon, Nethermind) support it, whatever the trace method
+pub async fn get_state_changes<T>(
+    block: u64,
+    eth_client: Arc<T>,
+) -> Result<Vec<StateChange>, <T as Middleware>::Error>
+where
+    T: Middleware,
+{
+    let traces = eth_client
+        .trace_replay_block_transactions(block.into(), vec![TraceType::StateDiff])
+        .await?;
+    Ok(StateChange::from_block_traces(block, traces))
+}
+
+/// Check that the connected node supports the chosen trace method, by tracing the given block.
+/// Errors other than a missing RPC method are ignored, they're handled while extracting.
+pub async fn check_trace_method<T>(
+    block: u64,
+    eth_client: Arc<T>,
+    trace_method: TraceMethod,
+) -> Result<(), String>
+where
+    T: Middleware,
+{
+    match get_traces(block, eth_client, trace_method).await {
+        // -32601 is the JSON-RPC code for a method that doesn't exist
+        Err(e) if e.as_error_response().map(|e| e.code) == Some(-32601) => Err(format!(
+            "The connected node doesn't support {}, try running with --trace-method {}",
+            trace_method.rpc_method(),
+            trace_method.other()
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
     use super::*;
-    use ethers::providers::Provider;
+    use ethers::providers::{JsonRpcError, MockResponse, Provider};
+    use ethers::types::{Address, Block};
 
     #[tokio::test]
     async fn test_get_traces() {
@@ -64,7 +403,9 @@ mod tests {
 
         let block = 1;
 
-        get_traces(block, eth_client).await.unwrap();
+        get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -75,7 +416,86 @@ mod tests {
 
         let block = 14174380;
 
-        get_traces(block, eth_client).await.unwrap();
+        get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_huge_trace_block_by_transaction() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+
+        let eth_client = Arc::new(Provider::try_from(eth_node).unwrap());
+
+        let block = 14174380;
+
+        // the fallback path returns the same traces, except the rewards
+        let by_transaction = get_traces_by_transaction(block, eth_client.clone())
+            .await
+            .unwrap();
+        let by_block = eth_client.trace_block(block.into()).await.unwrap();
+        let by_block = by_block
+            .into_iter()
+            .filter(|t| t.action_type != ActionType::Reward)
+            .collect::<Vec<Trace>>();
+        assert_eq!(by_transaction, by_block);
+    }
+
+    #[tokio::test]
+    async fn test_trace_block_fallback() {
+        let (provider, mock) = Provider::mocked();
+        let trace = |position: usize| Trace {
+            action: Action::Call(Call::default()),
+            result: None,
+            trace_address: vec![],
+            subtraces: 0,
+            transaction_position: Some(position),
+            transaction_hash: Some(TxHash::from_low_u64_be(position as u64)),
+            block_number: 1,
+            block_hash: H256::zero(),
+            action_type: ActionType::Call,
+            error: None,
+        };
+        let block = Block::<TxHash> {
+            number: Some(1.into()),
+            transactions: vec![TxHash::from_low_u64_be(0), TxHash::from_low_u64_be(1)],
+            ..Default::default()
+        };
+        // responses are popped from the last one pushed
+        mock.push::<Vec<Trace>, _>(vec![trace(1)]).unwrap();
+        mock.push::<Vec<Trace>, _>(vec![trace(0)]).unwrap();
+        mock.push::<Block<TxHash>, _>(block).unwrap();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "Response size exceeded".to_string(),
+            data: None,
+        }));
+
+        let traces = get_traces(1, Arc::new(provider), TraceMethod::Parity)
+            .await
+            .unwrap();
+        assert_eq!(traces.0, vec![trace(0), trace(1)]);
+
+        // other errors are returned as they are
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "header not found".to_string(),
+            data: None,
+        }));
+        assert!(get_traces(1, Arc::new(provider), TraceMethod::Parity)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_state_changes() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+
+        let eth_client = Arc::new(Provider::try_from(eth_node).unwrap());
+
+        // a small block, with two transactions
+        get_state_changes(1_000_000, eth_client).await.unwrap();
     }
 
     #[tokio::test]
@@ -86,7 +506,9 @@ mod tests {
 
         let block = 4719568;
 
-        let traces = get_traces(block, eth_client).await.unwrap();
+        let traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
         println!("{:?}", traces);
         let contracts = traces.get_creation_traces();
 
@@ -101,7 +523,9 @@ mod tests {
 
         let block = 16_634_562;
 
-        let traces = get_traces(block, eth_client).await.unwrap();
+        let traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
 
         traces
             .0
@@ -124,4 +548,83 @@ mod tests {
                 }
             });
     }
+
+    #[test]
+    fn test_flatten_call_frame() {
+        let frame: CallFrame = serde_json::from_str(
+            r#"{
+                "type": "CALL",
+                "from": "0x0000000000000000000000000000000000000001",
+                "to": "0x0000000000000000000000000000000000000002",
+                "value": "0x1",
+                "gas": "0x100",
+                "gasUsed": "0x80",
+                "input": "0x",
+                "output": "0x",
+                "calls": [
+                    {
+                        "type": "CREATE2",
+                        "from": "0x0000000000000000000000000000000000000002",
+                        "to": "0x0000000000000000000000000000000000000003",
+                        "gas": "0x50",
+                        "gasUsed": "0x40",
+                        "input": "0x6000",
+                        "output": "0x00"
+                    },
+                    {
+                        "type": "DELEGATECALL",
+                        "from": "0x0000000000000000000000000000000000000002",
+                        "to": "0x0000000000000000000000000000000000000004",
+                        "gas": "0x20",
+                        "gasUsed": "0x20",
+                        "input": "0x",
+                        "error": "execution reverted",
+                        "calls": [
+                            {
+                                "type": "SELFDESTRUCT",
+                                "from": "0x0000000000000000000000000000000000000004",
+                                "to": "0x0000000000000000000000000000000000000001",
+                                "value": "0x0",
+                                "gas": "0x0",
+                                "gasUsed": "0x0",
+                                "input": "0x"
+                            }
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let mut traces = Vec::new();
+        flatten_call_frame(
+            frame,
+            vec![],
+            0,
+            TxHash::from_low_u64_be(1),
+            1,
+            H256::zero(),
+            &mut traces,
+        );
+        propagate_errors(&mut traces);
+
+        let addresses: Vec<Vec<usize>> = traces.iter().map(|t| t.trace_address.clone()).collect();
+        assert_eq!(addresses, vec![vec![], vec![0], vec![1], vec![1, 0]]);
+        assert_eq!(traces[0].subtraces, 2);
+
+        match (&traces[1].action, &traces[1].result) {
+            (Action::Create(_), Some(Res::Create(res))) => {
+                assert_eq!(res.address, Address::from_low_u64_be(3))
+            }
+            _ => panic!("expected a successful create"),
+        }
+        match &traces[2].action {
+            Action::Call(call) => assert_eq!(call.call_type, CallType::DelegateCall),
+            _ => panic!("expected a call"),
+        }
+        assert!(traces[2].result.is_none());
+        assert_eq!(traces[3].action_type, ActionType::Suicide);
+        // the self destruct is reverted with its parent
+        assert!(traces[3].error.is_some());
+    }
 }
@@ -1,15 +1,44 @@
+use crate::extraction::errors::{ErrorStage, ExtractionError};
+use crate::extraction::layout::OutputLayout;
+use crate::extraction::manifest::FileCounters;
+use crate::extraction::s3::is_s3_url;
+#[cfg(feature = "s3")]
+use crate::extraction::s3::put_object;
+use crate::info;
 use crate::models::log::Log;
 use crate::models::{
-    abi::ABIStructure, block::Block, contract_deployment::ContractDeployment,
-    contract_destruction::ContractDestruction, error::ErrorABI, event::EventABI,
-    function::FunctionABI, skeleton::Skeleton, transaction::Transaction, transfer::TokenTransfer,
-    SerializeDgraph,
+    abi::ABIStructure,
+    account::Account,
+    block::Block,
+    contract_deployment::ContractDeployment,
+    contract_destruction::ContractDestruction,
+    contract_gas_usage::ContractGasUsage,
+    creation_code::CreationCode,
+    error::ErrorABI,
+    event::EventABI,
+    function::FunctionABI,
+    internal_transaction::InternalTransaction,
+    skeleton::Skeleton,
+    state_change::StateChange,
+    transaction::Transaction,
+    transfer::{TokenTransfer, CSV_HEADER},
+    OrderKey, SerializeDgraph,
 };
+use ethers::types::Address;
 use flate2::Compression;
 use primitive_types::H256;
-use std::mem::size_of_val;
-use std::{collections::HashSet, io::Write};
-use tokio::sync::mpsc::Receiver;
+use sha2::{Digest, Sha256};
+use std::mem::{size_of, size_of_val};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+};
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinHandle};
 use tokio::time::Instant;
 
 #[derive(Debug)]
@@ -21,12 +50,73 @@ pub enum WriteCommand {
     ContractDestruction(ContractDestruction),
     Skeleton(Skeleton),
     Log(Log),
+    InternalTx(InternalTransaction),
+    StateChange(StateChange),
+    Account(Account),
+    GasUsage(ContractGasUsage),
+    /// Block that couldn't be extracted
+    FailedBlock(u64),
+    /// Block over --max-block-size-bytes, not extracted
+    SkippedBlock(u64),
+    /// All the entities of the block have been sent
+    BlockComplete(u64),
+}
+
+impl WriteCommand {
+    /// Block of the entities stored per block, which `--flush-on-block` keeps in the same file.
+    /// Skeletons and ABI nodes are shared among blocks, accounts and gas usages are merged
+    fn block_number(&self) -> Option<u64> {
+        match self {
+            WriteCommand::Block(block) => Some(block.order_key().0),
+            WriteCommand::Transfer(transfer) => Some(transfer.order_key().0),
+            WriteCommand::Transaction(tx) => Some(tx.order_key().0),
+            WriteCommand::ContractDeployment(deployment) => Some(deployment.order_key().0),
+            WriteCommand::ContractDestruction(destruction) => Some(destruction.order_key().0),
+            WriteCommand::Log(log) => Some(log.order_key().0),
+            WriteCommand::InternalTx(internal_tx) => Some(internal_tx.order_key().0),
+            WriteCommand::StateChange(state_change) => Some(state_change.order_key().0),
+            WriteCommand::Skeleton(_)
+            | WriteCommand::Account(_)
+            | WriteCommand::GasUsage(_)
+            | WriteCommand::FailedBlock(_)
+            | WriteCommand::SkippedBlock(_)
+            | WriteCommand::BlockComplete(_) => None,
+        }
+    }
+}
+
+/// An output file written by a flush, with the SHA-256 of its content and the bytes written
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushedFile {
+    pub path: String,
+    pub sha256: H256,
+    pub size: u64,
+}
+
+impl FlushedFile {
+    fn new(path: &str, content: &[u8]) -> Self {
+        Self {
+            path: path.to_string(),
+            sha256: H256::from_slice(&Sha256::digest(content)),
+            size: content.len() as u64,
+        }
+    }
 }
 
-pub fn flush<T>(vec: &Vec<T>, output_file: &str, compression_level: u32)
+pub fn flush<T>(
+    vec: &mut Vec<T>,
+    output_file: &str,
+    compression_level: u32,
+    deterministic: bool,
+    validate: bool,
+) -> Result<FlushedFile, String>
 where
-    T: SerializeDgraph,
+    T: SerializeDgraph + OrderKey,
 {
+    if deterministic {
+        // sort by block number, then by the entity's secondary key, see OrderKey
+        vec.sort_by_cached_key(|item| item.order_key());
+    }
     let mut json: Vec<u8> = Vec::new();
     json.push(b'[');
     for item in vec {
@@ -39,23 +129,363 @@ where
         json.pop();
     }
     json.push(b']');
-    let mut encoder = flate2::write::GzEncoder::new(
-        std::fs::File::create(output_file).unwrap(),
-        Compression::new(compression_level),
+    let mut encoder =
+        flate2::write::GzEncoder::new(Vec::new(), Compression::new(compression_level));
+    let compressed = encoder
+        .write_all(&json)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| format!("could not compress {}: {}", output_file, e))?;
+    write_output_file(output_file, &compressed)?;
+    if validate {
+        // uploaded files are checked from the buffer, local ones are read back from disk
+        let res = if is_s3_url(output_file) {
+            validate_json_gz(&compressed)
+        } else {
+            validate_output(output_file)
+        };
+        if let Err(e) = res {
+            eprintln!("ERROR: invalid output file {}: {}", output_file, e);
+            return Err(format!("invalid output file {}: {}", output_file, e));
+        }
+    }
+    Ok(FlushedFile::new(output_file, &compressed))
+}
+
+/// Temporary files of the content-addressed writes, unique within the process
+static CONTENT_ADDRESSED_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Flush the entities to a content-addressed file, whose name depends only on its content.
+/// Local files are written to a temporary file in the same folder and renamed, so concurrent
+/// writers of the same file never leave a partial one, and files already written are kept.
+/// Uploads to S3 are atomic already
+pub fn flush_content_addressed<T>(
+    vec: &mut Vec<T>,
+    output_file: &str,
+    compression_level: u32,
+    validate: bool,
+) -> Result<FlushedFile, String>
+where
+    T: SerializeDgraph + OrderKey,
+{
+    if is_s3_url(output_file) {
+        return flush(vec, output_file, compression_level, true, validate);
+    }
+    let path = Path::new(output_file);
+    if path.exists() {
+        let content = std::fs::read(path).map_err(|e| e.to_string())?;
+        // written by another extraction, nothing is added to the output
+        return Ok(FlushedFile {
+            size: 0,
+            ..FlushedFile::new(output_file, &content)
+        });
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    }
+    let tmp = format!(
+        "{}.{}-{}.tmp",
+        output_file,
+        std::process::id(),
+        CONTENT_ADDRESSED_TMP.fetch_add(1, Ordering::Relaxed)
     );
-    encoder.write_all(&json).unwrap();
-    encoder.finish().unwrap();
+    let res = flush(vec, &tmp, compression_level, true, validate).and_then(|flushed| {
+        std::fs::rename(&tmp, output_file).map_err(|e| e.to_string())?;
+        Ok(FlushedFile {
+            path: output_file.to_string(),
+            ..flushed
+        })
+    });
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res.map_err(|e| format!("could not write {}: {}", output_file, e))
 }
 
-pub async fn writer_task(
+/// Read back a flushed file and check that it's a valid JSON array, as expected by the Dgraph bulk loader
+pub fn validate_output(output_file: &str) -> Result<(), String> {
+    let compressed = std::fs::read(output_file).map_err(|e| e.to_string())?;
+    validate_json_gz(&compressed)
+}
+
+fn validate_json_gz(compressed: &[u8]) -> Result<(), String> {
+    let mut json = Vec::new();
+    flate2::read::GzDecoder::new(compressed)
+        .read_to_end(&mut json)
+        .map_err(|e| e.to_string())?;
+    serde_json::from_slice::<Vec<serde_json::Value>>(&json).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Write a whole output file, `s3://` paths are uploaded when built with the `s3` feature.
+/// Uploads block on the current runtime, so it must be called from a blocking task
+pub fn write_output_file(output_file: &str, content: &[u8]) -> Result<(), String> {
+    if is_s3_url(output_file) {
+        #[cfg(feature = "s3")]
+        return tokio::runtime::Handle::current()
+            .block_on(put_object(output_file, content.to_vec()));
+        #[cfg(not(feature = "s3"))]
+        return Err(format!(
+            "could not write {}: built without the s3 feature",
+            output_file
+        ));
+    }
+    std::fs::write(output_file, content)
+        .map_err(|e| format!("could not write {}: {}", output_file, e))
+}
+
+/// Flat CSV of the token transfers, `transfers.csv` in the output path, written row by row
+/// next to the JSON files. The SHA-256 of the rows is computed on the way, for the checksums
+struct TransfersCsv {
+    path: String,
+    file: std::io::BufWriter<std::fs::File>,
+    sha256: Sha256,
+}
+
+impl TransfersCsv {
+    /// Create the file, writing the header
+    fn create(output_path: &str) -> Result<Self, String> {
+        let path = format!("{}/transfers.csv", output_path.trim_end_matches('/'));
+        let file = std::fs::File::create(&path)
+            .map_err(|e| format!("could not create {}: {}", path, e))?;
+        let mut csv = Self {
+            path,
+            file: std::io::BufWriter::new(file),
+            sha256: Sha256::new(),
+        };
+        csv.write_line(CSV_HEADER)?;
+        Ok(csv)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        let line = format!("{}\n", line);
+        self.sha256.update(line.as_bytes());
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("could not write {}: {}", self.path, e))
+    }
+
+    fn write(&mut self, transfer: &TokenTransfer) -> Result<(), String> {
+        self.write_line(&transfer.csv_row())
+    }
+
+    fn finish(mut self) -> Result<FlushedFile, String> {
+        self.file
+            .flush()
+            .map_err(|e| format!("could not write {}: {}", self.path, e))?;
+        let size = self.file.get_ref().metadata().map_or(0, |m| m.len());
+        Ok(FlushedFile {
+            path: self.path,
+            sha256: H256::from_slice(&self.sha256.finalize()),
+            size,
+        })
+    }
+}
+
+/// Compression jobs of the writer, at most `concurrency` of them run at the same time so a burst
+/// of flushes doesn't take all the cores from the extraction tasks.
+/// Once the limit is reached spawning waits for a free slot, and so do the blocks sent to the writer
+struct FlushPool {
+    permits: Arc<Semaphore>,
+    handles: Vec<JoinHandle<Result<FlushedFile, String>>>,
+    error_log: UnboundedSender<ExtractionError>,
+    /// Files written so far, kept only if their checksums are needed
+    flushed: Option<Vec<FlushedFile>>,
+    /// Bytes written by the completed flushes, if someone is watching them
+    written: Option<Arc<AtomicU64>>,
+}
+
+impl FlushPool {
+    fn new(concurrency: usize, error_log: UnboundedSender<ExtractionError>) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            handles: Vec::new(),
+            error_log,
+            flushed: None,
+            written: None,
+        }
+    }
+
+    /// Keep the files written by the flushes, see `join`
+    fn with_checksums(mut self) -> Self {
+        self.flushed = Some(Vec::new());
+        self
+    }
+
+    /// Add the size of every file written to `written`, as soon as its flush completes
+    fn with_written_bytes(mut self, written: Arc<AtomicU64>) -> Self {
+        self.written = Some(written);
+        self
+    }
+
+    /// Run a flush on the blocking pool, once a slot is free
+    async fn spawn<F>(&mut self, flush: F)
+    where
+        F: FnOnce() -> Result<FlushedFile, String> + Send + 'static,
+    {
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        self.reap().await;
+        let written = self.written.clone();
+        self.handles.push(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let res = flush();
+            if let (Ok(file), Some(written)) = (&res, written) {
+                written.fetch_add(file.size, Ordering::SeqCst);
+            }
+            res
+        }));
+    }
+
+    /// Report the failures of the completed flushes and forget them
+    async fn reap(&mut self) {
+        let (done, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.handles)
+            .into_iter()
+            .partition(|h| h.is_finished());
+        self.handles = running;
+        for handle in done {
+            self.report(handle.await);
+        }
+    }
+
+    /// Wait for all the running flushes, returning the files written if they were kept
+    async fn join(mut self) -> Vec<FlushedFile> {
+        for res in futures::future::join_all(std::mem::take(&mut self.handles)).await {
+            self.report(res);
+        }
+        self.flushed.unwrap_or_default()
+    }
+
+    fn report(&mut self, res: Result<Result<FlushedFile, String>, JoinError>) {
+        match res.map_err(|e| e.to_string()).and_then(|r| r) {
+            Ok(file) => {
+                if let Some(flushed) = &mut self.flushed {
+                    flushed.push(file);
+                }
+            }
+            Err(e) => {
+                let _ = self
+                    .error_log
+                    .send(ExtractionError::new(None, ErrorStage::Writer, e));
+            }
+        }
+    }
+}
+
+/// Write the blocks to `<output_path>/<file>`, sorted and one per line. If the file can't be
+/// written the error is logged with the blocks, so the list isn't lost
+async fn write_block_list(
     output_path: &str,
+    file: &str,
+    blocks: &mut [u64],
+    error_log: &UnboundedSender<ExtractionError>,
+) -> bool {
+    blocks.sort();
+    let content: String = blocks.iter().map(|b| format!("{}\n", b)).collect();
+    let o = format!("{}/{}", output_path, file);
+    let res = tokio::task::spawn_blocking(move || write_output_file(&o, content.as_bytes()))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r);
+    if let Err(e) = res {
+        let blocks = blocks
+            .iter()
+            .map(|b| b.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let e = format!("could not write {}: {}, blocks: {}", file, e, blocks);
+        eprintln!("ERROR: {}", e);
+        let _ = error_log.send(ExtractionError::new(None, ErrorStage::Writer, e));
+        return false;
+    }
+    true
+}
+
+/// Write the `SHA256SUMS` file of the output path, in the format of `sha256sum`, with the paths
+/// relative to the output path.
+/// Local files are written to a temporary file and renamed, so a partial file is never left
+pub fn write_checksums(output_path: &str, mut files: Vec<FlushedFile>) -> Result<(), String> {
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let prefix = format!("{}/", output_path.trim_end_matches('/'));
+    let content: String = files
+        .iter()
+        .map(|f| {
+            let path = f.path.strip_prefix(&prefix).unwrap_or(&f.path);
+            format!("{:x}  {}\n", f.sha256, path)
+        })
+        .collect();
+    let output_file = format!("{}SHA256SUMS", prefix);
+    if is_s3_url(&output_file) {
+        return write_output_file(&output_file, content.as_bytes());
+    }
+    let tmp = format!("{}.tmp", output_file);
+    write_output_file(&tmp, content.as_bytes())?;
+    std::fs::rename(&tmp, &output_file)
+        .map_err(|e| format!("could not write {}: {}", output_file, e))
+}
+
+/// Output files of `writer_task`: where they go, how they're rotated and compressed
+#[derive(Debug, Clone, Default)]
+pub struct WriterConfig {
+    pub output_path: String,
+    pub layout: OutputLayout,
+    /// First counter of the files of each entity, continuing a previous run
+    pub counters: FileCounters,
+    /// Size in kB of the entities buffered before a file is written
+    pub output_size_kb: usize,
+    pub compression_level: u32,
+    /// Sort the entities of each file. Rotation still follows the order they're received in
+    pub deterministic: bool,
+    /// Read back every file written, to check it decompresses and parses
+    pub validate: bool,
+    pub content_addressed_skeletons: bool,
+    /// Files compressed at the same time, 0 for half of the CPUs
+    pub flush_concurrency: usize,
+    pub checksums: bool,
+    pub flush_on_block: bool,
+    pub transfers_csv: bool,
+}
+
+/// Blocks a run couldn't extract, listed in `failed_blocks.txt` and `skipped_blocks.txt`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnextractedBlocks {
+    pub failed: Vec<u64>,
+    pub skipped: Vec<u64>,
+}
+
+/// Write the entities received to the output files, until all the senders are dropped.
+/// `written` is increased by the size of each file written
+pub async fn writer_task(
+    config: WriterConfig,
     mut receiver: Receiver<WriteCommand>,
-    output_size_kb: usize,
-    compression_level: u32,
-) {
+    written: Option<Arc<AtomicU64>>,
+    error_log: UnboundedSender<ExtractionError>,
+) -> UnextractedBlocks {
+    let WriterConfig {
+        output_path,
+        layout,
+        counters,
+        output_size_kb,
+        compression_level,
+        deterministic,
+        validate,
+        content_addressed_skeletons,
+        flush_concurrency,
+        checksums,
+        flush_on_block,
+        transfers_csv,
+    } = config;
+    let output_path = output_path.as_str();
+    // compression jobs take half of the cores at most, the others are left to the extraction
+    let flush_concurrency = if flush_concurrency == 0 {
+        (num_cpus::get() / 2).max(1)
+    } else {
+        flush_concurrency
+    };
+
     let mut stored_function_signatures: HashSet<H256> = HashSet::new();
     let mut stored_event_signatures: HashSet<H256> = HashSet::new();
     let mut stored_error_signatures: HashSet<H256> = HashSet::new();
+    let mut stored_creation_codes: HashSet<H256> = HashSet::new();
 
     let mut skeletons: Vec<Skeleton> = Vec::new();
     let mut transfers: Vec<TokenTransfer> = Vec::new();
@@ -66,102 +496,194 @@ pub async fn writer_task(
     let mut transactions: Vec<Transaction> = Vec::new();
     let mut contract_deployments: Vec<ContractDeployment> = Vec::new();
     let mut contract_destructions: Vec<ContractDestruction> = Vec::new();
+    let mut creation_codes: Vec<CreationCode> = Vec::new();
+    // creation codes are large and of variable size, their buffer is sized on the bytecode
+    let mut creation_codes_size = 0;
     let mut logs: Vec<Log> = Vec::new();
+    let mut internal_txs: Vec<InternalTransaction> = Vec::new();
+    let mut state_changes: Vec<StateChange> = Vec::new();
+    // accounts are merged during the whole run, and flushed at the end
+    let mut accounts: HashMap<Address, Account> = HashMap::new();
+    // and so is the gas used by each contract
+    let mut gas_usages: HashMap<Address, ContractGasUsage> = HashMap::new();
+    let mut failed_blocks: Vec<u64> = Vec::new();
+    let mut skipped_blocks: Vec<u64> = Vec::new();
 
-    let mut transfers_file_counter = 0;
-    let mut events_file_counter = 0;
-    let mut errors_file_counter = 0;
-    let mut functions_file_counter = 0;
-    let mut blocks_file_counter = 0;
-    let mut transactions_file_counter = 0;
-    let mut contract_deployments_file_counter = 0;
-    let mut contract_destructions_file_counter = 0;
-    let mut skeletons_file_counter = 0;
-    let mut logs_file_counter = 0;
+    // counters continue from the ones of a previous run, see `--since`
+    let first_counter = |name: &str| counters.get(name).copied().unwrap_or(0);
+    let mut transfers_file_counter = first_counter("transfers");
+    let mut events_file_counter = first_counter("events");
+    let mut errors_file_counter = first_counter("errors");
+    let mut functions_file_counter = first_counter("functions");
+    let mut blocks_file_counter = first_counter("blocks");
+    let mut transactions_file_counter = first_counter("transactions");
+    let mut contract_deployments_file_counter = first_counter("deployments");
+    let mut contract_destructions_file_counter = first_counter("destructions");
+    let mut creation_codes_file_counter = first_counter("creation_codes");
+    let mut skeletons_file_counter = first_counter("skeletons");
+    let mut logs_file_counter = first_counter("logs");
+    let mut internal_txs_file_counter = first_counter("internal_txs");
+    let mut state_changes_file_counter = first_counter("state_changes");
 
-    let mut handles = Vec::new();
+    let mut transfers_csv = if transfers_csv {
+        match TransfersCsv::create(output_path) {
+            Ok(csv) => Some(csv),
+            Err(e) => {
+                let _ = error_log.send(ExtractionError::new(None, ErrorStage::Writer, e));
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    while let Some(comm) = receiver.recv().await {
-        match comm {
-            WriteCommand::Transfer(transfer) => {
-                transfers.push(transfer);
-                let size = size_of_val(&*transfers) / 1024; // in KB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
+    let mut flushes = FlushPool::new(flush_concurrency, error_log.clone());
+    if checksums {
+        flushes = flushes.with_checksums();
+    }
+    if let Some(written) = written {
+        flushes = flushes.with_written_bytes(written);
+    }
+
+    // with --flush-on-block, the entities of the blocks still being extracted are held back
+    // until their BlockComplete, then processed all together before receiving again
+    let mut incomplete_blocks: HashMap<u64, Vec<WriteCommand>> = HashMap::new();
+    let mut completed_block = Vec::new().into_iter();
+
+    // flush the buffer of an entity to its next file, if it's over the size limit. With
+    // `$per_block` the rotation waits for the BlockComplete of the block with --flush-on-block
+    macro_rules! rotate {
+        ($vec:ident, $dir:ident, $name:literal, $counter:ident, $per_block:literal) => {
+            if !($per_block && flush_on_block) && size_of_val(&*$vec) / 1024 > output_size_kb {
+                let o = OutputLayout::file_path(output_path, &layout.$dir, $name, $counter);
+                let mut items = std::mem::take(&mut $vec);
+                flushes
+                    .spawn(move || {
                         flush(
-                            &transfers,
-                            format!(
-                                "{}/dynamic/transfers/transfers_{}.json.gz",
-                                o, transfers_file_counter
-                            )
-                            .as_str(),
+                            &mut items,
+                            o.as_str(),
                             compression_level,
-                        );
-                    }));
-                    transfers_file_counter += 1;
-                    transfers = Vec::new();
+                            deterministic,
+                            validate,
+                        )
+                    })
+                    .await;
+                $counter += 1;
+            }
+        };
+    }
+
+    loop {
+        let comm = match completed_block.next() {
+            Some(comm) => comm,
+            None => {
+                let Some(comm) = receiver.recv().await else {
+                    break;
+                };
+                if flush_on_block {
+                    if let Some(block) = comm.block_number() {
+                        incomplete_blocks.entry(block).or_default().push(comm);
+                        continue;
+                    }
+                    if let WriteCommand::BlockComplete(block) = comm {
+                        let mut comms = incomplete_blocks.remove(&block).unwrap_or_default();
+                        comms.push(comm);
+                        completed_block = comms.into_iter();
+                        continue;
+                    }
                 }
+                comm
+            }
+        };
+        match comm {
+            WriteCommand::Transfer(transfer) => {
+                if let Some(csv) = transfers_csv.as_mut() {
+                    // the JSON output goes on, only the CSV is given up
+                    if let Err(e) = csv.write(&transfer) {
+                        let _ = error_log.send(ExtractionError::new(None, ErrorStage::Writer, e));
+                        transfers_csv = None;
+                    }
+                }
+                transfers.push(transfer);
+                rotate!(
+                    transfers,
+                    transfers,
+                    "transfers",
+                    transfers_file_counter,
+                    true
+                );
             }
             WriteCommand::Block(block) => {
                 blocks.push(block);
-                let size = size_of_val(&*blocks) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &blocks,
-                            format!("{}/static/blocks/blocks_{}.json.gz", o, blocks_file_counter)
-                                .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    blocks_file_counter += 1;
-                    blocks = Vec::new();
-                }
+                rotate!(blocks, blocks, "blocks", blocks_file_counter, true);
             }
             WriteCommand::Transaction(transaction) => {
                 transactions.push(transaction);
-                let size = size_of_val(&*transactions) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &transactions,
-                            format!(
-                                "{}/dynamic/transactions/transactions_{}.json.gz",
-                                o, transactions_file_counter
-                            )
-                            .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    transactions_file_counter += 1;
-                    transactions = Vec::new();
-                }
+                rotate!(
+                    transactions,
+                    transactions,
+                    "transactions",
+                    transactions_file_counter,
+                    true
+                );
             }
             WriteCommand::ContractDeployment(contract_deployment) => {
-                contract_deployments.push(contract_deployment);
-                let size = size_of_val(&*contract_deployments) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &contract_deployments,
-                            format!(
-                                "{}/static/deployments/deployments_{}.json.gz",
-                                o, contract_deployments_file_counter
-                            )
-                            .as_str(),
-                            compression_level,
+                // deployments sharing the init code link the same CreationCode node
+                if contract_deployment.hashes_creation_code()
+                    && stored_creation_codes.insert(contract_deployment.creation_code_hash())
+                {
+                    let creation_code =
+                        CreationCode::new(contract_deployment.creation_code().clone());
+                    creation_codes_size += creation_code.code_size();
+                    creation_codes.push(creation_code);
+                    if creation_codes_size / 1024 > output_size_kb {
+                        let o = OutputLayout::file_path(
+                            output_path,
+                            &layout.creation_codes,
+                            "creation_codes",
+                            creation_codes_file_counter,
                         );
-                    }));
-                    contract_deployments_file_counter += 1;
-                    contract_deployments = Vec::new();
+                        flushes
+                            .spawn(move || {
+                                flush(
+                                    &mut creation_codes,
+                                    o.as_str(),
+                                    compression_level,
+                                    deterministic,
+                                    validate,
+                                )
+                            })
+                            .await;
+                        creation_codes_file_counter += 1;
+                        creation_codes = Vec::new();
+                        creation_codes_size = 0;
+                    }
                 }
+                contract_deployments.push(contract_deployment);
+                rotate!(
+                    contract_deployments,
+                    deployments,
+                    "deployments",
+                    contract_deployments_file_counter,
+                    true
+                );
             }
             WriteCommand::Skeleton(skeleton) => {
-                skeletons.push(skeleton.clone()); // TODO check this
+                if content_addressed_skeletons {
+                    let o = OutputLayout::content_addressed_path(
+                        output_path,
+                        &layout.skeletons,
+                        skeleton.hash(),
+                    );
+                    let mut content = vec![skeleton.clone()];
+                    flushes
+                        .spawn(move || {
+                            flush_content_addressed(&mut content, &o, compression_level, validate)
+                        })
+                        .await;
+                } else {
+                    skeletons.push(skeleton.clone()); // TODO check this
+                }
                 if let Some(abi) = skeleton.get_abi() {
                     for node in &abi.nodes {
                         let sig_hash = node.get_signature_hash();
@@ -191,257 +713,1191 @@ pub async fn writer_task(
                     }
                 }
 
-                let size = size_of_val(&*events) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &events,
-                            format!("{}/static/events/events_{}.json.gz", o, events_file_counter)
-                                .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    events_file_counter += 1;
-                    events = Vec::new();
-                }
+                rotate!(events, events, "events", events_file_counter, false);
 
-                let size = size_of_val(&*errors) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &errors,
-                            format!("{}/static/errors/errors_{}.json.gz", o, errors_file_counter)
-                                .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    errors_file_counter += 1;
-                    errors = Vec::new();
-                }
+                rotate!(errors, errors, "errors", errors_file_counter, false);
 
-                let size = size_of_val(&*functions) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &functions,
-                            format!(
-                                "{}/static/functions/functions_{}.json.gz",
-                                o, functions_file_counter
-                            )
-                            .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    functions_file_counter += 1;
-                    functions = Vec::new();
-                }
+                rotate!(
+                    functions,
+                    functions,
+                    "functions",
+                    functions_file_counter,
+                    false
+                );
 
-                let size = size_of_val(&*skeletons) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &skeletons,
-                            format!(
-                                "{}/static/skeletons/skeletons_{}.json.gz",
-                                o, skeletons_file_counter
-                            )
-                            .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    skeletons_file_counter += 1;
-                    skeletons = Vec::new();
-                }
+                rotate!(
+                    skeletons,
+                    skeletons,
+                    "skeletons",
+                    skeletons_file_counter,
+                    false
+                );
             }
             WriteCommand::ContractDestruction(contract_destruction) => {
                 contract_destructions.push(contract_destruction);
-                let size = size_of_val(&*contract_destructions) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &contract_destructions,
-                            format!(
-                                "{}/static/destructions/destructions_{}.json.gz",
-                                o, contract_destructions_file_counter
-                            )
-                            .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    contract_destructions_file_counter += 1;
-                    contract_destructions = Vec::new();
-                }
+                rotate!(
+                    contract_destructions,
+                    destructions,
+                    "destructions",
+                    contract_destructions_file_counter,
+                    true
+                );
             }
             WriteCommand::Log(log) => {
                 logs.push(log);
-                let size = size_of_val(&*logs) / 1024; // in kB
-                if size > output_size_kb {
-                    let o = output_path.to_string();
-                    handles.push(tokio::task::spawn_blocking(move || {
-                        flush(
-                            &logs,
-                            format!("{}/dynamic/logs/logs_{}.json.gz", o, logs_file_counter)
-                                .as_str(),
-                            compression_level,
-                        );
-                    }));
-                    logs_file_counter += 1;
-                    logs = Vec::new();
+                rotate!(logs, logs, "logs", logs_file_counter, true);
+            }
+            WriteCommand::InternalTx(internal_tx) => {
+                internal_txs.push(internal_tx);
+                rotate!(
+                    internal_txs,
+                    internal_txs,
+                    "internal_txs",
+                    internal_txs_file_counter,
+                    true
+                );
+            }
+            WriteCommand::StateChange(state_change) => {
+                state_changes.push(state_change);
+                rotate!(
+                    state_changes,
+                    state_changes,
+                    "state_changes",
+                    state_changes_file_counter,
+                    true
+                );
+            }
+            WriteCommand::Account(account) => {
+                accounts
+                    .entry(account.address())
+                    .and_modify(|a| a.merge(&account))
+                    .or_insert(account);
+            }
+            WriteCommand::GasUsage(usage) => {
+                gas_usages
+                    .entry(usage.address())
+                    .and_modify(|u| u.merge(&usage))
+                    .or_insert(usage);
+            }
+            WriteCommand::FailedBlock(block) => {
+                failed_blocks.push(block);
+            }
+            WriteCommand::SkippedBlock(block) => {
+                skipped_blocks.push(block);
+            }
+            WriteCommand::BlockComplete(_) => {
+                // the files of the per-block entities are rotated only between whole blocks
+                if flush_on_block {
+                    rotate!(blocks, blocks, "blocks", blocks_file_counter, false);
+                    rotate!(
+                        transactions,
+                        transactions,
+                        "transactions",
+                        transactions_file_counter,
+                        false
+                    );
+                    rotate!(
+                        contract_deployments,
+                        deployments,
+                        "deployments",
+                        contract_deployments_file_counter,
+                        false
+                    );
+                    rotate!(
+                        contract_destructions,
+                        destructions,
+                        "destructions",
+                        contract_destructions_file_counter,
+                        false
+                    );
+                    rotate!(
+                        transfers,
+                        transfers,
+                        "transfers",
+                        transfers_file_counter,
+                        false
+                    );
+                    rotate!(logs, logs, "logs", logs_file_counter, false);
+                    rotate!(
+                        internal_txs,
+                        internal_txs,
+                        "internal_txs",
+                        internal_txs_file_counter,
+                        false
+                    );
+                    rotate!(
+                        state_changes,
+                        state_changes,
+                        "state_changes",
+                        state_changes_file_counter,
+                        false
+                    );
                 }
             }
         }
     }
 
-    println!("Flushing remaining data...");
+    // blocks whose extraction stopped halfway are not written, so they can be extracted again
+    if !incomplete_blocks.is_empty() {
+        eprintln!(
+            "{} blocks were not completed, recording them as failed",
+            incomplete_blocks.len()
+        );
+        failed_blocks.extend(incomplete_blocks.into_keys());
+    }
+
+    if !failed_blocks.is_empty()
+        && write_block_list(
+            output_path,
+            "failed_blocks.txt",
+            &mut failed_blocks,
+            &error_log,
+        )
+        .await
+    {
+        info!(
+            "{} blocks failed, list written to {}/failed_blocks.txt",
+            failed_blocks.len(),
+            output_path
+        );
+    }
+
+    if !skipped_blocks.is_empty()
+        && write_block_list(
+            output_path,
+            "skipped_blocks.txt",
+            &mut skipped_blocks,
+            &error_log,
+        )
+        .await
+    {
+        info!(
+            "{} blocks skipped, list written to {}/skipped_blocks.txt",
+            skipped_blocks.len(),
+            output_path
+        );
+    }
+
+    info!("Flushing remaining data...");
 
     let now = Instant::now();
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(output_path, &layout.blocks, "blocks", blocks_file_counter);
+    flushes
+        .spawn(move || {
             flush(
-                &blocks,
-                format!("{}/static/blocks/blocks_{}.json.gz", o, blocks_file_counter).as_str(),
+                &mut blocks,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.transactions,
+        "transactions",
+        transactions_file_counter,
+    );
+    flushes
+        .spawn(move || {
             flush(
-                &transactions,
-                format!(
-                    "{}/dynamic/transactions/transactions_{}.json.gz",
-                    o, transactions_file_counter
-                )
-                .as_str(),
+                &mut transactions,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.deployments,
+        "deployments",
+        contract_deployments_file_counter,
+    );
+    flushes
+        .spawn(move || {
             flush(
-                &contract_deployments,
-                format!(
-                    "{}/static/deployments/deployments_{}.json.gz",
-                    o, contract_deployments_file_counter
+                &mut contract_deployments,
+                o.as_str(),
+                compression_level,
+                deterministic,
+                validate,
+            )
+        })
+        .await;
+
+    if !creation_codes.is_empty() {
+        let o = OutputLayout::file_path(
+            output_path,
+            &layout.creation_codes,
+            "creation_codes",
+            creation_codes_file_counter,
+        );
+        flushes
+            .spawn(move || {
+                flush(
+                    &mut creation_codes,
+                    o.as_str(),
+                    compression_level,
+                    deterministic,
+                    validate,
                 )
-                .as_str(),
+            })
+            .await;
+    }
+
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.destructions,
+        "destructions",
+        contract_destructions_file_counter,
+    );
+    flushes
+        .spawn(move || {
+            flush(
+                &mut contract_destructions,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(output_path, &layout.logs, "logs", logs_file_counter);
+    flushes
+        .spawn(move || {
             flush(
-                &contract_destructions,
-                format!(
-                    "{}/static/destructions/destructions_{}.json.gz",
-                    o, contract_destructions_file_counter
-                )
-                .as_str(),
+                &mut logs,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.internal_txs,
+        "internal_txs",
+        internal_txs_file_counter,
+    );
+    flushes
+        .spawn(move || {
             flush(
-                &logs,
-                format!("{}/dynamic/logs/logs_{}.json.gz", o, logs_file_counter).as_str(),
+                &mut internal_txs,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.state_changes,
+        "state_changes",
+        state_changes_file_counter,
+    );
+    flushes
+        .spawn(move || {
             flush(
-                &events,
-                format!("{}/static/events/events_{}.json.gz", o, events_file_counter).as_str(),
+                &mut state_changes,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(output_path, &layout.events, "events", events_file_counter);
+    flushes
+        .spawn(move || {
             flush(
-                &errors,
-                format!("{}/static/errors/errors_{}.json.gz", o, errors_file_counter).as_str(),
+                &mut events,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(output_path, &layout.errors, "errors", errors_file_counter);
+    flushes
+        .spawn(move || {
             flush(
-                &functions,
-                format!(
-                    "{}/static/functions/functions_{}.json.gz",
-                    o, functions_file_counter
-                )
-                .as_str(),
+                &mut errors,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.functions,
+        "functions",
+        functions_file_counter,
+    );
+    flushes
+        .spawn(move || {
             flush(
-                &transfers,
-                format!(
-                    "{}/dynamic/transfers/transfers_{}.json.gz",
-                    o, transfers_file_counter
-                )
-                .as_str(),
+                &mut functions,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
 
-    handles.push({
-        let o = output_path.to_string();
-        tokio::task::spawn_blocking(move || {
+    let o = OutputLayout::file_path(
+        output_path,
+        &layout.transfers,
+        "transfers",
+        transfers_file_counter,
+    );
+    flushes
+        .spawn(move || {
             flush(
-                &skeletons,
-                format!(
-                    "{}/static/skeletons/skeletons_{}.json.gz",
-                    o, skeletons_file_counter
-                )
-                .as_str(),
+                &mut transfers,
+                o.as_str(),
                 compression_level,
-            );
+                deterministic,
+                validate,
+            )
         })
-    });
+        .await;
+
+    // content-addressed skeletons are already written
+    if !content_addressed_skeletons {
+        let o = OutputLayout::file_path(
+            output_path,
+            &layout.skeletons,
+            "skeletons",
+            skeletons_file_counter,
+        );
+        flushes
+            .spawn(move || {
+                flush(
+                    &mut skeletons,
+                    o.as_str(),
+                    compression_level,
+                    deterministic,
+                    validate,
+                )
+            })
+            .await;
+    }
+
+    // accounts are split in files of the same size of the other entities
+    let mut accounts: Vec<Account> = accounts.into_values().collect();
+    if deterministic {
+        accounts.sort_by_cached_key(|a| a.order_key());
+    }
+    let chunk_len = (output_size_kb * 1024 / size_of::<Account>()).max(1);
+    for (accounts_file_counter, chunk) in
+        (first_counter("accounts")..).zip(accounts.chunks(chunk_len))
+    {
+        let mut chunk = chunk.to_vec();
+        let o = OutputLayout::file_path(
+            output_path,
+            &layout.accounts,
+            "accounts",
+            accounts_file_counter,
+        );
+        flushes
+            .spawn(move || {
+                flush(
+                    &mut chunk,
+                    o.as_str(),
+                    compression_level,
+                    deterministic,
+                    validate,
+                )
+            })
+            .await;
+    }
+
+    let mut gas_usages: Vec<ContractGasUsage> = gas_usages.into_values().collect();
+    if deterministic {
+        gas_usages.sort_by_cached_key(|u| u.order_key());
+    }
+    let chunk_len = (output_size_kb * 1024 / size_of::<ContractGasUsage>()).max(1);
+    for (gas_usage_file_counter, chunk) in
+        (first_counter("gas_usage")..).zip(gas_usages.chunks(chunk_len))
+    {
+        let mut chunk = chunk.to_vec();
+        let o = OutputLayout::file_path(
+            output_path,
+            &layout.gas_usage,
+            "gas_usage",
+            gas_usage_file_counter,
+        );
+        flushes
+            .spawn(move || {
+                flush(
+                    &mut chunk,
+                    o.as_str(),
+                    compression_level,
+                    deterministic,
+                    validate,
+                )
+            })
+            .await;
+    }
 
-    for jh in handles {
-        let _ = jh.await;
+    let mut flushed = flushes.join().await;
+    if let Some(csv) = transfers_csv {
+        match csv.finish() {
+            Ok(file) => flushed.push(file),
+            Err(e) => {
+                let _ = error_log.send(ExtractionError::new(None, ErrorStage::Writer, e));
+            }
+        }
     }
 
     let elapsed = now.elapsed();
 
-    println!("Flushing took: {}s", elapsed.as_secs());
+    info!("Flushing took: {}s", elapsed.as_secs());
+
+    if checksums {
+        let o = output_path.to_string();
+        let files = flushed.len();
+        let res = tokio::task::spawn_blocking(move || write_checksums(&o, flushed))
+            .await
+            .unwrap();
+        match res {
+            Ok(_) => info!("Checksums of {} files written to SHA256SUMS", files),
+            Err(e) => {
+                let _ = error_log.send(ExtractionError::new(None, ErrorStage::Writer, e));
+            }
+        }
+    }
+
+    info!("Writer task finished");
+    UnextractedBlocks {
+        failed: failed_blocks,
+        skipped: skipped_blocks,
+    }
+}
+
+/// Output path writing the entities to stdout, see ndjson_writer_task
+pub fn is_stdout(output_path: &str) -> bool {
+    output_path == "-"
+}
+
+/// One NDJSON line of an entity, `{"type": <kind>, "data": <Dgraph JSON>}`
+fn ndjson_line<T: SerializeDgraph>(kind: &str, item: &T) -> Result<Vec<u8>, String> {
+    let data = item
+        .serialize_dgraph(serde_json::value::Serializer)
+        .map_err(|e| format!("could not serialize {}: {}", kind, e))?;
+    let mut line = serde_json::to_vec(&serde_json::json!({ "type": kind, "data": data }))
+        .map_err(|e| format!("could not serialize {}: {}", kind, e))?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Writer of `--output-path -`, every entity is written to `out` as soon as it's received, one
+/// NDJSON line tagged with its type. ABI nodes and creation codes are written once, like the
+/// files of writer_task, accounts and gas usages are merged and written at the end.
+/// Entities are written in the order they're received, which is the block order only with one task
+pub async fn ndjson_writer_task<W: Write>(
+    mut out: W,
+    mut receiver: Receiver<WriteCommand>,
+    error_log: UnboundedSender<ExtractionError>,
+) -> W {
+    let mut stored_signatures: HashSet<H256> = HashSet::new();
+    let mut stored_creation_codes: HashSet<H256> = HashSet::new();
+    let mut accounts: HashMap<Address, Account> = HashMap::new();
+    let mut gas_usages: HashMap<Address, ContractGasUsage> = HashMap::new();
+    // once the output is closed, e.g. by the reader of the pipe, the entities are dropped
+    let mut closed = false;
+
+    let mut write = |out: &mut W, line: Result<Vec<u8>, String>| {
+        if closed {
+            return;
+        }
+        if let Err(e) = line.and_then(|line| out.write_all(&line).map_err(|e| e.to_string())) {
+            closed = true;
+            let _ = error_log.send(ExtractionError::new(
+                None,
+                ErrorStage::Writer,
+                format!("could not write to stdout: {}", e),
+            ));
+        }
+    };
+
+    while let Some(command) = receiver.recv().await {
+        match command {
+            WriteCommand::Block(block) => write(&mut out, ndjson_line("block", &block)),
+            WriteCommand::Transfer(transfer) => write(&mut out, ndjson_line("transfer", &transfer)),
+            WriteCommand::Transaction(tx) => write(&mut out, ndjson_line("transaction", &tx)),
+            WriteCommand::ContractDeployment(deployment) => {
+                if deployment.hashes_creation_code()
+                    && stored_creation_codes.insert(deployment.creation_code_hash())
+                {
+                    let creation_code = CreationCode::new(deployment.creation_code().clone());
+                    write(&mut out, ndjson_line("creation_code", &creation_code));
+                }
+                write(&mut out, ndjson_line("deployment", &deployment));
+            }
+            WriteCommand::ContractDestruction(destruction) => {
+                write(&mut out, ndjson_line("destruction", &destruction))
+            }
+            WriteCommand::Skeleton(skeleton) => {
+                write(&mut out, ndjson_line("skeleton", &skeleton));
+                if let Some(abi) = skeleton.get_abi() {
+                    for node in &abi.nodes {
+                        if !stored_signatures.insert(node.get_signature_hash()) {
+                            continue;
+                        }
+                        let line = match node {
+                            ABIStructure::Event(event) => ndjson_line("event", event),
+                            ABIStructure::Error(error) => ndjson_line("error", error),
+                            ABIStructure::Function(function) => ndjson_line("function", function),
+                        };
+                        write(&mut out, line);
+                    }
+                }
+            }
+            WriteCommand::Log(log) => write(&mut out, ndjson_line("log", &log)),
+            WriteCommand::InternalTx(internal_tx) => {
+                write(&mut out, ndjson_line("internal_tx", &internal_tx))
+            }
+            WriteCommand::StateChange(state_change) => {
+                write(&mut out, ndjson_line("state_change", &state_change))
+            }
+            WriteCommand::Account(account) => {
+                accounts
+                    .entry(account.address())
+                    .and_modify(|a| a.merge(&account))
+                    .or_insert(account);
+            }
+            WriteCommand::GasUsage(usage) => {
+                gas_usages
+                    .entry(usage.address())
+                    .and_modify(|u| u.merge(&usage))
+                    .or_insert(usage);
+            }
+            WriteCommand::FailedBlock(block) => {
+                eprintln!("ERROR: block {} failed", block);
+            }
+            WriteCommand::SkippedBlock(block) => {
+                eprintln!(
+                    "WARNING: block {} skipped, over --max-block-size-bytes",
+                    block
+                );
+            }
+            WriteCommand::BlockComplete(_) => {
+                // the reader gets whole blocks as soon as they're ready, a closed output
+                // fails the next write
+                let _ = out.flush();
+            }
+        }
+    }
+
+    let mut accounts = accounts.into_values().collect::<Vec<_>>();
+    accounts.sort_by_cached_key(|a| a.order_key());
+    for account in &accounts {
+        write(&mut out, ndjson_line("account", account));
+    }
+    let mut gas_usages = gas_usages.into_values().collect::<Vec<_>>();
+    gas_usages.sort_by_cached_key(|u| u.order_key());
+    for usage in &gas_usages {
+        write(&mut out, ndjson_line("gas_usage", usage));
+    }
+    let _ = out.flush();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        flush, flush_content_addressed, ndjson_writer_task, validate_output, writer_task,
+        FlushPool, FlushedFile, WriteCommand, WriterConfig,
+    };
+    use crate::extraction::errors::ExtractionError;
+    use crate::extraction::layout::OutputLayout;
+    use crate::models::account::Account;
+    use crate::models::block::Block;
+    use crate::models::contract_deployment::ContractDeployment;
+    use crate::models::skeleton::Skeleton;
+    use crate::models::transfer::{TokenTransfer, TokenType};
+    use ethers::types::{
+        Action, ActionType, Address, Bytes, Create, CreateResult, Res, Trace, TxHash, U256, U64,
+    };
+    use ethers::utils::keccak256;
+    use sha2::{Digest, Sha256};
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn transfer(block: u64, value: u64) -> TokenTransfer {
+        let mut transfer = TokenTransfer::new(
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(3),
+            U256::from(value),
+            U64::from(block),
+            TxHash::from_low_u64_be(block),
+            TokenType::ERC20,
+        );
+        transfer.set_timestamp(block * 12);
+        transfer
+    }
+
+    #[test]
+    fn test_deterministic_flush() {
+        let dir = std::env::temp_dir();
+        let first = dir.join("eth2dgraph_deterministic_0.json.gz");
+        let second = dir.join("eth2dgraph_deterministic_1.json.gz");
+
+        let mut a = vec![transfer(2, 1), transfer(1, 2), transfer(2, 0)];
+        let mut b = vec![transfer(2, 0), transfer(2, 1), transfer(1, 2)];
+
+        flush(&mut a, first.to_str().unwrap(), 6, true, false).unwrap();
+        flush(&mut b, second.to_str().unwrap(), 6, true, false).unwrap();
+
+        let first_bytes = std::fs::read(&first).unwrap();
+        let second_bytes = std::fs::read(&second).unwrap();
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+
+        assert_eq!(first_bytes, second_bytes);
+    }
+
+    fn read_json_gz(path: &std::path::Path) -> Vec<serde_json::Value> {
+        let mut json = Vec::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap())
+            .read_to_end(&mut json)
+            .unwrap();
+        serde_json::from_slice(&json).unwrap()
+    }
+
+    /// Config of a writer test in `dir`: default layout, 1 MB files, sorted entities
+    fn test_config(dir: &Path) -> WriterConfig {
+        WriterConfig {
+            output_path: dir.to_str().unwrap().to_string(),
+            output_size_kb: 1024,
+            compression_level: 6,
+            deterministic: true,
+            flush_concurrency: 2,
+            ..Default::default()
+        }
+    }
+
+    /// Run `writer_task` until all the commands are written, creating the folders of the
+    /// layout first. Returns the errors it logged
+    async fn run_writer(
+        config: WriterConfig,
+        commands: Vec<WriteCommand>,
+        written: Option<Arc<AtomicU64>>,
+    ) -> Vec<ExtractionError> {
+        for folder in config.layout.dirs() {
+            std::fs::create_dir_all(Path::new(&config.output_path).join(folder)).unwrap();
+        }
+        let (writer, receiver) = tokio::sync::mpsc::channel(10);
+        let (error_log, mut errors) = tokio::sync::mpsc::unbounded_channel();
+        let handle = tokio::spawn(writer_task(config, receiver, written, error_log));
+        for command in commands {
+            writer.send(command).await.unwrap();
+        }
+        drop(writer);
+        handle.await.unwrap();
+        let mut logged = Vec::new();
+        while let Ok(error) = errors.try_recv() {
+            logged.push(error);
+        }
+        logged
+    }
+
+    #[tokio::test]
+    async fn test_hash_creation_code() {
+        let deployment = |tx: u64, init: &'static [u8]| {
+            let trace = Trace {
+                action: Action::Create(Create {
+                    init: Bytes::from_static(init),
+                    ..Default::default()
+                }),
+                result: Some(Res::Create(CreateResult {
+                    address: Address::from_low_u64_be(tx),
+                    ..Default::default()
+                })),
+                trace_address: vec![],
+                subtraces: 0,
+                transaction_position: Some(0),
+                transaction_hash: Some(TxHash::from_low_u64_be(tx)),
+                block_number: 1,
+                block_hash: Default::default(),
+                action_type: ActionType::Create,
+                error: None,
+            };
+            let mut deployment = ContractDeployment::try_from(trace).unwrap();
+            deployment.set_hash_creation_code(true);
+            deployment
+        };
+
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_creation_{}", std::process::id()));
+        // two deployments share the same init code
+        let commands = [
+            (1, &[0x60u8, 0x80][..]),
+            (2, &[0x60, 0x80]),
+            (3, &[0x60, 0x40]),
+        ]
+        .into_iter()
+        .map(|(tx, init)| WriteCommand::ContractDeployment(deployment(tx, init)))
+        .collect();
+        run_writer(test_config(&dir), commands, None).await;
+
+        let deployments = read_json_gz(&dir.join("static/deployments/deployments_0.json.gz"));
+        let creation_codes =
+            read_json_gz(&dir.join("static/creation_codes/creation_codes_0.json.gz"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let hash = format!("{:?}", ethers::types::H256::from(keccak256([0x60, 0x80])));
+        assert_eq!(deployments.len(), 3);
+        assert_eq!(
+            deployments[0]["ContractDeployment.creation_code_hash"],
+            hash
+        );
+        assert_eq!(
+            deployments[1]["ContractDeployment.creation_code_hash"],
+            hash
+        );
+        assert!(deployments[0]
+            .get("ContractDeployment.creation_bytecode")
+            .is_none());
+        assert_eq!(creation_codes.len(), 2);
+        assert!(creation_codes
+            .iter()
+            .any(|c| c["CreationCode.hash"] == hash && c["CreationCode.bytecode"] == "0x6080"));
+    }
+
+    #[tokio::test]
+    async fn test_content_addressed_skeletons() {
+        let skeleton = |code: &'static [u8]| Skeleton::new(Bytes::from_static(code));
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_cas_{}", std::process::id()));
+        let output = dir.to_str().unwrap().to_string();
+        let layout = OutputLayout::default();
+
+        // two workers extracting different ranges find the same skeleton
+        for codes in [
+            [&[0x60u8, 0x80][..], &[0x60, 0x40]],
+            [&[0x60, 0x80], &[0x60, 0x20]],
+        ] {
+            let config = WriterConfig {
+                content_addressed_skeletons: true,
+                ..test_config(&dir)
+            };
+            let commands = codes
+                .into_iter()
+                .map(|code| WriteCommand::Skeleton(skeleton(code)))
+                .collect();
+            run_writer(config, commands, None).await;
+        }
+
+        let shared = skeleton(&[0x60, 0x80]);
+        let path = OutputLayout::content_addressed_path(&output, &layout.skeletons, shared.hash());
+        let hash = format!("{:x}", shared.hash());
+        assert!(path.ends_with(&format!("static/skeletons/{}/{}.json.gz", &hash[..2], hash)));
+        let written = read_json_gz(std::path::Path::new(&path));
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0]["Skeleton.bytecode"], "0x6080");
+
+        // 3 skeletons, no chunked file and no leftover temporary file
+        let files = glob::glob(&format!("{}/static/skeletons/**/*", output))
+            .unwrap()
+            .map(|p| p.unwrap())
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(files.len(), 3, "{:?}", files);
+        assert!(files
+            .iter()
+            .all(|f| f.to_str().unwrap().ends_with(".json.gz")
+                && !f.to_str().unwrap().contains("skeletons_")));
+    }
+
+    #[test]
+    fn test_concurrent_content_addressed_flush() {
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_cas_flush_{}", std::process::id()));
+        let path = dir.join("ab/abcd.json.gz").to_str().unwrap().to_string();
+        let writers = (0..8)
+            .map(|_| {
+                let p = path.clone();
+                std::thread::spawn(move || {
+                    flush_content_addressed(&mut vec![transfer(1, 1)], &p, 6, true)
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        assert!(validate_output(&path).is_ok());
+        let files = std::fs::read_dir(dir.join("ab")).unwrap().count();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(files, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_concurrency() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (error_log, mut errors) = tokio::sync::mpsc::unbounded_channel();
+        let mut flushes = FlushPool::new(2, error_log);
+        for i in 0..8 {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            flushes
+                .spawn(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    if i == 3 {
+                        Err("disk full".to_string())
+                    } else {
+                        Ok(FlushedFile::new("", &[]))
+                    }
+                })
+                .await;
+        }
+        flushes.join().await;
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert!(format!("{:?}", errors.recv().await.unwrap()).contains("disk full"));
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_written_bytes() {
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_written_{}", std::process::id()));
+        let layout = OutputLayout::default();
+        let written = Arc::new(AtomicU64::new(0));
+        let config = WriterConfig {
+            output_size_kb: 0,
+            ..test_config(&dir)
+        };
+        let blocks = (0..20u64)
+            .map(|number| {
+                WriteCommand::Block(Block::from(ethers::types::Block {
+                    number: Some(number.into()),
+                    ..Default::default()
+                }))
+            })
+            .collect();
+        run_writer(config, blocks, Some(written.clone())).await;
+
+        // every file is counted as it's written, the last ones of the other entities too
+        let blocks_files = std::fs::read_dir(dir.join(&layout.blocks)).unwrap().count();
+        let size = layout
+            .dirs()
+            .into_iter()
+            .flat_map(|folder| std::fs::read_dir(dir.join(folder)).unwrap())
+            .map(|f| f.unwrap().metadata().unwrap().len())
+            .sum::<u64>();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(blocks_files > 1);
+        assert_eq!(written.load(Ordering::SeqCst), size);
+    }
+
+    #[tokio::test]
+    async fn test_transfers_csv() {
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_csv_{}", std::process::id()));
+        let mut erc1155 = TokenTransfer::new(
+            Address::from_low_u64_be(4),
+            Address::zero(),
+            Address::from_low_u64_be(3),
+            U256::from(5),
+            U64::from(2),
+            TxHash::from_low_u64_be(2),
+            TokenType::ERC1155,
+        );
+        erc1155.set_timestamp(24);
+        erc1155.set_token_id(U256::from(42));
+        let config = WriterConfig {
+            checksums: true,
+            transfers_csv: true,
+            ..test_config(&dir)
+        };
+        let commands = vec![
+            WriteCommand::Transfer(transfer(1, 1000)),
+            WriteCommand::Transfer(erc1155),
+        ];
+        let errors = run_writer(config, commands, None).await;
+
+        let csv = std::fs::read_to_string(dir.join("transfers.csv")).unwrap();
+        let sums = std::fs::read_to_string(dir.join("SHA256SUMS")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "contract,from,to,value,token_id,token_type,block,tx_hash,datetime",
+                "0x0000000000000000000000000000000000000001,\
+                 0x0000000000000000000000000000000000000002,\
+                 0x0000000000000000000000000000000000000003,1000,,erc20,1,\
+                 0x0000000000000000000000000000000000000000000000000000000000000001,\
+                 1970-01-01T00:00:12+00:00",
+                "0x0000000000000000000000000000000000000004,\
+                 0x0000000000000000000000000000000000000000,\
+                 0x0000000000000000000000000000000000000003,5,42,erc1155,2,\
+                 0x0000000000000000000000000000000000000000000000000000000000000002,\
+                 1970-01-01T00:00:24+00:00",
+            ]
+        );
+        // the CSV is listed in the checksums too
+        let sum = hex::encode(Sha256::digest(csv.as_bytes()));
+        assert!(
+            sums.contains(&format!("{}  transfers.csv", sum)),
+            "{}",
+            sums
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checksums() {
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_sums_{}", std::process::id()));
+        let config = WriterConfig {
+            checksums: true,
+            ..test_config(&dir)
+        };
+        let commands = (0..3)
+            .map(|i| WriteCommand::Transfer(transfer(i, i)))
+            .collect();
+        let errors = run_writer(config, commands, None).await;
+
+        let sums = std::fs::read_to_string(dir.join("SHA256SUMS")).unwrap();
+        let checked = sums
+            .lines()
+            .map(|line| {
+                let (sum, path) = line.split_once("  ").unwrap();
+                let content = std::fs::read(dir.join(path)).unwrap();
+                (sum.to_string(), hex::encode(Sha256::digest(content)), path)
+            })
+            .collect::<Vec<_>>();
+        let leftovers = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+            .count();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(leftovers, 0);
+        // a file per entity, empty creation codes, accounts and gas usages are not written
+        assert_eq!(checked.len(), 12, "{}", sums);
+        assert!(checked
+            .iter()
+            .any(|(_, _, path)| *path == "dynamic/transfers/transfers_0.json.gz"));
+        for (sum, expected, path) in checked {
+            assert_eq!(sum, expected, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_block() {
+        for flush_on_block in [false, true] {
+            let dir = std::env::temp_dir().join(format!(
+                "eth2dgraph_flush_on_block_{}_{}",
+                flush_on_block,
+                std::process::id()
+            ));
+            // every file is over the size limit once it has a whole block
+            let config = WriterConfig {
+                output_size_kb: 0,
+                flush_on_block,
+                ..test_config(&dir)
+            };
+            let block_len = 1024 / std::mem::size_of::<TokenTransfer>() + 1;
+            // blocks 1 and 2 are extracted concurrently, block 3 never completes
+            let mut commands = (0..block_len as u64)
+                .flat_map(|value| {
+                    [1, 2, 3].map(|block| WriteCommand::Transfer(transfer(block, value)))
+                })
+                .collect::<Vec<_>>();
+            commands.push(WriteCommand::BlockComplete(1));
+            commands.push(WriteCommand::BlockComplete(2));
+            run_writer(config, commands, None).await;
+
+            let mut files = Vec::new();
+            for counter in 0.. {
+                let path = dir.join(format!("dynamic/transfers/transfers_{}.json.gz", counter));
+                if !path.exists() {
+                    break;
+                }
+                let blocks = read_json_gz(&path)
+                    .iter()
+                    .map(|t| {
+                        t["TokenTransfer.block"]["uid"]
+                            .as_str()
+                            .unwrap()
+                            .to_string()
+                    })
+                    .collect::<Vec<_>>();
+                files.push(blocks);
+            }
+            let failed = std::fs::read_to_string(dir.join("failed_blocks.txt")).ok();
+            let _ = std::fs::remove_dir_all(&dir);
+
+            if flush_on_block {
+                // a file per block, and the empty final one
+                assert_eq!(files.len(), 3);
+                assert_eq!(files[0], vec!["_:1"; block_len]);
+                assert_eq!(files[1], vec!["_:2"; block_len]);
+                assert!(files[2].is_empty());
+                assert_eq!(failed.as_deref(), Some("3\n"));
+            } else {
+                // the files are rotated in the middle of the blocks
+                assert!(files.iter().all(|f| f.len() < 3 * block_len));
+                assert!(files
+                    .iter()
+                    .any(|f| f.contains(&"_:1".to_string()) && f.contains(&"_:2".to_string())));
+                assert!(failed.is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_order() {
+        let commands = |blocks: [u64; 3]| {
+            let mut commands = (0..4)
+                .flat_map(|value| {
+                    blocks.map(|block| WriteCommand::Transfer(transfer(block, value)))
+                })
+                .collect::<Vec<_>>();
+            commands.extend(blocks.map(WriteCommand::BlockComplete));
+            commands
+        };
+        // the same blocks extracted in a different order give the same files
+        let mut outputs = Vec::new();
+        for (run, blocks) in [[1, 2, 3], [3, 1, 2]].into_iter().enumerate() {
+            let dir = std::env::temp_dir().join(format!(
+                "eth2dgraph_deterministic_order_{}_{}",
+                run,
+                std::process::id()
+            ));
+            run_writer(test_config(&dir), commands(blocks), None).await;
+            let transfers = std::fs::read(dir.join("dynamic/transfers/transfers_0.json.gz"));
+            let _ = std::fs::remove_dir_all(&dir);
+            outputs.push(transfers.unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[tokio::test]
+    async fn test_skipped_blocks() {
+        // skipped blocks are listed for a separate run
+        let dir = std::env::temp_dir().join(format!("eth2dgraph_skipped_{}", std::process::id()));
+        let commands = vec![WriteCommand::SkippedBlock(3), WriteCommand::SkippedBlock(1)];
+        run_writer(test_config(&dir), commands, None).await;
+        let skipped = std::fs::read_to_string(dir.join("skipped_blocks.txt")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(skipped, "1\n3\n");
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_block_lists() {
+        // the lists of failed and skipped blocks can't be written, so they're logged instead
+        let dir =
+            std::env::temp_dir().join(format!("eth2dgraph_unwritable_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("failed_blocks.txt")).unwrap();
+        std::fs::create_dir_all(dir.join("skipped_blocks.txt")).unwrap();
+        let commands = vec![
+            WriteCommand::FailedBlock(7),
+            WriteCommand::FailedBlock(5),
+            WriteCommand::SkippedBlock(9),
+        ];
+        let errors = run_writer(test_config(&dir), commands, None).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        let errors = format!("{:?}", errors);
+        assert!(errors.contains("failed_blocks.txt"), "{}", errors);
+        assert!(errors.contains("blocks: 5,7"), "{}", errors);
+        assert!(errors.contains("skipped_blocks.txt"), "{}", errors);
+        assert!(errors.contains("blocks: 9"), "{}", errors);
+    }
+
+    #[test]
+    fn test_validate_output() {
+        let dir = std::env::temp_dir();
+        let valid = dir.join("eth2dgraph_validate_0.json.gz");
+        let invalid = dir.join("eth2dgraph_validate_1.json.gz");
+
+        flush(
+            &mut vec![transfer(1, 1), transfer(2, 2)],
+            valid.to_str().unwrap(),
+            6,
+            false,
+            true,
+        )
+        .unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&invalid).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder
+            .write_all(br#"[{"name": "unescaped "quote""}]"#)
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let valid_result = validate_output(valid.to_str().unwrap());
+        let invalid_result = validate_output(invalid.to_str().unwrap());
+        let _ = std::fs::remove_file(&valid);
+        let _ = std::fs::remove_file(&invalid);
 
-    println!("Writer task finished");
+        assert!(valid_result.is_ok());
+        assert!(invalid_result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_stdout() {
+        let (writer, receiver) = tokio::sync::mpsc::channel(10);
+        let (error_log, mut errors) = tokio::sync::mpsc::unbounded_channel();
+        let handle =
+            tokio::spawn(async move { ndjson_writer_task(Vec::new(), receiver, error_log).await });
+        let block = Block::from(ethers::types::Block {
+            number: Some(1.into()),
+            ..Default::default()
+        });
+        let commands = [
+            WriteCommand::Block(block),
+            WriteCommand::Transfer(transfer(1, 1000)),
+            WriteCommand::Account(Account::new(Address::from_low_u64_be(2), 1, false)),
+            WriteCommand::Account(Account::new(Address::from_low_u64_be(2), 1, false)),
+            WriteCommand::BlockComplete(1),
+        ];
+        for command in commands {
+            writer.send(command).await.unwrap();
+        }
+        drop(writer);
+        let out = handle.await.unwrap();
+
+        assert!(errors.try_recv().is_err());
+        let lines = out
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let types = lines
+            .iter()
+            .map(|line| line["type"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        // the accounts are merged and written once, at the end
+        assert_eq!(types, vec!["block", "transfer", "account"]);
+        assert_eq!(lines[0]["data"]["Block.number"], 1);
+        assert_eq!(lines[1]["data"]["dgraph.type"], "TokenTransfer");
+    }
 }
@@ -1,18 +1,60 @@
 mod analysys;
+mod error;
 mod extraction;
 mod models;
 mod utils;
 
 use crate::analysys::lifetimes::analyse_lifetimes;
-use crate::analysys::similarities::find_similar_skeletons;
-use clap::{Args, Parser, Subcommand};
-use extraction::{extract::run_extraction, stream::run_stream_extraction};
+use crate::analysys::signatures::export_signatures;
+use crate::analysys::similarities::{find_similar_skeletons, SimilarityOptions, SimilaritySink};
+use crate::analysys::similarity_eval::evaluate_similarities;
+use crate::analysys::verify::verify_graph;
+use chrono::{DateTime, Utc};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use error::Error;
+use ethers::types::{Address, TxHash};
+use extraction::{
+    bench::run_bench,
+    blocks::parse_rfc3339,
+    extract::{run_extraction, RetryPolicy},
+    layout::{load_output_layout, OutputLayout},
+    load::{run_load, Loader},
+    logs::{load_transfer_signatures, ContractScope, TransferSignatures},
+    merge::merge_outputs,
+    skeleton_set::load_skeleton_set,
+    stream::{run_backfill, run_stream_extraction},
+    trace_tx::run_trace_tx,
+    traces::TraceMethod,
+    writer::is_stdout,
+};
+use primitive_types::H256;
+use std::collections::HashSet;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use utils::{
+    addresses::parse_address_list,
+    config::expand_config,
+    queries::print_sample_queries,
+    schema::check_schema,
+    verbosity::{set_verbosity, Verbosity},
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print the queries sent to Dgraph
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// TOML file with the flags of extract or stream, e.g. `num_tasks = 10` or `include_tx = true`.
+    /// The flags given on the command line override the ones of the file, and the ones conflicting
+    /// with them are ignored. `--no-<flag>` turns off a boolean flag of the file
+    #[arg(long, global = true)]
+    config: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -21,66 +63,225 @@ enum Commands {
     Extract(ExtractArgs),
     /// Stream data from node to Dgraph
     Stream(StreamDgraphArgs),
+    /// Re-process a range of blocks in Dgraph, replacing their data like the stream does
+    Backfill(BackfillArgs),
     /// Analyse smart contracts
     Analyse(AnalyseArgs),
+    /// Print sample DQL queries for the data produced by eth2dgraph
+    Queries(QueriesArgs),
+    /// Merge the output folders of several extractions, writing the shared entities only once
+    Merge(MergeArgs),
+    /// Print the contract deployments and destructions built from the traces of a transaction
+    TraceTx(TraceTxArgs),
+    /// Load an extracted output folder into Dgraph with the bulk or live loader
+    Load(LoadArgs),
+    /// Measure the extraction throughput with different numbers of tasks
+    #[command(hide = true)]
+    Bench(BenchArgs),
 }
 
+/// Entities to store, shared by all the commands writing blocks data
 #[derive(Debug, Args, Clone)]
-#[command(args_conflicts_with_subcommands = true)]
+pub struct IncludeArgs {
+    /// Include transactions
+    #[arg(long, default_value_t = false)]
+    include_tx: bool,
+    /// Include token transfers. WETH deposits and withdrawals are decoded only for the mainnet WETH9
+    /// contract, on other chains they're not extracted
+    #[arg(long, alias = "include-tokens", default_value_t = false)]
+    include_transfers: bool,
+    /// Include all logs
+    #[arg(long, default_value_t = false)]
+    include_logs: bool,
+    /// Include internal transactions, i.e. the calls performed by contracts.
+    /// Blocks usually have several internal calls per transaction, so this
+    /// output can grow larger than the transactions one
+    #[arg(long, default_value_t = false)]
+    include_internal_tx: bool,
+    /// Include accounts, with the first and last block they were seen in and whether they're contracts.
+    /// When extracting, accounts are kept in memory until the end of the extraction
+    #[arg(long, default_value_t = false)]
+    include_accounts: bool,
+    /// Only store token transfers of these contracts, file or comma separated list of addresses
+    #[arg(long, value_parser = parse_address_list)]
+    transfer_contracts: Option<HashSet<Address>>,
+    /// JSON file with the layouts of non-standard transfer events, by topic 0
+    #[arg(long, value_parser = load_transfer_signatures)]
+    transfer_signatures: Option<TransferSignatures>,
+    /// Skip the contract deployments whose creation reverted
+    #[arg(long, default_value_t = false)]
+    skip_failed_deployments: bool,
+    /// Resolve the name of the deployed contracts by calling their `name()` method.
+    /// It costs an extra eth_call per deployment, so it's off by default
+    #[arg(long, default_value_t = false)]
+    resolve_names: bool,
+    /// Resolve the ERC-165 interfaces supported by the deployed contracts (ERC-721, ERC-1155, ...)
+    /// by calling their `supportsInterface(bytes4)` method. It costs a few eth_calls per deployment
+    #[arg(long, default_value_t = false)]
+    resolve_interfaces: bool,
+    /// Store the gas used by contract creations, calls, plain transfers and reverted transactions
+    /// in Block.gas_*, from the transaction receipts. It costs an eth_getBlockReceipts call per block
+    #[arg(long, default_value_t = false)]
+    gas_breakdown: bool,
+}
+
+#[derive(Debug, Args, Clone)]
+#[command(args_conflicts_with_subcommands = true, args_override_self = true)]
 pub struct StreamDgraphArgs {
     /// Ethereum node to connect to, with websocket scheme
     #[arg(short, long, default_value = "ws://localhost:8545")]
     endpoint: String,
+    /// RPC method used to trace blocks: parity (trace_block) or geth (debug_traceBlockByNumber)
+    #[arg(long, value_enum, default_value_t = TraceMethod::Parity)]
+    trace_method: TraceMethod,
     /// Dgraph GRPC endpoint
     #[arg(short, long, default_value = "http://localhost:9080")]
     dgraph: String,
-    /// Include transactions
-    #[arg(long, default_value_t = false)]
-    include_tx: bool,
-    /// Include token transfers
-    #[arg(long, default_value_t = false)]
-    include_tokens: bool,
-    /// Include logs
-    #[arg(long, default_value_t = false)]
-    include_logs: bool,
+    #[command(flatten)]
+    include: IncludeArgs,
     /// Decompiler timeout in milliseconds
     #[arg(long, default_value_t = 5000)]
     decompiler_timeout: u64,
     /// Skip syncronization from last indexed block in Dgraph, just get live blocks
     #[arg(long, default_value_t = false)]
     no_sync: bool,
+    /// Block to start the syncronization from, if Dgraph has no blocks yet.
+    /// If not set, an empty Dgraph is filled starting from the chain head
+    #[arg(long)]
+    start_block: Option<u64>,
     /// Number of Tokio tasks run in parallel
     #[arg(short, long, default_value = "1")]
     num_jobs: usize,
+    /// Exit if the Dgraph schema misses predicates or indexes, instead of printing a warning
+    #[arg(long, default_value_t = false)]
+    strict_schema: bool,
+    /// Only process a block once N blocks are mined on top of it, to not store blocks dropped by reorgs.
+    /// The sync also stops N blocks before the head
+    #[arg(long, default_value_t = 0)]
+    confirmations: u64,
+    /// Also stream the pending transactions of the node mempool, stored with `Transaction.pending`
+    /// until they're mined. Busy nodes see thousands of them per minute, each costing an RPC call
+    /// and a Dgraph upsert
+    #[arg(long, default_value_t = false, requires = "include_tx")]
+    include_pending: bool,
+    /// Skip deleting the data already stored for each block during the sync, for initial syncs into
+    /// an empty Dgraph. Blocks already stored, e.g. by an interrupted sync, get duplicated data.
+    /// Deletes are back on once the stream is live
+    #[arg(long, default_value_t = false, conflicts_with = "no_sync")]
+    assume_empty: bool,
+    /// EIP-1967 proxies whose implementation is checked at every block, a file or a comma separated
+    /// list of addresses. A change from the implementation at the end of the previous block is stored
+    /// as a `ProxyUpgrade`
+    #[arg(long, value_parser = parse_address_list)]
+    proxy_watchlist: Option<HashSet<Address>>,
 }
 
 #[derive(Debug, Args)]
 #[command(args_conflicts_with_subcommands = true)]
+pub struct BackfillArgs {
+    /// RPC endpoint to connect to
+    #[arg(short, long, default_value = "http://localhost:8545")]
+    endpoint: String,
+    /// RPC method used to trace blocks: parity (trace_block) or geth (debug_traceBlockByNumber)
+    #[arg(long, value_enum, default_value_t = TraceMethod::Parity)]
+    trace_method: TraceMethod,
+    /// Dgraph GRPC endpoint
+    #[arg(short, long, default_value = "http://localhost:9080")]
+    dgraph: String,
+    /// From block
+    #[arg(short, long, required_unless_present = "block")]
+    from_block: Option<u64>,
+    /// To block, included
+    #[arg(short, long, required_unless_present = "block")]
+    to_block: Option<u64>,
+    /// Single block, same as --from-block N --to-block N
+    #[arg(long, conflicts_with_all = ["from_block", "to_block"])]
+    block: Option<u64>,
+    #[command(flatten)]
+    include: IncludeArgs,
+    /// Decompiler timeout in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    decompiler_timeout: u64,
+    /// Number of Tokio tasks run in parallel
+    #[arg(short, long, default_value = "1")]
+    num_jobs: usize,
+    /// Exit if the Dgraph schema misses predicates or indexes, instead of printing a warning
+    #[arg(long, default_value_t = false)]
+    strict_schema: bool,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true, args_override_self = true)]
 pub struct ExtractArgs {
     /// RPC endpoint to connect to
     #[arg(short, long, default_value = "http://localhost:8545")]
     endpoint: String,
-    /// Output path
+    /// RPC method used to trace blocks: parity (trace_block) or geth (debug_traceBlockByNumber)
+    #[arg(long, value_enum, default_value_t = TraceMethod::Parity)]
+    trace_method: TraceMethod,
+    /// Output path, or s3://bucket/prefix to upload the files (requires the s3 feature),
+    /// or - to write the entities to stdout as NDJSON, one {"type", "data"} object per line
     #[arg(short, long, default_value = "./extracted")]
     output_path: String,
     /// From block
-    #[arg(short, long)]
-    from_block: u64,
+    #[arg(short, long, required_unless_present_any = ["from_date", "block", "since"])]
+    from_block: Option<u64>,
     /// To block
-    #[arg(short, long)]
-    to_block: u64,
+    #[arg(short, long, required_unless_present_any = ["to_date", "block", "since"])]
+    to_block: Option<u64>,
+    /// Single block, same as --from-block N --to-block N
+    #[arg(long, conflicts_with_all = ["from_block", "to_block", "from_date", "to_date"])]
+    block: Option<u64>,
+    /// manifest.json of a previous extraction: extract from the block after its last one, up to
+    /// --to-block or --to-date or the latest block, continuing the counters of its files
+    #[arg(long, conflicts_with_all = ["from_block", "from_date", "block"])]
+    since: Option<String>,
+    /// Start from the first block mined at or after this RFC3339 date, e.g. 2023-01-01T00:00:00Z
+    #[arg(long, conflicts_with = "from_block", value_parser = parse_rfc3339)]
+    from_date: Option<DateTime<Utc>>,
+    /// Stop at the last block mined at or before this RFC3339 date
+    #[arg(long, conflicts_with = "to_block", value_parser = parse_rfc3339)]
+    to_date: Option<DateTime<Utc>>,
     /// Number of Tokio tasks ran in parallel
     #[arg(short, long, default_value = "0")]
     num_tasks: usize,
-    /// Include transactions
-    #[arg(long, default_value_t = false)]
-    include_tx: bool,
-    /// Include token transfers
-    #[arg(long, default_value_t = false)]
-    include_transfers: bool,
-    /// Include all logs
-    #[arg(long, default_value_t = false)]
-    include_logs: bool,
+    /// Max number of blocks fetched and not yet handed to the writer, defaults to the number of tasks.
+    /// Only <num_tasks> blocks are fetched at a time, the others wait for decompilation or for the writer,
+    /// which buffers up to 10000 more entities before blocking them
+    #[arg(long)]
+    max_blocks_in_flight: Option<usize>,
+    #[command(flatten)]
+    include: IncludeArgs,
+    /// Only keep logs and token transfers emitted by contracts deployed in the extracted range.
+    /// `block` keeps the events emitted in the same block as the deployment, `range` also keeps
+    /// those of contracts deployed in earlier blocks of the range, as long as those blocks were
+    /// processed first: with more than one task it's best effort, use `-n 1` to make it exact
+    #[arg(long, value_enum)]
+    only_contracts: Option<ContractScope>,
+    /// Preset for the lifetimes analysis: only blocks, deployments, destructions and the skeletons of
+    /// the deployments are extracted, without decompiling them (implies --skip-decompilation).
+    /// The other entities and the options costing extra RPC calls can't be enabled
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = [
+            "include_tx",
+            "include_transfers",
+            "include_logs",
+            "include_internal_tx",
+            "include_accounts",
+            "transfer_contracts",
+            "transfer_signatures",
+            "resolve_names",
+            "resolve_interfaces",
+            "only_contracts",
+            "store_decompiled_source",
+            "aggregate_gas",
+            "include_state_diffs",
+            "gas_breakdown",
+        ]
+    )]
+    lifetimes_only: bool,
     /// smart-contract-sanctuary-ethereum root path
     #[arg(short, long)]
     scs_path: Option<String>,
@@ -93,9 +294,244 @@ pub struct ExtractArgs {
     /// Decompiler timeout in milliseconds
     #[arg(long, default_value_t = 5000)]
     decompiler_timeout: u64,
+    /// Scale the decompiler timeout with the number of decompilations running in parallel,
+    /// up to this value in milliseconds. By default the timeout is fixed
+    #[arg(long)]
+    max_decompiler_timeout: Option<u64>,
     /// Skip the extraction of the ABI with heimdall
     #[arg(long, default_value_t = false)]
     skip_decompilation: bool,
+    /// Also store the Solidity-like source produced by heimdall as Skeleton.decompiled_source.
+    /// It's often much larger than the ABI, so it's off by default
+    #[arg(long, default_value_t = false, conflicts_with = "skip_decompilation")]
+    store_decompiled_source: bool,
+    /// Keep the heimdall output in <tmp_dir>/<address>/ when a decompilation fails, to debug contracts
+    /// without ABI. By default it's always deleted
+    #[arg(long, default_value_t = false, conflicts_with = "skip_decompilation")]
+    keep_decompiler_output: bool,
+    /// Folder of the heimdall outputs, one subfolder per decompilation deleted when it's done.
+    /// Defaults to eth2dgraph-<pid> in the system temp folder, so runs don't share it
+    #[arg(long)]
+    tmp_dir: Option<PathBuf>,
+    /// Don't decompile skeletons shorter than this number of bytes, storing them with
+    /// Skeleton.skipped_decompilation. 0 to decompile all of them
+    #[arg(long, default_value_t = 0)]
+    min_code_size: usize,
+    /// Don't decompile skeletons whose bytecode has a Shannon entropy (0 to 8 bits per byte)
+    /// lower than this, storing them with Skeleton.skipped_decompilation. 0 to decompile all of them
+    #[arg(long, default_value_t = 0.0)]
+    min_entropy: f64,
+    /// Length of the MinHash signature of the interface stored with each skeleton, used by
+    /// `analyse similarities --minhash-size` instead of loading the whole ABI. 0 to not store it
+    #[arg(long, default_value_t = 64)]
+    minhash_size: usize,
+    /// Minimum number of the 6 ERC-20 functions, `transfer` included, for a skeleton to be
+    /// stored with Skeleton.is_erc20
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=6))]
+    erc20_threshold: u8,
+    /// Minimum number of the 9 ERC-721 functions for a skeleton to be stored with Skeleton.is_erc721
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=9))]
+    erc721_threshold: u8,
+    /// Store the keccak256 hash of the creation code of each deployment instead of the code.
+    /// The code is written once per hash as a CreationCode node, in <output_path>/static/creation_codes
+    #[arg(long, default_value_t = false)]
+    hash_creation_code: bool,
+    /// Sum up the gas used by the transactions sent to each contract over the range, written as
+    /// ContractGasUsage nodes in <output_path>/dynamic/gas_usage at the end of the extraction
+    #[arg(long, default_value_t = false)]
+    aggregate_gas: bool,
+    /// Include the storage slots changed by each transaction as StateChange nodes, replaying the
+    /// blocks with trace_replayBlockTransactions (parity style nodes only). Expensive: it's one more
+    /// heavy RPC call per block, and busy blocks change thousands of slots
+    #[arg(long, default_value_t = false)]
+    include_state_diffs: bool,
+    /// Set ContractDeployment.batch_size on the deployments sharing their skeleton with other
+    /// deployments of the same transaction, e.g. the contracts of airdrop and spam campaigns
+    #[arg(long, default_value_t = false)]
+    detect_batch_deploys: bool,
+    /// Gzipped file with the hashes of the skeletons extracted by a previous run, one per line.
+    /// These skeletons are neither decompiled nor stored again
+    #[arg(long, value_parser = load_skeleton_set)]
+    known_skeletons: Option<HashSet<H256>>,
+    /// Write the hashes of all the skeletons known at the end of the extraction to this gzipped file,
+    /// to be passed as --known-skeletons to the next run
+    #[arg(long)]
+    dump_skeletons: Option<String>,
+    /// Maximum number of skeletons remembered to avoid decompiling them again, unbounded if not set.
+    /// Once exceeded, the least recently seen are forgotten: they're decompiled and written again
+    /// if met later, and they're missing from --dump-skeletons
+    #[arg(long)]
+    max_cached_skeletons: Option<usize>,
+    /// Write each skeleton to its own file, <skeletons folder>/<first 2 hash chars>/<hash>.json.gz,
+    /// so the outputs of extractions of different ranges can be merged without duplicated skeletons
+    #[arg(long, default_value_t = false)]
+    content_addressed_skeletons: bool,
+    /// Max number of output files compressed at the same time, 0 for half of the CPUs.
+    /// When all of them are busy the writer waits, and so do the blocks sent to it
+    #[arg(long, default_value_t = 0)]
+    flush_concurrency: usize,
+    /// Sort entities inside each output file. Which file an entity ends up in still depends on the
+    /// order the blocks are extracted in, so runs give the same files only when each entity fits
+    /// in a single file or with `-n 1`
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+    /// Read back every output file after writing it, reporting the ones that are not valid JSON
+    #[arg(long, default_value_t = false)]
+    validate_output: bool,
+    /// Write a SHA256SUMS file in the output path with the checksum of every output file,
+    /// to verify copies of the output with `sha256sum -c SHA256SUMS`
+    #[arg(long, default_value_t = false)]
+    checksums: bool,
+    /// Rotate the output files of the per-block entities only between whole blocks, so no file
+    /// has part of a block. The entities of the blocks being extracted are kept in memory until
+    /// their block is complete, and files can go over --output-size by up to a block
+    #[arg(long, default_value_t = false)]
+    flush_on_block: bool,
+    /// Also write the token transfers to <output_path>/transfers.csv, a flat CSV with the
+    /// contract, from, to, value, token_id, token_type, block, tx_hash and datetime columns
+    #[arg(long, default_value_t = false, requires = "include_transfers")]
+    transfers_csv: bool,
+    /// Max number of retries of a block on network errors, before it's recorded in <output_path>/failed_blocks.txt
+    #[arg(long, default_value_t = 3)]
+    max_block_retries: u32,
+    /// Skip the blocks whose fetched block, logs, traces and state diffs are over this size in
+    /// JSON, recording them in <output_path>/skipped_blocks.txt to extract them separately.
+    /// Guards against the few blocks with huge traces running the extraction out of memory
+    #[arg(long)]
+    max_block_size_bytes: Option<usize>,
+    /// Stop once the compressed output files add up to this many bytes, e.g. to extract a sample
+    /// of a given size. The blocks already started are completed, so the output goes a bit over it
+    #[arg(long)]
+    max_output_bytes: Option<u64>,
+    /// Extract even if the node doesn't have the data of --from-block, which usually means
+    /// it's a non-archive node and the requested blocks are pruned
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    /// Max number of RPC requests per second, shared by all the tasks.
+    /// Use it with metered providers, to throttle the requests instead of retrying them on 429 errors
+    #[arg(long)]
+    max_rps: Option<NonZeroU32>,
+    /// Max number of retries of a rate limited RPC request (HTTP 429)
+    #[arg(long, default_value_t = 10)]
+    rpc_rate_limit_retries: u32,
+    /// Max number of retries of a timed out RPC request
+    #[arg(long, default_value_t = 5)]
+    rpc_timeout_retries: u32,
+    /// Wait before retrying an RPC request in milliseconds, unless the provider asks for a longer one
+    #[arg(long, default_value_t = 500)]
+    rpc_initial_backoff_ms: u64,
+    /// TOML file with the folder of each entity's files, relative to the output path,
+    /// e.g. `blocks = "raw/blocks"`. Entities not listed keep the default static/ and dynamic/ folders
+    #[arg(long, value_parser = load_output_layout)]
+    layout_config: Option<OutputLayout>,
+}
+
+impl ExtractArgs {
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(
+            self.rpc_rate_limit_retries,
+            self.rpc_timeout_retries,
+            self.rpc_initial_backoff_ms,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct QueriesArgs {
+    /// Account address used as default value of $address in the queries
+    #[arg(
+        short,
+        long,
+        default_value = "0x0000000000000000000000000000000000000000"
+    )]
+    address: String,
+    /// Block number used as default value of $block in the queries
+    #[arg(short, long, default_value_t = 0)]
+    block: u64,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct MergeArgs {
+    /// Output folders of the extractions, with the same layout
+    #[arg(required = true, num_args = 2..)]
+    inputs: Vec<String>,
+    /// Merged output folder, it must be empty
+    #[arg(short, long)]
+    output: String,
+    /// Max size of the files of the deduplicated entities before they're compressed, in KB
+    #[arg(long, default_value_t = 8192)]
+    size_output: usize,
+    /// Compression level of the files of the deduplicated entities, from 0 to 9
+    #[arg(long, default_value_t = 6)]
+    compression_level: u32,
+    /// TOML file with the folder of each entity's files, the same used by the extractions
+    #[arg(long, value_parser = load_output_layout)]
+    layout_config: Option<OutputLayout>,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct LoadArgs {
+    /// Output path of the extraction
+    #[arg(short, long, default_value = "./extracted")]
+    output_path: String,
+    /// Loader to run: bulk (dgraph bulk, into a new cluster) or live (dgraph live, into running Alphas)
+    #[arg(long, value_enum, default_value_t = Loader::Bulk)]
+    loader: Loader,
+    /// Dgraph Alpha GRPC endpoint, used by the live loader
+    #[arg(long, default_value = "localhost:9080")]
+    dgraph_alpha: String,
+    /// Dgraph Zero GRPC endpoint
+    #[arg(long, default_value = "localhost:5080")]
+    zero: String,
+    /// Dgraph schema to load
+    #[arg(long, default_value = "dgraph/contracts.schema")]
+    schema: String,
+    /// Output folder of the bulk loader, with the posting lists to start the Alphas on
+    #[arg(long, default_value = "./out")]
+    bulk_out: String,
+    /// Path of the dgraph binary
+    #[arg(long, default_value = "dgraph")]
+    dgraph_bin: String,
+    /// TOML file with the folder of each entity's files, the same used by the extraction
+    #[arg(long, value_parser = load_output_layout)]
+    layout_config: Option<OutputLayout>,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct TraceTxArgs {
+    /// RPC endpoint to connect to
+    #[arg(short, long, default_value = "http://localhost:8545")]
+    endpoint: String,
+    /// Hash of the transaction to trace
+    #[arg(long)]
+    tx: TxHash,
+    /// RPC method used to trace the transaction: parity (trace_transaction) or geth (debug_traceTransaction)
+    #[arg(long, value_enum, default_value_t = TraceMethod::Parity)]
+    trace_method: TraceMethod,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct BenchArgs {
+    /// RPC endpoint to connect to
+    #[arg(short, long, default_value = "http://localhost:8545")]
+    endpoint: String,
+    /// RPC method used to trace blocks: parity (trace_block) or geth (debug_traceBlockByNumber)
+    #[arg(long, value_enum, default_value_t = TraceMethod::Parity)]
+    trace_method: TraceMethod,
+    /// First block of the benchmarked range
+    #[arg(short, long, default_value_t = 16000000)]
+    from_block: u64,
+    /// Number of blocks extracted in each run
+    #[arg(short, long, default_value_t = 100)]
+    blocks: u64,
+    /// Comma separated numbers of tasks to test, defaults to 1, 2, 5 and 10 times the number of CPUs
+    #[arg(short, long, value_delimiter = ',')]
+    num_tasks: Option<Vec<usize>>,
 }
 
 #[derive(Debug, Args)]
@@ -103,6 +539,9 @@ pub struct ExtractArgs {
 struct AnalyseArgs {
     #[command(subcommand)]
     command: AnalyseCommands,
+    /// Exit if the Dgraph schema misses predicates or indexes, instead of printing a warning
+    #[arg(long, global = true, default_value_t = false)]
+    strict_schema: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -111,12 +550,23 @@ pub enum AnalyseCommands {
         /// Dgraph GRPC endpoint
         #[arg(short, long, default_value = "http://localhost:9080")]
         endpoint: String,
-        /// Output file
+        /// Output file, required with the file sink
         #[arg(short, long)]
-        output_file: String,
+        output_file: Option<String>,
+        /// Where the similarity edges are stored: N-Quads in --output-file, or set directly
+        /// in Dgraph between the skeletons. Running again doesn't duplicate the Dgraph edges
+        #[arg(long, value_enum, default_value_t = SimilaritySink::File)]
+        sink: SimilaritySink,
         /// Contract address to calculate the similarities for
         #[arg(short, long)]
         address: Option<String>,
+        /// Calculate the similarities for these contracts, file or comma separated list of addresses.
+        /// All the skeletons are loaded once and compared with every target, output is grouped by target
+        #[arg(long, value_parser = parse_address_list, conflicts_with_all = ["address", "since_block"])]
+        addresses_file: Option<HashSet<Address>>,
+        /// Number of targets of --addresses-file compared at the same time
+        #[arg(long, default_value_t = 4)]
+        target_jobs: usize,
         /// Calculate interface similarity
         #[arg(long, default_value_t = true)]
         interface_sim: bool,
@@ -132,6 +582,24 @@ pub enum AnalyseCommands {
         /// Length of N-grams to use for cosine similarity
         #[arg(long, default_value_t = 5)]
         ngram_length: u8,
+        /// Incremental mode: only compare the skeletons deployed from this block on against all
+        /// the others, skipping the pairs that already have a similarity edge in Dgraph
+        #[arg(long, conflicts_with = "address")]
+        since_block: Option<u64>,
+        /// Estimate the interface similarity from MinHash signatures of this length, loading the
+        /// signatures stored by the extraction instead of the ABIs. Skeletons without a stored
+        /// signature are loaded with their ABI and signed on the fly
+        #[arg(long, conflicts_with = "address")]
+        minhash_size: Option<usize>,
+        /// Append the uid of each skeleton to this file once it's compared with all the others,
+        /// and skip the skeletons listed in it: an interrupted run can be resumed by running
+        /// it again with the same options, edges are appended to --output-file
+        #[arg(long, conflicts_with_all = ["address", "addresses_file"])]
+        checkpoint: Option<String>,
+        /// Number of skeletons loaded from Dgraph per query, lower it if the queries of large
+        /// corpora hit the Dgraph limits or take too much memory
+        #[arg(long, default_value_t = 1000)]
+        page_size: usize,
     },
     Lifetimes {
         /// Dgraph GRPC endpoint
@@ -143,104 +611,362 @@ pub enum AnalyseCommands {
         /// Cache file to use
         #[arg(short, long)]
         cache_file: Option<String>,
+        /// Number of concurrent Dgraph transactions used to fetch the contracts
+        #[arg(short, long, default_value_t = 8)]
+        num_jobs: usize,
+    },
+    /// Check the consistency of the graph stored in Dgraph, exits with an error if any check fails
+    Verify {
+        /// Dgraph GRPC endpoint
+        #[arg(short, long, default_value = "http://localhost:9080")]
+        endpoint: String,
+    },
+    /// Export the resolved function and event names as a `selector,name,inputs,count` CSV,
+    /// where count is the number of skeletons using the signature
+    Signatures {
+        /// Dgraph GRPC endpoint
+        #[arg(short, long, default_value = "http://localhost:9080")]
+        endpoint: String,
+        /// Output CSV file
+        #[arg(short, long)]
+        output_file: String,
     },
+    /// Evaluate the similarity thresholds on labeled pairs of contracts, printing the precision,
+    /// recall and F1 of each metric for thresholds from 0 to 1
+    EvalSimilarities {
+        /// Dgraph GRPC endpoint
+        #[arg(short, long, default_value = "http://localhost:9080")]
+        endpoint: String,
+        /// CSV of `address_a,address_b,similar` lines, with similar `true` or `false`
+        #[arg(short, long)]
+        labels_file: String,
+        /// Length of N-grams to use for cosine similarity
+        #[arg(long, default_value_t = 5)]
+        ngram_length: u8,
+        /// Step between the evaluated thresholds
+        #[arg(long, default_value_t = 0.05)]
+        step: f64,
+    },
+}
+
+/// Run a command on a new multi-threaded Tokio runtime
+fn run_command<F, T>(command: F) -> Result<T, Error>
+where
+    F: std::future::Future<Output = Result<T, Error>>,
+{
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::Runtime)?
+        .block_on(command)
+}
+
+/// Run an analysis on a new single-threaded Tokio runtime
+fn run_analysis<F, T>(command: F) -> Result<T, Error>
+where
+    F: std::future::Future<Output = Result<T, Error>>,
+{
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::Runtime)?
+        .block_on(command)
+}
+
+/// Fail with an invalid arguments error when `invalid`
+fn check_arg(invalid: bool, message: &str) -> Result<(), Error> {
+    if invalid {
+        return Err(Error::InvalidArgs(message.to_string()));
+    }
+    Ok(())
 }
 
 fn main() {
-    let cli = Cli::parse();
+    // the flags of the config file are parsed as if given before the ones on the command line
+    let res = expand_config(std::env::args().collect(), &Cli::command())
+        .map_err(Error::InvalidArgs)
+        .and_then(|args| run(Cli::parse_from(args)));
+    if let Err(e) = res {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    // with the entities on stdout, nothing else can be printed there
+    let to_stdout = matches!(&cli.command, Commands::Extract(e) if is_stdout(&e.output_path));
+    set_verbosity(if cli.quiet || to_stdout {
+        Verbosity::Quiet
+    } else if cli.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Info
+    });
+    if let Some(config) = &cli.config {
+        info!("Using the flags of {}", config);
+    }
 
     match cli.command {
         Commands::Extract(mut extract_args) => {
             if extract_args.num_tasks == 0 {
-                extract_args.num_tasks = 5 * num_cpus::get(); // optimal number from benchmarks
+                extract_args.num_tasks = 5 * num_cpus::get(); // optimal number from benchmarks, see the hidden bench command
             }
-            if (extract_args.include_tx || extract_args.include_transfers)
-                && (extract_args.to_block - extract_args.from_block) > 1e6 as u64
-            {
-                println!("WARNING: Extracting transactions and/or token transfers for a large number of blocks may produce a large number of files");
+            if let Some(block) = extract_args.block {
+                extract_args.from_block = Some(block);
+                extract_args.to_block = Some(block);
             }
-            if extract_args.compression_level > 9 {
-                panic!("Compression level must be between 0 and 9");
+            if extract_args.lifetimes_only {
+                extract_args.skip_decompilation = true;
             }
-            // create the Tokio runtime and run the extraction
-            tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(async {
-                    run_extraction(extract_args).await;
-                });
-        }
-        Commands::Analyse(analyse) => match analyse.command {
-            AnalyseCommands::Similarities {
-                endpoint,
-                output_file,
-                address,
-                interface_sim,
-                interface_threshold,
-                cosine_sim,
-                cosine_threshold,
-                ngram_length,
-            } => {
-                if interface_threshold < 0.0 || interface_threshold > 1.0 {
-                    panic!("Interface similarity threshold must be between 0.0 and 1.0");
-                }
-                if cosine_threshold < 0.0 || cosine_threshold > 1.0 {
-                    panic!("Cosine similarity threshold must be between 0.0 and 1.0");
-                }
-                if cosine_threshold < 0.9 {
-                    println!("WARNING: Cosine similarity threshold is low, this may result in a large number of stored similarities");
-                }
-                if address.is_none() {
-                    println!("WARNING: No contract address specified, all contracts will be analysed, this may take a long time");
+            check_arg(
+                extract_args.max_blocks_in_flight == Some(0),
+                "Max blocks in flight must be at least 1",
+            )?;
+            check_arg(
+                extract_args.compression_level > 9,
+                "Compression level must be between 0 and 9",
+            )?;
+            extract_args
+                .retry_policy()
+                .validate()
+                .map_err(Error::InvalidArgs)?;
+            check_arg(
+                extract_args.transfers_csv && extract_args.output_path.starts_with("s3://"),
+                "--transfers-csv is written locally, it doesn't support S3 output",
+            )?;
+            check_arg(
+                cfg!(not(feature = "s3")) && extract_args.output_path.starts_with("s3://"),
+                "S3 output requires eth2dgraph to be built with the s3 feature",
+            )?;
+            if to_stdout {
+                let file_flags = [
+                    ("--checksums", extract_args.checksums),
+                    (
+                        "--content-addressed-skeletons",
+                        extract_args.content_addressed_skeletons,
+                    ),
+                    ("--transfers-csv", extract_args.transfers_csv),
+                    ("--validate-output", extract_args.validate_output),
+                    ("--deterministic", extract_args.deterministic),
+                    ("--layout-config", extract_args.layout_config.is_some()),
+                    (
+                        "--max-output-bytes",
+                        extract_args.max_output_bytes.is_some(),
+                    ),
+                    ("--since", extract_args.since.is_some()),
+                ];
+                if let Some((flag, _)) = file_flags.iter().find(|(_, set)| *set) {
+                    return Err(Error::InvalidArgs(format!(
+                        "{} works on output files, it doesn't support stdout output",
+                        flag
+                    )));
                 }
-                if cosine_sim && ngram_length < 2 {
-                    panic!("N-gram length must be at least 2");
+                if extract_args.num_tasks > 1 {
+                    eprintln!("WARNING: with more than one task the entities are written out of block order, use -n 1 to keep it");
                 }
-                tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .unwrap()
-                    .block_on(async {
+            }
+            run_command(run_extraction(extract_args))
+        }
+        Commands::Analyse(analyse) => {
+            let endpoint = match &analyse.command {
+                AnalyseCommands::Similarities { endpoint, .. }
+                | AnalyseCommands::Lifetimes { endpoint, .. }
+                | AnalyseCommands::Verify { endpoint }
+                | AnalyseCommands::Signatures { endpoint, .. }
+                | AnalyseCommands::EvalSimilarities { endpoint, .. } => endpoint,
+            };
+            run_analysis(async {
+                check_schema(endpoint, analyse.strict_schema)
+                    .await
+                    .map_err(Error::Dgraph)
+            })?;
+            match analyse.command {
+                AnalyseCommands::Similarities {
+                    endpoint,
+                    output_file,
+                    sink,
+                    address,
+                    addresses_file,
+                    target_jobs,
+                    interface_sim,
+                    interface_threshold,
+                    cosine_sim,
+                    cosine_threshold,
+                    ngram_length,
+                    since_block,
+                    minhash_size,
+                    checkpoint,
+                    page_size,
+                } => {
+                    check_arg(
+                        !interface_sim && !cosine_sim,
+                        "At least one similarity metric must be enabled",
+                    )?;
+                    check_arg(
+                        !(0.0..=1.0).contains(&interface_threshold),
+                        "Interface similarity threshold must be between 0.0 and 1.0",
+                    )?;
+                    check_arg(
+                        !(0.0..=1.0).contains(&cosine_threshold),
+                        "Cosine similarity threshold must be between 0.0 and 1.0",
+                    )?;
+                    if cosine_threshold < 0.9 {
+                        info!("WARNING: Cosine similarity threshold is low, this may result in a large number of stored similarities");
+                    }
+                    check_arg(target_jobs == 0, "Number of target jobs must be at least 1")?;
+                    check_arg(
+                        sink == SimilaritySink::File && output_file.is_none(),
+                        "Output file is required with the file sink",
+                    )?;
+                    if let Some(address) = &address {
+                        address.parse::<Address>().map_err(|e| {
+                            Error::InvalidArgs(format!("Invalid address {}: {}", address, e))
+                        })?;
+                    }
+                    if address.is_none() && addresses_file.is_none() && since_block.is_none() {
+                        info!("WARNING: No contract address specified, all contracts will be analysed, this may take a long time");
+                    }
+                    check_arg(
+                        cosine_sim && ngram_length < 2,
+                        "N-gram length must be at least 2",
+                    )?;
+                    check_arg(minhash_size == Some(0), "MinHash size must be at least 1")?;
+                    check_arg(page_size == 0, "Page size must be at least 1")?;
+                    let options = SimilarityOptions {
+                        interface_sim,
+                        interface_threshold,
+                        cosine_sim,
+                        cosine_threshold,
+                        ngram_length,
+                        since_block,
+                        minhash_size,
+                        page_size,
+                        target_jobs,
+                    };
+                    run_analysis(async {
                         find_similar_skeletons(
                             &endpoint,
-                            &output_file,
+                            sink,
+                            output_file.as_deref(),
                             address,
-                            interface_sim,
-                            interface_threshold,
-                            cosine_sim,
-                            cosine_threshold,
-                            ngram_length,
+                            addresses_file,
+                            checkpoint.as_deref(),
+                            options,
                         )
                         .await;
-                    });
-            }
-            AnalyseCommands::Lifetimes {
-                endpoint,
-                output_path,
-                cache_file,
-            } => {
-                tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .unwrap()
-                    .block_on(async {
-                        analyse_lifetimes(&endpoint, &output_path, cache_file).await;
-                    });
+                        Ok(())
+                    })
+                }
+                AnalyseCommands::Lifetimes {
+                    endpoint,
+                    output_path,
+                    cache_file,
+                    num_jobs,
+                } => {
+                    check_arg(num_jobs == 0, "Number of jobs must be at least 1")?;
+                    run_command(async {
+                        analyse_lifetimes(&endpoint, &output_path, cache_file, num_jobs).await;
+                        Ok(())
+                    })
+                }
+                AnalyseCommands::Verify { endpoint } => {
+                    let consistent = run_analysis(async { Ok(verify_graph(&endpoint).await) })?;
+                    if !consistent {
+                        return Err(Error::Analysis(
+                            "the graph failed the consistency checks".to_string(),
+                        ));
+                    }
+                    Ok(())
+                }
+                AnalyseCommands::Signatures {
+                    endpoint,
+                    output_file,
+                } => run_analysis(async {
+                    export_signatures(&endpoint, &output_file)
+                        .await
+                        .map_err(|e| {
+                            Error::Analysis(format!("could not export the signatures: {}", e))
+                        })
+                }),
+                AnalyseCommands::EvalSimilarities {
+                    endpoint,
+                    labels_file,
+                    ngram_length,
+                    step,
+                } => {
+                    check_arg(
+                        step <= 0.0 || step > 1.0,
+                        "Threshold step must be between 0.0 and 1.0",
+                    )?;
+                    check_arg(ngram_length < 2, "N-gram length must be at least 2")?;
+                    run_analysis(async {
+                        evaluate_similarities(&endpoint, &labels_file, ngram_length, step)
+                            .await
+                            .map_err(|e| {
+                                Error::Analysis(format!(
+                                    "could not evaluate the similarities: {}",
+                                    e
+                                ))
+                            })
+                    })
+                }
             }
-        },
+        }
         Commands::Stream(mut stream_args) => {
             if stream_args.num_jobs == 0 {
                 stream_args.num_jobs = 1;
             }
-            // create the Tokio runtime and run the extraction
-            tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(async {
-                    run_stream_extraction(stream_args).await;
-                });
+            run_command(run_stream_extraction(stream_args))
+        }
+        Commands::Backfill(mut backfill_args) => {
+            check_arg(
+                backfill_args.num_jobs == 0,
+                "Number of jobs must be at least 1",
+            )?;
+            if let Some(block) = backfill_args.block {
+                backfill_args.from_block = Some(block);
+                backfill_args.to_block = Some(block);
+            }
+            check_arg(
+                backfill_args.from_block > backfill_args.to_block,
+                "From block must not be greater than to block",
+            )?;
+            run_command(run_backfill(backfill_args))
+        }
+        Commands::Queries(queries_args) => {
+            print_sample_queries(&queries_args.address.to_lowercase(), queries_args.block);
+            Ok(())
+        }
+        Commands::Merge(merge_args) => {
+            check_arg(
+                merge_args.compression_level > 9,
+                "Compression level must be between 0 and 9",
+            )?;
+            merge_outputs(
+                &merge_args.inputs,
+                &merge_args.output,
+                &merge_args.layout_config.unwrap_or_default(),
+                merge_args.size_output,
+                merge_args.compression_level,
+            )
+            .map(|_| ())
+            .map_err(Error::Merge)
+        }
+        Commands::TraceTx(trace_tx_args) => run_command(run_trace_tx(trace_tx_args)),
+        Commands::Load(load_args) => run_load(load_args).map_err(Error::Load),
+        Commands::Bench(bench_args) => {
+            check_arg(
+                bench_args.blocks == 0,
+                "Number of blocks must be at least 1",
+            )?;
+            check_arg(
+                bench_args
+                    .num_tasks
+                    .as_ref()
+                    .is_some_and(|n| n.contains(&0)),
+                "Number of tasks must be at least 1",
+            )?;
+            run_command(run_bench(bench_args))
         }
     }
 }
@@ -1,6 +1,7 @@
 use super::{error::ErrorABI, event::EventABI, function::FunctionABI};
 use primitive_types::H256;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +26,20 @@ pub struct ABIToken {
     pub _name: String,
     #[serde(rename = "internalType")]
     pub internal_type: String,
+    /// Whether the parameter is stored in a topic of the log, always false for non-event parameters
+    #[serde(default)]
+    pub indexed: bool,
+}
+
+impl ABIToken {
+    /// Standard Solidity JSON of the parameter
+    fn to_solidity_json(&self) -> Value {
+        json!({
+            "name": self._name,
+            "type": self.internal_type,
+            "internalType": self.internal_type,
+        })
+    }
 }
 
 impl ABIStructure {
@@ -105,6 +120,45 @@ impl ContractABI {
         }
     }
 
+    /// Convert the ABI to the standard Solidity JSON format, usable by ethers, web3 and the like
+    pub fn to_solidity_json(&self) -> Value {
+        let tokens = |tokens: &Vec<ABIToken>| {
+            tokens
+                .iter()
+                .map(|t| t.to_solidity_json())
+                .collect::<Vec<Value>>()
+        };
+        Value::Array(
+            self.nodes
+                .iter()
+                .map(|node| match node {
+                    ABIStructure::Function(f) => json!({
+                        "type": "function",
+                        "name": f.name,
+                        "inputs": tokens(&f.inputs),
+                        "outputs": tokens(&f.outputs),
+                        "stateMutability": f.get_state_mutability(),
+                    }),
+                    ABIStructure::Event(e) => json!({
+                        "type": "event",
+                        "name": e.name,
+                        "inputs": e.inputs.iter().map(|t| {
+                            let mut input = t.to_solidity_json();
+                            input["indexed"] = json!(t.indexed);
+                            input
+                        }).collect::<Vec<Value>>(),
+                        "anonymous": false,
+                    }),
+                    ABIStructure::Error(e) => json!({
+                        "type": "error",
+                        "name": e.name,
+                        "inputs": tokens(&e.inputs),
+                    }),
+                })
+                .collect(),
+        )
+    }
+
     pub(crate) fn get_function_by_signature(
         &self,
         name: &str,
@@ -158,14 +212,16 @@ mod tests {
                 ABIToken {
                     _name: "to".to_string(),
                     internal_type: "address".to_string(),
+                    indexed: false,
                 },
                 ABIToken {
                     _name: "value".to_string(),
                     internal_type: "uint256".to_string(),
+                    indexed: false,
                 },
             ],
             outputs: vec![],
-            _state_mutability: "nonpayable".to_string(),
+            state_mutability: "nonpayable".to_string(),
             _constant: false,
         };
 
@@ -180,14 +236,17 @@ mod tests {
                 ABIToken {
                     _name: "from".to_string(),
                     internal_type: "address".to_string(),
+                    indexed: false,
                 },
                 ABIToken {
                     _name: "to".to_string(),
                     internal_type: "address".to_string(),
+                    indexed: false,
                 },
                 ABIToken {
                     _name: "value".to_string(),
                     internal_type: "uint256".to_string(),
+                    indexed: false,
                 },
             ],
         };
@@ -208,4 +267,47 @@ mod tests {
 
         println!("{:?}", decoded);
     }
+
+    #[test]
+    fn test_solidity_json() {
+        let output = |internal_type: &str| ABIToken {
+            _name: "".to_string(),
+            internal_type: internal_type.to_string(),
+            indexed: false,
+        };
+        let view = |name: &str, internal_type: &str| {
+            ABIStructure::Function(FunctionABI {
+                name: name.to_string(),
+                inputs: vec![],
+                outputs: vec![output(internal_type)],
+                state_mutability: "view".to_string(),
+                _constant: true,
+            })
+        };
+        let abi = ContractABI::new(vec![
+            view("name", "string"),
+            view("totalSupply", "uint256"),
+            view("decimals", "uint8"),
+            view("symbol", "string"),
+            ABIStructure::Event(EventABI {
+                name: "Transfer".to_string(),
+                inputs: vec![output("address"), output("address"), output("uint256")],
+            }),
+        ]);
+
+        let generated: ethabi::Contract =
+            serde_json::from_value(abi.to_solidity_json()).expect("Invalid Solidity ABI");
+        let known: ethabi::Contract =
+            serde_json::from_str(include_str!("../../res/erc20.json")).unwrap();
+
+        for function in known.functions() {
+            let other = generated.function(&function.name).unwrap();
+            assert_eq!(function.signature(), other.signature());
+            assert_eq!(function.state_mutability, other.state_mutability);
+        }
+        assert_eq!(
+            generated.event("Transfer").unwrap().signature().0,
+            ethers::utils::keccak256("Transfer(address,address,uint256)")
+        );
+    }
 }
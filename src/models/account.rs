@@ -0,0 +1,356 @@
+use super::trace::Traces;
+use super::{OrderKey, SerializeDgraph};
+use dgraph_tonic::{IClient, Mutate, Query};
+use ethers::types::{Action, Address, Res};
+use serde::{ser::SerializeStruct, Deserialize, Serializer};
+use std::collections::HashMap;
+
+/// Activity of an account, i.e. the range of blocks in which it was involved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    address: Address,
+    first_seen: u64,
+    last_seen: u64,
+    is_contract: bool,
+}
+
+impl Account {
+    pub fn new(address: Address, block: u64, is_contract: bool) -> Self {
+        Self {
+            address,
+            first_seen: block,
+            last_seen: block,
+            is_contract,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Merge the activity of the same account seen somewhere else
+    pub fn merge(&mut self, other: &Account) {
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.is_contract |= other.is_contract;
+    }
+
+    /// Insert or update the account in Dgraph, merging its activity with the stored one.
+    /// The stored range is read and written in the same transaction, so concurrent
+    /// updates of the same account make the commit fail instead of losing blocks
+    pub async fn upsert<S: IClient>(
+        &self,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<(), anyhow::Error> {
+        let address = format!("{:?}", self.address);
+
+        #[derive(Deserialize, Debug)]
+        struct QueryItem {
+            first_seen: Option<u64>,
+            last_seen: Option<u64>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Response {
+            account: Vec<QueryItem>,
+        }
+
+        let mut txn = dgraph_client.new_mutated_txn();
+        let res = txn
+            .query(format!(
+                r#"
+            {{
+                account(func: eq(Account.address, "{address}")) {{
+                    first_seen: Account.first_seen
+                    last_seen: Account.last_seen
+                }}
+            }}
+        "#
+            ))
+            .await?;
+        let stored: Response = serde_json::from_slice(&res.json)?;
+
+        let mut account = self.clone();
+        for item in stored.account {
+            if let (Some(first_seen), Some(last_seen)) = (item.first_seen, item.last_seen) {
+                account.merge(&Account {
+                    address: self.address,
+                    first_seen,
+                    last_seen,
+                    is_contract: false,
+                });
+            }
+        }
+
+        // Query part of the upsert
+        let query = format!(
+            r#"
+            query {{
+                var(func: eq(Account.address, "{address}")) {{
+                    Account as uid
+                }}
+            }}
+        "#
+        );
+
+        // Mutation part of the upsert
+        let mut set = format!(
+            r#"
+            uid(Account) <Account.address> "{address}" .
+            uid(Account) <dgraph.type> "Account" .
+            uid(Account) <Account.first_seen> "{first_seen}" .
+            uid(Account) <Account.last_seen> "{last_seen}" .
+        "#,
+            first_seen = account.first_seen,
+            last_seen = account.last_seen,
+        );
+        // the flag is never reset, an account is a contract if any deployment says so
+        if account.is_contract {
+            set.push_str(
+                r#"uid(Account) <Account.is_contract> "true" .
+                "#,
+            );
+        }
+
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_set_nquads(set);
+        txn.upsert(query, mu).await?;
+        txn.commit().await
+    }
+
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Account", 6)?;
+        state.serialize_field("uid", &format!("_:{:?}", self.address))?;
+        state.serialize_field("dgraph.type", "Account")?;
+        state.serialize_field("Account.address", &format!("{:?}", self.address))?;
+        // accounts not deployed in the extracted range may still be contracts,
+        // so the flag is only set when it's known to be true
+        if self.is_contract {
+            state.serialize_field("Account.is_contract", &true)?;
+        }
+        state.serialize_field("Account.first_seen", &self.first_seen)?;
+        state.serialize_field("Account.last_seen", &self.last_seen)?;
+        state.end()
+    }
+}
+
+/// Nested `Account` node referenced by the other entities.
+/// `is_contract` is true only where the entity proves the address has code: the emitter of a
+/// log or transfer, the caller of an internal transaction, a destroyed or deployed contract.
+/// Senders, recipients and beneficiaries may be EOAs or contracts, so the flag is omitted for
+/// them instead of being set to false, which would override a known contract in Dgraph
+pub fn account_node(address: &Address, is_contract: bool) -> serde_json::Value {
+    let mut node = serde_json::json!({
+        "uid": format!("_:{:?}", address),
+        "dgraph.type": "Account",
+        "Account.address": format!("{:?}", address),
+    });
+    if is_contract {
+        node["Account.is_contract"] = true.into();
+    }
+    node
+}
+
+impl From<&Traces> for Vec<Account> {
+    /// Accounts involved in the traces of a block, contracts are the ones successfully created
+    fn from(traces: &Traces) -> Self {
+        let mut accounts: HashMap<Address, Account> = HashMap::new();
+        let mut seen = |address: Address, block: u64, is_contract: bool| {
+            let account = Account::new(address, block, is_contract);
+            accounts
+                .entry(address)
+                .and_modify(|a| a.merge(&account))
+                .or_insert(account);
+        };
+        for trace in &traces.0 {
+            let block = trace.block_number;
+            match &trace.action {
+                Action::Call(call) => {
+                    seen(call.from, block, false);
+                    seen(call.to, block, false);
+                }
+                Action::Create(create) => {
+                    seen(create.from, block, false);
+                    if let Some(Res::Create(res)) = &trace.result {
+                        seen(res.address, block, trace.error.is_none());
+                    }
+                }
+                Action::Suicide(suicide) => {
+                    seen(suicide.address, block, true);
+                    seen(suicide.refund_address, block, false);
+                }
+                Action::Reward(reward) => {
+                    seen(reward.author, block, false);
+                }
+            }
+        }
+        accounts.into_values().collect()
+    }
+}
+
+impl SerializeDgraph for Account {
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_dgraph(serializer)
+    }
+}
+
+impl OrderKey for Account {
+    /// Accounts are sorted by first seen block, then by address
+    fn order_key(&self) -> (u64, String) {
+        (self.first_seen, format!("{:?}", self.address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Account;
+    use crate::models::{
+        contract_deployment::ContractDeployment,
+        contract_destruction::ContractDestruction,
+        internal_transaction::InternalTransaction,
+        trace::{TraceBuilder, Traces},
+        transfer::{TokenTransfer, TokenType},
+        SerializeDgraph,
+    };
+    use ethers::types::{
+        Action, Address, Call, Create, CreateResult, Res, Suicide, TxHash, U256, U64,
+    };
+    use serde_json::Value;
+
+    /// Creation of `contract` by `from` in block 10
+    fn creation(from: Address, contract: Address) -> TraceBuilder {
+        TraceBuilder::new(Action::Create(Create {
+            from,
+            ..Default::default()
+        }))
+        .result(Res::Create(CreateResult {
+            address: contract,
+            ..Default::default()
+        }))
+        .block(10)
+    }
+
+    /// Call from `from` to `to` in block 10
+    fn call(from: Address, to: Address) -> TraceBuilder {
+        TraceBuilder::new(Action::Call(Call {
+            from,
+            to,
+            ..Default::default()
+        }))
+        .block(10)
+    }
+
+    #[test]
+    fn test_accounts_from_traces() {
+        let eoa = Address::from_low_u64_be(1);
+        let contract = Address::from_low_u64_be(2);
+        let traces = Traces::from(vec![
+            creation(eoa, contract).build(),
+            call(eoa, contract).build(),
+        ]);
+
+        let mut accounts: Vec<Account> = Vec::from(&traces);
+        accounts.sort_by_key(|a| a.address());
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(eoa, 10, false),
+                Account::new(contract, 10, true)
+            ]
+        );
+
+        let mut account = accounts[0].clone();
+        account.merge(&Account::new(eoa, 5, false));
+        account.merge(&Account::new(eoa, 20, false));
+        assert_eq!((account.first_seen, account.last_seen), (5, 20));
+        assert!(!account.is_contract);
+    }
+
+    fn serialized<T: SerializeDgraph>(entity: &T) -> Value {
+        entity
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap()
+    }
+
+    /// `Account.is_contract` of the nested account, `None` when the flag is omitted
+    fn is_contract(entity: &Value, predicate: &str) -> Option<bool> {
+        entity[predicate]
+            .get("Account.is_contract")
+            .map(|flag| flag.as_bool().unwrap())
+    }
+
+    #[test]
+    fn test_contract_tagging() {
+        let eoa = Address::from_low_u64_be(1);
+        let contract = Address::from_low_u64_be(2);
+        let reverted = Address::from_low_u64_be(3);
+        let traces = Traces::from(vec![
+            creation(eoa, contract).build(),
+            creation(eoa, reverted).error("Reverted").build(),
+            call(contract, eoa).trace_address(vec![0]).build(),
+            TraceBuilder::new(Action::Suicide(Suicide {
+                address: contract,
+                refund_address: eoa,
+                balance: U256::zero(),
+            }))
+            .block(10)
+            .build(),
+        ]);
+
+        let deployments: Vec<ContractDeployment> = Vec::from(traces.clone());
+        let deployment = serialized(&deployments[0]);
+        assert_eq!(
+            is_contract(&deployment, "ContractDeployment.contract"),
+            Some(true)
+        );
+        assert_eq!(is_contract(&deployment, "ContractDeployment.creator"), None);
+        // a reverted creation leaves no code behind
+        let failed = serialized(&deployments[1]);
+        assert_eq!(is_contract(&failed, "ContractDeployment.contract"), None);
+
+        let internal_txs: Vec<InternalTransaction> = Vec::from(&traces);
+        let internal_tx = serialized(&internal_txs[0]);
+        assert_eq!(
+            is_contract(&internal_tx, "InternalTransaction.from"),
+            Some(true)
+        );
+        assert_eq!(is_contract(&internal_tx, "InternalTransaction.to"), None);
+
+        let destructions: Vec<ContractDestruction> = Vec::from(&traces);
+        let destruction = serialized(&destructions[0]);
+        assert_eq!(
+            is_contract(&destruction, "ContractDestruction.contract"),
+            Some(true)
+        );
+        assert_eq!(
+            is_contract(&destruction, "ContractDestruction.refound_address"),
+            None
+        );
+
+        let transfer = serialized(&TokenTransfer::new(
+            contract,
+            eoa,
+            reverted,
+            U256::one(),
+            U64::from(10),
+            TxHash::from_low_u64_be(1),
+            TokenType::ERC20,
+        ));
+        assert_eq!(is_contract(&transfer, "TokenTransfer.contract"), Some(true));
+        assert_eq!(is_contract(&transfer, "TokenTransfer.from"), None);
+        assert_eq!(is_contract(&transfer, "TokenTransfer.to"), None);
+
+        // the flag is never serialized as false
+        for entity in [deployment, failed, internal_tx, destruction, transfer] {
+            assert!(!entity
+                .to_string()
+                .contains(r#""Account.is_contract":false"#));
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use super::SerializeDgraph;
-use anyhow::{bail, Ok};
+use super::{trace::GasBreakdown, transfer::TransferCounts, OrderKey, SerializeDgraph};
+use crate::debug;
+use anyhow::Ok;
 use chrono::{NaiveDateTime, TimeZone, Utc};
 use dgraph_tonic::{IClient, Mutate};
 use ethabi::ethereum_types::U256;
@@ -7,10 +8,30 @@ use serde::{ser::SerializeStruct, Serializer};
 use serde_json::json;
 use std::ops::{Deref, DerefMut};
 
+/// RFC 3339 representation of a block timestamp, as stored in the datetime predicates
+pub fn rfc3339(timestamp: u64) -> String {
+    let datetime = NaiveDateTime::from_timestamp_millis(timestamp as i64 * 1000).unwrap();
+    Utc.from_utc_datetime(&datetime).to_rfc3339()
+}
+
+/// Block with its transactions, the gas breakdown computed from its traces,
+/// and the counts of its token transfers when they're extracted
 #[derive(Debug)]
-pub struct Block(ethers::types::Block<ethers::types::Transaction>);
+pub struct Block(
+    ethers::types::Block<ethers::types::Transaction>,
+    Option<GasBreakdown>,
+    Option<TransferCounts>,
+);
 
 impl Block {
+    pub fn set_gas_breakdown(&mut self, gas_breakdown: Option<GasBreakdown>) {
+        self.1 = gas_breakdown;
+    }
+
+    pub fn set_transfer_counts(&mut self, transfer_counts: Option<TransferCounts>) {
+        self.2 = transfer_counts;
+    }
+
     pub fn get_number(&self) -> u64 {
         self.number.unwrap().as_u64()
     }
@@ -24,9 +45,7 @@ impl Block {
     }
 
     pub fn get_rfc3339(&self) -> String {
-        let timestamp = self.get_timestamp() * 1000;
-        let datetime = NaiveDateTime::from_timestamp_millis(timestamp as i64).unwrap();
-        Utc.from_utc_datetime(&datetime).to_rfc3339()
+        rfc3339(self.get_timestamp())
     }
 
     /// get info about gas price in Gwei
@@ -67,6 +86,27 @@ impl Block {
         &self,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
     ) -> Result<(), anyhow::Error> {
+        let (query, set) = self.upsert_parts();
+
+        debug!("Upserting query: {}", query);
+        debug!("Upserting set: {}", set);
+
+        // Perform the upsert, the uncles of a block replaced by a reorg are removed,
+        // since setting a list predicate adds to its values: deletes are applied before
+        // the sets of the same mutation, so both take a single request
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_delete_nquads(r#"uid(Block) <Block.uncles> * ."#);
+        mu.set_set_nquads(set);
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Query and mutation parts of the upsert.
+    /// Blocks without author (e.g. on PoA chains) are stored without the miner, like in the JSON output
+    fn upsert_parts(&self) -> (String, String) {
         // fields
 
         let block_no = self.get_number();
@@ -88,34 +128,34 @@ impl Block {
             None
         };
 
-        if self.author.is_none() {
-            bail!("Block {} has no author", block_no);
-        }
-
-        let miner_address = format!("{:?}", self.author.as_ref().unwrap());
+        let miner_address = self.author.as_ref().map(|author| format!("{:?}", author));
 
         // Query part of the upsert
+        let miner_query = match &miner_address {
+            Some(miner_address) => format!(
+                r#"var(func: eq(Account.address, "{}")) {{
+                Miner as uid
+              }}"#,
+                miner_address
+            ),
+            None => String::new(),
+        };
         let query = format!(
             r#"
             query {{
               var(func: eq(Block.number, {block_no})) {{
                 Block as uid
               }}
-              var(func: eq(Account.number, {miner_address})) {{
-                Miner as uid
-              }}
+              {miner_query}
             }}
         "#,
             block_no = block_no,
-            miner_address = miner_address,
+            miner_query = miner_query,
         );
 
         // Mutation part of the upsert
         let mut set = format!(
             r#"
-            uid(Miner) <dgraph.type> "Account" .
-            uid(Miner) <Account.address> "{miner_address}" .
-
             uid(Block) <dgraph.type> "Block" .
             uid(Block) <Block.number> "{block_no}" .
             uid(Block) <Block.difficulty> "{difficulty}" .
@@ -138,9 +178,18 @@ impl Block {
             std_dev = std_dev,
             gas_limit = gas_limit,
             gas_used = gas_used,
-            miner_address = miner_address,
         );
 
+        if let Some(miner_address) = &miner_address {
+            set.push_str(&format!(
+                r#"uid(Miner) <dgraph.type> "Account" .
+                uid(Miner) <Account.address> "{miner_address}" .
+                uid(Block) <Block.miner> uid(Miner) .
+                "#,
+                miner_address = miner_address,
+            ));
+        }
+
         if base_fee_per_gas.is_some() {
             set.push_str(&format!(
                 r#"uid(Block) <Block.base_fee_per_gas> "{base_fee_per_gas}" .
@@ -155,17 +204,55 @@ impl Block {
                 size = size.unwrap(),
             ));
         }
+        // blob fields are only present after Dencun
+        if let Some(blob_gas_used) = &self.blob_gas_used {
+            set.push_str(&format!(
+                r#"uid(Block) <Block.blob_gas_used> "{blob_gas_used}" .
+                "#,
+                blob_gas_used = blob_gas_used.as_u64(),
+            ));
+        }
+        if let Some(excess_blob_gas) = &self.excess_blob_gas {
+            set.push_str(&format!(
+                r#"uid(Block) <Block.excess_blob_gas> "{excess_blob_gas}" .
+                "#,
+                excess_blob_gas = excess_blob_gas.as_u64(),
+            ));
+        }
+        // only pre-merge blocks have uncles
+        for uncle in &self.uncles {
+            set.push_str(&format!(
+                r#"uid(Block) <Block.uncles> "{:?}" .
+                "#,
+                uncle
+            ));
+        }
+        if let Some(gas) = &self.1 {
+            set.push_str(&format!(
+                r#"uid(Block) <Block.gas_creation> "{creations}" .
+                uid(Block) <Block.gas_calls> "{calls}" .
+                uid(Block) <Block.gas_transfers> "{transfers}" .
+                uid(Block) <Block.gas_reverted> "{reverted}" .
+                "#,
+                creations = gas.creations,
+                calls = gas.calls,
+                transfers = gas.transfers,
+                reverted = gas.reverted,
+            ));
+        }
+        if let Some(counts) = &self.2 {
+            set.push_str(&format!(
+                r#"uid(Block) <Block.erc20_transfer_count> "{erc20}" .
+                uid(Block) <Block.erc721_transfer_count> "{erc721}" .
+                uid(Block) <Block.erc1155_transfer_count> "{erc1155}" .
+                "#,
+                erc20 = counts.erc20,
+                erc721 = counts.erc721,
+                erc1155 = counts.erc1155,
+            ));
+        }
 
-        // Perform the upsert
-        let mut mu = dgraph_tonic::Mutation::new();
-        mu.set_set_nquads(set);
-        let mut txn = dgraph_client.new_mutated_txn();
-        txn.upsert(query, mu).await?;
-        txn.commit().await?;
-        // println!("Upserting query: {}", query);
-        // println!("Upserting set: {}", set);
-
-        Ok(())
+        (query, set)
     }
 
     /// Delete all logs related to this block in Dgraph
@@ -226,6 +313,35 @@ impl Block {
         txn.commit().await
     }
 
+    /// Delete all proxy upgrades seen at this block in Dgraph
+    pub async fn upsert_delete_proxy_upgrades<S: IClient>(
+        block_no: u64,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<(), anyhow::Error> {
+        let query = format!(
+            r#"
+            query {{
+                var(func: eq(Block.number, {block_no})) {{
+                    ~ProxyUpgrade.block {{
+                        upgrade as uid
+                    }}
+                }}
+            }}
+            "#,
+            block_no = block_no
+        );
+
+        let delete = r#"
+            uid(upgrade) * * .
+        "#;
+
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_delete_nquads(delete);
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await
+    }
+
     /// Delete all contract deployments related to this block in Dgraph
     pub async fn upsert_delete_deployments<S: IClient>(
         block_no: u64,
@@ -255,6 +371,66 @@ impl Block {
         txn.commit().await
     }
 
+    /// Delete all internal transactions related to this block in Dgraph
+    pub async fn upsert_delete_internal_txs<S: IClient>(
+        block_no: u64,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<(), anyhow::Error> {
+        let query = format!(
+            r#"
+            query {{
+                var(func: eq(Block.number, {block_no})) {{
+                    ~InternalTransaction.block {{
+                        internal_tx as uid
+                    }}
+                }}
+            }}
+            "#,
+            block_no = block_no
+        );
+
+        let delete = r#"
+            uid(internal_tx) * * .
+        "#;
+
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_delete_nquads(delete);
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await
+    }
+
+    /// Detach all transactions from this block in Dgraph, without deleting them.
+    /// Transactions of an orphaned block that are re-included elsewhere get their new block
+    /// edge when that block is processed
+    pub async fn upsert_detach_transactions<S: IClient>(
+        block_no: u64,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<(), anyhow::Error> {
+        let query = format!(
+            r#"
+            query {{
+                var(func: eq(Block.number, {block_no})) {{
+                    ~Transaction.block {{
+                        tx as uid
+                    }}
+                }}
+            }}
+            "#,
+            block_no = block_no
+        );
+
+        let delete = r#"
+            uid(tx) <Transaction.block> * .
+        "#;
+
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_delete_nquads(delete);
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await
+    }
+
     pub async fn upsert_delete_transfers<S: IClient>(
         block_no: u64,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
@@ -320,6 +496,31 @@ impl Block {
         if let Some(size) = &self.size {
             state.serialize_field("Block.size", &size.as_u64())?;
         }
+        if let Some(blob_gas_used) = &self.blob_gas_used {
+            state.serialize_field("Block.blob_gas_used", &blob_gas_used.as_u64())?;
+        }
+        if let Some(excess_blob_gas) = &self.excess_blob_gas {
+            state.serialize_field("Block.excess_blob_gas", &excess_blob_gas.as_u64())?;
+        }
+        if !self.uncles.is_empty() {
+            let uncles = self
+                .uncles
+                .iter()
+                .map(|uncle| format!("{:?}", uncle))
+                .collect::<Vec<_>>();
+            state.serialize_field("Block.uncles", &uncles)?;
+        }
+        if let Some(gas) = &self.1 {
+            state.serialize_field("Block.gas_creation", &gas.creations)?;
+            state.serialize_field("Block.gas_calls", &gas.calls)?;
+            state.serialize_field("Block.gas_transfers", &gas.transfers)?;
+            state.serialize_field("Block.gas_reverted", &gas.reverted)?;
+        }
+        if let Some(counts) = &self.2 {
+            state.serialize_field("Block.erc20_transfer_count", &counts.erc20)?;
+            state.serialize_field("Block.erc721_transfer_count", &counts.erc721)?;
+            state.serialize_field("Block.erc1155_transfer_count", &counts.erc1155)?;
+        }
         if let Some(withdrawals) = &self.withdrawals {
             let mut serialized_withdrawals = Vec::with_capacity(withdrawals.len());
             for withdrawal in withdrawals {
@@ -350,6 +551,13 @@ impl SerializeDgraph for Block {
     }
 }
 
+impl OrderKey for Block {
+    /// Blocks are sorted by number
+    fn order_key(&self) -> (u64, String) {
+        (self.get_number(), String::new())
+    }
+}
+
 impl Deref for Block {
     type Target = ethers::types::Block<ethers::types::Transaction>;
     fn deref(&self) -> &ethers::types::Block<ethers::types::Transaction> {
@@ -365,17 +573,128 @@ impl DerefMut for Block {
 
 impl From<ethers::types::Block<ethers::types::Transaction>> for Block {
     fn from(block: ethers::types::Block<ethers::types::Transaction>) -> Self {
-        Block(block)
+        Block(block, None, None)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use ethers::providers::Provider;
+    use ethers::types::Address;
     use std::sync::Arc;
 
+    use super::{Block, TransferCounts};
     use crate::extraction::blocks::get_block;
 
+    #[test]
+    fn test_authorless_block() {
+        let mut block = Block::from(ethers::types::Block {
+            number: Some(1.into()),
+            ..Default::default()
+        });
+
+        let (query, set) = block.upsert_parts();
+        assert!(!query.contains("Miner"));
+        assert!(!set.contains("Miner"));
+        assert!(set.contains(r#"uid(Block) <Block.number> "1" ."#));
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        block.serialize_dgraph(&mut serializer).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&serializer.into_inner()).unwrap();
+        assert!(json.get("Block.miner").is_none());
+
+        block.author = Some(Address::from_low_u64_be(1));
+        let (query, set) = block.upsert_parts();
+        assert!(
+            query.contains(r#"eq(Account.address, "0x0000000000000000000000000000000000000001")"#)
+        );
+        assert!(set.contains("uid(Block) <Block.miner> uid(Miner) ."));
+    }
+
+    #[test]
+    fn test_transfer_counts() {
+        let mut block = Block::from(ethers::types::Block {
+            number: Some(1.into()),
+            ..Default::default()
+        });
+        let json = |block: &Block| {
+            let mut serializer = serde_json::Serializer::new(Vec::new());
+            block.serialize_dgraph(&mut serializer).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&serializer.into_inner()).unwrap()
+        };
+        // omitted when the transfers are not extracted
+        assert!(json(&block).get("Block.erc20_transfer_count").is_none());
+        assert!(!block.upsert_parts().1.contains("transfer_count"));
+
+        block.set_transfer_counts(Some(TransferCounts {
+            erc20: 3,
+            erc721: 1,
+            erc1155: 0,
+        }));
+        let json = json(&block);
+        assert_eq!(json["Block.erc20_transfer_count"], 3);
+        assert_eq!(json["Block.erc721_transfer_count"], 1);
+        assert_eq!(json["Block.erc1155_transfer_count"], 0);
+        let (_, set) = block.upsert_parts();
+        assert!(set.contains(r#"uid(Block) <Block.erc20_transfer_count> "3" ."#));
+        assert!(set.contains(r#"uid(Block) <Block.erc1155_transfer_count> "0" ."#));
+    }
+
+    #[test]
+    fn test_blob_fields() {
+        let fixture = std::fs::read_to_string("test-data/block_post_dencun.json").unwrap();
+        let block: ethers::types::Block<ethers::types::Transaction> =
+            serde_json::from_str(&fixture).unwrap();
+        let mut block = Block::from(block);
+
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        block.serialize_dgraph(&mut serializer).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&serializer.into_inner()).unwrap();
+        assert_eq!(json["Block.blob_gas_used"], 393216);
+        assert_eq!(json["Block.excess_blob_gas"], 262144);
+        let (_, set) = block.upsert_parts();
+        assert!(set.contains(r#"uid(Block) <Block.blob_gas_used> "393216" ."#));
+        assert!(set.contains(r#"uid(Block) <Block.excess_blob_gas> "262144" ."#));
+
+        // pre-Dencun blocks have none of them
+        block.blob_gas_used = None;
+        block.excess_blob_gas = None;
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        block.serialize_dgraph(&mut serializer).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&serializer.into_inner()).unwrap();
+        assert!(json.get("Block.blob_gas_used").is_none());
+        assert!(json.get("Block.excess_blob_gas").is_none());
+        assert!(!block.upsert_parts().1.contains("blob"));
+    }
+
+    #[test]
+    fn test_uncles() {
+        // pre-merge block referencing one uncle
+        let uncle = ethers::types::H256::from_low_u64_be(0xc5);
+        let mut block = Block::from(ethers::types::Block {
+            number: Some(1000000.into()),
+            uncles: vec![uncle],
+            ..Default::default()
+        });
+
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        block.serialize_dgraph(&mut serializer).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&serializer.into_inner()).unwrap();
+        assert_eq!(
+            json["Block.uncles"],
+            serde_json::json!([format!("{:?}", uncle)])
+        );
+        let (_, set) = block.upsert_parts();
+        assert!(set.contains(&format!(r#"uid(Block) <Block.uncles> "{:?}" ."#, uncle)));
+
+        // post-merge blocks have none
+        block.uncles.clear();
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        block.serialize_dgraph(&mut serializer).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&serializer.into_inner()).unwrap();
+        assert!(json.get("Block.uncles").is_none());
+        assert!(!block.upsert_parts().1.contains("Block.uncles"));
+    }
+
     #[tokio::test]
     async fn block_serialization() {
         let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
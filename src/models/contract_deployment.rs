@@ -1,9 +1,11 @@
 use super::trace::Traces;
-use super::SerializeDgraph;
+use super::{account::account_node, OrderKey, SerializeDgraph};
 use crate::utils::metadata::{analyze_metadata, separate_metadata, Metadata};
-use crate::utils::skeleton::extract_skeleton;
+use crate::utils::rdf::escape_rdf;
+use crate::utils::skeleton::{extract_skeleton, opcodes};
 use dgraph_tonic::IClient;
 use dgraph_tonic::Mutate;
+use dgraph_tonic::Query;
 use ethabi::{ethereum_types::U64, Address};
 use ethers::providers::Middleware;
 use ethers::types::Trace;
@@ -16,22 +18,60 @@ use primitive_types::H256;
 use serde::Deserialize;
 use serde::{ser::SerializeStruct, Serialize, Serializer};
 use serde_json::json;
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 
+/// Interface id of ERC-165 itself, `supportsInterface(bytes4)`
+const ERC165_INTERFACE_ID: [u8; 4] = [0x01, 0xff, 0xc9, 0xa7];
+/// Interface id that ERC-165 contracts must not support
+const INVALID_INTERFACE_ID: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+/// ERC-165 interface ids checked with `--resolve-interfaces`
+pub const KNOWN_INTERFACES: [(&str, [u8; 4]); 7] = [
+    ("ERC-721", [0x80, 0xac, 0x58, 0xcd]),
+    ("ERC-721 Metadata", [0x5b, 0x5e, 0x13, 0x9f]),
+    ("ERC-721 Enumerable", [0x78, 0x0e, 0x9d, 0x63]),
+    ("ERC-1155", [0xd9, 0xb6, 0x7a, 0x26]),
+    ("ERC-1155 Metadata URI", [0x0e, 0x89, 0x34, 0x1c]),
+    ("ERC-2981", [0x2a, 0x55, 0x20, 0x5a]),
+    ("ERC-4906", [0x49, 0x06, 0x49, 0x06]),
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContractDeployment {
     failed: bool,
     contract_address: Address,
     creator: Address,
     tx_hash: TxHash,
+    /// Position of the creation in the call tree of the transaction
+    #[serde(default)]
+    trace_address: Vec<usize>,
     block_number: U64,
     creation_code: ethers::types::Bytes,
+    /// Store the hash of the creation code, linked to a `CreationCode` node, instead of the code
+    #[serde(default)]
+    hash_creation_code: bool,
     deployed_code: ethers::types::Bytes,
     skeleton: ethers::types::Bytes,
     metadata: Option<Metadata>,
     verified_source: Option<String>,
     name: Option<String>,
+    /// ERC-165 interface ids supported by the contract, from the known ones
+    #[serde(default)]
+    supported_interfaces: Vec<String>,
+    /// Calldata of the transaction that called the factory creating the contract
+    #[serde(default)]
+    deploy_tx_input: Option<ethers::types::Bytes>,
+    /// Number of deployments of the transaction with the same skeleton, when more than one
+    #[serde(default)]
+    batch_size: Option<usize>,
+    /// Position of the deployment among the ones of its block, in trace order
+    #[serde(default)]
+    block_index: Option<usize>,
+    /// uid of the previous deployment at the same address, if the contract was
+    /// destroyed and redeployed (e.g. with CREATE2)
+    previous_deployment: Option<String>,
 }
 
 impl From<Traces> for Vec<ContractDeployment> {
@@ -54,6 +94,7 @@ impl TryFrom<Trace> for ContractDeployment {
             return Err(());
         }
         let failed = trace.error.is_some();
+        let trace_address = trace.trace_address;
         let block_number = trace.block_number;
         let tx_hash = trace.transaction_hash.unwrap();
         let res = trace.result.unwrap();
@@ -83,13 +124,20 @@ impl TryFrom<Trace> for ContractDeployment {
             contract_address,
             creator,
             tx_hash,
+            trace_address,
             block_number: block_number.into(),
             creation_code,
+            hash_creation_code: false,
             deployed_code,
             skeleton,
             metadata,
             verified_source: None,
             name: None,
+            supported_interfaces: Vec::new(),
+            deploy_tx_input: None,
+            batch_size: None,
+            block_index: None,
+            previous_deployment: None,
         })
     }
 }
@@ -99,6 +147,11 @@ impl ContractDeployment {
         self.contract_address
     }
 
+    /// Whether the creation reverted
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
     pub fn deployed_code(&self) -> &ethers::types::Bytes {
         &self.deployed_code
     }
@@ -107,6 +160,42 @@ impl ContractDeployment {
         &self.creation_code
     }
 
+    pub fn creation_code_hash(&self) -> H256 {
+        H256::from(keccak256(&self.creation_code))
+    }
+
+    /// keccak256 of the runtime code, the `extcodehash` of the contract once deployed.
+    /// `None` for failed deployments, which leave no code at the address
+    pub fn code_hash(&self) -> Option<H256> {
+        (!self.failed).then(|| H256::from(keccak256(&self.deployed_code)))
+    }
+
+    /// Serialize the hash of the creation code instead of the code, which is then
+    /// written once per hash as a `CreationCode` node
+    pub fn set_hash_creation_code(&mut self, hash_creation_code: bool) {
+        self.hash_creation_code = hash_creation_code;
+    }
+
+    pub fn hashes_creation_code(&self) -> bool {
+        self.hash_creation_code
+    }
+
+    /// Unique key of the deployment, made of transaction hash, contract address and
+    /// position in the call tree. Used as upsert key, so storing a deployment twice
+    /// updates the same node
+    pub fn key(&self) -> String {
+        let trace_address = self
+            .trace_address
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<String>>()
+            .join("_");
+        format!(
+            "{:?}-{:?}-{}",
+            self.tx_hash, self.contract_address, trace_address
+        )
+    }
+
     pub fn skeleton_hash(&self) -> H256 {
         H256::from(keccak256(&self.skeleton))
     }
@@ -115,6 +204,14 @@ impl ContractDeployment {
         &self.skeleton
     }
 
+    /// Whether the deployed code has a CREATE (0xf0) or CREATE2 (0xf5) opcode, i.e. the contract
+    /// can deploy other contracts. The skeleton is scanned, so the metadata is left out; the
+    /// creation code isn't, since it ends with the constructor arguments.
+    /// It's a heuristic: data appended to the code (e.g. immutable tables) can look like opcodes
+    pub fn is_factory(&self) -> bool {
+        opcodes(&self.skeleton).any(|opcode| opcode == 0xf0 || opcode == 0xf5)
+    }
+
     pub async fn resolve_name<T>(&mut self, eth_client: Arc<T>) -> bool
     where
         T: Middleware,
@@ -155,6 +252,76 @@ impl ContractDeployment {
         false
     }
 
+    /// Query `supportsInterface(bytes4)` for the interface ids of KNOWN_INTERFACES, storing the
+    /// supported ones. As required by ERC-165, the contract must support `0x01ffc9a7` and not
+    /// `0xffffffff`: contracts without the method revert or return garbage, and get no interface
+    pub async fn resolve_supported_interfaces<T>(&mut self, eth_client: Arc<T>) -> bool
+    where
+        T: Middleware,
+    {
+        let abi: Abi = serde_json::from_str(
+            r#"[
+            {
+            "inputs": [
+                {
+                    "name": "interfaceId",
+                    "type": "bytes4"
+                }
+            ],
+            "name": "supportsInterface",
+            "outputs": [
+                {
+                    "name": "",
+                    "type": "bool"
+                }
+            ],
+            "stateMutability": "view",
+            "type": "function"
+        }
+        ]"#,
+        )
+        .unwrap();
+
+        let contract = ethers::contract::Contract::new(self.contract_address, abi, eth_client);
+        let supports = |interface_id: [u8; 4]| {
+            let method = contract.method::<_, bool>("supportsInterface", interface_id);
+            async move {
+                match method {
+                    Ok(method) => method.call().await.unwrap_or(false),
+                    Err(_) => false,
+                }
+            }
+        };
+
+        if !supports(ERC165_INTERFACE_ID).await || supports(INVALID_INTERFACE_ID).await {
+            return false;
+        }
+        self.supported_interfaces = vec![format!("0x{}", hex::encode(ERC165_INTERFACE_ID))];
+        for (_, interface_id) in KNOWN_INTERFACES {
+            if supports(interface_id).await {
+                self.supported_interfaces
+                    .push(format!("0x{}", hex::encode(interface_id)));
+            }
+        }
+        true
+    }
+
+    pub fn supported_interfaces(&self) -> &[String] {
+        &self.supported_interfaces
+    }
+
+    pub fn deploy_tx_input(&self) -> Option<&ethers::types::Bytes> {
+        self.deploy_tx_input.as_ref()
+    }
+
+    pub fn batch_size(&self) -> Option<usize> {
+        self.batch_size
+    }
+
+    pub fn block_index(&self) -> Option<usize> {
+        self.block_index
+    }
+
     pub fn check_verification(&mut self, scs_path: &str) {
         // search for the contract in the smart-contract-santuary-ethereum repo, cloned at scs_path
         // if found, read the source code and store it in the struct
@@ -192,18 +359,122 @@ impl ContractDeployment {
         self.verified_source = source_code;
     }
 
+    /// Look in Dgraph for a previous deployment at the same address that was destroyed before
+    /// this deployment. Returns the uid of the last previous deployment, if any.
+    pub async fn find_previous_deployment<S: IClient>(
+        &self,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let query = r#"
+            query q($address: string) {
+                q(func: eq(Account.address, $address)) {
+                    deployments: ~ContractDeployment.contract {
+                        uid
+                        block: ContractDeployment.block { number: Block.number }
+                    }
+                    destructions: ~ContractDestruction.contract @filter(NOT eq(ContractDestruction.actually_deleted, false)) {
+                        block: ContractDestruction.block { number: Block.number }
+                    }
+                }
+            }
+        "#;
+
+        #[derive(Deserialize, Debug)]
+        struct BlockItem {
+            number: u64,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct EventItem {
+            #[serde(default)]
+            uid: String,
+            block: Option<BlockItem>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct AccountItem {
+            #[serde(default)]
+            deployments: Vec<EventItem>,
+            #[serde(default)]
+            destructions: Vec<EventItem>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct QueryResult {
+            q: Vec<AccountItem>,
+        }
+
+        let mut vars = HashMap::new();
+        vars.insert("$address", format!("{:?}", self.contract_address));
+        let mut txn = dgraph_client.new_read_only_txn();
+        let res = txn.query_with_vars(query, vars).await?;
+        let res: QueryResult = serde_json::from_slice(&res.json)?;
+
+        let account = match res.q.into_iter().next() {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+        let deployments = account
+            .deployments
+            .into_iter()
+            .filter_map(|d| d.block.map(|b| (d.uid, b.number)))
+            .collect::<Vec<(String, u64)>>();
+        let destructions = account
+            .destructions
+            .into_iter()
+            .filter_map(|d| d.block.map(|b| b.number))
+            .collect::<Vec<u64>>();
+
+        Ok(previous_deployment(
+            &deployments,
+            &destructions,
+            self.block_number.as_u64(),
+        ))
+    }
+
+    pub fn set_previous_deployment(&mut self, uid: String) {
+        self.previous_deployment = Some(uid);
+    }
+
     /// Upsert the contract deployment in the graph database
-    /// it also manage the skeleton and its decompilation
+    /// it also manage the skeleton and its decompilation.
+    /// The deployment is matched by its key, upserting it again updates the existing node
     pub async fn upsert<S: IClient>(
         &self,
         skeleton_uid: &str,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
     ) -> Result<(), anyhow::Error> {
-        // WARNING:
-        // Deployments don't have a unique identifier
-        // upserting already existing deployment will result in a duplicate
-        // This function should be called just after checking if the deployment
-        // of a certain block already exists, or after deleting them using Block::upsert_delete_deployments
+        let block_no = self.block_number.as_u64();
+        let contract_address = format!("{:?}", self.contract_address);
+        let creator_address = format!("{:?}", self.creator);
+        let key = self.key();
+
+        // Query part of the upsert
+        let query = format!(
+            r#"
+            query{{
+            var(func: eq(Block.number, {block_no})) {{ Block as uid }}
+            var(func: eq(Account.address, "{contract_address}")) {{ Address as uid }}
+            var(func: eq(Account.address, "{creator_address}")) {{ Creator as uid }}
+            var(func: eq(ContractDeployment.key, "{key}")) {{ Deployment as uid }}
+            }}
+        "#,
+            block_no = block_no,
+            contract_address = contract_address,
+            creator_address = creator_address,
+            key = key
+        );
+
+        // Perform the upsert
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_set_nquads(self.upsert_nquads(skeleton_uid));
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await
+    }
+
+    /// Mutation part of the upsert, free-form strings are escaped
+    fn upsert_nquads(&self, skeleton_uid: &str) -> String {
         let block_no = self.block_number.as_u64();
         let contract_address = format!("{:?}", self.contract_address);
         let creator_address = format!("{:?}", self.creator);
@@ -211,6 +482,7 @@ impl ContractDeployment {
         let deployed_code = self.deployed_code().to_string();
         let failed_deploy = self.failed;
         let tx_hash = format!("{:?}", self.tx_hash);
+        let key = self.key();
         let verified_source = self.verified_source.is_some();
         let verified_source_code = self.verified_source.as_ref();
         let name = self.name.as_ref();
@@ -231,20 +503,6 @@ impl ContractDeployment {
             (None, None, None, None)
         };
 
-        // Query part of the upsert
-        let query = format!(
-            r#"
-            query{{
-            var(func: eq(Block.number, {block_no})) {{ Block as uid }}
-            var(func: eq(Account.address, "{contract_address}")) {{ Address as uid }}
-            var(func: eq(Account.address, "{creator_address}")) {{ Creator as uid }}
-            }}
-        "#,
-            block_no = block_no,
-            contract_address = contract_address,
-            creator_address = creator_address
-        );
-
         // Mutation part of the upsert
         let mut set = format!(
             r#"
@@ -252,16 +510,18 @@ impl ContractDeployment {
             uid(Address) <Account.address> "{contract_address}" .
             uid(Creator) <Account.address> "{creator_address}" .
 
-            _:deployment <ContractDeployment.contract> uid(Address) .
-            _:deployment <ContractDeployment.creator> uid(Creator) .
-            _:deployment <ContractDeployment.block> uid(Block) .
-            _:deployment <dgraph.type> "ContractDeployment" .
-            _:deployment <ContractDeployment.creation_code> "{creation_code}" .
-            _:deployment <ContractDeployment.deployed_code> "{deployed_code}" .
-            _:deployment <ContractDeployment.failed> "{failed_deploy}" .
-            _:deployment <ContractDeployment.tx_hash> "{tx_hash}" .
-            _:deployment <ContractDeployment.verified_source> "{verified_source}" .
-            _:deployment <ContractDeployment.skeleton> <{skeleton_uid}> .
+            uid(Deployment) <ContractDeployment.key> "{key}" .
+            uid(Deployment) <ContractDeployment.contract> uid(Address) .
+            uid(Deployment) <ContractDeployment.creator> uid(Creator) .
+            uid(Deployment) <ContractDeployment.block> uid(Block) .
+            uid(Deployment) <dgraph.type> "ContractDeployment" .
+            uid(Deployment) <ContractDeployment.creation_code> "{creation_code}" .
+            uid(Deployment) <ContractDeployment.deployed_code> "{deployed_code}" .
+            uid(Deployment) <ContractDeployment.failed> "{failed_deploy}" .
+            uid(Deployment) <ContractDeployment.tx_hash> "{tx_hash}" .
+            uid(Deployment) <ContractDeployment.verified_source> "{verified_source}" .
+            uid(Deployment) <ContractDeployment.is_factory> "{is_factory}" .
+            uid(Deployment) <ContractDeployment.skeleton> <{skeleton_uid}> .
 
         "#,
             block_no = block_no,
@@ -271,67 +531,124 @@ impl ContractDeployment {
             deployed_code = deployed_code,
             failed_deploy = failed_deploy,
             tx_hash = tx_hash,
+            key = key,
             verified_source = verified_source,
+            is_factory = self.is_factory(),
             skeleton_uid = skeleton_uid
         );
+        if !failed_deploy {
+            set.push_str(
+                r#"uid(Address) <Account.is_contract> "true" .
+            "#,
+            );
+        }
+
+        if let Some(code_hash) = self.code_hash() {
+            set.push_str(&format!(
+                r#"
+                uid(Deployment) <ContractDeployment.code_hash> "{code_hash:?}" .
+                "#,
+                code_hash = code_hash
+            ));
+        }
 
         if name.is_some() {
             set.push_str(&format!(
                 r#"
-                _:deployment <ContractDeployment.name> "{name}" .
+                uid(Deployment) <ContractDeployment.name> "{name}" .
                 "#,
-                name = name.unwrap()
+                name = escape_rdf(name.unwrap())
+            ));
+        }
+
+        if let Some(deploy_tx_input) = &self.deploy_tx_input {
+            set.push_str(&format!(
+                r#"
+                uid(Deployment) <ContractDeployment.deploy_tx_input> "{deploy_tx_input}" .
+                "#,
+                deploy_tx_input = deploy_tx_input
+            ));
+        }
+
+        if let Some(batch_size) = self.batch_size {
+            set.push_str(&format!(
+                r#"
+                uid(Deployment) <ContractDeployment.batch_size> "{batch_size}" .
+                "#,
+                batch_size = batch_size
+            ));
+        }
+
+        if let Some(block_index) = self.block_index {
+            set.push_str(&format!(
+                r#"
+                uid(Deployment) <ContractDeployment.block_index> "{block_index}" .
+                "#,
+                block_index = block_index
+            ));
+        }
+
+        for interface_id in &self.supported_interfaces {
+            set.push_str(&format!(
+                r#"
+                uid(Deployment) <ContractDeployment.supported_interfaces> "{interface_id}" .
+                "#,
+                interface_id = interface_id
             ));
         }
 
         if solc.is_some() {
             set.push_str(&format!(
                 r#"
-                _:deployment <ContractDeployment.solc_version> "{solc}" .
+                uid(Deployment) <ContractDeployment.solc_version> "{solc}" .
                 "#,
-                solc = solc.unwrap()
+                solc = escape_rdf(solc.unwrap())
             ));
         }
         if storage_protocol.is_some() {
             set.push_str(&format!(
                 r#"
-                _:deployment <ContractDeployment.storage_protocol> "{storage_protocol}" .
+                uid(Deployment) <ContractDeployment.storage_protocol> "{storage_protocol}" .
                 "#,
-                storage_protocol = storage_protocol.unwrap()
+                storage_protocol = escape_rdf(storage_protocol.unwrap())
             ));
         }
         if storage_address.is_some() {
             set.push_str(&format!(
                 r#"
-                _:deployment <ContractDeployment.storage_address> "{storage_address}" .
+                uid(Deployment) <ContractDeployment.storage_address> "{storage_address}" .
                 "#,
-                storage_address = storage_address.unwrap()
+                storage_address = escape_rdf(storage_address.unwrap())
             ));
         }
         if experimental.is_some() {
             set.push_str(&format!(
                 r#"
-                _:deployment <ContractDeployment.experimental> "{experimental}" .
+                uid(Deployment) <ContractDeployment.experimental> "{experimental}" .
                 "#,
                 experimental = experimental.unwrap()
             ));
         }
+        if let Some(previous_deployment) = &self.previous_deployment {
+            set.push_str(&format!(
+                r#"
+                uid(Deployment) <ContractDeployment.redeployment> "true" .
+                uid(Deployment) <ContractDeployment.previous_deployment> <{previous_deployment}> .
+                "#,
+                previous_deployment = previous_deployment
+            ));
+        }
         if verified_source_code.is_some() {
             let source_code = verified_source_code.unwrap();
             set.push_str(&format!(
                 r#"
-                _:deployment <ContractDeployment.verified_source_code> "{verified_source_code}" .
+                uid(Deployment) <ContractDeployment.verified_source_code> "{verified_source_code}" .
                 "#,
-                verified_source_code = source_code
+                verified_source_code = escape_rdf(source_code)
             ));
         }
 
-        // Perform the upsert
-        let mut mu = dgraph_tonic::Mutation::new();
-        mu.set_set_nquads(set);
-        let mut txn = dgraph_client.new_mutated_txn();
-        txn.upsert(query, mu).await?;
-        txn.commit().await
+        set
     }
 
     fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -343,25 +660,33 @@ impl ContractDeployment {
             uid: String,
         }
         let mut state = serializer.serialize_struct("ContractDeployment", 7)?;
+        state.serialize_field("uid", &format!("_:dep{}", self.key()))?;
         state.serialize_field("dgraph.type", &json!(["ContractDeployment"]))?;
+        state.serialize_field("ContractDeployment.key", &self.key())?;
         state.serialize_field(
             "ContractDeployment.contract",
-            &json!({
-                "uid": format!("_:{:?}", self.contract_address),
-                "dgraph.type": "Account",
-                "Account.address": format!("{:?}", self.contract_address),
-                "Account.is_contract": &true,
-            }),
+            // a reverted creation leaves no code at the address
+            &account_node(&self.contract_address, !self.failed),
         )?;
-        state.serialize_field("ContractDeployment.creation_bytecode", self.creation_code())?;
+        if self.hash_creation_code {
+            let hash = self.creation_code_hash();
+            state.serialize_field("ContractDeployment.creation_code_hash", &hash)?;
+            state.serialize_field(
+                "ContractDeployment.init_code",
+                &Uid {
+                    uid: format!("_:cc{:?}", hash),
+                },
+            )?;
+        } else {
+            state.serialize_field("ContractDeployment.creation_bytecode", self.creation_code())?;
+        }
         state.serialize_field("ContractDeployment.deployed_bytecode", self.deployed_code())?;
+        if let Some(code_hash) = self.code_hash() {
+            state.serialize_field("ContractDeployment.code_hash", &code_hash)?;
+        }
         state.serialize_field(
             "ContractDeployment.creator",
-            &json!({
-                "uid": format!("_:{:?}", self.creator),
-                "dgraph.type": ["Account"],
-                "Account.address": format!("{:?}", self.creator)
-            }),
+            &account_node(&self.creator, false),
         )?;
         state.serialize_field(
             "ContractDeployment.block",
@@ -370,6 +695,7 @@ impl ContractDeployment {
             },
         )?;
         state.serialize_field("ContractDeployment.failed_deploy", &self.failed)?;
+        state.serialize_field("ContractDeployment.is_factory", &self.is_factory())?;
         state.serialize_field("ContractDeployment.tx_hash", &self.tx_hash)?;
         let skeleton_key = H256::from(keccak256(&self.skeleton));
         state.serialize_field(
@@ -389,6 +715,21 @@ impl ContractDeployment {
         if self.name.is_some() {
             state.serialize_field("ContractDeployment.name", self.name.as_ref().unwrap())?;
         }
+        if let Some(deploy_tx_input) = &self.deploy_tx_input {
+            state.serialize_field("ContractDeployment.deploy_tx_input", deploy_tx_input)?;
+        }
+        if let Some(batch_size) = self.batch_size {
+            state.serialize_field("ContractDeployment.batch_size", &batch_size)?;
+        }
+        if let Some(block_index) = self.block_index {
+            state.serialize_field("ContractDeployment.block_index", &block_index)?;
+        }
+        if !self.supported_interfaces.is_empty() {
+            state.serialize_field(
+                "ContractDeployment.supported_interfaces",
+                &self.supported_interfaces,
+            )?;
+        }
         if self.metadata.is_some() {
             let metadata = self.metadata.as_ref().unwrap();
             if metadata.compiler.is_some() {
@@ -408,6 +749,71 @@ impl ContractDeployment {
     }
 }
 
+/// Set the calldata of the transaction behind each deployment made by a contract, from the
+/// transactions of the block. Contracts created by a transaction without recipient get nothing:
+/// the transaction input is their creation code, already stored
+pub fn link_deploy_tx_inputs(
+    deployments: &mut [ContractDeployment],
+    transactions: &[ethers::types::Transaction],
+) {
+    let inputs: HashMap<TxHash, &ethers::types::Bytes> = transactions
+        .iter()
+        .filter(|tx| tx.to.is_some())
+        .map(|tx| (tx.hash, &tx.input))
+        .collect();
+    for deployment in deployments {
+        if let Some(input) = inputs.get(&deployment.tx_hash) {
+            deployment.deploy_tx_input = Some((*input).clone());
+        }
+    }
+}
+
+/// Set the batch size of the deployments sharing their skeleton with other deployments of the
+/// same transaction, as airdrop and spam campaigns do deploying many copies of a contract.
+/// Failed deployments have no code, they're never part of a batch
+pub fn count_batch_deploys(deployments: &mut [ContractDeployment]) {
+    let mut batches: HashMap<(TxHash, H256), usize> = HashMap::new();
+    for deployment in deployments.iter().filter(|d| !d.failed) {
+        *batches
+            .entry((deployment.tx_hash, deployment.skeleton_hash()))
+            .or_default() += 1;
+    }
+    for deployment in deployments.iter_mut().filter(|d| !d.failed) {
+        let batch_size = batches[&(deployment.tx_hash, deployment.skeleton_hash())];
+        if batch_size > 1 {
+            deployment.batch_size = Some(batch_size);
+        }
+    }
+}
+
+/// Number the deployments of a block in the order of their traces, i.e. the order in which they
+/// were executed. They must be all the deployments of the block, before any of them is dropped
+pub fn index_block_deployments(deployments: &mut [ContractDeployment]) {
+    for (i, deployment) in deployments.iter_mut().enumerate() {
+        deployment.block_index = Some(i);
+    }
+}
+
+/// Given the deployments (uid, block number) and the destruction blocks of an address,
+/// return the last deployment before `block` that was followed by a destruction,
+/// i.e. the deployment replaced by a redeployment happening at `block`.
+fn previous_deployment(
+    deployments: &[(String, u64)],
+    destructions: &[u64],
+    block: u64,
+) -> Option<String> {
+    deployments
+        .iter()
+        .filter(|(_, deployed_at)| *deployed_at < block)
+        .filter(|(_, deployed_at)| {
+            destructions
+                .iter()
+                .any(|destroyed_at| destroyed_at >= deployed_at && *destroyed_at <= block)
+        })
+        .max_by_key(|(_, deployed_at)| *deployed_at)
+        .map(|(uid, _)| uid.clone())
+}
+
 impl SerializeDgraph for ContractDeployment {
     fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -417,16 +823,349 @@ impl SerializeDgraph for ContractDeployment {
     }
 }
 
+impl OrderKey for ContractDeployment {
+    /// Deployments are sorted by block number, then by transaction hash and contract address
+    fn order_key(&self) -> (u64, String) {
+        (
+            self.block_number.as_u64(),
+            format!("{:?}{:?}", self.tx_hash, self.contract_address),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::utils::decompile::decompile;
+    use crate::utils::decompile::{decompile, default_tmp_dir};
     use crate::{
-        extraction::traces::get_traces,
-        models::{block::Block, contract_deployment::ContractDeployment, skeleton::Skeleton},
+        extraction::traces::{get_traces, TraceMethod},
+        models::{
+            block::Block, contract_deployment::ContractDeployment, skeleton::Skeleton,
+            trace::TraceBuilder,
+        },
     };
+    use dgraph_tonic::Query;
     use ethers::providers::Provider;
+    use ethers::types::{Action, Bytes, Create, CreateResult, Res, Trace};
+    use ethers::utils::keccak256;
+    use primitive_types::H256;
     use std::sync::Arc;
 
+    /// Creation trace in block 10, with the given result, transaction, position in the call tree
+    /// and error
+    fn creation_trace(
+        result: CreateResult,
+        tx: u64,
+        trace_address: Vec<usize>,
+        error: Option<&str>,
+    ) -> Trace {
+        let creation = TraceBuilder::new(Action::Create(Create::default()))
+            .result(Res::Create(result))
+            .block(10)
+            .tx(tx)
+            .trace_address(trace_address);
+        match error {
+            Some(error) => creation.error(error).build(),
+            None => creation.build(),
+        }
+    }
+
+    /// Result of a creation deploying `code`
+    fn deployed(code: &str) -> CreateResult {
+        CreateResult {
+            code: code.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_failed_deployment() {
+        let creation = |error| creation_trace(CreateResult::default(), 0, vec![], error);
+        let traces =
+            crate::models::trace::Traces::from(vec![creation(None), creation(Some("Reverted"))]);
+
+        let mut deployments: Vec<ContractDeployment> = Vec::from(traces);
+        assert_eq!(deployments.len(), 2);
+        assert!(!deployments[0].failed());
+        assert!(deployments[1].failed());
+
+        // what --skip-failed-deployments keeps
+        deployments.retain(|d| !d.failed());
+        assert_eq!(deployments.len(), 1);
+    }
+
+    #[test]
+    fn test_previous_deployment() {
+        // deployed at 10, destroyed at 20, redeployed at 30, destroyed at 40, redeployed at 50
+        let deployments = vec![
+            ("0x1".to_string(), 10),
+            ("0x2".to_string(), 30),
+            ("0x3".to_string(), 50),
+        ];
+        let destructions = vec![20, 40];
+        assert_eq!(
+            super::previous_deployment(&deployments, &destructions, 30),
+            Some("0x1".to_string())
+        );
+        assert_eq!(
+            super::previous_deployment(&deployments, &destructions, 50),
+            Some("0x2".to_string())
+        );
+        // first deployment, or deployment never destroyed
+        assert_eq!(
+            super::previous_deployment(&deployments, &destructions, 10),
+            None
+        );
+        assert_eq!(super::previous_deployment(&deployments[..1], &[], 30), None);
+    }
+
+    #[test]
+    fn test_upsert_escaping() {
+        let deployment = ContractDeployment {
+            failed: false,
+            contract_address: Default::default(),
+            creator: Default::default(),
+            tx_hash: Default::default(),
+            trace_address: vec![],
+            block_number: 1.into(),
+            creation_code: Default::default(),
+            hash_creation_code: false,
+            deployed_code: Default::default(),
+            skeleton: Default::default(),
+            metadata: None,
+            verified_source: Some("contract A {\n    string s = \"\\\";\n}".to_string()),
+            name: Some("My \"Token\"".to_string()),
+            supported_interfaces: vec!["0x80ac58cd".to_string()],
+            deploy_tx_input: None,
+            batch_size: None,
+            block_index: None,
+            previous_deployment: None,
+        };
+
+        let nquads = deployment.upsert_nquads("0x1");
+        assert!(!nquads.contains("_:deployment"));
+        assert!(nquads.contains(&format!(
+            r#"uid(Deployment) <ContractDeployment.key> "{}" ."#,
+            deployment.key()
+        )));
+        assert!(nquads.contains(r#"<ContractDeployment.name> "My \"Token\"" ."#));
+        assert!(nquads.contains(r#"<ContractDeployment.supported_interfaces> "0x80ac58cd" ."#));
+        assert!(nquads.contains(
+            r#"<ContractDeployment.verified_source_code> "contract A {\n    string s = \"\\\";\n}" ."#
+        ));
+    }
+
+    #[test]
+    fn test_link_deploy_tx_inputs() {
+        let factory_call = ethers::types::Transaction {
+            hash: ethers::types::TxHash::from_low_u64_be(1),
+            to: Some(ethers::types::Address::from_low_u64_be(0xfac)),
+            // createPair(address,address) of the Uniswap V2 factory
+            input: ethers::types::Bytes::from(vec![0xc9, 0xc6, 0x53, 0x96, 0x01, 0x02]),
+            ..Default::default()
+        };
+        let direct_creation = ethers::types::Transaction {
+            hash: ethers::types::TxHash::from_low_u64_be(2),
+            to: None,
+            input: ethers::types::Bytes::from(vec![0x60, 0x80]),
+            ..Default::default()
+        };
+        let creation =
+            |tx, trace_address| creation_trace(CreateResult::default(), tx, trace_address, None);
+        let traces = crate::models::trace::Traces::from(vec![
+            creation(1, vec![0]),
+            creation(2, vec![]),
+            // transaction not in the block data
+            creation(3, vec![0]),
+        ]);
+        let mut deployments: Vec<ContractDeployment> = Vec::from(traces);
+
+        super::link_deploy_tx_inputs(&mut deployments, &[factory_call.clone(), direct_creation]);
+        assert_eq!(deployments[0].deploy_tx_input(), Some(&factory_call.input));
+        assert_eq!(deployments[1].deploy_tx_input(), None);
+        assert_eq!(deployments[2].deploy_tx_input(), None);
+
+        let json = deployments[0]
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["ContractDeployment.deploy_tx_input"], "0xc9c653960102");
+        let json = deployments[1]
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert!(json.get("ContractDeployment.deploy_tx_input").is_none());
+        assert!(deployments[0]
+            .upsert_nquads("0x1")
+            .contains(r#"<ContractDeployment.deploy_tx_input> "0xc9c653960102" ."#));
+    }
+
+    #[test]
+    fn test_batch_deploys() {
+        let creation = |tx, code: &[u8], failed: bool| {
+            let result = CreateResult {
+                code: code.to_vec().into(),
+                ..Default::default()
+            };
+            creation_trace(result, tx, vec![0], failed.then_some("Reverted"))
+        };
+        // the same token deployed three times by an airdrop transaction, with different
+        // constants, next to another contract and a reverted copy
+        let token = |supply: u8| vec![0x60, supply, 0x60, 0x00, 0x55, 0x00];
+        let traces = crate::models::trace::Traces::from(vec![
+            creation(1, &token(1), false),
+            creation(1, &token(2), false),
+            creation(1, &[0x60, 0x80, 0x00], false),
+            creation(1, &token(3), false),
+            creation(1, &token(5), true),
+            // a copy deployed by another transaction
+            creation(2, &token(4), false),
+        ]);
+        let mut deployments: Vec<ContractDeployment> = Vec::from(traces);
+        assert_eq!(deployments.len(), 6);
+
+        super::index_block_deployments(&mut deployments);
+        assert_eq!(
+            deployments
+                .iter()
+                .map(|d| d.block_index())
+                .collect::<Vec<_>>(),
+            (0..6).map(Some).collect::<Vec<_>>()
+        );
+
+        super::count_batch_deploys(&mut deployments);
+        assert_eq!(
+            deployments
+                .iter()
+                .map(|d| d.batch_size())
+                .collect::<Vec<_>>(),
+            vec![Some(3), Some(3), None, Some(3), None, None]
+        );
+
+        let json = deployments[0]
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["ContractDeployment.batch_size"], 3);
+        assert_eq!(json["ContractDeployment.block_index"], 0);
+        let json = deployments[2]
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert!(json.get("ContractDeployment.batch_size").is_none());
+        assert!(deployments[1]
+            .upsert_nquads("0x1")
+            .contains(r#"<ContractDeployment.batch_size> "3" ."#));
+        assert!(deployments[3]
+            .upsert_nquads("0x1")
+            .contains(r#"<ContractDeployment.block_index> "3" ."#));
+    }
+
+    #[test]
+    fn test_deployment_key() {
+        let creation =
+            |trace_address| creation_trace(CreateResult::default(), 0, trace_address, None);
+        let traces = crate::models::trace::Traces::from(vec![
+            creation(vec![]),
+            creation(vec![0, 1]),
+            creation(vec![]),
+        ]);
+        let deployments: Vec<ContractDeployment> = Vec::from(traces);
+
+        // same transaction and address, different position in the call tree
+        assert_ne!(deployments[0].key(), deployments[1].key());
+        assert!(deployments[1].key().ends_with("-0_1"));
+        // the same creation always gets the same key
+        assert_eq!(deployments[0].key(), deployments[2].key());
+    }
+
+    #[test]
+    fn test_is_factory() {
+        let deployment = |code| {
+            ContractDeployment::try_from(creation_trace(deployed(code), 0, vec![], None)).unwrap()
+        };
+        // clone factory: stores the EIP-1167 creation code in memory and runs
+        // create(0, 0x09, 0x37) on it
+        let factory = deployment(
+            "0x7f3d602d80600a3d3981f3363d3d373d3d3d363d730000000000000000000000006000526037600960\
+             00f000",
+        );
+        assert!(factory.is_factory());
+        // the EIP-1167 proxy it deploys, CREATE bytes in the pushed address are not opcodes
+        let proxy = deployment(
+            "0x363d3d373d3d3d363d73f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f05af43d82803e903d91\
+             602b57fd5bf3",
+        );
+        assert!(!proxy.is_factory());
+
+        let json = factory
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["ContractDeployment.is_factory"], true);
+        assert!(proxy
+            .upsert_nquads("0x1")
+            .contains(r#"<ContractDeployment.is_factory> "false" ."#));
+    }
+
+    #[test]
+    fn test_code_hash() {
+        let deployment = |code, error| {
+            ContractDeployment::try_from(creation_trace(deployed(code), 0, vec![], error)).unwrap()
+        };
+        // EIP-1167 proxy, its metadata-free code is also its skeleton
+        let code =
+            "0x363d3d373d3d3d363d73bebebebebebebebebebebebebebebebebebebebe5af43d82803e903d91\
+                    602b57fd5bf3";
+        let proxy = deployment(code, None);
+        let expected = H256::from(keccak256(code.parse::<Bytes>().unwrap()));
+        assert_eq!(proxy.code_hash(), Some(expected));
+
+        let json = proxy
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(
+            json["ContractDeployment.code_hash"],
+            format!("{:?}", expected)
+        );
+        assert!(proxy.upsert_nquads("0x1").contains(&format!(
+            r#"<ContractDeployment.code_hash> "{:?}" ."#,
+            expected
+        )));
+
+        // a reverted creation leaves no code to hash
+        let failed = deployment("0x", Some("Reverted"));
+        assert_eq!(failed.code_hash(), None);
+        let json = failed
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert!(json.get("ContractDeployment.code_hash").is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_deployment_upsert_twice() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let eth_endpoint = std::env::var("ETH_NODE").expect("Ethereum endpoint");
+
+        let eth_client = Arc::new(Provider::try_from(eth_endpoint).unwrap());
+        let dgraph = dgraph_tonic::Client::new(dgraph_endpoint).expect("Dgraph client");
+
+        let block = 16075682;
+        let traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
+        let deployments: Vec<ContractDeployment> = Vec::from(traces);
+        let deployment = deployments.first().expect("a deployment");
+
+        let skeleton = Skeleton::new(deployment.skeleton().clone());
+        let uid = skeleton.upsert(&dgraph).await.unwrap();
+        deployment.upsert(&uid, &dgraph).await.unwrap();
+        deployment.upsert(&uid, &dgraph).await.unwrap();
+
+        let query = format!(
+            r#"{{ q(func: eq(ContractDeployment.key, "{}")) {{ count(uid) }} }}"#,
+            deployment.key()
+        );
+        let res = dgraph.new_read_only_txn().query(query).await.unwrap();
+        let res: serde_json::Value = serde_json::from_slice(&res.json).unwrap();
+        assert_eq!(res["q"][0]["count"], 1);
+    }
+
     #[tokio::test]
     async fn test_source_verification() {
         let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
@@ -435,7 +1174,9 @@ mod tests {
 
         let block = 16075682;
 
-        let creation_traces = get_traces(block, eth_client).await.unwrap();
+        let creation_traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
         let deployments: Vec<ContractDeployment> = Vec::from(creation_traces);
 
         assert_eq!(deployments.len(), 1);
@@ -449,6 +1190,37 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_resolve_supported_interfaces() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+        let eth_client = Arc::new(Provider::try_from(eth_node).unwrap());
+
+        let deployment = |address: &str| {
+            let result = CreateResult {
+                address: address.parse().unwrap(),
+                ..Default::default()
+            };
+            ContractDeployment::try_from(creation_trace(result, 0, vec![], None)).unwrap()
+        };
+
+        // BAYC, an OpenZeppelin ERC-721
+        let mut bayc = deployment("0xBC4CA0EdA7647A8aB7C2061c2E118A18a936f13D");
+        assert!(bayc.resolve_supported_interfaces(eth_client.clone()).await);
+        for interface_id in ["0x01ffc9a7", "0x80ac58cd", "0x5b5e139f", "0x780e9d63"] {
+            assert!(bayc
+                .supported_interfaces()
+                .contains(&interface_id.to_string()));
+        }
+        assert!(!bayc
+            .supported_interfaces()
+            .contains(&"0xd9b67a26".to_string()));
+
+        // WETH doesn't implement ERC-165, the call ends in its fallback
+        let mut weth = deployment("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
+        assert!(!weth.resolve_supported_interfaces(eth_client.clone()).await);
+        assert!(weth.supported_interfaces().is_empty());
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_deployment_serialization() {
@@ -458,7 +1230,9 @@ mod tests {
 
         let block = 4719568; // weth creation block
 
-        let creation_traces = get_traces(block, eth_client.clone()).await.unwrap();
+        let creation_traces = get_traces(block, eth_client.clone(), TraceMethod::Parity)
+            .await
+            .unwrap();
         let deployments: Vec<ContractDeployment> = Vec::from(creation_traces);
 
         for mut deployment in deployments {
@@ -487,7 +1261,9 @@ mod tests {
             .await
             .unwrap();
         // 2: Get the deplyments of that block from traces
-        let traces = get_traces(block, eth_client).await.unwrap();
+        let traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
         let deployments: Vec<ContractDeployment> = Vec::from(traces);
 
         // For each deployment:
@@ -496,7 +1272,10 @@ mod tests {
             let decompiled_skeleton = decompile(
                 &deployment.contract_address(),
                 &deployment.deployed_code(),
+                &default_tmp_dir(),
                 5000,
+                false,
+                false,
             )
             .await;
 
@@ -504,7 +1283,7 @@ mod tests {
 
             match decompiled_skeleton {
                 Ok(decompiled_skeleton) => {
-                    skeleton.set_abi(decompiled_skeleton);
+                    skeleton.set_abi(decompiled_skeleton.abi);
                 }
                 Err(_) => {
                     skeleton.set_failed_decompilation(true);
@@ -1,12 +1,19 @@
 use super::trace::Traces;
-use super::SerializeDgraph;
+use super::{account::account_node, OrderKey, SerializeDgraph};
 use dgraph_tonic::{IClient, Mutate};
 use ethabi::{ethereum_types::U256, Address};
-use ethers::types::Trace;
 use ethers::types::TxHash;
+use ethers::types::{Action, Res, Trace};
 use serde::Deserialize;
 use serde::{ser::SerializeStruct, Serialize, Serializer};
 use serde_json::json;
+use std::collections::HashSet;
+
+/// First mainnet block of the Cancun fork. From here on, with EIP-6780, SELFDESTRUCT deletes
+/// the contract only if it was created in the same transaction, otherwise it just sends its balance.
+/// Mainnet only: other chains activated Cancun at other heights, and the destructions between
+/// their fork block and this one get the wrong `actually_deleted`
+pub const CANCUN_BLOCK: u64 = 19_426_587;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContractDestruction {
@@ -16,13 +23,35 @@ pub struct ContractDestruction {
     block_number: u64,
     balance_left: U256,
     refounded_address: Address,
+    /// False if the code and storage of the contract were left in place, see `CANCUN_BLOCK`
+    #[serde(default = "default_actually_deleted")]
+    actually_deleted: bool,
+}
+
+fn default_actually_deleted() -> bool {
+    true
 }
 
 impl From<&Traces> for Vec<ContractDestruction> {
     fn from(traces: &Traces) -> Self {
+        // contracts successfully created in the block, by transaction
+        let created: HashSet<(TxHash, Address)> = traces
+            .0
+            .iter()
+            .filter(|t| t.error.is_none())
+            .filter_map(|t| match (&t.action, &t.result, t.transaction_hash) {
+                (Action::Create(_), Some(Res::Create(r)), Some(tx_hash)) => {
+                    Some((tx_hash, r.address))
+                }
+                _ => None,
+            })
+            .collect();
+
         let mut destructions = Vec::new();
         for trace in &traces.0 {
-            if let Ok(destruction) = ContractDestruction::try_from(trace) {
+            if let Ok(mut destruction) = ContractDestruction::try_from(trace) {
+                destruction.actually_deleted = destruction.block_number < CANCUN_BLOCK
+                    || created.contains(&(destruction.tx_hash, destruction.contract_address));
                 destructions.push(destruction);
             }
         }
@@ -35,14 +64,14 @@ impl TryFrom<&Trace> for ContractDestruction {
 
     fn try_from(trace: &Trace) -> Result<Self, Self::Error> {
         let (contract_address, balance_left, refounded_address) = match &trace.action {
-            ethers::types::Action::Call(_) => return Err(()),
-            ethers::types::Action::Create(_) => return Err(()),
-            ethers::types::Action::Suicide(s) => (
+            Action::Call(_) => return Err(()),
+            Action::Create(_) => return Err(()),
+            Action::Suicide(s) => (
                 s.address.clone(),
                 s.balance.clone(),
                 s.refund_address.clone(),
             ),
-            ethers::types::Action::Reward(_) => return Err(()),
+            Action::Reward(_) => return Err(()),
         };
         let failed = trace.error.is_some();
         let block_number = trace.block_number;
@@ -54,6 +83,7 @@ impl TryFrom<&Trace> for ContractDestruction {
             block_number,
             balance_left,
             refounded_address,
+            actually_deleted: true,
         })
     }
 }
@@ -63,6 +93,11 @@ impl ContractDestruction {
         self.contract_address
     }
 
+    /// Whether the contract was deleted, always true before Cancun
+    pub fn actually_deleted(&self) -> bool {
+        self.actually_deleted
+    }
+
     pub async fn upsert<S: IClient>(
         &self,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
@@ -71,6 +106,7 @@ impl ContractDestruction {
         let balance_left = &self.balance_left;
         let tx_hash = format!("{:?}", self.tx_hash);
         let failed = self.failed;
+        let actually_deleted = self.actually_deleted;
         let refound_address = format!("{:?}", self.refounded_address);
         let block_number = self.block_number;
 
@@ -107,6 +143,7 @@ impl ContractDestruction {
             _:destr <ContractDestruction.balance_left> "{balance_left}" .
             _:destr <ContractDestruction.tx_hash> "{tx_hash}" .
             _:destr <ContractDestruction.failed> "{failed}" .
+            _:destr <ContractDestruction.actually_deleted> "{actually_deleted}" .
             _:destr <ContractDestruction.refound_address> uid(Refound) .
             _:destr <ContractDestruction.block> uid(Block) .
         "#,
@@ -114,6 +151,7 @@ impl ContractDestruction {
             tx_hash = tx_hash,
             balance_left = balance_left,
             failed = failed,
+            actually_deleted = actually_deleted,
             contract_address = contract_address,
             refound_address = refound_address
         );
@@ -137,23 +175,18 @@ impl ContractDestruction {
         state.serialize_field("dgraph.type", &json!(["ContractDestruction"]))?;
         state.serialize_field(
             "ContractDestruction.contract",
-            &json!({
-                "uid": format!("_:{:?}", self.contract_address),
-                "dgraph.type": "Account",
-                "Account.address": format!("{:?}", self.contract_address),
-                "Account.is_contract": true,
-            }),
+            &account_node(&self.contract_address, true),
         )?;
         state.serialize_field("ContractDestruction.balance_left", &self.balance_left)?;
         state.serialize_field("ContractDestruction.tx_hash", &self.tx_hash)?;
         state.serialize_field("ContractDestruction.failed", &self.failed)?;
+        state.serialize_field(
+            "ContractDestruction.actually_deleted",
+            &self.actually_deleted,
+        )?;
         state.serialize_field(
             "ContractDestruction.refound_address",
-            &json!({
-                "uid": format!("_:{:?}", self.refounded_address),
-                "dgraph.type": ["Account"],
-                "Account.address": format!("{:?}", self.refounded_address)
-            }),
+            &account_node(&self.refounded_address, false),
         )?;
         state.serialize_field(
             "ContractDestruction.block",
@@ -174,14 +207,78 @@ impl SerializeDgraph for ContractDestruction {
     }
 }
 
+impl OrderKey for ContractDestruction {
+    /// Destructions are sorted by block number, then by transaction hash and contract address
+    fn order_key(&self) -> (u64, String) {
+        (
+            self.block_number,
+            format!("{:?}{:?}", self.tx_hash, self.contract_address),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        extraction::traces::get_traces, models::contract_destruction::ContractDestruction,
+        extraction::traces::{get_traces, TraceMethod},
+        models::{contract_destruction::ContractDestruction, trace::TraceBuilder},
     };
     use ethers::providers::Provider;
+    use ethers::types::{Action, Address, Create, CreateResult, Res, Suicide, Trace};
     use std::sync::Arc;
 
+    fn creation(block: u64, tx: u64, contract: Address) -> Trace {
+        TraceBuilder::new(Action::Create(Create::default()))
+            .result(Res::Create(CreateResult {
+                address: contract,
+                ..Default::default()
+            }))
+            .block(block)
+            .tx(tx)
+            .build()
+    }
+
+    fn selfdestruct(block: u64, tx: u64, contract: Address) -> Trace {
+        TraceBuilder::new(Action::Suicide(Suicide {
+            address: contract,
+            ..Default::default()
+        }))
+        .block(block)
+        .tx(tx)
+        .build()
+    }
+
+    #[test]
+    fn test_actually_deleted() {
+        let created = Address::repeat_byte(1);
+        let existing = Address::repeat_byte(2);
+        for (block, post_cancun) in [
+            (super::CANCUN_BLOCK - 1, false),
+            (super::CANCUN_BLOCK, true),
+        ] {
+            let traces = crate::models::trace::Traces::from(vec![
+                creation(block, 1, created),
+                selfdestruct(block, 1, created),
+                selfdestruct(block, 2, existing),
+            ]);
+            let destructions: Vec<ContractDestruction> = Vec::from(&traces);
+            assert_eq!(destructions.len(), 2);
+            // created and destroyed in the same transaction
+            assert!(destructions[0].actually_deleted());
+            // created earlier, after Cancun the code is left in place
+            assert_eq!(destructions[1].actually_deleted(), !post_cancun);
+        }
+
+        // created in the block, but destroyed by another transaction
+        let block = super::CANCUN_BLOCK;
+        let traces = crate::models::trace::Traces::from(vec![
+            creation(block, 1, created),
+            selfdestruct(block, 2, created),
+        ]);
+        let destructions: Vec<ContractDestruction> = Vec::from(&traces);
+        assert!(!destructions[0].actually_deleted());
+    }
+
     #[tokio::test]
     async fn test_destruction_serialization() {
         let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
@@ -190,7 +287,9 @@ mod tests {
 
         let block = 5426322;
 
-        let creation_traces = get_traces(block, eth_client).await.unwrap();
+        let creation_traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
         let destructions: Vec<ContractDestruction> = Vec::from(&creation_traces);
 
         for destruction in destructions {
@@ -213,7 +312,9 @@ mod tests {
 
         let block = 16100062u64;
 
-        let traces = get_traces(block, eth_client).await.unwrap();
+        let traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
         let destructions: Vec<ContractDestruction> = Vec::from(&traces);
 
         let destr_to_test = destructions.get(0).unwrap();
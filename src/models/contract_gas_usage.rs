@@ -0,0 +1,179 @@
+use super::trace::Traces;
+use super::{account::account_node, OrderKey, SerializeDgraph};
+use ethers::types::{Action, Address, Res};
+use serde::{ser::SerializeStruct, Serializer};
+use std::collections::HashMap;
+
+/// Gas used by the transactions sent to a contract over the extracted range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractGasUsage {
+    address: Address,
+    total_gas: u64,
+    call_count: u64,
+    first_block: u64,
+    last_block: u64,
+}
+
+impl ContractGasUsage {
+    pub fn new(address: Address, gas_used: u64, block: u64) -> Self {
+        Self {
+            address,
+            total_gas: gas_used,
+            call_count: 1,
+            first_block: block,
+            last_block: block,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Add the gas used by the same contract somewhere else
+    pub fn merge(&mut self, other: &ContractGasUsage) {
+        self.total_gas += other.total_gas;
+        self.call_count += other.call_count;
+        self.first_block = self.first_block.min(other.first_block);
+        self.last_block = self.last_block.max(other.last_block);
+    }
+
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ContractGasUsage", 7)?;
+        state.serialize_field("dgraph.type", "ContractGasUsage")?;
+        state.serialize_field(
+            "ContractGasUsage.contract",
+            &account_node(&self.address, false),
+        )?;
+        state.serialize_field("ContractGasUsage.total_gas", &self.total_gas)?;
+        state.serialize_field("ContractGasUsage.call_count", &self.call_count)?;
+        state.serialize_field("ContractGasUsage.first_block", &self.first_block)?;
+        state.serialize_field("ContractGasUsage.last_block", &self.last_block)?;
+        state.end()
+    }
+}
+
+impl From<&Traces> for Vec<ContractGasUsage> {
+    /// Gas used by the transactions of a block, by `to` address.
+    /// Only the top level calls with input data are counted: the gas of internal calls is already
+    /// part of them, plain ETH transfers don't call a contract, and failed transactions are
+    /// skipped since their traces don't report the gas used
+    fn from(traces: &Traces) -> Self {
+        let mut usages: HashMap<Address, ContractGasUsage> = HashMap::new();
+        for trace in &traces.0 {
+            if !trace.trace_address.is_empty() || trace.transaction_hash.is_none() {
+                continue;
+            }
+            if let (Action::Call(call), Some(Res::Call(res))) = (&trace.action, &trace.result) {
+                if call.input.is_empty() {
+                    continue;
+                }
+                let usage =
+                    ContractGasUsage::new(call.to, res.gas_used.as_u64(), trace.block_number);
+                usages
+                    .entry(call.to)
+                    .and_modify(|u| u.merge(&usage))
+                    .or_insert(usage);
+            }
+        }
+        usages.into_values().collect()
+    }
+}
+
+impl SerializeDgraph for ContractGasUsage {
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_dgraph(serializer)
+    }
+}
+
+impl OrderKey for ContractGasUsage {
+    /// Usages are sorted by first block, then by address
+    fn order_key(&self) -> (u64, String) {
+        (self.first_block, format!("{:?}", self.address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContractGasUsage;
+    use crate::models::trace::{TraceBuilder, Traces};
+    use ethers::types::{Action, Address, Bytes, Call, CallResult, Res, Trace, U256};
+
+    fn call(to: Address, gas_used: u64, trace_address: Vec<usize>, block_number: u64) -> Trace {
+        TraceBuilder::new(Action::Call(Call {
+            to,
+            input: Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb]),
+            ..Default::default()
+        }))
+        .result(Res::Call(CallResult {
+            gas_used: U256::from(gas_used),
+            output: Bytes::new(),
+        }))
+        .block(block_number)
+        .trace_address(trace_address)
+        .build()
+    }
+
+    #[test]
+    fn test_gas_usage_over_range() {
+        let token = Address::from_low_u64_be(1);
+        let router = Address::from_low_u64_be(2);
+        let mut failed = call(router, 0, vec![], 11);
+        failed.result = None;
+        failed.error = Some("Reverted".to_string());
+
+        // blocks 10 to 12, aggregated like the writer does
+        let blocks = vec![
+            Traces(vec![
+                call(token, 50_000, vec![], 10),
+                call(router, 120_000, vec![], 10),
+                // internal call of the router, already counted in its gas
+                call(token, 30_000, vec![0], 10),
+            ]),
+            // plain ETH transfer
+            Traces(vec![Trace {
+                action: Action::Call(Call::default()),
+                ..call(router, 0, vec![], 11)
+            }]),
+            Traces(vec![call(token, 40_000, vec![], 11), failed]),
+            Traces(vec![call(router, 80_000, vec![], 12)]),
+        ];
+        let mut usages: Vec<ContractGasUsage> = Vec::new();
+        for traces in &blocks {
+            for usage in Vec::<ContractGasUsage>::from(traces) {
+                match usages.iter_mut().find(|u| u.address() == usage.address()) {
+                    Some(u) => u.merge(&usage),
+                    None => usages.push(usage),
+                }
+            }
+        }
+        usages.sort_by_key(|u| u.address());
+
+        let mut expected = ContractGasUsage::new(token, 50_000, 10);
+        expected.merge(&ContractGasUsage::new(token, 40_000, 11));
+        assert_eq!(
+            (expected.total_gas, expected.call_count, expected.last_block),
+            (90_000, 2, 11)
+        );
+        let mut router_usage = ContractGasUsage::new(router, 120_000, 10);
+        router_usage.merge(&ContractGasUsage::new(router, 80_000, 12));
+        assert_eq!(usages, vec![expected, router_usage]);
+
+        let json = usages[1]
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["ContractGasUsage.total_gas"], 200_000);
+        assert_eq!(json["ContractGasUsage.call_count"], 2);
+        assert_eq!(json["ContractGasUsage.first_block"], 10);
+        assert_eq!(json["ContractGasUsage.last_block"], 12);
+        assert_eq!(
+            json["ContractGasUsage.contract"]["uid"],
+            format!("_:{:?}", router)
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use super::{OrderKey, SerializeDgraph};
+use ethers::utils::keccak256;
+use primitive_types::H256;
+use serde::{ser::SerializeStruct, Serializer};
+
+/// Creation bytecode shared by the deployments with the same init code, stored once per hash
+/// when extracting with `--hash-creation-code`
+#[derive(Debug, Clone)]
+pub struct CreationCode {
+    bytecode: ethers::types::Bytes,
+}
+
+impl CreationCode {
+    pub fn new(bytecode: ethers::types::Bytes) -> Self {
+        Self { bytecode }
+    }
+
+    /// Length in bytes of the creation code
+    pub fn code_size(&self) -> usize {
+        self.bytecode.len()
+    }
+
+    pub fn hash(&self) -> H256 {
+        H256::from(keccak256(&self.bytecode))
+    }
+
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let hash = self.hash();
+        let mut state = serializer.serialize_struct("CreationCode", 4)?;
+        state.serialize_field("uid", &format!("_:cc{:?}", hash))?;
+        state.serialize_field("dgraph.type", "CreationCode")?;
+        state.serialize_field("CreationCode.hash", &hash)?;
+        state.serialize_field("CreationCode.bytecode", &self.bytecode)?;
+        state.end()
+    }
+}
+
+impl SerializeDgraph for CreationCode {
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_dgraph(serializer)
+    }
+}
+
+impl OrderKey for CreationCode {
+    /// Creation codes are not tied to a block, they're sorted by hash
+    fn order_key(&self) -> (u64, String) {
+        (0, format!("{:?}", self.hash()))
+    }
+}
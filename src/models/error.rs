@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use super::{abi::ABIToken, SerializeDgraph};
+use super::{abi::ABIToken, OrderKey, SerializeDgraph};
 use ethers::utils::keccak256;
 use primitive_types::H256;
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
@@ -50,7 +50,8 @@ impl ErrorABI {
             .join(",");
         let sig_hash = self.get_signature_hash();
         state.serialize_field("dgraph.type", "Error")?;
-        state.serialize_field("uid", &format!("_:{:?}", sig_hash))?;
+        // events can have the same signature, errors get their own blank nodes
+        state.serialize_field("uid", &format!("_:error_{:?}", sig_hash))?;
         state.serialize_field("Error.signature", &sig_hash)?;
         state.serialize_field("Error.name", &self.name)?;
         state.serialize_field("Error.inputs", &param_types)?;
@@ -66,3 +67,10 @@ impl SerializeDgraph for ErrorABI {
         self.serialize_dgraph(serializer)
     }
 }
+
+impl OrderKey for ErrorABI {
+    /// Signatures are not tied to a block, they're sorted by signature hash
+    fn order_key(&self) -> (u64, String) {
+        (0, format!("{:?}", self.get_signature_hash()))
+    }
+}
@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use super::{abi::ABIToken, SerializeDgraph};
+use super::{abi::ABIToken, OrderKey, SerializeDgraph};
 use ethers::utils::keccak256;
 use primitive_types::H256;
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
@@ -37,11 +37,22 @@ impl EventABI {
             .join(",")
     }
 
+    /// Comma separated list telling, for each input, whether it's an indexed parameter.
+    /// Events are shared by signature, so the first stored ABI wins when contracts disagree,
+    /// e.g. ERC-20 and ERC-721 `Transfer` events
+    pub fn get_indexed_inputs(&self) -> String {
+        self.inputs
+            .iter()
+            .map(|i| i.indexed.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
     fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("EventABI", 5)?;
+        let mut state = serializer.serialize_struct("EventABI", 6)?;
         let param_types = self
             .inputs
             .iter()
@@ -54,6 +65,7 @@ impl EventABI {
         state.serialize_field("Event.signature", &format!("{:?}", sig_hash))?;
         state.serialize_field("Event.name", &self.name)?;
         state.serialize_field("Event.inputs", &param_types)?;
+        state.serialize_field("Event.indexed_inputs", &self.get_indexed_inputs())?;
         state.end()
     }
 }
@@ -67,9 +79,17 @@ impl SerializeDgraph for EventABI {
     }
 }
 
+impl OrderKey for EventABI {
+    /// Signatures are not tied to a block, they're sorted by signature hash
+    fn order_key(&self) -> (u64, String) {
+        (0, format!("{:?}", self.get_signature_hash()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::abi::{ABIStructure, ContractABI};
 
     #[test]
     fn test_unresolved_signature() {
@@ -83,4 +103,50 @@ mod tests {
             "0xc0d559150c15862e872a031a8e11f466df4b16d14e736187f2e7fb162060f9d0"
         );
     }
+
+    #[test]
+    fn test_indexed_inputs() {
+        let abi = ContractABI::from_json(
+            r#"[
+                {
+                    "type": "event",
+                    "name": "Approval",
+                    "inputs": [
+                        { "name": "owner", "internalType": "address", "indexed": true },
+                        { "name": "spender", "internalType": "address", "indexed": true },
+                        { "name": "value", "internalType": "uint256", "indexed": false }
+                    ]
+                },
+                {
+                    "type": "function",
+                    "name": "approve",
+                    "inputs": [
+                        { "name": "spender", "internalType": "address" },
+                        { "name": "value", "internalType": "uint256" }
+                    ],
+                    "outputs": [],
+                    "stateMutability": "nonpayable",
+                    "constant": false
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        match &abi.nodes[0] {
+            ABIStructure::Event(e) => {
+                assert_eq!(e.get_indexed_inputs(), "true,true,false");
+                let mut serializer = serde_json::Serializer::new(Vec::new());
+                e.serialize_dgraph(&mut serializer).unwrap();
+                let json: serde_json::Value =
+                    serde_json::from_slice(&serializer.into_inner()).unwrap();
+                assert_eq!(json["Event.indexed_inputs"], "true,true,false");
+                assert_eq!(json["Event.inputs"], "address,address,uint256");
+            }
+            _ => panic!("expected an event"),
+        }
+        match &abi.nodes[1] {
+            ABIStructure::Function(f) => assert!(f.inputs.iter().all(|i| !i.indexed)),
+            _ => panic!("expected a function"),
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use super::{abi::ABIToken, SerializeDgraph};
+use super::{abi::ABIToken, OrderKey, SerializeDgraph};
 use ethers::utils::keccak256;
 use primitive_types::H256;
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
@@ -9,7 +9,7 @@ pub struct FunctionABI {
     pub inputs: Vec<ABIToken>,
     pub outputs: Vec<ABIToken>,
     #[serde(rename = "stateMutability")]
-    pub _state_mutability: String,
+    pub state_mutability: String,
     #[serde(rename = "constant")]
     pub _constant: bool,
 }
@@ -27,6 +27,30 @@ impl FunctionABI {
         H256(keccak256(sig.as_bytes()))
     }
 
+    /// 4-byte selector as hex without prefix, taken from the name when the
+    /// decompiler couldn't resolve the function
+    pub fn get_bytes4(&self) -> String {
+        if self.name.starts_with("Unresolved_") {
+            self.name.split('_').collect::<Vec<&str>>()[1].to_lowercase()
+        } else {
+            format!("{:?}", self.get_signature_hash())[2..10].to_string()
+        }
+    }
+
+    /// `pure`, `view`, `nonpayable` or `payable` as inferred by the decompiler,
+    /// `nonpayable` when it's missing from the ABI
+    pub fn get_state_mutability(&self) -> &str {
+        if self.state_mutability.is_empty() {
+            "nonpayable"
+        } else {
+            &self.state_mutability
+        }
+    }
+
+    pub fn is_payable(&self) -> bool {
+        self.get_state_mutability() == "payable"
+    }
+
     pub fn get_input_types(&self) -> String {
         self.inputs
             .iter()
@@ -47,7 +71,7 @@ impl FunctionABI {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("FunctionABI", 7)?;
+        let mut state = serializer.serialize_struct("FunctionABI", 9)?;
         let param_types = self
             .inputs
             .iter()
@@ -56,11 +80,7 @@ impl FunctionABI {
             .join(",");
         let sig_hash = self.get_signature_hash();
         let sig_hash = format!("{:?}", sig_hash).to_string();
-        let bytes_4 = if self.name.starts_with("Unresolved_") {
-            self.name.split("_").collect::<Vec<&str>>()[1]
-        } else {
-            sig_hash.get(2..10).unwrap()
-        };
+        let bytes_4 = self.get_bytes4();
         state.serialize_field("dgraph.type", "Function")?;
         state.serialize_field("uid", &format!("_:{}", sig_hash))?;
         state.serialize_field("Function.signature", &sig_hash)?;
@@ -76,6 +96,8 @@ impl FunctionABI {
                 .collect::<Vec<String>>()
                 .join(","),
         )?;
+        state.serialize_field("Function.state_mutability", self.get_state_mutability())?;
+        state.serialize_field("Function.payable", &self.is_payable())?;
         state.end()
     }
 }
@@ -89,6 +111,13 @@ impl SerializeDgraph for FunctionABI {
     }
 }
 
+impl OrderKey for FunctionABI {
+    /// Signatures are not tied to a block, they're sorted by signature hash
+    fn order_key(&self) -> (u64, String) {
+        (0, format!("{:?}", self.get_signature_hash()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -101,14 +130,16 @@ mod tests {
                 ABIToken {
                     _name: "to".to_string(),
                     internal_type: "address".to_string(),
+                    indexed: false,
                 },
                 ABIToken {
                     _name: "value".to_string(),
                     internal_type: "uint256".to_string(),
+                    indexed: false,
                 },
             ],
             outputs: vec![],
-            _state_mutability: "nonpayable".to_string(),
+            state_mutability: "nonpayable".to_string(),
             _constant: false,
         };
         assert_eq!(
@@ -125,14 +156,16 @@ mod tests {
                 ABIToken {
                     _name: "to".to_string(),
                     internal_type: "address".to_string(),
+                    indexed: false,
                 },
                 ABIToken {
                     _name: "value".to_string(),
                     internal_type: "uint256".to_string(),
+                    indexed: false,
                 },
             ],
             outputs: vec![],
-            _state_mutability: "nonpayable".to_string(),
+            state_mutability: "nonpayable".to_string(),
             _constant: false,
         };
         println!("{}", serde_json::to_string(&abi).unwrap());
@@ -141,13 +174,35 @@ mod tests {
         println!("{}", String::from_utf8(serializer.into_inner()).unwrap());
     }
 
+    #[test]
+    fn test_state_mutability() {
+        let function = |state_mutability: &str| FunctionABI {
+            name: "deposit".to_string(),
+            inputs: vec![],
+            outputs: vec![],
+            state_mutability: state_mutability.to_string(),
+            _constant: false,
+        };
+        for (state_mutability, stored, payable) in [
+            ("payable", "payable", true),
+            ("view", "view", false),
+            ("", "nonpayable", false),
+        ] {
+            let json = function(state_mutability)
+                .serialize_dgraph(serde_json::value::Serializer)
+                .unwrap();
+            assert_eq!(json["Function.state_mutability"], stored);
+            assert_eq!(json["Function.payable"], payable);
+        }
+    }
+
     #[test]
     fn test_unresolved_signature() {
         let abi = FunctionABI {
             name: "Unresolved_f8b2cb4f".to_string(),
             inputs: vec![],
             outputs: vec![],
-            _state_mutability: "nonpayable".to_string(),
+            state_mutability: "nonpayable".to_string(),
             _constant: false,
         };
         let mut serializer = serde_json::Serializer::new(Vec::new());
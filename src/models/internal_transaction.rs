@@ -0,0 +1,238 @@
+use super::trace::Traces;
+use super::{account::account_node, OrderKey, SerializeDgraph};
+use crate::utils::rdf::escape_rdf;
+use dgraph_tonic::{IClient, Mutate};
+use ethabi::{ethereum_types::U256, Address};
+use ethers::types::{Action, Trace, TxHash};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+/// Call performed by a contract during the execution of a transaction,
+/// i.e. a call trace that is not the top level call of the transaction
+#[derive(Debug, Clone)]
+pub struct InternalTransaction {
+    tx_hash: TxHash,
+    block_number: u64,
+    trace_address: Vec<usize>,
+    from: Address,
+    to: Address,
+    value: U256,
+    gas: U256,
+    call_type: String,
+    error: Option<String>,
+}
+
+impl From<&Traces> for Vec<InternalTransaction> {
+    fn from(traces: &Traces) -> Self {
+        let mut internal_txs = Vec::new();
+        for trace in &traces.0 {
+            if let Ok(internal_tx) = InternalTransaction::try_from(trace) {
+                internal_txs.push(internal_tx);
+            }
+        }
+        internal_txs
+    }
+}
+
+impl TryFrom<&Trace> for InternalTransaction {
+    type Error = ();
+
+    fn try_from(trace: &Trace) -> Result<Self, Self::Error> {
+        if trace.trace_address.is_empty() || trace.transaction_hash.is_none() {
+            // top level calls are the transactions themselves
+            return Err(());
+        }
+        let call = match &trace.action {
+            Action::Call(call) => call,
+            _ => return Err(()),
+        };
+        Ok(Self {
+            tx_hash: trace.transaction_hash.unwrap(),
+            block_number: trace.block_number,
+            trace_address: trace.trace_address.clone(),
+            from: call.from,
+            to: call.to,
+            value: call.value,
+            gas: call.gas,
+            call_type: format!("{:?}", call.call_type).to_lowercase(),
+            error: trace.error.clone(),
+        })
+    }
+}
+
+impl InternalTransaction {
+    /// Depth of the call, 1 for calls performed directly by the called contract
+    pub fn depth(&self) -> usize {
+        self.trace_address.len()
+    }
+
+    pub async fn upsert<S: IClient>(
+        &self,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<(), anyhow::Error> {
+        // WARNING:
+        // Internal transactions don't have a unique identifier
+        // upserting already existing ones will result in a duplicate
+        // This function should be called just after deleting the internal transactions
+        // of the block using Block::upsert_delete_internal_txs
+
+        let block_no = self.block_number;
+        let tx_hash = format!("{:?}", self.tx_hash);
+        let from = format!("{:?}", self.from);
+        let to = format!("{:?}", self.to);
+
+        // Query part of the upsert
+        let query = format!(
+            r#"
+            query {{
+                var(func: eq(Block.number, {block_no})) {{
+                    Block as uid
+                }}
+                var(func: eq(Transaction.hash, "{tx_hash}")) {{
+                    Tx as uid
+                }}
+                var(func: eq(Account.address, "{from}")) {{
+                    From as uid
+                }}
+                var(func: eq(Account.address, "{to}")) {{
+                    To as uid
+                }}
+            }}
+        "#,
+        );
+
+        // Mutation part of the upsert
+        let mut set = format!(
+            r#"
+            uid(Block) <Block.number> "{block_no}" .
+            uid(Block) <dgraph.type> "Block" .
+            uid(Tx) <Transaction.hash> "{tx_hash}" .
+            uid(Tx) <dgraph.type> "Transaction" .
+            uid(From) <Account.address> "{from}" .
+            uid(From) <Account.is_contract> "true" .
+            uid(From) <dgraph.type> "Account" .
+            uid(To) <Account.address> "{to}" .
+            uid(To) <dgraph.type> "Account" .
+            _:internal_tx <dgraph.type> "InternalTransaction" .
+            _:internal_tx <InternalTransaction.block> uid(Block) .
+            _:internal_tx <InternalTransaction.tx> uid(Tx) .
+            _:internal_tx <InternalTransaction.from> uid(From) .
+            _:internal_tx <InternalTransaction.to> uid(To) .
+            _:internal_tx <InternalTransaction.value> "{value}" .
+            _:internal_tx <InternalTransaction.gas> "{gas}" .
+            _:internal_tx <InternalTransaction.call_type> "{call_type}" .
+            _:internal_tx <InternalTransaction.depth> "{depth}" .
+        "#,
+            value = self.value,
+            gas = self.gas.as_u64(),
+            call_type = self.call_type,
+            depth = self.depth(),
+        );
+        if let Some(error) = &self.error {
+            set.push_str(&format!(
+                r#"_:internal_tx <InternalTransaction.error> "{}" .
+                "#,
+                escape_rdf(error)
+            ));
+        }
+
+        // Perform the upsert
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_set_nquads(set);
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct Uid {
+            uid: String,
+        }
+        let mut state = serializer.serialize_struct("InternalTransaction", 10)?;
+        state.serialize_field("dgraph.type", "InternalTransaction")?;
+        state.serialize_field("InternalTransaction.from", &account_node(&self.from, true))?;
+        state.serialize_field("InternalTransaction.to", &account_node(&self.to, false))?;
+        state.serialize_field("InternalTransaction.value", &self.value.to_string())?;
+        state.serialize_field("InternalTransaction.gas", &self.gas.as_u64())?;
+        state.serialize_field("InternalTransaction.call_type", &self.call_type)?;
+        state.serialize_field("InternalTransaction.depth", &self.depth())?;
+        if let Some(error) = &self.error {
+            state.serialize_field("InternalTransaction.error", error)?;
+        }
+        state.serialize_field(
+            "InternalTransaction.tx",
+            &Uid {
+                uid: format!("_:{:?}", self.tx_hash),
+            },
+        )?;
+        state.serialize_field(
+            "InternalTransaction.block",
+            &Uid {
+                uid: format!("_:{}", self.block_number),
+            },
+        )?;
+        state.end()
+    }
+}
+
+impl SerializeDgraph for InternalTransaction {
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_dgraph(serializer)
+    }
+}
+
+impl OrderKey for InternalTransaction {
+    /// Internal transactions are sorted by block number, then by transaction hash and trace address
+    fn order_key(&self) -> (u64, String) {
+        let trace_address = self
+            .trace_address
+            .iter()
+            .map(|i| format!("{:05}", i))
+            .collect::<Vec<String>>()
+            .join("-");
+        (
+            self.block_number,
+            format!("{:?}{}", self.tx_hash, trace_address),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        extraction::traces::{get_traces, TraceMethod},
+        models::internal_transaction::InternalTransaction,
+    };
+    use ethers::providers::Provider;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_internal_tx_serialization() {
+        let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
+
+        let eth_client = Arc::new(Provider::try_from(eth_node).unwrap());
+
+        let block = 16100001;
+
+        let traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
+        let internal_txs: Vec<InternalTransaction> = Vec::from(&traces);
+
+        assert!(internal_txs.iter().all(|tx| tx.depth() > 0));
+
+        for internal_tx in internal_txs {
+            let mut serializer = serde_json::Serializer::new(Vec::new());
+            internal_tx.serialize_dgraph(&mut serializer).unwrap();
+            println!("{}", String::from_utf8(serializer.into_inner()).unwrap());
+        }
+    }
+}
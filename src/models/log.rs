@@ -1,15 +1,17 @@
-use super::SerializeDgraph;
+use super::{account::account_node, block::rfc3339, OrderKey, SerializeDgraph};
+use crate::debug;
 use dgraph_tonic::{IClient, Mutate};
 use serde::{ser::SerializeStruct, Serializer};
 use serde_json::json;
 use std::ops::{Deref, DerefMut};
 
+/// Log with the timestamp of its block, if known
 #[derive(Debug)]
-pub struct Log(ethers::types::Log);
+pub struct Log(ethers::types::Log, Option<u64>);
 
 impl From<ethers::types::Log> for Log {
     fn from(tx: ethers::types::Log) -> Self {
-        Self(tx)
+        Self(tx, None)
     }
 }
 
@@ -28,22 +30,35 @@ impl DerefMut for Log {
 }
 
 impl Log {
+    /// Log emitted in a block with the given timestamp, stored as `Log.datetime`
+    pub fn new(log: ethers::types::Log, timestamp: u64) -> Self {
+        Self(log, Some(timestamp))
+    }
+
+    /// Unique key of the log, made of block number and log index, `None` for pending logs.
+    /// Used as upsert key, so storing a log twice updates the same node
+    pub fn key(&self) -> Option<String> {
+        let block_no = self.block_number?.as_u64();
+        let index = self.log_index?.as_u64();
+        Some(format!("{}-{}", block_no, index))
+    }
+
+    /// Number of topics of the log, stored as `Log.topic_count`.
+    /// The EVM emits at most 4 topics (LOG0 to LOG4), further ones are not stored
+    pub fn topic_count(&self) -> usize {
+        self.topics.len().min(4)
+    }
+
     pub async fn upsert<S: IClient>(
         &self,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
     ) -> Result<(), anyhow::Error> {
-        // WARNING:
-        // Logs don't have a unique identifier
-        // upserting already existing logs will result in a duplicate
-        // This function should be called just after checking if the log
-        // of a certain block already exists, or after deleting them using Block::upsert_delete_logs
-
+        // the log is matched on its key, so upserting it again, e.g. when the
+        // delete of its block with Block::upsert_delete_logs failed, updates the same node
         let block_no = self.block_number.as_ref().unwrap().as_u64();
         let contract_address = format!("{:?}", self.address);
         let tx_hash = format!("{:?}", self.transaction_hash.as_ref().unwrap());
-        let data = self.data.to_string();
-        let tx_index = self.transaction_index.as_ref().unwrap().as_u64();
-        let index = self.log_index.as_ref().unwrap().as_u64();
+        let key = self.key().unwrap();
 
         // Query part of the upsert
         let query = format!(
@@ -58,14 +73,42 @@ impl Log {
               var(func: eq(Account.address, "{contract_address}")) {{
                 Contract as uid
               }}
+              var(func: eq(Log.key, "{key}")) {{
+                Log as uid
+              }}
             }}
         "#,
             block_no = block_no,
             tx_hash = tx_hash,
-            contract_address = contract_address
+            contract_address = contract_address,
+            key = key
         );
 
         // Mutation part of the upsert
+        let set = self.upsert_nquads();
+
+        debug!("Upserting query: {}", query);
+        debug!("Upserting set: {}", set);
+
+        // Perform the upsert
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_set_nquads(set);
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// N-Quads of the upsert, using the `Block`, `Tx`, `Contract` and `Log` variables of its query
+    fn upsert_nquads(&self) -> String {
+        let block_no = self.block_number.as_ref().unwrap().as_u64();
+        let contract_address = format!("{:?}", self.address);
+        let tx_hash = format!("{:?}", self.transaction_hash.as_ref().unwrap());
+        let data = self.data.to_string();
+        let tx_index = self.transaction_index.as_ref().unwrap().as_u64();
+        let index = self.log_index.as_ref().unwrap().as_u64();
+
         let mut set = format!(
             r#"
             uid(Block) <Block.number> "{block_no}" .
@@ -75,48 +118,60 @@ impl Log {
             uid(Contract) <Account.address> "{contract_address}" .
             uid(Contract) <Account.is_contract> "true" .
             uid(Contract) <dgraph.type> "Account" .
-            _:log <dgraph.type> "Log" .
-            _:log <Log.block> uid(Block) .
-            _:log <Log.transaction> uid(Tx) .
-            _:log <Log.contract> uid(Contract) .
-            _:log <Log.data> "{data}" .
-            _:log <Log.tx_index> "{tx_index}" .
-            _:log <Log.index> "{index}" .
+            uid(Log) <dgraph.type> "Log" .
+            uid(Log) <Log.key> "{key}" .
+            uid(Log) <Log.block> uid(Block) .
+            uid(Log) <Log.transaction> uid(Tx) .
+            uid(Log) <Log.contract> uid(Contract) .
+            uid(Log) <Log.data> "{data}" .
+            uid(Log) <Log.tx_index> "{tx_index}" .
+            uid(Log) <Log.index> "{index}" .
+            uid(Log) <Log.topic_count> "{topic_count}" .
         "#,
             block_no = block_no,
             tx_hash = tx_hash,
             contract_address = contract_address,
             data = data,
             tx_index = tx_index,
-            index = index
+            index = index,
+            topic_count = self.topic_count(),
+            key = self.key().unwrap()
         );
 
+        if let Some(timestamp) = self.1 {
+            set.push_str(&format!(
+                r#"uid(Log) <Log.datetime> "{datetime}" .
+                "#,
+                datetime = rfc3339(timestamp)
+            ));
+        }
+
         for (i, topic) in self.topics.iter().enumerate() {
             match i {
                 0 => {
                     set.push_str(&format!(
-                        r#"_:log <Log.topic_0> "{topic_0}" .
+                        r#"uid(Log) <Log.topic_0> "{topic_0}" .
                         "#,
                         topic_0 = format!("{:?}", topic)
                     ));
                 }
                 1 => {
                     set.push_str(&format!(
-                        r#"_:log <Log.topic_1> "{topic_1}" .
+                        r#"uid(Log) <Log.topic_1> "{topic_1}" .
                         "#,
                         topic_1 = format!("{:?}", topic)
                     ));
                 }
                 2 => {
                     set.push_str(&format!(
-                        r#"_:log <Log.topic_2> "{topic_2}" .
+                        r#"uid(Log) <Log.topic_2> "{topic_2}" .
                         "#,
                         topic_2 = format!("{:?}", topic)
                     ));
                 }
                 3 => {
                     set.push_str(&format!(
-                        r#"_:log <Log.topic_3> "{topic_3}" .
+                        r#"uid(Log) <Log.topic_3> "{topic_3}" .
                         "#,
                         topic_3 = format!("{:?}", topic)
                     ));
@@ -127,33 +182,20 @@ impl Log {
             }
         }
 
-        // Perform the upsert
-        let mut mu = dgraph_tonic::Mutation::new();
-        mu.set_set_nquads(set);
-        let mut txn = dgraph_client.new_mutated_txn();
-        txn.upsert(query, mu).await?;
-        txn.commit().await?;
-        // println!("Upserting query: {}", query);
-        // println!("Upserting set: {}", set);
-
-        Ok(())
+        set
     }
 
     fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Log", 7)?;
+        let mut state = serializer.serialize_struct("Log", 8)?;
+        if let Some(key) = self.key() {
+            state.serialize_field("uid", &format!("_:log{}", key))?;
+            state.serialize_field("Log.key", &key)?;
+        }
         state.serialize_field("dgraph.type", "Log")?;
-        state.serialize_field(
-            "Log.contract",
-            &json!({
-                "uid": format!("_:{:?}", self.address),
-                "dgraph.type": "Account",
-                "Account.address": format!("{:?}", self.address),
-                "Account.is_contract": true,
-            }),
-        )?;
+        state.serialize_field("Log.contract", &account_node(&self.address, true))?;
         for (i, topic) in self.topics.iter().enumerate() {
             match i {
                 0 => {
@@ -173,7 +215,11 @@ impl Log {
                 }
             }
         }
+        state.serialize_field("Log.topic_count", &self.topic_count())?;
         state.serialize_field("Log.data", &format!("{}", self.data))?;
+        if let Some(timestamp) = self.1 {
+            state.serialize_field("Log.datetime", &rfc3339(timestamp))?;
+        }
         if self.block_number.is_some() {
             state.serialize_field(
                 "Log.block",
@@ -216,13 +262,103 @@ impl SerializeDgraph for Log {
     }
 }
 
+impl OrderKey for Log {
+    /// Logs are sorted by block number, then by log index inside the block
+    fn order_key(&self) -> (u64, String) {
+        let block_no = self.block_number.map(|b| b.as_u64()).unwrap_or_default();
+        let index = self.log_index.map(|i| i.as_u64()).unwrap_or_default();
+        (block_no, format!("{:010}", index))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Log;
     use crate::{extraction::logs::get_all_logs, models::block::Block};
-    use ethers::providers::Provider;
+    use dgraph_tonic::Query;
+    use ethers::{providers::Provider, types::H256};
     use std::sync::Arc;
 
+    #[test]
+    fn test_log_datetime() {
+        let log = ethers::types::Log {
+            block_number: Some(16000000u64.into()),
+            ..Default::default()
+        };
+        let serialized = |log: &Log| {
+            let mut serializer = serde_json::Serializer::new(Vec::new());
+            log.serialize_dgraph(&mut serializer).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&serializer.into_inner()).unwrap()
+        };
+
+        let with_datetime = serialized(&Log::new(log.clone(), 1668211271));
+        assert_eq!(with_datetime["Log.datetime"], "2022-11-12T00:01:11+00:00");
+        assert_eq!(with_datetime["Log.block"]["uid"], "_:16000000");
+
+        let without_datetime = serialized(&Log::from(log));
+        assert!(without_datetime.get("Log.datetime").is_none());
+    }
+
+    #[test]
+    fn test_topic_count() {
+        for topics in [1, 3, 4, 5] {
+            let log = Log::from(ethers::types::Log {
+                topics: (0..topics).map(H256::from_low_u64_be).collect(),
+                block_number: Some(16000000u64.into()),
+                transaction_hash: Some(H256::zero()),
+                transaction_index: Some(0u64.into()),
+                log_index: Some(0u64.into()),
+                ..Default::default()
+            });
+            // a fifth topic can't be emitted by the EVM, it's left out
+            let stored = topics.min(4);
+            assert_eq!(log.topic_count(), stored as usize);
+
+            let mut serializer = serde_json::Serializer::new(Vec::new());
+            log.serialize_dgraph(&mut serializer).unwrap();
+            let json =
+                serde_json::from_slice::<serde_json::Value>(&serializer.into_inner()).unwrap();
+            assert_eq!(json["Log.topic_count"], stored);
+            assert_eq!(
+                json["Log.topic_0"],
+                format!("{:?}", H256::from_low_u64_be(0))
+            );
+            assert!(json.get(format!("Log.topic_{}", stored)).is_none());
+
+            let nquads = log.upsert_nquads();
+            assert!(nquads.contains(&format!(r#"<Log.topic_count> "{}" ."#, stored)));
+            assert!(nquads.contains(&format!("<Log.topic_{}>", stored - 1)));
+            assert!(!nquads.contains(&format!("<Log.topic_{}>", stored)));
+        }
+    }
+
+    #[test]
+    fn test_log_key() {
+        let log = Log::from(ethers::types::Log {
+            block_number: Some(16000000u64.into()),
+            transaction_hash: Some(H256::zero()),
+            transaction_index: Some(3u64.into()),
+            log_index: Some(12u64.into()),
+            ..Default::default()
+        });
+        assert_eq!(log.key().as_deref(), Some("16000000-12"));
+
+        let json = log.serialize_dgraph(serde_json::value::Serializer).unwrap();
+        assert_eq!(json["uid"], "_:log16000000-12");
+        assert_eq!(json["Log.key"], "16000000-12");
+        // the upsert matches the stored node instead of creating a new one
+        let nquads = log.upsert_nquads();
+        assert!(nquads.contains(r#"uid(Log) <Log.key> "16000000-12" ."#));
+        assert!(!nquads.contains("_:log"));
+
+        let pending = Log::from(ethers::types::Log::default());
+        assert_eq!(pending.key(), None);
+        let json = pending
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert!(json.get("uid").is_none());
+    }
+
     #[tokio::test]
     async fn test_log_serialization() {
         let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
@@ -290,4 +426,33 @@ mod tests {
         let log_to_test: crate::models::log::Log = logs[0].clone().into();
         log_to_test.upsert(&dgraph).await.unwrap();
     }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_log_upsert_twice() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let eth_endpoint = std::env::var("ETH_NODE").expect("Ethereum endpoint");
+
+        let eth_client = Arc::new(Provider::try_from(eth_endpoint).unwrap());
+        let dgraph = dgraph_tonic::Client::new(dgraph_endpoint.clone()).expect("Dgraph client");
+
+        let block = 16100001u64;
+        let logs = get_all_logs(block, eth_client).await.unwrap();
+        assert!(!logs.is_empty());
+
+        // the block is processed again without deleting its logs, as when the delete fails
+        for _ in 0..2 {
+            for log in &logs {
+                Log::from(log.clone()).upsert(&dgraph).await.unwrap();
+            }
+        }
+
+        let query = format!(
+            r#"{{ q(func: eq(Block.number, {})) {{ count(~Log.block) }} }}"#,
+            block
+        );
+        let res = dgraph.new_read_only_txn().query(query).await.unwrap();
+        let res: serde_json::Value = serde_json::from_slice(&res.json).unwrap();
+        assert_eq!(res["q"][0]["count(~Log.block)"], logs.len());
+    }
 }
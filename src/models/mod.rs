@@ -1,14 +1,21 @@
 use serde::Serializer;
 
 pub mod abi;
+pub mod account;
 pub mod block;
 pub mod contract_deployment;
 pub mod contract_destruction;
+pub mod contract_gas_usage;
+pub mod creation_code;
 pub mod error;
 pub mod event;
 pub mod function;
+pub mod internal_transaction;
 pub mod log;
+pub mod predicates;
+pub mod proxy_upgrade;
 pub mod skeleton;
+pub mod state_change;
 pub mod trace;
 pub mod transaction;
 pub mod transfer;
@@ -18,3 +25,11 @@ pub trait SerializeDgraph {
     where
         S: Serializer;
 }
+
+/// Stable ordering key of an entity, used to sort the buffers before they're flushed
+/// when the extraction runs in deterministic mode.
+/// The first element is the block number (0 for entities not tied to a block),
+/// the second one is a stable secondary key to break ties inside the same block.
+pub trait OrderKey {
+    fn order_key(&self) -> (u64, String);
+}
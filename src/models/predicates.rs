@@ -0,0 +1,214 @@
+//! Names of the Dgraph types and predicates written by the models.
+//! Keep them in sync with `dgraph/contracts.schema`, the test below checks that the models
+//! still emit each of them.
+
+pub const ACCOUNT: &str = "Account";
+pub const ACCOUNT_ADDRESS: &str = "Account.address";
+
+pub const BLOCK_NUMBER: &str = "Block.number";
+pub const BLOCK_DATETIME: &str = "Block.datetime";
+pub const BLOCK_TX_COUNT: &str = "Block.tx_count";
+pub const BLOCK_GAS_USED: &str = "Block.gas_used";
+pub const BLOCK_MINER: &str = "Block.miner";
+
+pub const CONTRACT_DEPLOYMENT: &str = "ContractDeployment";
+pub const CONTRACT_DEPLOYMENT_CONTRACT: &str = "ContractDeployment.contract";
+pub const CONTRACT_DEPLOYMENT_BLOCK: &str = "ContractDeployment.block";
+pub const CONTRACT_DEPLOYMENT_CREATOR: &str = "ContractDeployment.creator";
+pub const CONTRACT_DEPLOYMENT_TX_HASH: &str = "ContractDeployment.tx_hash";
+pub const CONTRACT_DEPLOYMENT_FAILED: &str = "ContractDeployment.failed_deploy";
+pub const CONTRACT_DEPLOYMENT_SKELETON: &str = "ContractDeployment.skeleton";
+pub const CONTRACT_DEPLOYMENT_NAME: &str = "ContractDeployment.name";
+pub const CONTRACT_DEPLOYMENT_KEY: &str = "ContractDeployment.key";
+
+pub const CONTRACT_DESTRUCTION_CONTRACT: &str = "ContractDestruction.contract";
+pub const CONTRACT_DESTRUCTION_BLOCK: &str = "ContractDestruction.block";
+pub const CONTRACT_DESTRUCTION_TX_HASH: &str = "ContractDestruction.tx_hash";
+
+pub const SKELETON_BYTECODE: &str = "Skeleton.bytecode";
+pub const SKELETON_FUNCTIONS: &str = "Skeleton.functions";
+pub const SKELETON_EVENTS: &str = "Skeleton.events";
+pub const SKELETON_ERRORS: &str = "Skeleton.errors";
+pub const SKELETON_ERC20_COMPLIANCY: &str = "Skeleton.erc20_compliancy";
+pub const SKELETON_SIMILAR_CODE: &str = "Skeleton.similar_code";
+pub const SKELETON_SIMILAR_INTERFACE: &str = "Skeleton.similar_interface";
+
+pub const FUNCTION_SIGNATURE: &str = "Function.signature";
+pub const FUNCTION_NAME: &str = "Function.name";
+pub const EVENT_SIGNATURE: &str = "Event.signature";
+pub const ERROR_SIGNATURE: &str = "Error.signature";
+
+pub const TRANSACTION_HASH: &str = "Transaction.hash";
+pub const TRANSACTION_FROM: &str = "Transaction.from";
+pub const TRANSACTION_TO: &str = "Transaction.to";
+pub const TRANSACTION_BLOCK: &str = "Transaction.block";
+pub const TRANSACTION_VALUE: &str = "Transaction.value";
+
+pub const TOKEN_TRANSFER_CONTRACT: &str = "TokenTransfer.contract";
+pub const TOKEN_TRANSFER_FROM: &str = "TokenTransfer.from";
+pub const TOKEN_TRANSFER_TO: &str = "TokenTransfer.to";
+pub const TOKEN_TRANSFER_VALUE: &str = "TokenTransfer.value";
+pub const TOKEN_TRANSFER_BLOCK: &str = "TokenTransfer.block";
+
+pub const LOG_CONTRACT: &str = "Log.contract";
+pub const LOG_BLOCK: &str = "Log.block";
+pub const LOG_TOPIC_0: &str = "Log.topic_0";
+pub const LOG_DATA: &str = "Log.data";
+pub const LOG_INDEX: &str = "Log.index";
+
+#[cfg(test)]
+mod tests {
+    use crate::models::{
+        abi::{ABIStructure, ContractABI},
+        block::Block,
+        contract_deployment::ContractDeployment,
+        contract_destruction::ContractDestruction,
+        error::ErrorABI,
+        event::EventABI,
+        function::FunctionABI,
+        log::Log,
+        skeleton::Skeleton,
+        trace::{TraceBuilder, Traces},
+        transaction::Transaction,
+        transfer::{TokenTransfer, TokenType},
+        SerializeDgraph,
+    };
+    use ethers::types::{
+        Action, Address, Bytes, Create, CreateResult, Res, Suicide, TxHash, H256, U256, U64,
+    };
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+
+    fn serialized<T: SerializeDgraph>(entity: &T) -> Value {
+        entity
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap()
+    }
+
+    /// Predicates and types of a serialized entity, including its nested nodes
+    fn collect_names(value: &Value, names: &mut HashSet<String>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    names.insert(key.clone());
+                    if key == "dgraph.type" {
+                        match field {
+                            Value::String(name) => {
+                                names.insert(name.clone());
+                            }
+                            Value::Array(types) => names
+                                .extend(types.iter().filter_map(|t| t.as_str()).map(String::from)),
+                            _ => {}
+                        }
+                    }
+                    collect_names(field, names);
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| collect_names(item, names)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_predicates_are_emitted() {
+        let declared: Vec<&str> = include_str!("predicates.rs")
+            .lines()
+            .filter(|line| line.starts_with("pub const"))
+            .map(|line| line.split('"').nth(1).unwrap())
+            .collect();
+        assert!(!declared.is_empty());
+
+        let block = Block::from(ethers::types::Block {
+            number: Some(1.into()),
+            author: Some(Address::from_low_u64_be(1)),
+            ..Default::default()
+        });
+        let transaction = Transaction::from(ethers::types::Transaction {
+            block_number: Some(1.into()),
+            to: Some(Address::from_low_u64_be(2)),
+            ..Default::default()
+        });
+        let traces = Traces::from(vec![
+            TraceBuilder::new(Action::Create(Create::default()))
+                .result(Res::Create(CreateResult {
+                    address: Address::from_low_u64_be(3),
+                    ..Default::default()
+                }))
+                .build(),
+            TraceBuilder::new(Action::Suicide(Suicide {
+                address: Address::from_low_u64_be(3),
+                ..Default::default()
+            }))
+            .build(),
+        ]);
+        let destructions: Vec<ContractDestruction> = Vec::from(&traces);
+        let deployments: Vec<ContractDeployment> = Vec::from(traces);
+        // the name is only emitted once it's resolved from the node
+        let mut deployment = serde_json::to_value(&deployments[0]).unwrap();
+        deployment["name"] = json!("Token");
+        let deployment: ContractDeployment = serde_json::from_value(deployment).unwrap();
+
+        let abi: Vec<ABIStructure> = serde_json::from_value(json!([
+            {"type": "function", "name": "f", "inputs": [], "outputs": [], "stateMutability": "view", "constant": false},
+            {"type": "event", "name": "E", "inputs": []},
+            {"type": "error", "name": "Err", "inputs": []}
+        ]))
+        .unwrap();
+        let mut skeleton = Skeleton::new(Bytes::from(vec![0x00]));
+        skeleton.set_abi(ContractABI::new(abi.clone()));
+        let transfer = TokenTransfer::new(
+            Address::from_low_u64_be(3),
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            U256::one(),
+            U64::from(1),
+            TxHash::zero(),
+            TokenType::ERC20,
+        );
+        let log = Log::from(ethers::types::Log {
+            address: Address::from_low_u64_be(3),
+            topics: vec![H256::zero()],
+            block_number: Some(1.into()),
+            transaction_hash: Some(H256::zero()),
+            transaction_index: Some(0u64.into()),
+            log_index: Some(0u64.into()),
+            ..Default::default()
+        });
+
+        let mut emitted = HashSet::new();
+        for value in [
+            serialized(&block),
+            serialized(&transaction),
+            serialized(&deployment),
+            serialized(&destructions[0]),
+            serialized(&skeleton),
+            serialized(&transfer),
+            serialized(&log),
+        ] {
+            collect_names(&value, &mut emitted);
+        }
+        for structure in &abi {
+            let value = match structure {
+                ABIStructure::Function(function) => serialized::<FunctionABI>(function),
+                ABIStructure::Event(event) => serialized::<EventABI>(event),
+                ABIStructure::Error(error) => serialized::<ErrorABI>(error),
+            };
+            collect_names(&value, &mut emitted);
+        }
+        // the similarity edges are written as N-Quads by the analysis
+        let similarities = include_str!("../analysys/similarities.rs");
+        for edge in [
+            super::SKELETON_SIMILAR_CODE,
+            super::SKELETON_SIMILAR_INTERFACE,
+        ] {
+            assert!(similarities.contains(&format!("<{}>", edge)), "{edge}");
+            emitted.insert(edge.to_string());
+        }
+
+        let missing: Vec<&&str> = declared
+            .iter()
+            .filter(|name| !emitted.contains(**name))
+            .collect();
+        assert!(missing.is_empty(), "not emitted by the models: {missing:?}");
+    }
+}
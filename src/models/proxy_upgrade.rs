@@ -0,0 +1,300 @@
+use dgraph_tonic::{IClient, Mutate, Query};
+use ethers::providers::Middleware;
+use ethers::types::{Address, H256};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// EIP-1967 storage slot of the implementation of a proxy,
+/// `bytes32(uint256(keccak256("eip1967.proxy.implementation")) - 1)`
+pub const EIP1967_IMPLEMENTATION_SLOT: H256 = H256([
+    0x36, 0x08, 0x94, 0xa1, 0x3b, 0xa1, 0xa3, 0x21, 0x06, 0x67, 0xc8, 0x28, 0x49, 0x2d, 0xb9, 0x8d,
+    0xca, 0x3e, 0x20, 0x76, 0xcc, 0x37, 0x35, 0xa9, 0x20, 0xa3, 0xca, 0x50, 0x5d, 0x38, 0x2b, 0xbc,
+]);
+
+/// Change of the implementation of an EIP-1967 proxy, seen at the end of a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyUpgrade {
+    proxy: Address,
+    old_implementation: Address,
+    new_implementation: Address,
+    block_number: u64,
+}
+
+/// Implementations of the proxies at the end of a block, read from the EIP-1967 slot.
+/// Proxies with an empty slot are left out
+pub async fn read_implementations<T: Middleware>(
+    proxies: &HashSet<Address>,
+    block_number: u64,
+    eth_client: Arc<T>,
+) -> Result<HashMap<Address, Address>, T::Error> {
+    let mut implementations = HashMap::new();
+    for proxy in proxies {
+        let slot = eth_client
+            .get_storage_at(
+                *proxy,
+                EIP1967_IMPLEMENTATION_SLOT,
+                Some(block_number.into()),
+            )
+            .await?;
+        let implementation = Address::from(slot);
+        if !implementation.is_zero() {
+            implementations.insert(*proxy, implementation);
+        }
+    }
+    Ok(implementations)
+}
+
+/// Upgrades of the proxies whose implementation at the end of `block_number` differs from the
+/// one at the end of the previous block. Both are read from the node, so blocks can be compared
+/// in any order. Proxies without a previous implementation are not upgrades, their first one
+/// is just stored
+pub fn detect_upgrades(
+    previous: &HashMap<Address, Address>,
+    current: &HashMap<Address, Address>,
+    block_number: u64,
+) -> Vec<ProxyUpgrade> {
+    let mut upgrades = current
+        .iter()
+        .filter_map(|(proxy, new_implementation)| {
+            let old_implementation = previous.get(proxy)?;
+            (old_implementation != new_implementation).then_some(ProxyUpgrade {
+                proxy: *proxy,
+                old_implementation: *old_implementation,
+                new_implementation: *new_implementation,
+                block_number,
+            })
+        })
+        .collect::<Vec<_>>();
+    upgrades.sort_by_key(|u| u.proxy);
+    upgrades
+}
+
+/// Implementations of the proxies stored in Dgraph with `Account.implementation`
+pub async fn stored_implementations<S: IClient>(
+    proxies: &HashSet<Address>,
+    dgraph_client: &dgraph_tonic::ClientVariant<S>,
+) -> Result<HashMap<Address, Address>, anyhow::Error> {
+    if proxies.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let addresses = proxies
+        .iter()
+        .map(|a| format!("\"{:?}\"", a))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let query = format!(
+        r#"{{
+            q(func: eq(Account.address, [{addresses}])) @filter(has(Account.implementation)) {{
+                Account.address
+                Account.implementation {{
+                    Account.address
+                }}
+            }}
+        }}"#,
+        addresses = addresses
+    );
+
+    #[derive(Deserialize)]
+    struct Implementation {
+        #[serde(rename = "Account.address")]
+        address: Address,
+    }
+
+    #[derive(Deserialize)]
+    struct Proxy {
+        #[serde(rename = "Account.address")]
+        address: Address,
+        #[serde(rename = "Account.implementation")]
+        implementation: Implementation,
+    }
+
+    #[derive(Deserialize)]
+    struct QueryResult {
+        q: Vec<Proxy>,
+    }
+
+    let res = dgraph_client.new_read_only_txn().query(query).await?;
+    let res: QueryResult = serde_json::from_slice(&res.json)?;
+    Ok(res
+        .q
+        .into_iter()
+        .map(|p| (p.address, p.implementation.address))
+        .collect())
+}
+
+/// Store the implementation of a proxy with `Account.implementation`, replacing the previous one
+pub async fn upsert_implementation<S: IClient>(
+    proxy: Address,
+    implementation: Address,
+    dgraph_client: &dgraph_tonic::ClientVariant<S>,
+) -> Result<(), anyhow::Error> {
+    let mut txn = dgraph_client.new_mutated_txn();
+    txn.upsert(
+        implementation_query(proxy, implementation, ""),
+        implementation_mutation(proxy, implementation, ""),
+    )
+    .await?;
+    txn.commit().await
+}
+
+/// Query of the proxy, its stored implementation and the new one, with `extra` blocks
+fn implementation_query(proxy: Address, implementation: Address, extra: &str) -> String {
+    format!(
+        r#"
+        query {{
+            var(func: eq(Account.address, "{proxy:?}")) {{
+                Proxy as uid
+                OldImpl as Account.implementation
+            }}
+            var(func: eq(Account.address, "{implementation:?}")) {{
+                Impl as uid
+            }}
+            {extra}
+        }}
+    "#,
+        proxy = proxy,
+        implementation = implementation,
+        extra = extra
+    )
+}
+
+/// Mutation replacing the implementation of the proxy, with `extra` N-Quads in the same upsert
+fn implementation_mutation(
+    proxy: Address,
+    implementation: Address,
+    extra: &str,
+) -> dgraph_tonic::Mutation {
+    let set = format!(
+        r#"
+        uid(Proxy) <Account.address> "{proxy:?}" .
+        uid(Proxy) <Account.is_contract> "true" .
+        uid(Proxy) <dgraph.type> "Account" .
+        uid(Impl) <Account.address> "{implementation:?}" .
+        uid(Impl) <Account.is_contract> "true" .
+        uid(Impl) <dgraph.type> "Account" .
+        uid(Proxy) <Account.implementation> uid(Impl) .
+        {extra}
+    "#,
+        proxy = proxy,
+        implementation = implementation,
+        extra = extra
+    );
+    let mut mu = dgraph_tonic::Mutation::new();
+    mu.set_delete_nquads("uid(Proxy) <Account.implementation> uid(OldImpl) .");
+    mu.set_set_nquads(set);
+    mu
+}
+
+impl ProxyUpgrade {
+    pub fn proxy(&self) -> Address {
+        self.proxy
+    }
+
+    pub fn old_implementation(&self) -> Address {
+        self.old_implementation
+    }
+
+    pub fn new_implementation(&self) -> Address {
+        self.new_implementation
+    }
+
+    /// Store the upgrade, and the new implementation of the proxy
+    pub async fn upsert<S: IClient>(
+        &self,
+        dgraph_client: &dgraph_tonic::ClientVariant<S>,
+    ) -> Result<(), anyhow::Error> {
+        let vars = format!(
+            r#"
+            var(func: eq(Account.address, "{old:?}")) {{
+                Old as uid
+            }}
+            var(func: eq(Block.number, {block_number})) {{
+                Block as uid
+            }}"#,
+            old = self.old_implementation,
+            block_number = self.block_number
+        );
+        let query = implementation_query(self.proxy, self.new_implementation, &vars);
+        let upgrade = format!(
+            r#"
+        uid(Block) <Block.number> "{block_number}" .
+        uid(Block) <dgraph.type> "Block" .
+        uid(Old) <Account.address> "{old:?}" .
+        uid(Old) <Account.is_contract> "true" .
+        uid(Old) <dgraph.type> "Account" .
+        _:upgrade <dgraph.type> "ProxyUpgrade" .
+        _:upgrade <ProxyUpgrade.proxy> uid(Proxy) .
+        _:upgrade <ProxyUpgrade.old_implementation> uid(Old) .
+        _:upgrade <ProxyUpgrade.new_implementation> uid(Impl) .
+        _:upgrade <ProxyUpgrade.block> uid(Block) .
+    "#,
+            block_number = self.block_number,
+            old = self.old_implementation
+        );
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(
+            query,
+            implementation_mutation(self.proxy, self.new_implementation, &upgrade),
+        )
+        .await?;
+        txn.commit().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::Provider;
+    use ethers::types::U256;
+    use ethers::utils::keccak256;
+
+    #[test]
+    fn test_implementation_slot() {
+        let slot = U256::from(keccak256("eip1967.proxy.implementation")) - 1;
+        assert_eq!(
+            H256::from(<[u8; 32]>::from(slot)),
+            EIP1967_IMPLEMENTATION_SLOT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detect_upgrade() {
+        let proxy = Address::from_low_u64_be(0x10);
+        let other = Address::from_low_u64_be(0x20);
+        let old = Address::from_low_u64_be(0x1);
+        let new = Address::from_low_u64_be(0x2);
+
+        let (provider, mock) = Provider::mocked();
+        // responses are popped from the last one pushed, proxies are read in any order
+        mock.push(H256::from(new)).unwrap();
+        mock.push(H256::from(new)).unwrap();
+        let current = read_implementations(&HashSet::from([proxy, other]), 100, Arc::new(provider))
+            .await
+            .unwrap();
+        assert_eq!(current, HashMap::from([(proxy, new), (other, new)]));
+
+        // `other` had no implementation in the previous block, so it's not an upgrade
+        let previous = HashMap::from([(proxy, old)]);
+        let upgrades = detect_upgrades(&previous, &current, 100);
+        assert_eq!(
+            upgrades,
+            vec![ProxyUpgrade {
+                proxy,
+                old_implementation: old,
+                new_implementation: new,
+                block_number: 100,
+            }]
+        );
+        assert!(detect_upgrades(&current, &current, 101).is_empty());
+
+        // an empty slot is not a proxy implementation
+        let (provider, mock) = Provider::mocked();
+        mock.push(H256::zero()).unwrap();
+        let current = read_implementations(&HashSet::from([proxy]), 100, Arc::new(provider))
+            .await
+            .unwrap();
+        assert!(current.is_empty());
+        assert!(detect_upgrades(&previous, &current, 100).is_empty());
+    }
+}
@@ -1,14 +1,45 @@
-use super::{abi::ContractABI, SerializeDgraph};
-use crate::models::abi::ABIStructure;
+use super::{abi::ContractABI, OrderKey, SerializeDgraph};
+use crate::{
+    models::abi::ABIStructure,
+    utils::{
+        minhash::{encode_signature, minhash},
+        rdf::escape_rdf,
+    },
+};
 use dgraph_tonic::{IClient, Mutate};
 use ethers::utils::keccak256;
+use primitive_types::H256;
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use std::collections::BTreeSet;
+
+/// Minimum number of functions of each standard for a skeleton to be classified as compliant.
+/// The defaults, 5 of the 6 ERC-20 functions and 8 of the 9 ERC-721 ones, tolerate a function
+/// missed or renamed by the decompiler, while the ERC-20 `transfer` is always required
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErcThresholds {
+    pub erc20: u8,
+    pub erc721: u8,
+}
+
+impl Default for ErcThresholds {
+    fn default() -> Self {
+        Self {
+            erc20: 5,
+            erc721: 8,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Skeleton {
     bytecode: ethers::types::Bytes,
     abi: Option<ContractABI>,
     failed_decompilation: bool,
+    skipped_decompilation: bool,
+    minhash_size: usize,
+    decompiled_source: Option<String>,
+    decompiler_version: Option<String>,
+    erc_thresholds: ErcThresholds,
 }
 
 impl Skeleton {
@@ -17,13 +48,33 @@ impl Skeleton {
             bytecode,
             abi: None,
             failed_decompilation: false,
+            skipped_decompilation: false,
+            minhash_size: 0,
+            decompiled_source: None,
+            decompiler_version: None,
+            erc_thresholds: ErcThresholds::default(),
         }
     }
 
+    /// Keccak256 of the skeleton bytecode
+    pub fn hash(&self) -> H256 {
+        H256::from(keccak256(&self.bytecode))
+    }
+
+    /// Length of the interface MinHash signature stored with the skeleton, 0 to not store it
+    pub fn set_minhash_size(&mut self, size: usize) {
+        self.minhash_size = size;
+    }
+
     pub fn set_failed_decompilation(&mut self, failed: bool) {
         self.failed_decompilation = failed;
     }
 
+    /// Mark the skeleton as not decompiled on purpose, see `is_trivial`
+    pub fn set_skipped_decompilation(&mut self, skipped: bool) {
+        self.skipped_decompilation = skipped;
+    }
+
     pub fn set_abi(&mut self, abi: ContractABI) {
         self.abi = Some(abi);
     }
@@ -32,6 +83,88 @@ impl Skeleton {
         &self.abi
     }
 
+    /// Solidity-like source produced by the decompiler, stored with `--store-decompiled-source`
+    pub fn set_decompiled_source(&mut self, source: Option<String>) {
+        self.decompiled_source = source;
+    }
+
+    /// Version of heimdall that decompiled the skeleton, if known
+    pub fn set_decompiler_version(&mut self, version: Option<String>) {
+        self.decompiler_version = version;
+    }
+
+    /// Thresholds of `is_erc20` and `is_erc721`
+    pub fn set_erc_thresholds(&mut self, thresholds: ErcThresholds) {
+        self.erc_thresholds = thresholds;
+    }
+
+    /// Length in bytes of the skeleton bytecode
+    pub fn code_size(&self) -> usize {
+        self.bytecode.len()
+    }
+
+    /// Shannon entropy of the skeleton bytecode, computed over bytes.
+    /// Returns a value between 0 (single repeated byte) and 8 (uniform distribution),
+    /// packed or obfuscated code tends to have a higher entropy than compiler output.
+    pub fn entropy(&self) -> f64 {
+        if self.bytecode.is_empty() {
+            return 0.0;
+        }
+        let mut frequencies = [0usize; 256];
+        for byte in self.bytecode.iter() {
+            frequencies[*byte as usize] += 1;
+        }
+        let len = self.bytecode.len() as f64;
+        frequencies
+            .iter()
+            .filter(|f| **f > 0)
+            .map(|f| {
+                let p = *f as f64 / len;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Whether the bytecode is shorter than `min_code_size` bytes or has an entropy lower than
+    /// `min_entropy`, e.g. minimal proxies or `STOP`-only code, not worth decompiling.
+    /// Thresholds of 0 never match
+    pub fn is_trivial(&self, min_code_size: usize, min_entropy: f64) -> bool {
+        self.code_size() < min_code_size || self.entropy() < min_entropy
+    }
+
+    /// Hash of the sorted set of 4-byte function selectors, contracts exposing the
+    /// same public interface share it regardless of their bytecode.
+    /// Returns None if the skeleton has no ABI
+    pub fn interface_hash(&self) -> Option<H256> {
+        let abi = self.abi.as_ref()?;
+        let selectors = abi
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                ABIStructure::Function(f) => Some(f.get_bytes4()),
+                _ => None,
+            })
+            .collect::<BTreeSet<String>>();
+        let joined = selectors.into_iter().collect::<Vec<String>>().join(",");
+        Some(H256(keccak256(joined.as_bytes())))
+    }
+
+    /// MinHash signature of the names of the functions, events and errors, the same set
+    /// compared by the interface similarity.
+    /// Returns None if the skeleton has no ABI or the signature is disabled
+    pub fn interface_minhash(&self) -> Option<Vec<u64>> {
+        let abi = self.abi.as_ref()?;
+        if self.minhash_size == 0 {
+            return None;
+        }
+        let names = abi.nodes.iter().map(|node| match node {
+            ABIStructure::Function(f) => &f.name,
+            ABIStructure::Event(e) => &e.name,
+            ABIStructure::Error(e) => &e.name,
+        });
+        Some(minhash(names, self.minhash_size))
+    }
+
     /// How much the contract is ERC20 compliant
     /// Returns:
     /// - how many functions of the standard are present (1 to 6)
@@ -78,17 +211,21 @@ impl Skeleton {
     }
 
     /// Returns true if the contract is ERC20 compliant, false otherwise
-    /// It checks if the contract has at least 5 functions of the standard and if it has the transfer function
-    // pub(crate) fn is_erc20(&self) -> bool {
-    //     self.erc20_compliancy() >= 5
-    //         && self.abi.as_ref().unwrap().get_function_by_signature("transfer", "address,uint256").is_some()
-    // }
-
-    // /// Returns true if the contract is ERC721 compliant, false otherwise
-    // /// It checks if the contract has at least 8 functions of the standard
-    // pub(crate) fn is_erc721(&self) -> bool {
-    //     self.erc721_compliancy() >= 8
-    // }
+    /// It checks if the contract has at least `erc_thresholds.erc20` functions of the standard
+    /// and if it has the transfer function
+    pub(crate) fn is_erc20(&self) -> bool {
+        self.erc20_compliancy() >= self.erc_thresholds.erc20
+            && self.abi.as_ref().is_some_and(|abi| {
+                abi.get_function_by_signature("transfer", "address,uint256")
+                    .is_some()
+            })
+    }
+
+    /// Returns true if the contract is ERC721 compliant, false otherwise
+    /// It checks if the contract has at least `erc_thresholds.erc721` functions of the standard
+    pub(crate) fn is_erc721(&self) -> bool {
+        self.abi.is_some() && self.erc721_compliancy() >= self.erc_thresholds.erc721
+    }
 
     /// How much the contract is ERC721 compliant
     /// Parameters:
@@ -163,8 +300,80 @@ impl Skeleton {
         &self,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
     ) -> Result<String, anyhow::Error> {
+        let (query, set) = self.upsert_parts();
+
+        // Perform the upsert
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_set_nquads(set);
+        let mut txn = dgraph_client.new_mutated_txn();
+        let res = txn.upsert(query, mu).await?;
+        txn.commit().await?;
+
+        #[derive(Deserialize, Debug)]
+        struct QueryItem {
+            uid: String,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Response {
+            skeleton: Vec<QueryItem>,
+        }
+
+        let res_parsed: Response = serde_json::from_slice(&res.json)?;
+
+        let uid = if res_parsed.skeleton.is_empty() {
+            res.uids.get("uid(Skeleton)").unwrap().clone()
+        } else {
+            res_parsed.skeleton.get(0).unwrap().uid.clone()
+        };
+
+        Ok(uid)
+    }
+
+    /// Query and mutation parts of the upsert, names and types coming
+    /// from the decompiled ABI are escaped
+    fn upsert_parts(&self) -> (String, String) {
         let bytecode = self.bytecode.to_string();
         let failed_decompilation = self.failed_decompilation;
+        let skipped_decompilation = self.skipped_decompilation;
+        let code_size = self.code_size();
+        let entropy = self.entropy();
+        let abi_json = match &self.abi {
+            Some(abi) => format!(
+                r#"uid(Skeleton) <Skeleton.abi_json> "{}" .
+        uid(Skeleton) <Skeleton.is_erc20> "{}" .
+        uid(Skeleton) <Skeleton.is_erc721> "{}" ."#,
+                escape_rdf(&abi.to_solidity_json().to_string()),
+                self.is_erc20(),
+                self.is_erc721()
+            ),
+            None => String::new(),
+        };
+        let interface_hash = match self.interface_hash() {
+            Some(hash) => format!(r#"uid(Skeleton) <Skeleton.interface_hash> "{:?}" ."#, hash),
+            None => String::new(),
+        };
+        let interface_minhash = match self.interface_minhash() {
+            Some(signature) => format!(
+                r#"uid(Skeleton) <Skeleton.minhash> "{}" ."#,
+                encode_signature(&signature)
+            ),
+            None => String::new(),
+        };
+        let decompiled_source = match &self.decompiled_source {
+            Some(source) => format!(
+                r#"uid(Skeleton) <Skeleton.decompiled_source> "{}" ."#,
+                escape_rdf(source)
+            ),
+            None => String::new(),
+        };
+        let decompiler_version = match &self.decompiler_version {
+            Some(version) => format!(
+                r#"uid(Skeleton) <Skeleton.decompiler_version> "{}" ."#,
+                escape_rdf(version)
+            ),
+            None => String::new(),
+        };
 
         let abi_queries = if self.abi.is_some() {
             self.abi
@@ -187,12 +396,16 @@ impl Skeleton {
                         uid(f{i}) <Function.name> "{name}" .
                         uid(f{i}) <Function.inputs> "{inputs}" .
                         uid(f{i}) <Function.outputs> "{outputs}" .
+                        uid(f{i}) <Function.state_mutability> "{state_mutability}" .
+                        uid(f{i}) <Function.payable> "{payable}" .
                         "#,
                             i = i,
                             sig = format!("{:?}", f.get_signature_hash()),
-                            name = f.name,
-                            inputs = f.get_input_types(),
-                            outputs = f.get_output_types()
+                            name = escape_rdf(&f.name),
+                            inputs = escape_rdf(&f.get_input_types()),
+                            outputs = escape_rdf(&f.get_output_types()),
+                            state_mutability = escape_rdf(f.get_state_mutability()),
+                            payable = f.is_payable()
                         ),
                     ),
                     ABIStructure::Event(e) => (
@@ -207,16 +420,18 @@ impl Skeleton {
                         uid(e{i}) <Event.signature> "{sig}" .
                         uid(e{i}) <Event.name> "{name}" .
                         uid(e{i}) <Event.inputs> "{inputs}" .
+                        uid(e{i}) <Event.indexed_inputs> "{indexed}" .
                         "#,
                             i = i,
                             sig = format!("{:?}", e.get_signature_hash()),
-                            name = e.name,
-                            inputs = e.get_input_types(),
+                            name = escape_rdf(&e.name),
+                            inputs = escape_rdf(&e.get_input_types()),
+                            indexed = e.get_indexed_inputs(),
                         ),
                     ),
                     ABIStructure::Error(e) => (
                         format!(
-                            r#"var(func: eq(Event.signature, "{:?}")){{ err{} as uid }}"#,
+                            r#"var(func: eq(Error.signature, "{:?}")){{ err{} as uid }}"#,
                             e.get_signature_hash(),
                             i
                         ),
@@ -229,8 +444,8 @@ impl Skeleton {
                         "#,
                             i = i,
                             sig = format!("{:?}", e.get_signature_hash()),
-                            name = e.name,
-                            inputs = e.get_input_types(),
+                            name = escape_rdf(&e.name),
+                            inputs = escape_rdf(&e.get_input_types()),
                         ),
                     ),
                 })
@@ -257,10 +472,26 @@ impl Skeleton {
             r#"
         uid(Skeleton) <Skeleton.bytecode> "{}" .
         uid(Skeleton) <Skeleton.failed_decompilation> "{}" .
+        uid(Skeleton) <Skeleton.skipped_decompilation> "{}" .
+        uid(Skeleton) <Skeleton.code_size> "{}" .
+        uid(Skeleton) <Skeleton.entropy> "{}" .
         uid(Skeleton) <dgraph.type> "Skeleton" .
+        {}
+        {}
+        {}
+        {}
+        {}
         {}"#,
             bytecode,
             failed_decompilation,
+            skipped_decompilation,
+            code_size,
+            entropy,
+            abi_json,
+            interface_hash,
+            interface_minhash,
+            decompiled_source,
+            decompiler_version,
             abi_queries
                 .iter()
                 .map(|(_, s)| s.clone())
@@ -268,32 +499,7 @@ impl Skeleton {
                 .join("\n")
         );
 
-        // Perform the upsert
-        let mut mu = dgraph_tonic::Mutation::new();
-        mu.set_set_nquads(set);
-        let mut txn = dgraph_client.new_mutated_txn();
-        let res = txn.upsert(query, mu).await?;
-        txn.commit().await?;
-
-        #[derive(Deserialize, Debug)]
-        struct QueryItem {
-            uid: String,
-        }
-
-        #[derive(Deserialize, Debug)]
-        struct Response {
-            skeleton: Vec<QueryItem>,
-        }
-
-        let res_parsed: Response = serde_json::from_slice(&res.json)?;
-
-        let uid = if res_parsed.skeleton.is_empty() {
-            res.uids.get("uid(Skeleton)").unwrap().clone()
-        } else {
-            res_parsed.skeleton.get(0).unwrap().uid.clone()
-        };
-
-        Ok(uid)
+        (query, set)
     }
 
     fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -313,13 +519,35 @@ impl Skeleton {
         state.serialize_field("uid", &uid)?;
         state.serialize_field("Skeleton.bytecode", &self.bytecode)?;
         state.serialize_field("Skeleton.failed_decompilation", &self.failed_decompilation)?;
+        state.serialize_field(
+            "Skeleton.skipped_decompilation",
+            &self.skipped_decompilation,
+        )?;
         state.serialize_field("Skeleton.erc20_compliancy", &self.erc20_compliancy())?;
         state.serialize_field("Skeleton.erc721_compliancy", &self.erc721_compliancy())?;
+        state.serialize_field("Skeleton.code_size", &self.code_size())?;
+        state.serialize_field("Skeleton.entropy", &self.entropy())?;
+        if let Some(source) = &self.decompiled_source {
+            state.serialize_field("Skeleton.decompiled_source", source)?;
+        }
+        if let Some(version) = &self.decompiler_version {
+            state.serialize_field("Skeleton.decompiler_version", version)?;
+        }
         let mut functions = Vec::new();
         let mut events = Vec::new();
         let mut errors = Vec::new();
         if self.abi.is_some() {
             let abi = self.abi.as_ref().unwrap();
+            state.serialize_field("Skeleton.abi_json", &abi.to_solidity_json().to_string())?;
+            state.serialize_field(
+                "Skeleton.interface_hash",
+                &format!("{:?}", self.interface_hash().unwrap()),
+            )?;
+            if let Some(signature) = self.interface_minhash() {
+                state.serialize_field("Skeleton.minhash", &encode_signature(&signature))?;
+            }
+            state.serialize_field("Skeleton.is_erc20", &self.is_erc20())?;
+            state.serialize_field("Skeleton.is_erc721", &self.is_erc721())?;
             for node in &abi.nodes {
                 let sig_hash = node.get_signature_hash();
                 match node {
@@ -330,7 +558,7 @@ impl Skeleton {
                         uid: format!("_:{:?}", sig_hash),
                     }),
                     ABIStructure::Error(_) => errors.push(Uid {
-                        uid: format!("_:{:?}", sig_hash),
+                        uid: format!("_:error_{:?}", sig_hash),
                     }),
                 }
             }
@@ -351,17 +579,315 @@ impl SerializeDgraph for Skeleton {
     }
 }
 
+impl OrderKey for Skeleton {
+    /// Skeletons are not tied to a block, they're sorted by bytecode hash
+    fn order_key(&self) -> (u64, String) {
+        (0, format!("{:?}", self.hash()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        models::skeleton::Skeleton,
-        utils::{decompile::decompile, metadata::separate_metadata, skeleton::extract_skeleton},
+        models::{
+            abi::{ABIStructure, ContractABI},
+            skeleton::{ErcThresholds, Skeleton},
+            SerializeDgraph,
+        },
+        utils::{
+            decompile::{decompile, default_tmp_dir},
+            metadata::separate_metadata,
+            skeleton::extract_skeleton,
+        },
     };
     use ethabi::Address;
     use ethers::providers::Middleware;
     use ethers::providers::Provider;
     use std::{str::FromStr, sync::Arc};
 
+    #[test]
+    fn test_code_size_and_entropy() {
+        let same_byte = Skeleton::new(ethers::types::Bytes::from(vec![0x60u8; 64]));
+        assert_eq!(same_byte.code_size(), 64);
+        assert_eq!(same_byte.entropy(), 0.0);
+
+        let uniform = Skeleton::new(ethers::types::Bytes::from((0..=255u8).collect::<Vec<u8>>()));
+        assert_eq!(uniform.code_size(), 256);
+        assert!((uniform.entropy() - 8.0).abs() < 1e-9);
+
+        let empty = Skeleton::new(ethers::types::Bytes::new());
+        assert_eq!(empty.code_size(), 0);
+        assert_eq!(empty.entropy(), 0.0);
+    }
+
+    #[test]
+    fn test_trivial_skeleton() {
+        let stop_only = Skeleton::new(ethers::types::Bytes::from(vec![0x00u8]));
+        let same_byte = Skeleton::new(ethers::types::Bytes::from(vec![0x5bu8; 128]));
+        let uniform = Skeleton::new(ethers::types::Bytes::from((0..=255u8).collect::<Vec<u8>>()));
+
+        // no filtering by default
+        for skeleton in [&stop_only, &same_byte, &uniform] {
+            assert!(!skeleton.is_trivial(0, 0.0));
+        }
+        assert!(stop_only.is_trivial(32, 0.0));
+        assert!(!same_byte.is_trivial(32, 0.0));
+        assert!(same_byte.is_trivial(32, 1.0));
+        assert!(!uniform.is_trivial(32, 1.0));
+
+        let mut skipped = stop_only.clone();
+        skipped.set_skipped_decompilation(true);
+        let json = skipped
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["Skeleton.skipped_decompilation"], true);
+        assert_eq!(json["Skeleton.failed_decompilation"], false);
+        assert!(json.get("Skeleton.abi_json").is_none());
+    }
+
+    #[test]
+    fn test_decompiler_version() {
+        let mut skeleton = Skeleton::new(ethers::types::Bytes::from(vec![0x60u8, 0x80]));
+        let json = skeleton
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert!(json.get("Skeleton.decompiler_version").is_none());
+        assert!(!skeleton.upsert_parts().1.contains("decompiler_version"));
+
+        skeleton.set_decompiler_version(Some("0.7.3".to_string()));
+        let json = skeleton
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["Skeleton.decompiler_version"], "0.7.3");
+        assert!(skeleton
+            .upsert_parts()
+            .1
+            .contains(r#"uid(Skeleton) <Skeleton.decompiler_version> "0.7.3" ."#));
+    }
+
+    #[test]
+    fn test_upsert_escaping() {
+        let abi = ContractABI::from_json(
+            r#"[
+                {
+                    "type": "function",
+                    "name": "say\"hi\"\\\n",
+                    "inputs": [{ "name": "s", "internalType": "string\"" }],
+                    "outputs": [{ "name": "", "internalType": "bool" }],
+                    "stateMutability": "view",
+                    "constant": true
+                },
+                {
+                    "type": "error",
+                    "name": "Oops\n",
+                    "inputs": []
+                }
+            ]"#,
+        )
+        .unwrap();
+        let mut skeleton = Skeleton::new(ethers::types::Bytes::from(vec![0x60u8, 0x80]));
+        skeleton.set_abi(abi);
+
+        let (_, set) = skeleton.upsert_parts();
+        assert!(set.contains(r#"<Function.name> "say\"hi\"\\\n" ."#));
+        assert!(set.contains(r#"<Function.inputs> "string\"" ."#));
+        assert!(set.contains(r#"<Function.state_mutability> "view" ."#));
+        assert!(set.contains(r#"<Function.payable> "false" ."#));
+        assert!(set.contains(r#"<Error.name> "Oops\n" ."#));
+        assert!(set.contains(r#"<Skeleton.abi_json> "[{\""#));
+        assert!(!set.contains("<Skeleton.decompiled_source>"));
+
+        skeleton.set_decompiled_source(Some("function say() {\n    return \"hi\";\n}".to_string()));
+        let (_, set) = skeleton.upsert_parts();
+        assert!(set.contains(
+            r#"<Skeleton.decompiled_source> "function say() {\n    return \"hi\";\n}" ."#
+        ));
+    }
+
+    #[test]
+    fn test_error_event_collision() {
+        // an event and an error with the same signature have the same hash
+        let abi = ContractABI::from_json(
+            r#"[
+                {
+                    "type": "event",
+                    "name": "Transfer",
+                    "inputs": [
+                        { "name": "from", "internalType": "address", "indexed": true },
+                        { "name": "to", "internalType": "address", "indexed": true },
+                        { "name": "value", "internalType": "uint256", "indexed": false }
+                    ],
+                    "anonymous": false
+                },
+                {
+                    "type": "error",
+                    "name": "Transfer",
+                    "inputs": [
+                        { "name": "from", "internalType": "address" },
+                        { "name": "to", "internalType": "address" },
+                        { "name": "value", "internalType": "uint256" }
+                    ]
+                }
+            ]"#,
+        )
+        .unwrap();
+        let hash = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+        let mut skeleton = Skeleton::new(ethers::types::Bytes::from(vec![0x60u8, 0x80]));
+        skeleton.set_abi(abi);
+
+        // each one is matched against the signatures of its own type
+        let (query, set) = skeleton.upsert_parts();
+        assert!(query.contains(&format!(
+            r#"eq(Event.signature, "{}")){{ e0 as uid }}"#,
+            hash
+        )));
+        assert!(query.contains(&format!(
+            r#"eq(Error.signature, "{}")){{ err1 as uid }}"#,
+            hash
+        )));
+        assert!(set.contains(r#"uid(e0) <dgraph.type> "Event" ."#));
+        assert!(set.contains(r#"uid(err1) <dgraph.type> "Error" ."#));
+
+        // and gets its own blank node in the bulk output
+        let json = skeleton
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        let abi = skeleton.abi.as_ref().unwrap();
+        let nodes = abi
+            .nodes
+            .iter()
+            .map(|n| match n {
+                ABIStructure::Event(e) => e.serialize_dgraph(serde_json::value::Serializer),
+                ABIStructure::Error(e) => e.serialize_dgraph(serde_json::value::Serializer),
+                ABIStructure::Function(f) => f.serialize_dgraph(serde_json::value::Serializer),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(json["Skeleton.events"][0]["uid"], nodes[0]["uid"]);
+        assert_eq!(json["Skeleton.errors"][0]["uid"], nodes[1]["uid"]);
+        assert_ne!(nodes[0]["uid"], nodes[1]["uid"]);
+    }
+
+    #[test]
+    fn test_interface_hash() {
+        let function = |name: &str, input: &str| {
+            format!(
+                r#"{{ "type": "function", "name": "{name}", "inputs": [{{ "name": "a", "internalType": "{input}" }}], "outputs": [], "stateMutability": "nonpayable", "constant": false }}"#
+            )
+        };
+        let with_abi = |functions: &[String]| {
+            let abi = ContractABI::from_json(&format!("[{}]", functions.join(","))).unwrap();
+            let mut skeleton = Skeleton::new(ethers::types::Bytes::from(vec![0x60u8, 0x80]));
+            skeleton.set_abi(abi);
+            skeleton
+        };
+        let transfer = function("transfer", "address");
+        let approve = function("approve", "address");
+        let unresolved = function("Unresolved_a9059cbb", "uint256");
+
+        let mut skeleton = with_abi(&[transfer.clone(), approve.clone()]);
+        let hash = skeleton.interface_hash();
+        assert!(hash.is_some());
+        assert_eq!(skeleton.interface_minhash(), None);
+        skeleton.set_minhash_size(16);
+        assert_eq!(skeleton.interface_minhash().unwrap().len(), 16);
+        assert!(skeleton.upsert_parts().1.contains("<Skeleton.minhash>"));
+        assert_eq!(
+            hash,
+            with_abi(&[approve.clone(), transfer.clone()]).interface_hash()
+        );
+        assert_ne!(
+            hash,
+            with_abi(&[transfer, approve, unresolved]).interface_hash()
+        );
+        assert_eq!(
+            Skeleton::new(ethers::types::Bytes::new()).interface_hash(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_erc_thresholds() {
+        let function = |name: &str, inputs: &[&str]| {
+            let inputs = inputs
+                .iter()
+                .map(|input| format!(r#"{{ "name": "a", "internalType": "{input}" }}"#))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                r#"{{ "type": "function", "name": "{name}", "inputs": [{inputs}], "outputs": [], "stateMutability": "nonpayable", "constant": false }}"#
+            )
+        };
+        let with_abi = |functions: &[String]| {
+            let abi = ContractABI::from_json(&format!("[{}]", functions.join(","))).unwrap();
+            let mut skeleton = Skeleton::new(ethers::types::Bytes::from(vec![0x60u8, 0x80]));
+            skeleton.set_abi(abi);
+            skeleton
+        };
+        let erc20 = [
+            function("totalSupply", &[]),
+            function("balanceOf", &["address"]),
+            function("transfer", &["address", "uint256"]),
+            function("transferFrom", &["address", "address", "uint256"]),
+            function("approve", &["address", "uint256"]),
+        ];
+        let erc721 = [
+            function("balanceOf", &["address"]),
+            function("ownerOf", &["uint256"]),
+            function("approve", &["address", "uint256"]),
+            function("getApproved", &["uint256"]),
+            function("setApprovalForAll", &["address", "bool"]),
+            function("isApprovedForAll", &["address", "address"]),
+            function("transferFrom", &["address", "address", "uint256"]),
+            function("safeTransferFrom", &["address", "address", "uint256"]),
+        ];
+
+        // 5 of the 6 ERC-20 functions and 8 of the 9 ERC-721 ones by default
+        let mut token = with_abi(&erc20);
+        assert_eq!(token.erc20_compliancy(), 5);
+        assert!(token.is_erc20());
+        let mut nft = with_abi(&erc721);
+        assert_eq!(nft.erc721_compliancy(), 8);
+        assert!(nft.is_erc721());
+        assert!(!with_abi(&erc20[..4]).is_erc20());
+        assert!(!with_abi(&erc721[..7]).is_erc721());
+
+        let strict = ErcThresholds {
+            erc20: 6,
+            erc721: 9,
+        };
+        token.set_erc_thresholds(strict);
+        nft.set_erc_thresholds(strict);
+        assert!(!token.is_erc20());
+        assert!(!nft.is_erc721());
+        let json = token
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(json["Skeleton.erc20_compliancy"], 5);
+        assert_eq!(json["Skeleton.is_erc20"], false);
+
+        // the transfer function is required whatever the threshold
+        let mut without_transfer = with_abi(&[&erc20[..2], &erc20[3..]].concat());
+        without_transfer.set_erc_thresholds(ErcThresholds {
+            erc20: 1,
+            erc721: 1,
+        });
+        assert!(!without_transfer.is_erc20());
+        assert!(without_transfer.is_erc721());
+
+        // skeletons without ABI are never classified
+        let mut no_abi = Skeleton::new(ethers::types::Bytes::new());
+        no_abi.set_erc_thresholds(ErcThresholds {
+            erc20: 0,
+            erc721: 0,
+        });
+        assert!(!no_abi.is_erc20() && !no_abi.is_erc721());
+        let json = no_abi
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert!(json.get("Skeleton.is_erc20").is_none());
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_skeleton_upsert() {
@@ -387,10 +913,18 @@ mod tests {
             extract_skeleton(&deployed_code)
         };
         let mut skeleton = Skeleton::new(skeleton);
-        let abi = decompile(&Address::from_str(address).unwrap(), &deployed_code, 5000).await;
+        let abi = decompile(
+            &Address::from_str(address).unwrap(),
+            &deployed_code,
+            &default_tmp_dir(),
+            5000,
+            false,
+            false,
+        )
+        .await;
 
         match abi {
-            Ok(abi) => skeleton.set_abi(abi),
+            Ok(decompiled) => skeleton.set_abi(decompiled.abi),
             Err(_) => skeleton.set_failed_decompilation(true),
         }
 
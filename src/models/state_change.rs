@@ -0,0 +1,173 @@
+use super::{account::account_node, OrderKey, SerializeDgraph};
+use ethers::types::{Address, BlockTrace, Diff, TxHash, H256};
+use serde::{ser::SerializeStruct, Serializer};
+use serde_json::json;
+
+/// A storage slot of a contract changed by a transaction, from the `stateDiff`
+/// of `trace_replayBlockTransactions`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    block: u64,
+    tx_hash: TxHash,
+    tx_index: usize,
+    contract: Address,
+    slot: H256,
+    from: H256,
+    to: H256,
+}
+
+impl StateChange {
+    /// Storage slots changed by the transactions of a block, in transaction order.
+    /// Slots set for the first time change from zero, cleared slots change to zero
+    pub fn from_block_traces(block: u64, traces: Vec<BlockTrace>) -> Vec<StateChange> {
+        let mut changes = Vec::new();
+        for (tx_index, trace) in traces.into_iter().enumerate() {
+            let (Some(tx_hash), Some(state_diff)) = (trace.transaction_hash, trace.state_diff)
+            else {
+                continue;
+            };
+            for (contract, diff) in state_diff.0 {
+                for (slot, value) in diff.storage {
+                    let (from, to) = match value {
+                        Diff::Same => continue,
+                        Diff::Born(to) => (H256::zero(), to),
+                        Diff::Died(from) => (from, H256::zero()),
+                        Diff::Changed(c) => (c.from, c.to),
+                    };
+                    changes.push(StateChange {
+                        block,
+                        tx_hash,
+                        tx_index,
+                        contract,
+                        slot,
+                        from,
+                        to,
+                    });
+                }
+            }
+        }
+        changes
+    }
+
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("StateChange", 8)?;
+        state.serialize_field("dgraph.type", "StateChange")?;
+        state.serialize_field("StateChange.contract", &account_node(&self.contract, true))?;
+        state.serialize_field(
+            "StateChange.block",
+            &json!({ "uid": format!("_:{:?}", self.block) }),
+        )?;
+        state.serialize_field(
+            "StateChange.tx",
+            &json!({ "uid": format!("_:{:?}", self.tx_hash) }),
+        )?;
+        state.serialize_field("StateChange.tx_index", &self.tx_index)?;
+        state.serialize_field("StateChange.slot", &format!("{:?}", self.slot))?;
+        state.serialize_field("StateChange.from", &format!("{:?}", self.from))?;
+        state.serialize_field("StateChange.to", &format!("{:?}", self.to))?;
+        state.end()
+    }
+}
+
+impl SerializeDgraph for StateChange {
+    fn serialize_dgraph<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_dgraph(serializer)
+    }
+}
+
+impl OrderKey for StateChange {
+    /// Changes are sorted by block number, then by transaction, contract and slot
+    fn order_key(&self) -> (u64, String) {
+        (
+            self.block,
+            format!("{:08}:{:?}:{:?}", self.tx_index, self.contract, self.slot),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StateChange;
+    use ethers::types::{Address, BlockTrace, H256};
+
+    #[test]
+    fn test_state_changes() {
+        let slot = |n: u64| format!("{:?}", H256::from_low_u64_be(n));
+        let contract = Address::from_low_u64_be(0xc0);
+        // the first transaction sets slot 1 and updates slot 2, the second one clears slot 1
+        let traces: Vec<BlockTrace> = serde_json::from_value(serde_json::json!([
+            {
+                "output": "0x",
+                "trace": [],
+                "vmTrace": null,
+                "stateDiff": {
+                    format!("{:?}", contract): {
+                        "balance": "=",
+                        "nonce": "=",
+                        "code": "=",
+                        "storage": {
+                            slot(1): { "+": slot(5) },
+                            slot(2): { "*": { "from": slot(7), "to": slot(8) } },
+                            slot(3): "="
+                        }
+                    },
+                    format!("{:?}", Address::from_low_u64_be(0xee)): {
+                        "balance": { "*": { "from": "0x10", "to": "0x5" } },
+                        "nonce": { "*": { "from": "0x1", "to": "0x2" } },
+                        "code": "=",
+                        "storage": {}
+                    }
+                },
+                "transactionHash": slot(100)
+            },
+            {
+                "output": "0x",
+                "trace": [],
+                "vmTrace": null,
+                "stateDiff": {
+                    format!("{:?}", contract): {
+                        "balance": "=",
+                        "nonce": "=",
+                        "code": "=",
+                        "storage": { slot(1): { "-": slot(5) } }
+                    }
+                },
+                "transactionHash": slot(101)
+            }
+        ]))
+        .unwrap();
+
+        let changes = StateChange::from_block_traces(10, traces);
+        let summary = changes
+            .iter()
+            .map(|c| (c.tx_index, c.slot, c.from, c.to))
+            .collect::<Vec<_>>();
+        let h = H256::from_low_u64_be;
+        assert_eq!(
+            summary,
+            vec![
+                (0, h(1), H256::zero(), h(5)),
+                (0, h(2), h(7), h(8)),
+                (1, h(1), h(5), H256::zero()),
+            ]
+        );
+
+        let json = changes[1]
+            .serialize_dgraph(serde_json::value::Serializer)
+            .unwrap();
+        assert_eq!(
+            json["StateChange.contract"]["uid"],
+            format!("_:{:?}", contract)
+        );
+        assert_eq!(json["StateChange.block"]["uid"], "_:10");
+        assert_eq!(json["StateChange.tx"]["uid"], format!("_:{}", slot(100)));
+        assert_eq!(json["StateChange.from"], slot(7));
+        assert_eq!(json["StateChange.to"], slot(8));
+    }
+}
@@ -1,11 +1,27 @@
 use ethabi::Address;
 use ethers::types::TxHash;
-use ethers::types::{Bytes, Trace};
+use ethers::types::{Action, Bytes, Trace, TransactionReceipt};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct Traces(pub Vec<Trace>);
 
+/// Gas used by the transactions of a block, split by kind of transaction.
+/// The gas of each transaction is the one of its receipt, so the buckets add up to the gas
+/// used by the block.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct GasBreakdown {
+    /// gas used by contract creations
+    pub creations: u64,
+    /// gas used by calls with input data
+    pub calls: u64,
+    /// gas used by calls without input data, i.e. plain ETH transfers
+    pub transfers: u64,
+    /// gas used by the reverted transactions, whatever their kind
+    pub reverted: u64,
+}
+
 pub struct CreationTrace {
     failed: bool,
     address: Address,
@@ -123,14 +139,211 @@ impl Traces {
             })
             .collect()
     }
+
+    /// Aggregate the gas used by the `tx_count` transactions of the block, by kind of transaction.
+    /// The gas used is taken from the receipts, the kind from the top level trace of each
+    /// transaction; reverted transactions are counted apart, since they're charged whatever
+    /// they were meant to do. Returns None if the data is
+    /// incomplete, i.e. a receipt, its gas used or the top level trace of its transaction is missing.
+    pub fn get_gas_breakdown(
+        &self,
+        tx_count: usize,
+        receipts: &[TransactionReceipt],
+    ) -> Option<GasBreakdown> {
+        if receipts.len() != tx_count {
+            return None;
+        }
+        let top_level = self
+            .0
+            .iter()
+            .filter(|t| t.trace_address.is_empty())
+            .filter_map(|t| Some((t.transaction_hash?, t)))
+            .collect::<HashMap<TxHash, &Trace>>();
+
+        let mut breakdown = GasBreakdown::default();
+        for receipt in receipts {
+            let gas_used = receipt.gas_used?.as_u64();
+            let trace = top_level.get(&receipt.transaction_hash)?;
+            // receipts have no status before Byzantium, the trace tells if it reverted
+            if receipt
+                .status
+                .map_or(trace.error.is_some(), |status| status.is_zero())
+            {
+                breakdown.reverted += gas_used;
+                continue;
+            }
+            match &trace.action {
+                Action::Create(_) => breakdown.creations += gas_used,
+                Action::Call(a) if a.input.is_empty() => breakdown.transfers += gas_used,
+                Action::Call(_) => breakdown.calls += gas_used,
+                _ => return None,
+            }
+        }
+        Some(breakdown)
+    }
+}
+
+/// Trace of the model tests, by default the top level call of transaction 1 in block 1,
+/// without result
+#[cfg(test)]
+pub(crate) struct TraceBuilder(Trace);
+
+#[cfg(test)]
+impl TraceBuilder {
+    pub(crate) fn new(action: Action) -> Self {
+        use ethers::types::ActionType;
+        let action_type = match action {
+            Action::Call(_) => ActionType::Call,
+            Action::Create(_) => ActionType::Create,
+            Action::Suicide(_) => ActionType::Suicide,
+            Action::Reward(_) => ActionType::Reward,
+        };
+        Self(Trace {
+            action,
+            result: None,
+            trace_address: vec![],
+            subtraces: 0,
+            transaction_position: Some(0),
+            transaction_hash: Some(TxHash::from_low_u64_be(1)),
+            block_number: 1,
+            block_hash: Default::default(),
+            action_type,
+            error: None,
+        })
+    }
+
+    pub(crate) fn result(mut self, result: ethers::types::Res) -> Self {
+        self.0.result = Some(result);
+        self
+    }
+
+    pub(crate) fn block(mut self, block_number: u64) -> Self {
+        self.0.block_number = block_number;
+        self
+    }
+
+    /// Transaction of the trace, its hash is `tx` as a big endian number
+    pub(crate) fn tx(mut self, tx: u64) -> Self {
+        self.0.transaction_hash = Some(TxHash::from_low_u64_be(tx));
+        self
+    }
+
+    pub(crate) fn trace_address(mut self, trace_address: Vec<usize>) -> Self {
+        self.0.trace_address = trace_address;
+        self
+    }
+
+    pub(crate) fn error(mut self, error: &str) -> Self {
+        self.0.error = Some(error.to_string());
+        self
+    }
+
+    pub(crate) fn build(self) -> Trace {
+        self.0
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::extraction::traces::get_traces;
+    use super::{GasBreakdown, TraceBuilder, Traces};
+    use crate::extraction::traces::{get_traces, TraceMethod};
     use ethers::providers::Provider;
+    use ethers::types::{
+        Action, Bytes, Call, CallResult, Create, CreateResult, Res, TransactionReceipt, TxHash,
+        U256,
+    };
     use std::sync::Arc;
 
+    fn call(input: Bytes) -> Action {
+        Action::Call(Call {
+            input,
+            ..Default::default()
+        })
+    }
+
+    fn call_result(gas_used: u64) -> Res {
+        Res::Call(CallResult {
+            gas_used: U256::from(gas_used),
+            output: Bytes::new(),
+        })
+    }
+
+    fn receipt(tx: u64, gas_used: u64, status: Option<u64>) -> TransactionReceipt {
+        TransactionReceipt {
+            transaction_hash: TxHash::from_low_u64_be(tx),
+            gas_used: Some(U256::from(gas_used)),
+            status: status.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_gas_breakdown() {
+        let tx_call = |tx, input: Vec<u8>, gas_used| {
+            TraceBuilder::new(call(Bytes::from(input)))
+                .result(call_result(gas_used))
+                .tx(tx)
+        };
+        let reverted = |tx| {
+            TraceBuilder::new(call(Bytes::from(vec![1])))
+                .tx(tx)
+                .error("Reverted")
+                .build()
+        };
+        let traces = Traces(vec![
+            TraceBuilder::new(Action::Create(Create::default()))
+                .result(Res::Create(CreateResult {
+                    gas_used: U256::from(100),
+                    ..Default::default()
+                }))
+                .tx(1)
+                .build(),
+            tx_call(2, vec![1, 2], 50).build(),
+            // internal calls are part of the gas of their transaction
+            tx_call(2, vec![1, 2], 20).trace_address(vec![0]).build(),
+            tx_call(3, vec![], 0).build(),
+            tx_call(4, vec![], 0).build(),
+            reverted(5),
+            // before Byzantium reverted transactions are only told by their trace
+            reverted(6),
+        ]);
+        // the receipts count the intrinsic gas too, not reported by the traces
+        let receipts = vec![
+            receipt(1, 53_100, Some(1)),
+            receipt(2, 21_550, Some(1)),
+            receipt(3, 21_000, Some(1)),
+            receipt(4, 21_000, Some(1)),
+            receipt(5, 22_000, Some(0)),
+            receipt(6, 30_000, None),
+        ];
+        let block_gas_used = 168_650;
+        let breakdown = traces.get_gas_breakdown(6, &receipts).unwrap();
+        assert_eq!(
+            breakdown,
+            GasBreakdown {
+                creations: 53_100,
+                calls: 21_550,
+                transfers: 42_000,
+                reverted: 52_000,
+            }
+        );
+        assert_eq!(
+            breakdown.creations + breakdown.calls + breakdown.transfers + breakdown.reverted,
+            block_gas_used
+        );
+
+        // incomplete data: missing receipts, traces or gas used
+        assert_eq!(traces.get_gas_breakdown(7, &receipts), None);
+        assert_eq!(Traces(vec![]).get_gas_breakdown(6, &receipts), None);
+        let mut no_gas = receipts.clone();
+        no_gas[0].gas_used = None;
+        assert_eq!(traces.get_gas_breakdown(6, &no_gas), None);
+        assert_eq!(
+            Traces(vec![]).get_gas_breakdown(0, &[]),
+            Some(GasBreakdown::default())
+        );
+    }
+
     #[tokio::test]
     async fn test_creation_trace_multiple_blocks() {
         let eth_node = std::env::var("ETH_NODE").expect("ETH_NODE env var is not set");
@@ -141,7 +354,9 @@ mod tests {
         let to = 14752500;
 
         while from < to {
-            let creation_traces = get_traces(from, eth_client.clone()).await.unwrap();
+            let creation_traces = get_traces(from, eth_client.clone(), TraceMethod::Parity)
+                .await
+                .unwrap();
             let creation_traces = creation_traces.get_creation_traces();
             println!(
                 "Block: {}, Creation traces: {}",
@@ -165,7 +380,9 @@ mod tests {
 
         let block = 14752490;
 
-        let creation_traces = get_traces(block, eth_client).await.unwrap();
+        let creation_traces = get_traces(block, eth_client, TraceMethod::Parity)
+            .await
+            .unwrap();
         let creation_traces = creation_traces.get_creation_traces();
 
         assert_eq!(creation_traces.len(), 2);
@@ -1,4 +1,4 @@
-use super::SerializeDgraph;
+use super::{OrderKey, SerializeDgraph};
 use dgraph_tonic::IClient;
 use dgraph_tonic::Mutate;
 use serde::{ser::SerializeStruct, Serialize, Serializer};
@@ -32,8 +32,29 @@ impl Transaction {
         &self,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
     ) -> Result<(), anyhow::Error> {
-        // mandatory
-        let block_no = self.block_number.as_ref().unwrap().as_u64();
+        let (query, set) = self.upsert_parts();
+
+        // Perform the upsert
+        let mut mu = dgraph_tonic::Mutation::new();
+        mu.set_set_nquads(set);
+        if self.block_number.is_none() {
+            // the pending transaction may have been mined in the meantime
+            mu.set_cond("@if(eq(len(Mined), 0))");
+        }
+        let mut txn = dgraph_client.new_mutated_txn();
+        txn.upsert(query, mu).await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Query and mutation of the upsert. The transaction is matched by hash, so upserting it
+    /// again with another block moves its `Transaction.block` edge to the new block.
+    /// Transactions without a block are pending: they're stored with `Transaction.pending`
+    /// and no block, unless the same hash is already stored as mined (`Mined` is then not empty)
+    fn upsert_parts(&self) -> (String, String) {
+        // mandatory, except for pending transactions
+        let block_no = self.block_number.map(|b| b.as_u64());
         let from = format!("{:?}", self.from);
         let to = if self.to.is_some() {
             format!("{:?}", self.to.as_ref().unwrap())
@@ -72,13 +93,24 @@ impl Transaction {
             None
         };
 
+        let block_query = match block_no {
+            Some(block_no) => format!(
+                r#"var(func: eq(Block.number, {block_no})) {{
+                Block as uid
+              }}"#
+            ),
+            None => format!(
+                r#"var(func: eq(Transaction.hash, "{tx_hash}")) @filter(has(Transaction.block)) {{
+                Mined as uid
+              }}"#
+            ),
+        };
+
         // Query part of the upsert
         let query = format!(
             r#"
             query {{
-              var(func: eq(Block.number, {block_no})) {{
-                Block as uid
-              }}
+              {block_query}
               var(func: eq(Transaction.hash, "{tx_hash}")) {{
                 Tx as uid
               }}
@@ -90,7 +122,7 @@ impl Transaction {
               }}
             }}
         "#,
-            block_no = block_no,
+            block_query = block_query,
             tx_hash = tx_hash,
             from = from,
             to = to
@@ -99,8 +131,6 @@ impl Transaction {
         // Mutation part of the upsert
         let mut set = format!(
             r#"
-            uid(Block) <Block.number> "{block_no}" .
-            uid(Block) <dgraph.type> "Block" .
             uid(From) <Account.address> "{from}" .
             uid(From) <dgraph.type> "Account" .
             uid(To) <Account.address> "{to}" .
@@ -108,7 +138,6 @@ impl Transaction {
 
             uid(Tx) <dgraph.type> "Transaction" .
             uid(Tx) <Transaction.hash> "{tx_hash}" .
-            uid(Tx) <Transaction.block> uid(Block) .
             uid(Tx) <Transaction.from> uid(From) .
             uid(Tx) <Transaction.to> uid(To) .
             uid(Tx) <Transaction.input> "{input}" .
@@ -129,6 +158,21 @@ impl Transaction {
             v = v,
         );
 
+        match block_no {
+            // mined, possibly after being stored as pending
+            Some(block_no) => set.push_str(&format!(
+                r#"uid(Block) <Block.number> "{block_no}" .
+            uid(Block) <dgraph.type> "Block" .
+            uid(Tx) <Transaction.block> uid(Block) .
+            uid(Tx) <Transaction.pending> "false" .
+            "#
+            )),
+            None => set.push_str(
+                r#"uid(Tx) <Transaction.pending> "true" .
+            "#,
+            ),
+        }
+
         if gas_price.is_some() {
             set.push_str(&format!(
                 r#"uid(Tx) <Transaction.gas_price> "{gas_price}" .
@@ -161,14 +205,7 @@ impl Transaction {
             ));
         }
 
-        // Perform the upsert
-        let mut mu = dgraph_tonic::Mutation::new();
-        mu.set_set_nquads(set);
-        let mut txn = dgraph_client.new_mutated_txn();
-        txn.upsert(query, mu).await?;
-        txn.commit().await?;
-
-        Ok(())
+        (query, set)
     }
 
     async fn _bake_tx<M: Mutate>(&self, dgraph_mut_tx: &mut M) -> Result<(), anyhow::Error> {
@@ -420,9 +457,21 @@ impl SerializeDgraph for Transaction {
     }
 }
 
+impl OrderKey for Transaction {
+    /// Transactions are sorted by block number, then by index inside the block
+    fn order_key(&self) -> (u64, String) {
+        let block_no = self.block_number.map(|b| b.as_u64()).unwrap_or_default();
+        let index = self
+            .transaction_index
+            .map(|i| i.as_u64())
+            .unwrap_or_default();
+        (block_no, format!("{:010}", index))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use dgraph_tonic::Mutate;
+    use dgraph_tonic::{Mutate, Query};
     use ethers::providers::{Middleware, Provider};
     use std::{str::FromStr, sync::Arc};
 
@@ -475,6 +524,139 @@ mod tests {
         println!("Transaction upsert took {:?}", elapsed);
     }
 
+    #[test]
+    fn test_move_tx_to_another_block() {
+        let mut tx = super::Transaction::from(ethers::types::Transaction {
+            hash: ethers::types::TxHash::from_low_u64_be(1),
+            block_number: Some(ethers::types::U64::from(100)),
+            ..Default::default()
+        });
+        let (query, set) = tx.upsert_parts();
+        assert!(query.contains("eq(Block.number, 100)"));
+        assert!(set.contains("uid(Tx) <Transaction.block> uid(Block) ."));
+
+        // re-included in a block of the new chain
+        tx.block_number = Some(ethers::types::U64::from(101));
+        let (moved_query, moved_set) = tx.upsert_parts();
+        assert!(moved_query.contains("eq(Block.number, 101)"));
+        assert!(!moved_query.contains("eq(Block.number, 100)"));
+        assert!(moved_query.contains(&format!("eq(Transaction.hash, \"{:?}\")", tx.hash)));
+        assert_eq!(set.replace("100", "101"), moved_set);
+    }
+
+    #[test]
+    fn test_pending_to_mined() {
+        let mut tx = super::Transaction::from(ethers::types::Transaction {
+            hash: ethers::types::TxHash::from_low_u64_be(1),
+            block_number: None,
+            ..Default::default()
+        });
+        let (query, set) = tx.upsert_parts();
+        assert!(query.contains("@filter(has(Transaction.block))"));
+        assert!(!query.contains("Block.number"));
+        assert!(set.contains("uid(Tx) <Transaction.pending> \"true\" ."));
+        assert!(!set.contains("Transaction.block"));
+
+        // mined, the same node gets the block and isn't pending anymore
+        tx.block_number = Some(ethers::types::U64::from(100));
+        let (mined_query, mined_set) = tx.upsert_parts();
+        assert!(mined_query.contains("eq(Block.number, 100)"));
+        assert!(mined_query.contains(&format!("eq(Transaction.hash, \"{:?}\")", tx.hash)));
+        assert!(mined_set.contains("uid(Tx) <Transaction.block> uid(Block) ."));
+        assert!(mined_set.contains("uid(Tx) <Transaction.pending> \"false\" ."));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upsert_pending_tx() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let dgraph = dgraph_tonic::Client::new(dgraph_endpoint.clone()).expect("Dgraph client");
+
+        let mut tx = super::Transaction::from(ethers::types::Transaction {
+            hash: ethers::types::TxHash::from_low_u64_be(0xbeef),
+            block_number: None,
+            ..Default::default()
+        });
+        let query = format!(
+            r#"{{
+                q(func: eq(Transaction.hash, "{:?}")) {{
+                    Transaction.pending
+                    Transaction.block {{ Block.number }}
+                }}
+            }}"#,
+            tx.hash
+        );
+        let stored = || async {
+            let resp = dgraph
+                .new_read_only_txn()
+                .query(query.clone())
+                .await
+                .expect("Query failed");
+            serde_json::from_slice::<serde_json::Value>(&resp.json).unwrap()["q"].clone()
+        };
+
+        // seen twice in the mempool, then mined
+        tx.upsert(&dgraph).await.expect("Upsert failed");
+        tx.upsert(&dgraph).await.expect("Upsert failed");
+        let json = stored().await;
+        assert_eq!(json.as_array().unwrap().len(), 1);
+        assert_eq!(json[0]["Transaction.pending"], true);
+
+        let pending = super::Transaction::from(tx.0.clone());
+        tx.block_number = Some(ethers::types::U64::from(100));
+        tx.upsert(&dgraph).await.expect("Upsert failed");
+        let json = stored().await;
+        assert_eq!(json[0]["Transaction.pending"], false);
+        assert_eq!(json[0]["Transaction.block"]["Block.number"], 100);
+
+        // a late mempool notification doesn't make it pending again
+        pending.upsert(&dgraph).await.expect("Upsert failed");
+        let json = stored().await;
+        assert_eq!(json.as_array().unwrap().len(), 1);
+        assert_eq!(json[0]["Transaction.pending"], false);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_upsert_moved_tx() {
+        let dgraph_endpoint = std::env::var("DGRAPH").expect("Dgraph endpoint");
+        let dgraph = dgraph_tonic::Client::new(dgraph_endpoint.clone()).expect("Dgraph client");
+
+        let mut tx = super::Transaction::from(ethers::types::Transaction {
+            hash: ethers::types::TxHash::from_low_u64_be(0xdead),
+            block_number: Some(ethers::types::U64::from(100)),
+            ..Default::default()
+        });
+        tx.upsert(&dgraph).await.expect("Upsert failed");
+
+        // block 100 is reorged out, the tx is re-included in block 101
+        crate::models::block::Block::upsert_detach_transactions(100, &dgraph)
+            .await
+            .expect("Detach failed");
+        tx.block_number = Some(ethers::types::U64::from(101));
+        tx.upsert(&dgraph).await.expect("Upsert failed");
+
+        let query = format!(
+            r#"{{
+                q(func: eq(Transaction.hash, "{:?}")) {{
+                    Transaction.block {{ Block.number }}
+                }}
+                old(func: eq(Block.number, 100)) {{
+                    count(~Transaction.block)
+                }}
+            }}"#,
+            tx.hash
+        );
+        let resp = dgraph
+            .new_read_only_txn()
+            .query(query)
+            .await
+            .expect("Query failed");
+        let json: serde_json::Value = serde_json::from_slice(&resp.json).unwrap();
+        assert_eq!(json["q"][0]["Transaction.block"]["Block.number"], 101);
+        assert_eq!(json["old"][0]["count(~Transaction.block)"], 0);
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_upsert_whole_block() {
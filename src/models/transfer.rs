@@ -1,14 +1,18 @@
+use crate::debug;
 use dgraph_tonic::{IClient, Mutate};
 use ethers::types::{Address, TxHash, U256, U64};
-use serde::{ser::SerializeStruct, Serialize, Serializer};
-use serde_json::json;
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 
-use super::SerializeDgraph;
+use super::{account::account_node, block::rfc3339, OrderKey, SerializeDgraph};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TokenType {
     ERC20,
     ERC721,
+    ERC1155,
+    /// Wrapped ether deposits and withdrawals, i.e. mints and burns of WETH
+    Weth,
 }
 
 #[derive(Debug)]
@@ -18,8 +22,48 @@ pub struct TokenTransfer {
     to: Address,
     value: U256,
     block: U64,
+    timestamp: u64,
     tx_hash: TxHash,
     token_type: TokenType,
+    /// Id of the token moved by ERC-1155 transfers, whose `value` is the amount
+    token_id: Option<U256>,
+}
+
+/// Header of the CSV export of the transfers, see `TokenTransfer::csv_row`
+pub const CSV_HEADER: &str = "contract,from,to,value,token_id,token_type,block,tx_hash,datetime";
+
+impl TokenType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokenType::ERC20 => "erc20",
+            TokenType::ERC721 => "erc721",
+            TokenType::ERC1155 => "erc1155",
+            TokenType::Weth => "weth",
+        }
+    }
+}
+
+/// Number of token transfers of a block per standard, WETH deposits and withdrawals are
+/// counted as ERC-20 transfers
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct TransferCounts {
+    pub erc20: u64,
+    pub erc721: u64,
+    pub erc1155: u64,
+}
+
+impl TransferCounts {
+    pub fn new(transfers: &[TokenTransfer]) -> Self {
+        let mut counts = Self::default();
+        for transfer in transfers {
+            match transfer.token_type() {
+                TokenType::ERC20 | TokenType::Weth => counts.erc20 += 1,
+                TokenType::ERC721 => counts.erc721 += 1,
+                TokenType::ERC1155 => counts.erc1155 += 1,
+            }
+        }
+        counts
+    }
 }
 
 impl TokenTransfer {
@@ -38,11 +82,59 @@ impl TokenTransfer {
             to,
             value,
             block,
+            timestamp: 0,
             tx_hash,
             token_type,
+            token_id: None,
         }
     }
 
+    pub fn set_token_id(&mut self, token_id: U256) {
+        self.token_id = Some(token_id);
+    }
+
+    /// Timestamp of the block of the transfer, 0 until it's set
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn sender(&self) -> Address {
+        self.from
+    }
+
+    pub fn recipient(&self) -> Address {
+        self.to
+    }
+
+    pub fn token_type(&self) -> TokenType {
+        self.token_type
+    }
+
+    /// Row of the CSV export, with the columns of `CSV_HEADER`. Like in Dgraph, ERC-721
+    /// transfers have the token id and no value. No field can contain a comma, so none is quoted
+    pub fn csv_row(&self) -> String {
+        let (value, token_id) = match self.token_type {
+            TokenType::ERC20 | TokenType::Weth => (self.value.to_string(), String::new()),
+            TokenType::ERC721 => (String::new(), self.value.to_string()),
+            TokenType::ERC1155 => (
+                self.value.to_string(),
+                self.token_id.map(|id| id.to_string()).unwrap_or_default(),
+            ),
+        };
+        format!(
+            "{:?},{:?},{:?},{},{},{},{},{:?},{}",
+            self.contract,
+            self.from,
+            self.to,
+            value,
+            token_id,
+            self.token_type.as_str(),
+            self.block,
+            self.tx_hash,
+            rfc3339(self.timestamp)
+        )
+    }
+
     pub async fn upsert<S: IClient>(
         &self,
         dgraph_client: &dgraph_tonic::ClientVariant<S>,
@@ -59,6 +151,7 @@ impl TokenTransfer {
         let from = format!("{:?}", self.from);
         let to = format!("{:?}", self.to);
         let value = format!("{}", self.value);
+        let datetime = rfc3339(self.timestamp);
 
         // Query part of the upsert
         let query = format!(
@@ -89,7 +182,7 @@ impl TokenTransfer {
         );
 
         // Mutation part of the upsert
-        let set = format!(
+        let mut set = format!(
             r#"
             uid(Block) <Block.number> "{block_no}" .
             uid(Block) <dgraph.type> "Block" .
@@ -109,14 +202,26 @@ impl TokenTransfer {
             _:transfer <TokenTransfer.from> uid(From) .
             _:transfer <TokenTransfer.to> uid(To) .
             _:transfer <TokenTransfer.value> "{value}" .
+            _:transfer <TokenTransfer.datetime> "{datetime}" .
         "#,
             block_no = block_no,
             contract_address = contract_address,
             value = value,
+            datetime = datetime,
             from = from,
             to = to,
             tx_hash = tx_hash
         );
+        if let Some(token_id) = &self.token_id {
+            set.push_str(&format!(
+                r#"_:transfer <TokenTransfer.token_id> "{token_id}" .
+                "#,
+                token_id = token_id,
+            ));
+        }
+
+        debug!("Upserting query: {}", query);
+        debug!("Upserting set: {}", set);
 
         // Perform the upsert
         let mut mu = dgraph_tonic::Mutation::new();
@@ -124,8 +229,6 @@ impl TokenTransfer {
         let mut txn = dgraph_client.new_mutated_txn();
         txn.upsert(query, mu).await?;
         txn.commit().await?;
-        // println!("Upserting query: {}", query);
-        // println!("Upserting set: {}", set);
 
         Ok(())
     }
@@ -146,48 +249,27 @@ impl TokenTransfer {
             #[serde(rename = "Transaction.hash")]
             hash: String,
         }
-        #[derive(Serialize)]
-        struct AddressReference {
-            uid: String,
-            #[serde(rename = "dgraph.type")]
-            _type: String,
-            #[serde(rename = "Account.address")]
-            address: String,
-        }
-        let mut state = serializer.serialize_struct("TokenTransfer", 7)?;
+        let mut state = serializer.serialize_struct("TokenTransfer", 8)?;
         state.serialize_field("dgraph.type", "TokenTransfer")?;
         state.serialize_field(
             "TokenTransfer.contract",
-            &json!({
-                "uid": format!("_:{:?}", self.contract),
-                "dgraph.type": "Account",
-                "Account.address": format!("{:?}", self.contract),
-                "Account.is_contract": true,
-            }),
-        )?;
-        state.serialize_field(
-            "TokenTransfer.from",
-            &AddressReference {
-                uid: format!("_:{:?}", self.from),
-                _type: "Account".to_string(),
-                address: format!("{:?}", self.from),
-            },
-        )?;
-        state.serialize_field(
-            "TokenTransfer.to",
-            &AddressReference {
-                uid: format!("_:{:?}", self.to),
-                _type: "Account".to_string(),
-                address: format!("{:?}", self.to),
-            },
+            &account_node(&self.contract, true),
         )?;
+        state.serialize_field("TokenTransfer.from", &account_node(&self.from, false))?;
+        state.serialize_field("TokenTransfer.to", &account_node(&self.to, false))?;
         match self.token_type {
-            TokenType::ERC20 => {
+            TokenType::ERC20 | TokenType::Weth => {
                 state.serialize_field("TokenTransfer.value", &format!("{}", self.value))?;
             }
             TokenType::ERC721 => {
                 state.serialize_field("TokenTransfer.token_id", &format!("{}", self.value))?;
             }
+            TokenType::ERC1155 => {
+                if let Some(token_id) = &self.token_id {
+                    state.serialize_field("TokenTransfer.token_id", &format!("{}", token_id))?;
+                }
+                state.serialize_field("TokenTransfer.value", &format!("{}", self.value))?;
+            }
         }
         state.serialize_field(
             "TokenTransfer.block",
@@ -195,6 +277,7 @@ impl TokenTransfer {
                 uid: format!("_:{}", self.block.as_u64()),
             },
         )?;
+        state.serialize_field("TokenTransfer.datetime", &rfc3339(self.timestamp))?;
         state.serialize_field(
             "TokenTransfer.tx",
             &Uid {
@@ -214,6 +297,21 @@ impl SerializeDgraph for TokenTransfer {
     }
 }
 
+impl OrderKey for TokenTransfer {
+    /// Transfers are sorted by block number, then by transaction hash, contract, sender, receiver,
+    /// value and ERC-1155 token id
+    fn order_key(&self) -> (u64, String) {
+        let mut key = format!(
+            "{:?}{:?}{:?}{:?}{:064x}",
+            self.tx_hash, self.contract, self.from, self.to, self.value
+        );
+        if let Some(token_id) = &self.token_id {
+            key.push_str(&format!("{:064x}", token_id));
+        }
+        (self.block.as_u64(), key)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -231,7 +329,7 @@ mod tests {
 
     #[test]
     fn test_transfer_serialization() {
-        let transfer = TokenTransfer::new(
+        let mut transfer = TokenTransfer::new(
             Address::from_low_u64_be(1),
             Address::from_low_u64_be(2),
             Address::from_low_u64_be(3),
@@ -242,6 +340,7 @@ mod tests {
                 .unwrap(),
             TokenType::ERC20,
         );
+        transfer.set_timestamp(1672531200);
         let expected = json!({
             "dgraph.type": "TokenTransfer",
             "TokenTransfer.contract": {
@@ -264,6 +363,7 @@ mod tests {
             "TokenTransfer.block": {
                 "uid": "_:5"
             },
+            "TokenTransfer.datetime": "2023-01-01T00:00:00+00:00",
             "TokenTransfer.tx": {
                 "uid": "_:0x1844fe0131ddb020be1764d1c28f0ae03335a9d1b1348fb8c13d84a279c4a955"
             }
@@ -288,7 +388,7 @@ mod tests {
         let block = 16100001u64;
 
         let logs = get_all_logs(block, eth_client).await.unwrap();
-        let transfers: Vec<TokenTransfer> = get_transfer_from_logs(&logs);
+        let transfers: Vec<TokenTransfer> = get_transfer_from_logs(&logs, 0, None, None);
 
         for transfer in transfers {
             let mut serializer = serde_json::Serializer::new(Vec::new());
@@ -312,7 +412,7 @@ mod tests {
         let block = 16100001u64;
 
         let logs = get_all_logs(block, eth_client).await.unwrap();
-        let transfers: Vec<TokenTransfer> = get_transfer_from_logs(&logs);
+        let transfers: Vec<TokenTransfer> = get_transfer_from_logs(&logs, 0, None, None);
 
         let transfer_to_test = transfers.get(0).unwrap();
         transfer_to_test.upsert(&dgraph).await.unwrap();
@@ -332,7 +432,7 @@ mod tests {
         let block_no = 16100001u64;
 
         let logs = get_all_logs(block_no, eth_client).await.unwrap();
-        let transfers: Vec<TokenTransfer> = get_transfer_from_logs(&logs);
+        let transfers: Vec<TokenTransfer> = get_transfer_from_logs(&logs, 0, None, None);
 
         let now = tokio::time::Instant::now();
 
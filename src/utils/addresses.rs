@@ -0,0 +1,39 @@
+use ethers::types::Address;
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+/// Parse a list of addresses from a file or from a comma separated list.
+/// If `input` is the path of an existing file, addresses are read from it,
+/// separated by new lines or commas. Addresses are accepted case-insensitively.
+/// Used as clap value parser.
+pub fn parse_address_list(input: &str) -> Result<HashSet<Address>, String> {
+    let content = if Path::new(input).is_file() {
+        std::fs::read_to_string(input).map_err(|e| format!("Cannot read {}: {}", input, e))?
+    } else {
+        input.to_string()
+    };
+    content
+        .split([',', '\n'])
+        .map(|a| a.trim())
+        .filter(|a| !a.is_empty())
+        .map(|a| {
+            Address::from_str(&a.to_lowercase()).map_err(|_| format!("Invalid address: {}", a))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_address_list;
+    use ethers::types::Address;
+
+    #[test]
+    fn test_parse_address_list() {
+        let list = parse_address_list(
+            "0x06012c8cf97BEaD5deAe237070F9587f8E7A266d, 0x06012c8cf97bead5deae237070f9587f8e7a266d,0x0000000000000000000000000000000000000001",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list.contains(&Address::from_low_u64_be(1)));
+        assert!(parse_address_list("0x123").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use clap::{parser::ValueSource, Arg, ArgAction, Command, Id};
+use std::collections::HashSet;
+
+/// Subcommands accepting `--config`
+const CONFIG_COMMANDS: [&str; 2] = ["extract", "stream"];
+
+/// Path given with `--config <path>` or `--config=<path>`, if any
+fn config_path(args: &[String]) -> Option<String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().cloned();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// Args of the subcommand at `position` given on the command line, including the boolean flags
+/// turned off with `--no-<flag>`, which are removed from `args`
+fn command_line_args(cli: &Command, args: &mut Vec<String>, position: usize) -> HashSet<Id> {
+    let command = cli
+        .find_subcommand(&args[position])
+        .expect("position of a subcommand");
+    let mut given = HashSet::new();
+    let mut i = position + 1;
+    while i < args.len() {
+        let negated = args[i].strip_prefix("--no-").and_then(|long| {
+            command.get_arguments().find(|a| {
+                a.get_long() == Some(long) && matches!(a.get_action(), ArgAction::SetTrue)
+            })
+        });
+        match negated {
+            Some(arg) => {
+                given.insert(arg.get_id().clone());
+                args.remove(i);
+            }
+            None => i += 1,
+        }
+    }
+    // errors like missing arguments are reported by the real parse, once the config is expanded
+    if let Ok(matches) = cli
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(args.iter())
+    {
+        if let Some(matches) = matches.subcommand_matches(command.get_name()) {
+            given.extend(
+                command
+                    .get_arguments()
+                    .filter(|a| {
+                        matches.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine)
+                    })
+                    .map(|a| a.get_id().clone()),
+            );
+        }
+    }
+    given
+}
+
+/// Whether `arg` is given on the command line, or conflicts with an arg that is
+fn overridden(command: &Command, arg: &Arg, given: &HashSet<Id>) -> bool {
+    given.contains(arg.get_id())
+        || command
+            .get_arguments()
+            .filter(|a| given.contains(a.get_id()))
+            .any(|a| {
+                command.get_arg_conflicts_with(a).contains(&arg)
+                    || command.get_arg_conflicts_with(arg).contains(&a)
+            })
+}
+
+/// Command line flags of the keys of a TOML config, e.g. `num_tasks = 10` is `--num-tasks 10`.
+/// Keys are the names of the flags of the subcommand, with `_` or `-`.
+/// A `true` boolean sets the flag, a `false` one leaves it unset, arrays give the flag once
+/// per value if it can be repeated, otherwise their values are joined with commas.
+/// Keys of the args in `given`, or conflicting with one of them, are left out
+fn config_flags(
+    content: &str,
+    command: &Command,
+    given: &HashSet<Id>,
+) -> Result<Vec<String>, String> {
+    let table: toml::Table = toml::from_str(content).map_err(|e| e.to_string())?;
+    let mut flags = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(long.as_str()) && long != "config")
+            .ok_or_else(|| format!("unknown key {} for {}", key, command.get_name()))?;
+        if overridden(command, arg, given) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        let scalar = |value: &toml::Value| match value {
+            toml::Value::String(s) => Ok(s.clone()),
+            toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Datetime(_) => {
+                Ok(value.to_string())
+            }
+            _ => Err(format!("invalid value of {}: {}", key, value)),
+        };
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, toml::Value::Boolean(true)) => flags.push(flag),
+            (ArgAction::SetTrue, toml::Value::Boolean(false)) => {}
+            (ArgAction::SetTrue, value) => {
+                return Err(format!("{} must be true or false, got {}", key, value))
+            }
+            (ArgAction::Append, toml::Value::Array(values)) => {
+                for value in &values {
+                    flags.push(flag.clone());
+                    flags.push(scalar(value)?);
+                }
+            }
+            (_, toml::Value::Array(values)) => {
+                let values = values.iter().map(scalar).collect::<Result<Vec<_>, _>>()?;
+                flags.push(flag);
+                flags.push(values.join(","));
+            }
+            (_, value) => {
+                flags.push(flag);
+                flags.push(scalar(&value)?);
+            }
+        }
+    }
+    Ok(flags)
+}
+
+/// Expand the `--config <path>` of the command line with the flags of the TOML file, inserted
+/// right after the subcommand. The keys of the flags on the command line, or of flags conflicting
+/// with them, are ignored, and `--no-<flag>` turns off a boolean flag of the config
+pub fn expand_config(mut args: Vec<String>, cli: &Command) -> Result<Vec<String>, String> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let Some((position, command)) = args.iter().enumerate().skip(1).find_map(|(i, arg)| {
+        cli.get_subcommands()
+            .find(|c| c.get_name() == arg)
+            .map(|c| (i, c))
+    }) else {
+        return Ok(args);
+    };
+    if !CONFIG_COMMANDS.contains(&command.get_name()) {
+        return Err(format!(
+            "--config is only supported by {}",
+            CONFIG_COMMANDS.join(" and ")
+        ));
+    }
+    let given = command_line_args(cli, &mut args, position);
+    let content =
+        std::fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let flags = config_flags(&content, command, &given)
+        .map_err(|e| format!("Invalid config {}: {}", path, e))?;
+    let mut expanded = args[..=position].to_vec();
+    expanded.extend(flags);
+    expanded.extend_from_slice(&args[position + 1..]);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_config;
+    use crate::{Cli, Commands};
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn test_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("eth2dgraph_config_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "endpoint = \"http://node:8545\"\n\
+             num_tasks = 4\n\
+             output-path = \"config_output\"\n\
+             include_tx = true\n\
+             include_logs = false\n\
+             transfer_contracts = [\"0x0000000000000000000000000000000000000001\", \"0x0000000000000000000000000000000000000002\"]\n",
+        )
+        .unwrap();
+        let args = |args: &[&str]| {
+            let args = [
+                "eth2dgraph",
+                "-q",
+                "extract",
+                "--config",
+                path.to_str().unwrap(),
+            ]
+            .iter()
+            .chain(args)
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>();
+            expand_config(args, &Cli::command())
+        };
+
+        // the flags on the command line win over the config
+        let expanded = args(&["-f", "1", "-t", "2", "-n", "8"]).unwrap();
+        let Commands::Extract(extract) = Cli::try_parse_from(expanded).unwrap().command else {
+            panic!("not an extract command");
+        };
+        assert_eq!(extract.num_tasks, 8);
+        assert_eq!(extract.endpoint, "http://node:8545");
+        assert_eq!(extract.output_path, "config_output");
+        assert!(extract.include.include_tx);
+        assert!(!extract.include.include_logs);
+        assert_eq!(extract.include.transfer_contracts.unwrap().len(), 2);
+
+        std::fs::write(&path, "num_taks = 4\n").unwrap();
+        let err = args(&["-f", "1", "-t", "2"]).unwrap_err();
+        assert!(err.contains("unknown key num_taks"), "{}", err);
+        std::fs::write(&path, "include_tx = \"yes\"\n").unwrap();
+        assert!(args(&["-f", "1", "-t", "2"]).is_err());
+        let _ = std::fs::remove_file(&path);
+
+        // only the subcommands parsing it accept a config
+        let queries = ["eth2dgraph", "queries", "--config", "config.toml"]
+            .map(|a| a.to_string())
+            .to_vec();
+        assert!(expand_config(queries, &Cli::command()).is_err());
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let path = std::env::temp_dir().join(format!(
+            "eth2dgraph_config_overrides_{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "endpoint = \"http://node:8545\"\n\
+             from_block = 5\n\
+             to_block = 10\n\
+             include_tx = true\n",
+        )
+        .unwrap();
+        let parse = |args: &[&str]| {
+            let args = ["eth2dgraph", "extract", "--config", path.to_str().unwrap()]
+                .iter()
+                .chain(args)
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>();
+            let expanded = expand_config(args, &Cli::command()).unwrap();
+            match Cli::try_parse_from(expanded).unwrap().command {
+                Commands::Extract(extract) => extract,
+                _ => panic!("not an extract command"),
+            }
+        };
+
+        // a boolean of the config is turned off with --no-<flag>
+        let extract = parse(&["--no-include-tx", "-q"]);
+        assert!(!extract.include.include_tx);
+        assert_eq!(extract.from_block, Some(5));
+
+        // flags conflicting with the range of the config replace it
+        let extract = parse(&["--block", "7"]);
+        assert_eq!(extract.block, Some(7));
+        assert_eq!((extract.from_block, extract.to_block), (None, None));
+        assert!(extract.include.include_tx);
+        let extract = parse(&["--since", "manifest.json"]);
+        assert_eq!(extract.since.as_deref(), Some("manifest.json"));
+        assert_eq!((extract.from_block, extract.to_block), (None, Some(10)));
+        let _ = std::fs::remove_file(&path);
+    }
+}
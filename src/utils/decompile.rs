@@ -1,9 +1,20 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use ethabi::Address;
 
+use crate::info;
 use crate::models::abi::ContractABI;
 
+/// Artifacts of a decompilation
+#[derive(Debug)]
+pub struct Decompiled {
+    pub abi: ContractABI,
+    /// Solidity-like source, if requested and produced by heimdall
+    pub source: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum DecompilationError {
     Timeout,
@@ -11,18 +22,127 @@ pub enum DecompilationError {
     FailedToParseABI,
 }
 
+/// Timeout of a decompilation when `running` decompilations share the `cpus` cores:
+/// `timeout` is meant for one decompilation per core, so it's multiplied by the number
+/// of decompilations each core is running, without exceeding `max_timeout`
+pub fn scaled_timeout(timeout: u64, max_timeout: u64, running: usize, cpus: usize) -> u64 {
+    let per_core = running.div_ceil(cpus.max(1)).max(1) as u64;
+    timeout
+        .saturating_mul(per_core)
+        .min(max_timeout.max(timeout))
+}
+
+/// Version of heimdall, probed once
+static DECOMPILER_VERSION: OnceLock<Result<String, String>> = OnceLock::new();
+
+/// Version in the output of `heimdall --version`, e.g. `0.7.3` from `heimdall 0.7.3`
+fn parse_version(output: &str) -> Option<String> {
+    output
+        .lines()
+        .next()?
+        .split_whitespace()
+        .last()
+        .map(|version| version.to_string())
+}
+
+/// Version of the heimdall in the PATH, stored with the skeletons it decompiles.
+/// The probe runs on the first call only, its error is returned by every call
+pub fn decompiler_version() -> Result<&'static str, &'static str> {
+    let version = DECOMPILER_VERSION.get_or_init(|| {
+        let output = std::process::Command::new("heimdall")
+            .arg("--version")
+            .output()
+            .map_err(|e| format!("could not run heimdall --version: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("heimdall --version exited with {}", output.status));
+        }
+        parse_version(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| "heimdall --version printed no version".to_string())
+    });
+    version.as_deref().map_err(|e| e.as_str())
+}
+
+/// Probe the decompiler version at startup, skeletons are stored without it if it fails
+pub fn probe_decompiler_version() {
+    match decompiler_version() {
+        Ok(version) => info!("Decompiling with heimdall {}", version),
+        Err(e) => eprintln!(
+            "WARNING: {}, skeletons are stored without the decompiler version",
+            e
+        ),
+    }
+}
+
+/// Default root of the heimdall outputs, unique to the process so runs in the same folder
+/// don't share it
+pub fn default_tmp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("eth2dgraph-{}", std::process::id()))
+}
+
+/// Output directory of a decompilation, deleted when dropped, so it's cleaned up on every
+/// return and on panic, unless it's kept to debug a failed decompilation
+struct OutputDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl OutputDir {
+    fn new(tmp_dir: &Path, address: &Address) -> Self {
+        Self {
+            path: tmp_dir.join(format!("{:?}", address)),
+            keep: false,
+        }
+    }
+
+    /// Keep the heimdall output of a failed decompilation with `keep_output`
+    fn failed(&mut self, address: &Address, keep_output: bool) {
+        if keep_output {
+            info!(
+                "Decompiler output of {:?} kept in {}",
+                address,
+                self.path.display()
+            );
+            self.keep = true;
+        }
+    }
+}
+
+impl Drop for OutputDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Decompile a contract with heimdall, reading the ABI and, with `include_source`,
+/// the Solidity-like source. Heimdall may produce the ABI without the source,
+/// in that case the decompilation succeeds with no source.
+/// Heimdall works in `<tmp_dir>/<address>/`, with `keep_output` the directory of failed
+/// decompilations isn't deleted
 pub async fn decompile(
     address: &Address,
     bytecode: &ethers::types::Bytes,
+    tmp_dir: &Path,
     timeout: u64,
-) -> Result<ContractABI, DecompilationError> {
+    include_source: bool,
+    keep_output: bool,
+) -> Result<Decompiled, DecompilationError> {
+    // work in <tmp_dir>/<contract_address>/ since it's unique
+    let mut dir = OutputDir::new(tmp_dir, address);
     // spawn a new heimdall process to decompile the contract using the async tokio implementation of process
-    let mut cmd = tokio::process::Command::new("heimdall")
+    let mut command = tokio::process::Command::new("heimdall");
+    command
         .arg("decompile")
         .arg(bytecode.to_string())
         .arg("--default")
         .arg("--output") // output directory
-        .arg(format!(".tmp/{}/", address)) // work in .tmp/<contract_address>/ since it's unique
+        .arg(&dir.path)
+        .kill_on_drop(true); // don't leave heimdall running if the task is dropped
+    if include_source {
+        command.arg("--include-sol"); // also write decompiled.sol
+    }
+    let mut cmd = command
         .stdout(std::process::Stdio::null()) // redirect stdout and stderr to /dev/null
         .stderr(std::process::Stdio::null())
         .spawn()
@@ -31,17 +151,17 @@ pub async fn decompile(
     // wait for the process to finish, or kill it after <timeout> milliseconds
     if (tokio::time::timeout(Duration::from_millis(timeout), cmd.wait()).await).is_err() {
         let _ = cmd.kill().await;
-        println!("Contract {:?} decompilation timed out", address);
-        let _ = tokio::fs::remove_dir_all(format!(".tmp/{}/", address)).await;
+        info!("Contract {:?} decompilation timed out", address);
+        dir.failed(address, keep_output);
         return Err(DecompilationError::Timeout);
     }
 
-    let json = &tokio::fs::read_to_string(format!(".tmp/{}/abi.json", address).as_str()).await;
+    let json = &tokio::fs::read_to_string(dir.path.join("abi.json")).await;
 
     if json.is_err() {
         let _ = cmd.kill().await;
-        println!("No ABI for {:?}.", address);
-        let _ = tokio::fs::remove_dir_all(format!(".tmp/{}/", address)).await;
+        info!("No ABI for {:?}.", address);
+        dir.failed(address, keep_output);
         return Err(DecompilationError::FailedToReadABI);
     }
 
@@ -49,19 +169,27 @@ pub async fn decompile(
 
     if abi.is_err() {
         let _ = cmd.kill().await;
-        println!(
+        info!(
             "Contract {:?} failed to parse abi.json. Error: {}",
             address,
             abi.err().unwrap()
         );
-        let _ = tokio::fs::remove_dir_all(format!(".tmp/{}/", address)).await;
+        dir.failed(address, keep_output);
         return Err(DecompilationError::FailedToParseABI);
     }
 
-    // finally delete the directory
-    let _ = tokio::fs::remove_dir_all(format!(".tmp/{}/", address)).await;
+    let source = if include_source {
+        tokio::fs::read_to_string(dir.path.join("decompiled.sol"))
+            .await
+            .ok()
+    } else {
+        None
+    };
 
-    Ok(abi.unwrap())
+    Ok(Decompiled {
+        abi: abi.unwrap(),
+        source,
+    })
 }
 
 #[cfg(test)]
@@ -72,11 +200,78 @@ mod tests {
     use primitive_types::H256;
 
     use crate::{
-        extraction::traces::get_traces,
+        extraction::traces::{get_traces, TraceMethod},
         models::{abi::ContractABI, contract_deployment::ContractDeployment},
-        utils::decompile::decompile,
+        utils::decompile::{decompile, default_tmp_dir, parse_version, scaled_timeout, OutputDir},
     };
 
+    #[test]
+    fn test_scaled_timeout() {
+        // up to one decompilation per core the timeout is unchanged
+        assert_eq!(scaled_timeout(5000, 30000, 0, 8), 5000);
+        assert_eq!(scaled_timeout(5000, 30000, 8, 8), 5000);
+        // then it grows with the decompilations per core
+        assert_eq!(scaled_timeout(5000, 30000, 9, 8), 10000);
+        assert_eq!(scaled_timeout(5000, 30000, 24, 8), 15000);
+        // without exceeding the max timeout
+        assert_eq!(scaled_timeout(5000, 30000, 100, 8), 30000);
+        // a max lower than the base timeout doesn't shorten it
+        assert_eq!(scaled_timeout(5000, 1000, 100, 8), 5000);
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("heimdall 0.7.3\n"), Some("0.7.3".to_string()));
+        assert_eq!(parse_version("0.8.0"), Some("0.8.0".to_string()));
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_keep_decompiler_output() {
+        let tmp_dir = default_tmp_dir();
+        let address = ethabi::Address::from_low_u64_be(0x1388);
+        let failed = |keep_output| {
+            let mut dir = OutputDir::new(&tmp_dir, &address);
+            std::fs::create_dir_all(&dir.path).unwrap();
+            std::fs::write(dir.path.join("abi.json"), "[").unwrap();
+            dir.failed(&address, keep_output);
+            dir.path.clone()
+        };
+
+        let dir = failed(true);
+        assert!(dir.join("abi.json").exists());
+        let dir = failed(false);
+        assert!(!dir.exists());
+        let _ = std::fs::remove_dir(&tmp_dir);
+    }
+
+    #[test]
+    fn test_output_dir_guard() {
+        let tmp_dir = default_tmp_dir().join("guard");
+        let address = ethabi::Address::from_low_u64_be(0x1389);
+        let read_abi = |tmp_dir: &std::path::Path| -> Result<String, std::io::Error> {
+            let dir = OutputDir::new(tmp_dir, &address);
+            std::fs::create_dir_all(&dir.path)?;
+            std::fs::write(dir.path.join("decompiled.sol"), "contract C {}")?;
+            // no abi.json, returns before reaching the end
+            std::fs::read_to_string(dir.path.join("abi.json"))
+        };
+
+        assert!(read_abi(&tmp_dir).is_err());
+        assert!(!tmp_dir.join(format!("{:?}", address)).exists());
+
+        // and on panic
+        let dir = tmp_dir.join(format!("{:?}", address));
+        let panicked = std::panic::catch_unwind(|| {
+            let dir = OutputDir::new(&tmp_dir, &address);
+            std::fs::create_dir_all(&dir.path).unwrap();
+            panic!("decompilation panicked");
+        });
+        assert!(panicked.is_err());
+        assert!(!dir.exists());
+        let _ = std::fs::remove_dir(&tmp_dir);
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_decompilation_cache_precision() {
@@ -93,16 +288,22 @@ mod tests {
         let mut skeleton_abis: HashMap<H256, ContractABI> = HashMap::new();
 
         while block <= to {
-            let creation_traces = get_traces(block, eth_client.clone()).await.unwrap();
+            let creation_traces = get_traces(block, eth_client.clone(), TraceMethod::Parity)
+                .await
+                .unwrap();
             let deployments: Vec<ContractDeployment> = Vec::from(creation_traces);
 
             for deployment in deployments {
                 let abi = decompile(
                     &deployment.contract_address(),
                     &deployment.deployed_code(),
+                    &default_tmp_dir(),
                     2000,
+                    false,
+                    false,
                 )
-                .await;
+                .await
+                .map(|decompiled| decompiled.abi);
 
                 if abi.is_err() {
                     continue;
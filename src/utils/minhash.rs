@@ -0,0 +1,119 @@
+use ethers::utils::keccak256;
+
+/// SplitMix64 finalizer, used to derive the independent hash functions of the signature
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// MinHash signature of a set, e.g. the names of a skeleton's functions, events and errors.
+/// The i-th value is the minimum of the i-th hash function over the items, so signatures
+/// of different sizes share their common prefix and can still be compared.
+/// The signature of an empty set is empty.
+/// @param items The items of the set, duplicates don't change the signature
+/// @param size The number of hash functions, i.e. the length of the signature
+/// @return The signature
+pub fn minhash<I: AsRef<[u8]>>(items: impl IntoIterator<Item = I>, size: usize) -> Vec<u64> {
+    let hashes = items
+        .into_iter()
+        .map(|item| {
+            let hash = keccak256(item.as_ref());
+            u64::from_be_bytes(hash[..8].try_into().unwrap())
+        })
+        .collect::<Vec<u64>>();
+    if hashes.is_empty() {
+        return Vec::new();
+    }
+    (0..size as u64)
+        .map(|i| {
+            let seed = mix(i);
+            hashes.iter().map(|h| mix(h ^ seed)).min().unwrap()
+        })
+        .collect()
+}
+
+/// Estimate of the Jaccard similarity of two sets from their signatures, i.e. the fraction
+/// of equal values over the common prefix. Returns 0 if either signature is empty
+pub fn minhash_similarity(a: &[u64], b: &[u64]) -> f64 {
+    let len = a.len().min(b.len());
+    if len == 0 {
+        return 0.0;
+    }
+    let equal = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    equal as f64 / len as f64
+}
+
+/// Hex encoding of a signature, 16 characters per value, as stored in `Skeleton.minhash`
+pub fn encode_signature(signature: &[u64]) -> String {
+    signature.iter().map(|v| format!("{:016x}", v)).collect()
+}
+
+/// Decode a signature encoded with `encode_signature`
+pub fn decode_signature(encoded: &str) -> Option<Vec<u64>> {
+    if !encoded.len().is_multiple_of(16) {
+        return None;
+    }
+    (0..encoded.len())
+        .step_by(16)
+        .map(|i| u64::from_str_radix(encoded.get(i..i + 16)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_signature, encode_signature, minhash, minhash_similarity};
+    use std::collections::HashSet;
+
+    fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+        a.intersection(b).count() as f64 / a.union(b).count() as f64
+    }
+
+    #[test]
+    fn test_minhash() {
+        let a = ["transfer", "approve", "balanceOf"];
+        let b = ["balanceOf", "transfer", "approve", "approve"];
+        // order and duplicates don't matter
+        assert_eq!(minhash(a, 64), minhash(b, 64));
+        assert_eq!(minhash_similarity(&minhash(a, 64), &minhash(b, 64)), 1.0);
+        // shorter signatures are a prefix of longer ones
+        assert_eq!(minhash(a, 16), minhash(a, 64)[..16]);
+        assert!(minhash(Vec::<&str>::new(), 64).is_empty());
+        assert_eq!(minhash_similarity(&[], &minhash(a, 64)), 0.0);
+    }
+
+    #[test]
+    fn test_jaccard_estimation() {
+        // pairs of sets with a Jaccard similarity from 0 to 1
+        let size = 256;
+        for shared in [0usize, 10, 25, 50, 75, 100] {
+            let a = (0..100)
+                .map(|i| format!("f{}", i))
+                .collect::<HashSet<String>>();
+            let b = (100 - shared..200 - shared)
+                .map(|i| format!("f{}", i))
+                .collect::<HashSet<String>>();
+            let exact = jaccard(&a, &b);
+            let estimate = minhash_similarity(&minhash(&a, size), &minhash(&b, size));
+            // the standard error is sqrt(J(1-J)/size), at most ~0.03 with 256 values
+            assert!(
+                (exact - estimate).abs() < 0.1,
+                "shared {}: exact {} estimate {}",
+                shared,
+                exact,
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn test_signature_encoding() {
+        let signature = minhash(["transfer", "approve"], 8);
+        let encoded = encode_signature(&signature);
+        assert_eq!(encoded.len(), 8 * 16);
+        assert_eq!(decode_signature(&encoded), Some(signature));
+        assert_eq!(decode_signature("abc"), None);
+        assert_eq!(decode_signature("zzzzzzzzzzzzzzzz"), None);
+    }
+}
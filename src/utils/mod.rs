@@ -1,3 +1,10 @@
+pub mod addresses;
+pub mod config;
 pub mod decompile;
 pub mod metadata;
+pub mod minhash;
+pub mod queries;
+pub mod rdf;
+pub mod schema;
 pub mod skeleton;
+pub mod verbosity;
@@ -0,0 +1,220 @@
+use crate::models::predicates::*;
+
+/// A ready-to-run DQL query, with a short description of what it returns
+pub struct SampleQuery {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub query: String,
+}
+
+/// Build the sample DQL queries for the schema produced by eth2dgraph.
+/// Parameters are declared as DQL variables with a default value, so the queries
+/// can be run as they are or with different variables.
+/// @param address The account address used as default value of `$address`
+/// @param block The block number used as default value of `$block`
+/// @return The list of sample queries
+pub fn sample_queries(address: &str, block: u64) -> Vec<SampleQuery> {
+    vec![
+        SampleQuery {
+            name: "erc20_deployments_by_creator",
+            description: "ERC-20 like contracts deployed by an account",
+            query: format!(
+                r#"query q($address: string = "{address}") {{
+  q(func: eq({ACCOUNT_ADDRESS}, $address)) {{
+    address: {ACCOUNT_ADDRESS}
+    deployments: ~{CONTRACT_DEPLOYMENT_CREATOR} @cascade {{
+      tx: {CONTRACT_DEPLOYMENT_TX_HASH}
+      name: {CONTRACT_DEPLOYMENT_NAME}
+      contract: {CONTRACT_DEPLOYMENT_CONTRACT} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+      skeleton: {CONTRACT_DEPLOYMENT_SKELETON} @filter(ge({SKELETON_ERC20_COMPLIANCY}, 5)) {{
+        uid
+        compliancy: {SKELETON_ERC20_COMPLIANCY}
+      }}
+    }}
+  }}
+}}"#
+            ),
+        },
+        SampleQuery {
+            name: "contract_lifetime",
+            description: "Deployment and destruction blocks of a contract",
+            query: format!(
+                r#"query q($address: string = "{address}") {{
+  q(func: eq({ACCOUNT_ADDRESS}, $address)) {{
+    address: {ACCOUNT_ADDRESS}
+    deployments: ~{CONTRACT_DEPLOYMENT_CONTRACT} {{
+      tx: {CONTRACT_DEPLOYMENT_TX_HASH}
+      failed: {CONTRACT_DEPLOYMENT_FAILED}
+      block: {CONTRACT_DEPLOYMENT_BLOCK} {{
+        number: {BLOCK_NUMBER}
+        datetime: {BLOCK_DATETIME}
+      }}
+    }}
+    destructions: ~{CONTRACT_DESTRUCTION_CONTRACT} {{
+      tx: {CONTRACT_DESTRUCTION_TX_HASH}
+      block: {CONTRACT_DESTRUCTION_BLOCK} {{
+        number: {BLOCK_NUMBER}
+        datetime: {BLOCK_DATETIME}
+      }}
+    }}
+  }}
+}}"#
+            ),
+        },
+        SampleQuery {
+            name: "similar_skeletons",
+            description: "Contracts sharing code or interface with the skeleton of a contract",
+            query: format!(
+                r#"query q($address: string = "{address}") {{
+  q(func: eq({ACCOUNT_ADDRESS}, $address)) {{
+    ~{CONTRACT_DEPLOYMENT_CONTRACT} {{
+      {CONTRACT_DEPLOYMENT_SKELETON} {{
+        uid
+        functions: {SKELETON_FUNCTIONS} {{
+          name: {FUNCTION_NAME}
+        }}
+        similar_code: {SKELETON_SIMILAR_CODE} {{
+          contracts: ~{CONTRACT_DEPLOYMENT_SKELETON} (first: 10) {{
+            {CONTRACT_DEPLOYMENT_CONTRACT} {{
+              address: {ACCOUNT_ADDRESS}
+            }}
+          }}
+        }}
+        similar_interface: {SKELETON_SIMILAR_INTERFACE} {{
+          contracts: ~{CONTRACT_DEPLOYMENT_SKELETON} (first: 10) {{
+            {CONTRACT_DEPLOYMENT_CONTRACT} {{
+              address: {ACCOUNT_ADDRESS}
+            }}
+          }}
+        }}
+      }}
+    }}
+  }}
+}}"#
+            ),
+        },
+        SampleQuery {
+            name: "block_content",
+            description: "Deployments, destructions and transactions of a block",
+            query: format!(
+                r#"query q($block: int = {block}) {{
+  q(func: eq({BLOCK_NUMBER}, $block)) {{
+    number: {BLOCK_NUMBER}
+    datetime: {BLOCK_DATETIME}
+    tx_count: {BLOCK_TX_COUNT}
+    gas_used: {BLOCK_GAS_USED}
+    miner: {BLOCK_MINER} {{
+      address: {ACCOUNT_ADDRESS}
+    }}
+    deployments: ~{CONTRACT_DEPLOYMENT_BLOCK} {{
+      contract: {CONTRACT_DEPLOYMENT_CONTRACT} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+    }}
+    destructions: ~{CONTRACT_DESTRUCTION_BLOCK} {{
+      contract: {CONTRACT_DESTRUCTION_CONTRACT} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+    }}
+    transactions: ~{TRANSACTION_BLOCK} (first: 100) {{
+      hash: {TRANSACTION_HASH}
+      value: {TRANSACTION_VALUE}
+      from: {TRANSACTION_FROM} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+      to: {TRANSACTION_TO} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+    }}
+  }}
+}}"#
+            ),
+        },
+        SampleQuery {
+            name: "token_transfers",
+            description: "Token transfers sent and received by an account",
+            query: format!(
+                r#"query q($address: string = "{address}") {{
+  q(func: eq({ACCOUNT_ADDRESS}, $address)) {{
+    sent: ~{TOKEN_TRANSFER_FROM} (first: 100) {{
+      value: {TOKEN_TRANSFER_VALUE}
+      token: {TOKEN_TRANSFER_CONTRACT} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+      to: {TOKEN_TRANSFER_TO} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+      block: {TOKEN_TRANSFER_BLOCK} {{
+        number: {BLOCK_NUMBER}
+      }}
+    }}
+    received: ~{TOKEN_TRANSFER_TO} (first: 100) {{
+      value: {TOKEN_TRANSFER_VALUE}
+      token: {TOKEN_TRANSFER_CONTRACT} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+      from: {TOKEN_TRANSFER_FROM} {{
+        address: {ACCOUNT_ADDRESS}
+      }}
+      block: {TOKEN_TRANSFER_BLOCK} {{
+        number: {BLOCK_NUMBER}
+      }}
+    }}
+  }}
+}}"#
+            ),
+        },
+        SampleQuery {
+            name: "contract_logs",
+            description: "Logs emitted by a contract in a block",
+            query: format!(
+                r#"query q($address: string = "{address}", $block: int = {block}) {{
+  var(func: eq({BLOCK_NUMBER}, $block)) {{
+    B as uid
+  }}
+  q(func: eq({ACCOUNT_ADDRESS}, $address)) {{
+    logs: ~{LOG_CONTRACT} @filter(uid_in({LOG_BLOCK}, uid(B))) {{
+      index: {LOG_INDEX}
+      topic_0: {LOG_TOPIC_0}
+      data: {LOG_DATA}
+    }}
+  }}
+}}"#
+            ),
+        },
+    ]
+}
+
+/// Print the sample queries to stdout
+pub fn print_sample_queries(address: &str, block: u64) {
+    for sample in sample_queries(address, block) {
+        println!("# {}: {}", sample.name, sample.description);
+        println!("{}", sample.query);
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sample_queries;
+
+    #[test]
+    fn test_sample_queries() {
+        let address = "0x06012c8cf97bead5deae237070f9587f8e7a266d";
+        let queries = sample_queries(address, 16000000);
+        assert!(!queries.is_empty());
+        for sample in queries {
+            // braces of the generated queries must be balanced
+            let open = sample.query.matches('{').count();
+            let close = sample.query.matches('}').count();
+            assert_eq!(open, close, "unbalanced braces in {}", sample.name);
+            assert!(
+                sample.query.contains(address) || sample.query.contains("16000000"),
+                "missing parameter in {}",
+                sample.name
+            );
+        }
+    }
+}
@@ -0,0 +1,29 @@
+/// Escape a free-form string to be used as a quoted literal in N-Quads,
+/// so that quotes, backslashes and line breaks don't break the upserts
+pub fn escape_rdf(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_rdf;
+
+    #[test]
+    fn test_escape_rdf() {
+        assert_eq!(escape_rdf("Token"), "Token");
+        assert_eq!(escape_rdf(r#"My "Token""#), r#"My \"Token\""#);
+        assert_eq!(escape_rdf(r"C:\path"), r"C:\\path");
+        assert_eq!(escape_rdf("line\nbreak\r\tend"), r"line\nbreak\r\tend");
+    }
+}
@@ -0,0 +1,181 @@
+//! Startup check of the Dgraph schema.
+//! Without `dgraph/contracts.schema` Dgraph accepts the mutations anyway, creating untyped
+//! predicates without indexes: upserts can't find the existing nodes and queries return nothing.
+
+use crate::models::predicates::*;
+use dgraph_tonic::{Client, Query};
+use serde::Deserialize;
+
+/// A predicate the upserts and queries rely on, with the index or reverse edge they need
+struct Requirement {
+    predicate: &'static str,
+    tokenizer: Option<&'static str>,
+    reverse: bool,
+}
+
+const fn indexed(predicate: &'static str, tokenizer: &'static str) -> Requirement {
+    Requirement {
+        predicate,
+        tokenizer: Some(tokenizer),
+        reverse: false,
+    }
+}
+
+const fn reversed(predicate: &'static str) -> Requirement {
+    Requirement {
+        predicate,
+        tokenizer: None,
+        reverse: true,
+    }
+}
+
+const REQUIREMENTS: [Requirement; 13] = [
+    indexed(ACCOUNT_ADDRESS, "hash"),
+    indexed(BLOCK_NUMBER, "int"),
+    indexed(TRANSACTION_HASH, "hash"),
+    indexed(FUNCTION_SIGNATURE, "hash"),
+    indexed(EVENT_SIGNATURE, "hash"),
+    indexed(ERROR_SIGNATURE, "hash"),
+    indexed(SKELETON_BYTECODE, "hash"),
+    indexed(CONTRACT_DEPLOYMENT_KEY, "hash"),
+    reversed(CONTRACT_DEPLOYMENT_CONTRACT),
+    reversed(CONTRACT_DEPLOYMENT_BLOCK),
+    reversed(CONTRACT_DESTRUCTION_CONTRACT),
+    reversed(SKELETON_FUNCTIONS),
+    reversed(SKELETON_EVENTS),
+];
+
+#[derive(Deserialize, Debug)]
+struct PredicateSchema {
+    predicate: String,
+    #[serde(default)]
+    tokenizer: Vec<String>,
+    #[serde(default)]
+    reverse: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct SchemaResult {
+    #[serde(default)]
+    schema: Vec<PredicateSchema>,
+}
+
+fn schema_query() -> String {
+    let predicates = REQUIREMENTS
+        .iter()
+        .map(|r| r.predicate)
+        .collect::<Vec<&str>>()
+        .join(", ");
+    format!(
+        "schema(pred: [{}]) {{ type index tokenizer reverse }}",
+        predicates
+    )
+}
+
+/// Compare the response of the schema query with the requirements.
+/// Returns a description of each missing predicate, index or reverse edge
+fn missing_requirements(response: &[u8]) -> Result<Vec<String>, serde_json::Error> {
+    let schema: SchemaResult = serde_json::from_slice(response)?;
+    let missing = REQUIREMENTS
+        .iter()
+        .filter_map(|r| {
+            let found = schema.schema.iter().find(|p| p.predicate == r.predicate);
+            match (found, r.tokenizer) {
+                (None, _) => Some(format!("{} is not defined", r.predicate)),
+                (Some(p), Some(tokenizer)) if !p.tokenizer.iter().any(|t| t == tokenizer) => {
+                    Some(format!("{} has no @index({})", r.predicate, tokenizer))
+                }
+                (Some(p), _) if r.reverse && !p.reverse => {
+                    Some(format!("{} has no @reverse", r.predicate))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    Ok(missing)
+}
+
+/// Check that Dgraph is reachable and has the schema of eth2dgraph.
+/// A missing schema is reported as a warning, or as an error with `strict`.
+/// @param endpoint The Dgraph GRPC endpoint
+/// @param strict Whether a missing predicate or index is an error
+/// @return Err with the reason if Dgraph is unreachable, or if the schema is missing in strict mode
+pub async fn check_schema(endpoint: &str, strict: bool) -> Result<(), String> {
+    let client = Client::new(endpoint).map_err(|e| format!("invalid Dgraph endpoint: {}", e))?;
+    let resp = client
+        .new_read_only_txn()
+        .query(schema_query())
+        .await
+        .map_err(|e| format!("could not reach Dgraph at {}: {}", endpoint, e))?;
+    let missing = missing_requirements(&resp.json)
+        .map_err(|e| format!("unexpected schema response: {}", e))?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let level = if strict { "ERROR" } else { "WARNING" };
+    println!(
+        "{}: the Dgraph schema at {} is incomplete, upserts will create duplicates and queries return nothing:",
+        level, endpoint
+    );
+    for m in &missing {
+        println!("  - {}", m);
+    }
+    println!(
+        "Apply the schema shipped in dgraph/contracts.schema, e.g. with: curl -X POST <dgraph-alpha>:8080/alter --data-binary @dgraph/contracts.schema"
+    );
+    if strict {
+        return Err(format!("{} schema requirements missing", missing.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{missing_requirements, schema_query};
+
+    #[test]
+    fn test_missing_requirements() {
+        assert!(schema_query().starts_with("schema(pred: [Account.address, Block.number,"));
+
+        // empty Dgraph
+        let missing = missing_requirements(br#"{"schema":[]}"#).unwrap();
+        assert_eq!(missing.len(), 13);
+        assert_eq!(missing[0], "Account.address is not defined");
+
+        let schema = std::fs::read_to_string("dgraph/contracts.schema").unwrap();
+        let predicate = |line: &str| {
+            let (name, rest) = line.trim_start_matches('<').split_once(">:")?;
+            let tokenizer = rest
+                .split_once("@index(")
+                .map(|(_, t)| t.split(')').next().unwrap().split(',').map(|t| t.trim()))
+                .map(|t| t.map(|t| format!("\"{}\"", t)).collect::<Vec<String>>())
+                .unwrap_or_default();
+            Some(format!(
+                r#"{{"predicate":"{}","tokenizer":[{}],"reverse":{}}}"#,
+                name,
+                tokenizer.join(","),
+                rest.contains("@reverse")
+            ))
+        };
+        // the schema shipped with the repo satisfies all the requirements
+        let predicates = schema
+            .lines()
+            .filter(|l| l.starts_with('<'))
+            .filter_map(predicate)
+            .collect::<Vec<String>>();
+        let response = format!(r#"{{"schema":[{}]}}"#, predicates.join(","));
+        assert!(missing_requirements(response.as_bytes())
+            .unwrap()
+            .is_empty());
+
+        // predicate created by a mutation, without index
+        let response = response.replace(
+            r#"{"predicate":"Account.address","tokenizer":["hash"],"reverse":false}"#,
+            r#"{"predicate":"Account.address"}"#,
+        );
+        assert_eq!(
+            missing_requirements(response.as_bytes()).unwrap(),
+            vec!["Account.address has no @index(hash)"]
+        );
+    }
+}
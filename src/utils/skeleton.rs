@@ -9,22 +9,41 @@ pub fn extract_skeleton(deployed_bytecode: &[u8]) -> Bytes {
     let mut result = deployed_bytecode.to_vec();
     let mut i = 0;
     while i < result.len() {
-        if result[i] >= 0x60 && result[i] <= 0x7f {
-            let remaining = result.len() - i - 1;
-            let to_push = min((result[i] - 0x5f) as usize, remaining);
-            for j in 0..to_push {
-                result[i + j + 1] = 0x00;
-            }
-            i += to_push;
+        let remaining = result.len() - i - 1;
+        let to_push = min(push_size(result[i]), remaining);
+        for j in 0..to_push {
+            result[i + j + 1] = 0x00;
         }
-        i += 1;
+        i += to_push + 1;
     }
     Bytes::from(result)
 }
 
+/// Number of bytes pushed by an opcode, 0 for all the opcodes but PUSH1 to PUSH32
+fn push_size(opcode: u8) -> usize {
+    if (0x60..=0x7f).contains(&opcode) {
+        (opcode - 0x5f) as usize
+    } else {
+        0
+    }
+}
+
+/// Opcodes of a bytecode, without the arguments of the PUSH ones
+pub fn opcodes(bytecode: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        let opcode = *bytecode.get(i)?;
+        i += push_size(opcode) + 1;
+        Some(opcode)
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::utils::{metadata::separate_metadata, skeleton::extract_skeleton};
+    use crate::utils::{
+        metadata::separate_metadata,
+        skeleton::{extract_skeleton, opcodes},
+    };
     use ethers::types::Bytes;
     use std::io::Write;
     use std::{collections::HashMap, str::FromStr};
@@ -33,6 +52,20 @@ mod tests {
         io::{AsyncBufReadExt, BufReader},
     };
 
+    #[test]
+    fn test_opcodes() {
+        // PUSH2 0x60f0 PUSH1 0x00 CREATE, truncated PUSH32 at the end
+        let bytecode = [0x61, 0x60, 0xf0, 0x60, 0x00, 0xf0, 0x7f, 0xf5];
+        assert_eq!(
+            opcodes(&bytecode).collect::<Vec<_>>(),
+            vec![0x61, 0x60, 0xf0, 0x7f]
+        );
+        assert_eq!(
+            extract_skeleton(&bytecode),
+            Bytes::from(vec![0x61, 0x00, 0x00, 0x60, 0x00, 0xf0, 0x7f, 0x00])
+        );
+    }
+
     #[tokio::test]
     async fn test_extract_skeleton() {
         // credits for test data to: https://github.com/gsalzer/ethutils
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much is printed while running, errors are always printed on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors
+    Quiet = 0,
+    /// Progress and summaries, the default
+    Info = 1,
+    /// Also the details of each request to Dgraph
+    Verbose = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Info as u8);
+
+/// Set the verbosity of the whole process, called once at startup
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of the given level are printed
+pub fn enabled(level: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// `println!` unless running with `--quiet`
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::utils::verbosity::enabled($crate::utils::verbosity::Verbosity::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// `println!` only when running with `--verbose`
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::utils::verbosity::enabled($crate::utils::verbosity::Verbosity::Verbose) {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enabled, set_verbosity, Verbosity};
+
+    #[test]
+    fn test_verbosity() {
+        assert!(enabled(Verbosity::Info));
+        assert!(!enabled(Verbosity::Verbose));
+        set_verbosity(Verbosity::Quiet);
+        assert!(enabled(Verbosity::Quiet));
+        assert!(!enabled(Verbosity::Info));
+        set_verbosity(Verbosity::Verbose);
+        assert!(enabled(Verbosity::Info) && enabled(Verbosity::Verbose));
+        set_verbosity(Verbosity::Info);
+    }
+}